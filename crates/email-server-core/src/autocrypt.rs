@@ -0,0 +1,263 @@
+use crate::message::{self, Handler, HandlerError, Message};
+use crate::mime;
+use crate::pgp::PublicKey;
+use crate::smtp::address;
+use crate::storage::{AutocryptPeer, SqliteStore};
+use crate::time::unix_time;
+use async_trait::async_trait;
+use base64::Engine;
+
+/// A valid `Autocrypt` header (Autocrypt Level 1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutocryptHeader {
+    pub addr: String,
+    pub prefer_encrypt: bool,
+    /// The sender's key in binary form.
+    pub keydata: Vec<u8>,
+}
+
+impl AutocryptHeader {
+    /// Parses an unfolded header value. Unknown attributes make the header
+    /// invalid unless they start with an underscore.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (mut addr, mut prefer_encrypt, mut keydata) = (None, false, None);
+        for attribute in value.split(';') {
+            let (name, value) = attribute.trim().split_once('=')?;
+            match name.trim() {
+                "addr" => addr = Some(value.trim().to_ascii_lowercase()),
+                "prefer-encrypt" => prefer_encrypt = value.trim() == "mutual",
+                "keydata" => {
+                    let encoded: String = value.split_whitespace().collect();
+                    keydata = base64::engine::general_purpose::STANDARD
+                        .decode(encoded)
+                        .ok();
+                }
+                name if name.starts_with('_') => {}
+                _ => return None,
+            }
+        }
+        Some(Self {
+            addr: addr?,
+            prefer_encrypt,
+            keydata: keydata?,
+        })
+    }
+
+    /// Formats the header field, folded, with its line ending.
+    pub fn to_field(&self) -> String {
+        let mut field = format!("Autocrypt: addr={};", self.addr);
+        if self.prefer_encrypt {
+            field.push_str(" prefer-encrypt=mutual;");
+        }
+        field.push_str(" keydata=");
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&self.keydata);
+        for line in encoded.as_bytes().chunks(76) {
+            field.push_str("\r\n ");
+            field.push_str(std::str::from_utf8(line).unwrap_or_default());
+        }
+        field.push_str("\r\n");
+        field
+    }
+}
+
+/// Returns `message` with the sender's `Autocrypt` header prepended, or
+/// `None` if the sender has no registered key, the message already carries
+/// a header or it is a report.
+pub async fn announce(store: &SqliteStore, message: &[u8]) -> Option<Vec<u8>> {
+    let part = mime::Part::parse(message);
+    if part.header("autocrypt").is_some() || part.content_type().value == "multipart/report" {
+        return None;
+    }
+    let from = sender(&part)?;
+    let keydata = match store.pgp_key(&from).await {
+        Ok(key) => key?,
+        Err(e) => {
+            tracing::warn!("failed to look up the key of {}: {}", from, e);
+            return None;
+        }
+    };
+    let header = AutocryptHeader {
+        addr: from,
+        prefer_encrypt: false,
+        keydata,
+    };
+    Some([header.to_field().as_bytes(), message].concat())
+}
+
+/// Records the keys peers announce in inbound mail in the keyring of each
+/// recipient. This never fails the message.
+pub struct AutocryptHandler {
+    store: SqliteStore,
+    inner: Box<dyn Handler + Send + Sync>,
+}
+
+impl AutocryptHandler {
+    pub fn new(store: SqliteStore, inner: Box<dyn Handler + Send + Sync>) -> Self {
+        Self { store, inner }
+    }
+
+    async fn collect(&self, message: &Message, part: &mime::Part<'_>) {
+        // Autocrypt ignores bounces and other reports.
+        if part.content_type().value == "multipart/report" {
+            return;
+        }
+        let Some(from) = sender(part) else {
+            return;
+        };
+        let headers: Vec<_> = part
+            .headers()
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("autocrypt"))
+            .filter_map(|h| AutocryptHeader::parse(&h.value()))
+            .filter(|h| h.addr == from)
+            .collect();
+        // More than one valid header is treated as none.
+        let [header] = &headers[..] else {
+            return;
+        };
+        if PublicKey::parse(&header.keydata).is_err() {
+            return;
+        }
+        let peer = AutocryptPeer {
+            address: from,
+            key: header.keydata.clone(),
+            prefer_encrypt: header.prefer_encrypt,
+            last_seen: unix_time(),
+        };
+        for recipient in &message.recipients {
+            if let Err(e) = self
+                .store
+                .update_autocrypt_peer(&recipient.address, &peer)
+                .await
+            {
+                tracing::warn!("failed to record Autocrypt key of {}: {}", peer.address, e);
+            }
+        }
+    }
+}
+
+/// The lowercased address in the `From` header.
+fn sender(part: &mime::Part) -> Option<String> {
+    let from = part.header("from")?;
+    let mailbox = address::mailbox(&from);
+    address::split(mailbox)?;
+    Some(mailbox.to_ascii_lowercase())
+}
+
+#[async_trait]
+impl Handler for AutocryptHandler {
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+        message::summarize(&self.handle_recipients(message).await)
+    }
+
+    async fn handle_recipients(&self, message: &Message) -> Vec<Result<(), HandlerError>> {
+        // Only headers are looked at.
+        match message.data.split_header().await {
+            Ok((header, _)) => self.collect(message, &mime::Part::parse(&header)).await,
+            Err(e) => return vec![Err(e.into()); message.recipients.len()],
+        }
+        self.inner.handle_recipients(message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Recipient;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Capture(Mutex<Vec<Vec<u8>>>);
+
+    #[async_trait]
+    impl Handler for Arc<Capture> {
+        async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+            let data = message.data.to_vec().await?;
+            self.0.lock().unwrap().push(data);
+            Ok(())
+        }
+    }
+
+    fn key() -> Vec<u8> {
+        let key = PublicKey::parse(include_bytes!("../tests/data/bob.asc")).unwrap();
+        key.to_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_header() {
+        let header = AutocryptHeader {
+            addr: "bob@example.com".to_string(),
+            prefer_encrypt: true,
+            keydata: key(),
+        };
+        let field = header.to_field();
+        assert!(field.lines().all(|line| line.len() <= 78));
+        let part = mime::Part::parse(field.as_bytes());
+        assert_eq!(
+            AutocryptHeader::parse(&part.header("autocrypt").unwrap()),
+            Some(header)
+        );
+
+        assert!(AutocryptHeader::parse("addr=a@b; keydata=AAAA; _ignored=1").is_some());
+        assert!(AutocryptHeader::parse("addr=a@b; keydata=AAAA; unknown=1").is_none());
+        assert!(AutocryptHeader::parse("addr=a@b").is_none());
+    }
+
+    async fn handler() -> (
+        AutocryptHandler,
+        SqliteStore,
+        Arc<Capture>,
+        tempfile::NamedTempFile,
+    ) {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let capture = Arc::new(Capture::default());
+        let handler = AutocryptHandler::new(store.clone(), Box::new(capture.clone()));
+        (handler, store, capture, temp_file)
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let (_, store, _, _file) = handler().await;
+        store.set_pgp_key("bob@example.com", &key()).await.unwrap();
+        let data = b"From: Bob <Bob@example.com>\r\nSubject: Away\r\n\r\nBack soon\r\n";
+        let sent = announce(&store, data).await.unwrap();
+        let header = mime::Part::parse(&sent).header("autocrypt").unwrap();
+        let header = AutocryptHeader::parse(&header).unwrap();
+        assert_eq!(header.addr, "bob@example.com");
+        assert_eq!(header.keydata, key());
+        assert!(sent.ends_with(data));
+
+        // Announced once, and only for senders with a key.
+        assert_eq!(announce(&store, &sent).await, None);
+        let data = b"From: carol@example.net\r\n\r\nHi\r\n";
+        assert_eq!(announce(&store, data).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_collect() {
+        let (handler, store, capture, _file) = handler().await;
+        let header = AutocryptHeader {
+            addr: "carol@example.net".to_string(),
+            prefer_encrypt: true,
+            keydata: key(),
+        };
+        for from in ["Carol <carol@example.net>", "mallory@example.net"] {
+            let data = format!("From: {}\r\n{}\r\nHello\r\n", from, header.to_field());
+            let message = Message {
+                recipients: vec![Recipient::new("bob@example.com")],
+                data: data.as_bytes().into(),
+                ..Default::default()
+            };
+            handler.handle_message(&message).await.unwrap();
+        }
+        assert_eq!(capture.0.lock().unwrap().len(), 2);
+
+        // Only the key whose `addr` matches `From` is learned.
+        let peers = store.autocrypt_peers("bob@example.com").await.unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].address, "carol@example.net");
+        assert!(peers[0].prefer_encrypt);
+        assert_eq!(peers[0].key, key());
+    }
+}
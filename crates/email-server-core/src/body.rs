@@ -0,0 +1,203 @@
+use std::io;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+
+pub const DEFAULT_SPOOL_THRESHOLD: usize = 1024 * 1024;
+
+/// Headers are read up to this size; anything after it is taken for body.
+pub const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// A message body that is kept in memory while small and spooled to a
+/// temporary file once it grows past the threshold.
+#[derive(Debug)]
+pub struct Body {
+    threshold: usize,
+    len: usize,
+    memory: Vec<u8>,
+    spool: Option<Spool>,
+}
+
+#[derive(Debug)]
+struct Spool {
+    file: NamedTempFile,
+    writer: tokio::fs::File,
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Self::new(DEFAULT_SPOOL_THRESHOLD)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(memory: Vec<u8>) -> Self {
+        Self {
+            threshold: DEFAULT_SPOOL_THRESHOLD.max(memory.len()),
+            len: memory.len(),
+            memory,
+            spool: None,
+        }
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(data: &[u8]) -> Self {
+        data.to_vec().into()
+    }
+}
+
+impl Body {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            len: 0,
+            memory: Vec::new(),
+            spool: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_spooled(&self) -> bool {
+        self.spool.is_some()
+    }
+
+    /// The size past which a body is spooled.
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.spool.is_none() && self.len + data.len() > self.threshold {
+            let file = NamedTempFile::new()?;
+            let mut writer = tokio::fs::File::from_std(file.reopen()?);
+            writer.write_all(&self.memory).await?;
+            self.memory = Vec::new();
+            self.spool = Some(Spool { file, writer });
+        }
+        match &mut self.spool {
+            Some(spool) => spool.writer.write_all(data).await?,
+            None => self.memory.extend_from_slice(data),
+        }
+        self.len += data.len();
+        Ok(())
+    }
+
+    /// Appends everything `reader` yields.
+    pub async fn write_from(&mut self, mut reader: impl AsyncRead + Unpin) -> io::Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.write(&buf[..n]).await?;
+        }
+    }
+
+    /// Flushes spooled data so that readers see all of it. Must be called
+    /// once writing is done.
+    pub async fn finish(&mut self) -> io::Result<()> {
+        if let Some(spool) = &mut self.spool {
+            spool.writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Returns a new reader positioned at the start of the body. Readers
+    /// are independent, so several handlers can read concurrently.
+    pub async fn reader(&self) -> io::Result<Box<dyn AsyncRead + Send + Unpin + '_>> {
+        match &self.spool {
+            Some(spool) => Ok(Box::new(tokio::fs::File::open(spool.file.path()).await?)),
+            None => Ok(Box::new(self.memory.as_slice())),
+        }
+    }
+
+    /// Reads the header, up to and including the empty line that ends it,
+    /// and returns it with a reader of the rest, so that handlers that
+    /// only look at headers need not load the whole body.
+    pub async fn split_header(
+        &self,
+    ) -> io::Result<(Vec<u8>, Box<dyn AsyncRead + Send + Unpin + '_>)> {
+        let mut reader = BufReader::new(self.reader().await?);
+        let mut header = vec![];
+        while header.len() < MAX_HEADER_SIZE {
+            let limit = (MAX_HEADER_SIZE - header.len()) as u64;
+            let start = header.len();
+            if (&mut reader)
+                .take(limit)
+                .read_until(b'\n', &mut header)
+                .await?
+                == 0
+            {
+                break;
+            }
+            if matches!(&header[start..], b"\r\n" | b"\n") {
+                break;
+            }
+        }
+        Ok((header, Box::new(reader)))
+    }
+
+    pub async fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.len);
+        self.reader().await?.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_small_body_stays_in_memory() {
+        let mut body = Body::new(16);
+        body.write(b"Hello, ").await.unwrap();
+        body.write(b"world!").await.unwrap();
+        body.finish().await.unwrap();
+        assert!(!body.is_spooled());
+        assert_eq!(body.len(), 13);
+        assert_eq!(body.to_vec().await.unwrap(), b"Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn test_large_body_spools_to_disk() {
+        let mut body = Body::new(8);
+        body.write(b"Hello, ").await.unwrap();
+        assert!(!body.is_spooled());
+        body.write(b"world!").await.unwrap();
+        body.finish().await.unwrap();
+        assert!(body.is_spooled());
+        assert_eq!(body.len(), 13);
+
+        let mut first = body.reader().await.unwrap();
+        let mut second = body.reader().await.unwrap();
+        let mut buf = [0; 5];
+        first.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello");
+        let mut rest = Vec::new();
+        second.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn test_split_header() {
+        let mut body = Body::new(8);
+        body.write(b"Subject: Hi\r\nTo: bob\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+        body.finish().await.unwrap();
+        let (header, mut rest) = body.split_header().await.unwrap();
+        assert_eq!(header, b"Subject: Hi\r\nTo: bob\r\n\r\n");
+        let mut data = Vec::new();
+        rest.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"Hello\r\n");
+    }
+}
@@ -0,0 +1,324 @@
+//! DNS blocklists. Connecting clients are looked up by address on DNSBLs,
+//! and the HELO and `MAIL FROM` domains by name on RHSBLs. A list names a
+//! host by answering an address record, by convention in `127.0.0.0/8`,
+//! whose value says why it is listed.
+
+use async_trait::async_trait;
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Looks up the addresses of names. One resolver is shared by all
+/// connections, so that its cache answers repeated lookups.
+#[async_trait]
+pub trait DnsResolver: Send + Sync + std::fmt::Debug {
+    /// The IPv4 addresses of `name`, none when it does not exist.
+    async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, ResolveError>;
+}
+
+#[async_trait]
+impl DnsResolver for TokioAsyncResolver {
+    async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, ResolveError> {
+        // Fully qualified, so that no search domain is appended.
+        match self.ipv4_lookup(format!("{}.", name)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|a| a.0).collect()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The resolver configured for the system, e.g. in `/etc/resolv.conf`.
+pub fn system_resolver() -> Result<Arc<dyn DnsResolver>, ResolveError> {
+    Ok(Arc::new(TokioAsyncResolver::tokio_from_system_conf()?))
+}
+
+/// Answers from records held in memory, e.g. a local copy of a list.
+#[derive(Debug, Default, Clone)]
+pub struct Zone {
+    records: HashMap<String, Vec<Ipv4Addr>>,
+}
+
+impl Zone {
+    pub fn insert(&mut self, name: &str, address: Ipv4Addr) {
+        self.records
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(address);
+    }
+}
+
+#[async_trait]
+impl DnsResolver for Zone {
+    async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, ResolveError> {
+        Ok(self
+            .records
+            .get(&name.to_ascii_lowercase())
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListKind {
+    /// Lists client addresses.
+    Address,
+    /// Lists domain names (right-hand side blocklist).
+    Domain,
+}
+
+#[derive(Debug)]
+pub enum BlocklistError {
+    MissingZone,
+    InvalidWeight(String),
+    InvalidCode(String),
+}
+
+impl Display for BlocklistError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            BlocklistError::MissingZone => write!(f, "MissingZone"),
+            BlocklistError::InvalidWeight(weight) => write!(f, "InvalidWeight: {}", weight),
+            BlocklistError::InvalidCode(code) => write!(f, "InvalidCode: {}", code),
+        }
+    }
+}
+
+impl std::error::Error for BlocklistError {}
+
+/// A DNS blocklist and how much a listing on it counts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blocklist {
+    pub zone: String,
+    pub weight: f64,
+    /// The answers that count as listed; any answer in `127.0.0.0/8` when
+    /// empty.
+    pub codes: Vec<Ipv4Addr>,
+}
+
+impl Blocklist {
+    pub fn new(zone: &str) -> Self {
+        Self {
+            zone: zone.trim_matches('.').to_ascii_lowercase(),
+            weight: 1.0,
+            codes: vec![],
+        }
+    }
+
+    fn lists(&self, answer: &Ipv4Addr) -> bool {
+        if self.codes.is_empty() {
+            answer.octets()[0] == 127
+        } else {
+            self.codes.contains(answer)
+        }
+    }
+}
+
+/// Parses `ZONE[=CODE,...][*WEIGHT]`, e.g. `zen.example=127.0.0.2,127.0.0.3*0.5`.
+impl FromStr for Blocklist {
+    type Err = BlocklistError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, weight) = match s.rsplit_once('*') {
+            Some((s, weight)) => (
+                s,
+                weight
+                    .parse()
+                    .map_err(|_| BlocklistError::InvalidWeight(weight.to_string()))?,
+            ),
+            None => (s, 1.0),
+        };
+        let (zone, codes) = s.split_once('=').unwrap_or((s, ""));
+        if zone.trim_matches('.').is_empty() {
+            return Err(BlocklistError::MissingZone);
+        }
+        let codes = codes
+            .split(',')
+            .filter(|code| !code.is_empty())
+            .map(|code| {
+                code.parse()
+                    .map_err(|_| BlocklistError::InvalidCode(code.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            weight,
+            codes,
+            ..Self::new(zone)
+        })
+    }
+}
+
+/// The lists that named a host, once their weights reach the threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub zones: Vec<String>,
+    pub score: f64,
+}
+
+/// The blocklists connections and sender domains are checked against.
+#[derive(Debug, Clone)]
+pub struct Blocklists {
+    resolver: Arc<dyn DnsResolver>,
+    addresses: Vec<Blocklist>,
+    domains: Vec<Blocklist>,
+    /// Hosts whose lists weigh at least this much are refused.
+    threshold: f64,
+}
+
+impl Blocklists {
+    pub fn new(resolver: Arc<dyn DnsResolver>) -> Self {
+        Self {
+            resolver,
+            addresses: vec![],
+            domains: vec![],
+            threshold: 1.0,
+        }
+    }
+
+    pub fn with_list(mut self, kind: ListKind, list: Blocklist) -> Self {
+        match kind {
+            ListKind::Address => self.addresses.push(list),
+            ListKind::Domain => self.domains.push(list),
+        }
+        self
+    }
+
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Looks `address` up on the address lists.
+    pub async fn check_address(&self, address: IpAddr) -> Option<Listing> {
+        self.check(&self.addresses, &reverse(address)).await
+    }
+
+    /// Looks `domain` up on the domain lists.
+    pub async fn check_domain(&self, domain: &str) -> Option<Listing> {
+        let domain = domain.trim_matches('.');
+        if domain.is_empty() || domain.starts_with('[') {
+            return None;
+        }
+        self.check(&self.domains, domain).await
+    }
+
+    /// Lists that cannot be reached do not count, so that an outage of a
+    /// list does not refuse all mail.
+    async fn check(&self, lists: &[Blocklist], name: &str) -> Option<Listing> {
+        let mut listing = Listing {
+            zones: vec![],
+            score: 0.0,
+        };
+        for list in lists {
+            let query = format!("{}.{}", name, list.zone);
+            match self.resolver.lookup_ipv4(&query).await {
+                Ok(answers) if answers.iter().any(|a| list.lists(a)) => {
+                    listing.zones.push(list.zone.clone());
+                    listing.score += list.weight;
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Looking up {} failed: {}", query, e),
+            }
+        }
+        (!listing.zones.is_empty() && listing.score >= self.threshold).then_some(listing)
+    }
+}
+
+/// The name an address is listed under: its octets, or the nibbles of an
+/// IPv6 address, in reverse.
+fn reverse(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{}.{}.{}.{}", d, c, b, a)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => reverse(IpAddr::V4(v4)),
+            None => v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|byte| [byte & 0xf, byte >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect::<Vec<_>>()
+                .join("."),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse() {
+        assert_eq!(reverse("192.0.2.1".parse().unwrap()), "1.2.0.192");
+        assert_eq!(reverse("::ffff:192.0.2.1".parse().unwrap()), "1.2.0.192");
+        assert_eq!(
+            reverse("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "Zen.Example.".parse::<Blocklist>().unwrap(),
+            Blocklist::new("zen.example")
+        );
+        let list: Blocklist = "zen.example=127.0.0.2,127.0.0.3*0.5".parse().unwrap();
+        assert_eq!(list.zone, "zen.example");
+        assert_eq!(list.weight, 0.5);
+        assert_eq!(
+            list.codes,
+            [Ipv4Addr::new(127, 0, 0, 2), Ipv4Addr::new(127, 0, 0, 3)]
+        );
+        assert!("=127.0.0.2".parse::<Blocklist>().is_err());
+        assert!("zen.example*x".parse::<Blocklist>().is_err());
+        assert!("zen.example=x".parse::<Blocklist>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let mut zone = Zone::default();
+        zone.insert("2.0.0.127.zen.example", Ipv4Addr::new(127, 0, 0, 2));
+        zone.insert("2.0.0.127.pbl.example", Ipv4Addr::new(127, 0, 0, 10));
+        zone.insert("2.0.0.127.weak.example", Ipv4Addr::new(127, 0, 0, 2));
+        zone.insert("3.0.0.127.weak.example", Ipv4Addr::new(127, 0, 0, 2));
+        zone.insert("spam.example.dbl.example", Ipv4Addr::new(127, 0, 1, 2));
+        // An error answer, not a listing.
+        zone.insert("error.example.dbl.example", Ipv4Addr::new(192, 0, 2, 1));
+        let lists = Blocklists::new(Arc::new(zone))
+            .with_list(ListKind::Address, "zen.example".parse().unwrap())
+            .with_list(ListKind::Address, "pbl.example=127.0.0.11".parse().unwrap())
+            .with_list(ListKind::Address, "weak.example*0.5".parse().unwrap())
+            .with_list(ListKind::Domain, "dbl.example".parse().unwrap());
+
+        let listing = lists.check_address("127.0.0.2".parse().unwrap()).await;
+        assert_eq!(
+            listing,
+            Some(Listing {
+                zones: vec!["zen.example".to_string(), "weak.example".to_string()],
+                score: 1.5
+            })
+        );
+        // Listed, but not weighty enough alone.
+        assert_eq!(
+            lists.check_address("127.0.0.3".parse().unwrap()).await,
+            None
+        );
+        assert_eq!(
+            lists.check_address("127.0.0.4".parse().unwrap()).await,
+            None
+        );
+
+        assert!(lists.check_domain("Spam.Example").await.is_some());
+        assert_eq!(lists.check_domain("error.example").await, None);
+        assert_eq!(lists.check_domain("[192.0.2.1]").await, None);
+        assert_eq!(lists.check_domain("").await, None);
+    }
+}
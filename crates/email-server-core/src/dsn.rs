@@ -0,0 +1,128 @@
+//! Delivery status notifications (RFC 3464) for recipients that mail
+//! accepted here could not reach.
+
+use crate::vacation;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How much of the original message is read to return its header.
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+
+/// A recipient the message could not be delivered to, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub recipient: String,
+    /// The `RCPT TO` it was reached by, if other than the recipient.
+    pub original_recipient: Option<String>,
+    pub reason: String,
+}
+
+/// The header of the message read from `reader`, without its body.
+pub async fn original_header(reader: impl AsyncRead + Unpin) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![];
+    reader.take(MAX_HEADER_SIZE).read_to_end(&mut data).await?;
+    let end = data
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(data.len(), |i| i + 2);
+    data.truncate(end);
+    Ok(data)
+}
+
+/// The bounce telling `sender` about `failures`, to be sent with a null
+/// envelope sender. `header` is the header of the original message.
+pub fn compose(
+    hostname: &str,
+    sender: &str,
+    failures: &[Failure],
+    header: &[u8],
+    now: i64,
+) -> Vec<u8> {
+    let boundary = uuid::Uuid::new_v4().simple().to_string();
+    let mut report = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{hostname}>\r\n\
+         To: <{sender}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}@{hostname}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         Your message could not be delivered to some of its recipients.\r\n\
+         \r\n",
+        date = vacation::date(now),
+        id = uuid::Uuid::new_v4(),
+    );
+    for failure in failures {
+        report.push_str(&format!("<{}>: {}\r\n", failure.recipient, failure.reason));
+    }
+    report.push_str(&format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {hostname}\r\n"
+    ));
+    for failure in failures {
+        report.push_str("\r\n");
+        if let Some(original) = &failure.original_recipient {
+            report.push_str(&format!("Original-Recipient: rfc822; {}\r\n", original));
+        }
+        report.push_str(&format!(
+            "Final-Recipient: rfc822; {}\r\n\
+             Action: failed\r\n\
+             Status: 5.0.0\r\n\
+             Diagnostic-Code: X-Local; {}\r\n",
+            failure.recipient,
+            failure.reason.replace(['\r', '\n'], " ")
+        ));
+    }
+    report.push_str(&format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: text/rfc822-headers\r\n\
+         \r\n"
+    ));
+    let mut data = report.into_bytes();
+    data.extend_from_slice(header);
+    data.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime;
+
+    #[tokio::test]
+    async fn test_compose() {
+        let original = b"Subject: Lunch\r\nMessage-ID: <1@example.net>\r\n\r\nHi\r\n";
+        let header = original_header(&original[..]).await.unwrap();
+        assert_eq!(header, b"Subject: Lunch\r\nMessage-ID: <1@example.net>\r\n");
+
+        let failures = [Failure {
+            recipient: "carol@example.com".to_string(),
+            original_recipient: Some("team@example.com".to_string()),
+            reason: "No thanks".to_string(),
+        }];
+        let data = compose("mx.example.com", "alice@example.net", &failures, &header, 0);
+        let report = mime::Part::parse(&data);
+        assert_eq!(report.header("to").unwrap(), "<alice@example.net>");
+        assert_eq!(
+            report.content_type().param("report-type"),
+            Some("delivery-status")
+        );
+        let parts = report.parts();
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0]
+            .text()
+            .unwrap()
+            .contains("<carol@example.com>: No thanks"));
+        let status = String::from_utf8_lossy(parts[1].raw_body());
+        assert!(status.contains("Original-Recipient: rfc822; team@example.com\r\n"));
+        assert!(status.contains("Final-Recipient: rfc822; carol@example.com\r\n"));
+        assert_eq!(parts[2].raw_body(), &header[..]);
+    }
+}
@@ -2,25 +2,164 @@ use crate::message::PrintHandler;
 use crate::socket::{SocketError, ToTcpListener};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
+pub mod autocrypt;
+pub mod body;
+pub mod dnsbl;
+pub mod dsn;
+pub mod lmtp;
 pub mod logging;
+pub mod managesieve;
 pub mod message;
+pub mod milter;
+pub mod mime;
+pub mod outbound;
+pub mod pgp;
+pub mod resolver;
+pub mod retry;
+pub mod sieve;
 pub mod smtp;
 pub mod socket;
+pub mod spam;
+pub mod srs;
 pub mod storage;
+pub mod time;
+pub mod tls;
+pub mod vacation;
+pub mod webhook;
+pub mod wkd;
+
+const WEBHOOK_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const RELAY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long side channels such as the webhook may hold up the reply to a
+/// message.
+const SIDE_CHANNEL_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub async fn smtp_server<L: ToTcpListener, P: AsRef<Path>>(
     addr: L,
     sqlite_db: P,
+    mut config: smtp::Config,
 ) -> Result<(), socket::SocketError> {
     let print_handler = Box::new(PrintHandler);
-    let storage_handler = Box::new(
-        storage::SqliteStore::new(sqlite_db)
-            .await
-            .map_err(SocketError::boxed)?,
+    let mut store = storage::SqliteStore::open(sqlite_db)
+        .await
+        .map_err(SocketError::boxed)?;
+    if let Some(path) = &config.blob_path {
+        store = store.with_blob_store(Arc::new(storage::FsBlobStore::new(path)));
+    }
+    // Migrated once the blob store is set, so that bodies moved out of the
+    // database end up where they will be read from.
+    store.migrate().await.map_err(SocketError::boxed)?;
+    if let Some(key) = &config.recovery_key {
+        store = store.with_recovery_key(key.clone());
+    }
+    if let Some(srs) = &config.srs {
+        store = store.with_srs(srs.clone());
+    }
+    if config.recipient_validator.is_none() {
+        config.recipient_validator = Some(Arc::new(store.clone()));
+    }
+    if config.resolver.is_none() {
+        config.resolver = Some(Arc::new(store.clone()));
+    }
+    if config.greylist_store.is_none() {
+        config.greylist_store = Some(Arc::new(store.clone()));
+    }
+    if config.delivery_log.is_none() {
+        config.delivery_log = Some(Arc::new(store.clone()));
+    }
+    if let Some(address) = &config.relay_host {
+        let relay = outbound::Relay::new(store.clone(), address.clone(), &config.hostname)
+            .with_autocrypt(config.autocrypt);
+        tokio::spawn(relay.run(RELAY_INTERVAL));
+    }
+    let mut delivery_handler: Box<dyn message::Handler + Send + Sync> = match &config.lmtp_delivery
+    {
+        Some(address) => Box::new(lmtp::LmtpHandler::new(address.clone(), &config.hostname)),
+        None => Box::new(store.clone()),
+    };
+    if config.pgp_encryption {
+        delivery_handler = Box::new(pgp::PgpHandler::new(store.clone(), delivery_handler));
+    }
+    // Outside of encryption, so that peers' headers are read in the clear.
+    if config.autocrypt {
+        delivery_handler = Box::new(autocrypt::AutocryptHandler::new(
+            store.clone(),
+            delivery_handler,
+        ));
+    }
+    // Outside of encryption too, so that spam is scored in the clear and
+    // Autocrypt does not learn keys from it.
+    if let Some(spam) = &config.spam {
+        delivery_handler = Box::new(spam::SpamHandler::new(
+            store.clone(),
+            spam.clone(),
+            delivery_handler,
+        ));
+    }
+    // Outermost, so that content filters see mail as it was received.
+    if let Some(milter) = &config.milter {
+        delivery_handler = Box::new(milter::MilterHandler::new(
+            milter.clone(),
+            &config.hostname,
+            delivery_handler,
+        ));
+    }
+    let mut side_channels = message::MultiHandler::default()
+        .best_effort(print_handler)
+        .timeout(SIDE_CHANNEL_TIMEOUT);
+    // A webhook outage must never bounce mail that was stored. Posts cut
+    // short by the timeout are retried from the queue.
+    if let Some(url) = &config.webhook_url {
+        let webhook = webhook::WebhookHandler::new(url, config.webhook_secret.clone(), store);
+        tokio::spawn(webhook.clone().run_retries(WEBHOOK_RETRY_INTERVAL));
+        side_channels = side_channels.best_effort(Box::new(webhook));
+    }
+    let handler = Arc::new(
+        message::MultiHandler::default()
+            .best_effort(Box::new(side_channels))
+            .required(delivery_handler),
     );
-    let handler = Arc::new(message::multi_handler(vec![print_handler, storage_handler]));
-    socket::run(addr, smtp::Server { handler }).await
+    socket::run(addr, smtp::Server { handler, config }).await
+}
+
+/// Runs the server as an LMTP delivery agent behind another MTA.
+pub async fn lmtp_server<L: ToTcpListener, P: AsRef<Path>>(
+    addr: L,
+    sqlite_db: P,
+    mut config: smtp::Config,
+) -> Result<(), socket::SocketError> {
+    config.lmtp = true;
+    smtp_server(addr, sqlite_db, config).await
+}
+
+/// Serves users' OpenPGP keys over the Web Key Directory protocol.
+pub async fn wkd_server<L: ToTcpListener, P: AsRef<Path>>(
+    addr: L,
+    sqlite_db: P,
+) -> Result<(), socket::SocketError> {
+    let store = storage::SqliteStore::new(sqlite_db)
+        .await
+        .map_err(SocketError::boxed)?;
+    socket::run(addr, wkd::Server::new(store)).await
+}
+
+/// Lets users manage their Sieve scripts over ManageSieve, offering
+/// STARTTLS when given an acceptor.
+pub async fn managesieve_server<L: ToTcpListener, P: AsRef<Path>>(
+    addr: L,
+    sqlite_db: P,
+    tls: Option<tls::TlsAcceptor>,
+) -> Result<(), socket::SocketError> {
+    let store = storage::SqliteStore::new(sqlite_db)
+        .await
+        .map_err(SocketError::boxed)?;
+    let mut server = managesieve::Server::new(store);
+    if let Some(acceptor) = tls {
+        server = server.with_tls(acceptor);
+    }
+    socket::run(addr, server).await
 }
 
 #[cfg(test)]
@@ -30,14 +169,27 @@ mod tests {
     use tokio::net::{TcpListener, TcpStream};
 
     async fn start_server() -> String {
+        start_server_with(crate::smtp::Config::default()).await
+    }
+
+    async fn start_server_with(config: crate::smtp::Config) -> String {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
         let local_addr = listener.local_addr().unwrap();
         let addr_str = local_addr.to_string();
         let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = crate::storage::SqliteStore::new(temp_file.path())
+            .await
+            .unwrap();
+        store.add_domain("example.com").await.unwrap();
+        store.add_user("bob@example.com").await.unwrap();
+        store
+            .add_alias("postmaster@example.com", "bob@example.com")
+            .await
+            .unwrap();
 
         tokio::spawn(async move {
-            if let Err(e) = smtp_server(listener, temp_file.path()).await {
+            if let Err(e) = smtp_server(listener, temp_file.path(), config).await {
                 tracing::error!("SMTP Server Error: {}", e);
             }
         });
@@ -142,6 +294,247 @@ mod tests {
         assert!(String::from_utf8_lossy(&buffer[..n]).starts_with("221"));
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_rejected_recipients() {
+        let server_address = start_server().await;
+
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+
+        stream
+            .write_all(b"HELO example.com\r\nMAIL FROM: <alice@example.net>\r\n")
+            .await
+            .unwrap();
+        let mut received = String::new();
+        while received.matches("\r\n").count() < 2 {
+            let n = stream.read(&mut buffer).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+
+        stream
+            .write_all(b"RCPT TO: <eve@example.com>\r\n")
+            .await
+            .unwrap();
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..n]).starts_with("550 5.1.1"));
+
+        stream
+            .write_all(b"RCPT TO: <carol@elsewhere.test>\r\n")
+            .await
+            .unwrap();
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buffer[..n]),
+            "554 5.7.1 Relay access denied\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_oversized_message() {
+        let config = crate::smtp::ConfigBuilder::default()
+            .max_message_size(64usize)
+            .build()
+            .unwrap();
+        let server_address = start_server_with(config).await;
+
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+
+        let long_line = "x".repeat(200);
+        let input = format!(
+            "EHLO example.com\r\nMAIL FROM: <alice@example.com>\r\nRCPT TO: <bob@example.com>\r\nDATA\r\nSubject: Test\r\n\r\n{}\r\n.\r\nQUIT\r\n",
+            long_line
+        );
+        let expected = "250-mail.example.com\r\n250-PIPELINING\r\n250 SIZE 64\r\n250 OK\r\n250 OK\r\n354 enter mail, end with line containing only \".\"\r\n552 5.3.4 Message size exceeds fixed maximum message size\r\n221 Goodbye\r\n";
+
+        stream.write_all(input.as_bytes()).await.unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_declared_size_too_big() {
+        let config = crate::smtp::ConfigBuilder::default()
+            .max_message_size(64usize)
+            .build()
+            .unwrap();
+        let server_address = start_server_with(config).await;
+
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+
+        let input = "EHLO example.com\r\nMAIL FROM: <alice@example.com> SIZE=1000\r\nMAIL FROM: <alice@example.com> SIZE=10\r\nRCPT TO: <bob@example.com>\r\nDATA\r\nHi\r\n.\r\nQUIT\r\n";
+        let expected = "250-mail.example.com\r\n250-PIPELINING\r\n250 SIZE 64\r\n552 5.3.4 Message size exceeds fixed maximum message size\r\n250 OK\r\n250 OK\r\n354 enter mail, end with line containing only \".\"\r\n250 Message sent\r\n221 Goodbye\r\n";
+
+        stream.write_all(input.as_bytes()).await.unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), expected);
+    }
+
+    struct FailingHandler;
+
+    #[async_trait::async_trait]
+    impl crate::message::Handler for FailingHandler {
+        async fn handle_message(
+            &self,
+            _message: &crate::message::Message,
+        ) -> Result<(), crate::message::HandlerError> {
+            Err(crate::message::HandlerError::temporary("disk full"))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_handler_failure_is_not_acknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = listener.local_addr().unwrap();
+        let server = crate::smtp::Server {
+            handler: std::sync::Arc::new(FailingHandler),
+            config: crate::smtp::Config::default(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = crate::socket::run(listener, server).await {
+                tracing::error!("SMTP Server Error: {}", e);
+            }
+        });
+
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+
+        let input = b"HELO example.com\r\nMAIL FROM: <alice@example.com>\r\nRCPT TO: <bob@example.com>\r\nDATA\r\nHello\r\n.\r\nQUIT\r\n";
+        let expected = "250 mail.example.com\r\n250 OK\r\n250 OK\r\n354 enter mail, end with line containing only \".\"\r\n451 4.3.0 Local error in processing\r\n221 Goodbye\r\n";
+        stream.write_all(input).await.unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), expected);
+    }
+
+    /// Delivers to bob, defers carol on the first attempt and refuses
+    /// dave, recording who got the message.
+    #[derive(Default)]
+    struct PartialHandler {
+        delivered: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::message::Handler for PartialHandler {
+        async fn handle_message(
+            &self,
+            _message: &crate::message::Message,
+        ) -> Result<(), crate::message::HandlerError> {
+            unreachable!()
+        }
+
+        async fn handle_recipients(
+            &self,
+            message: &crate::message::Message,
+        ) -> Vec<Result<(), crate::message::HandlerError>> {
+            let mut delivered = self.delivered.lock().unwrap();
+            let retry = delivered.iter().any(|r| r == "bob@example.com");
+            message
+                .recipients
+                .iter()
+                .map(|r| match r.address.as_str() {
+                    "carol@example.com" if !retry => {
+                        Err(crate::message::HandlerError::temporary("locked"))
+                    }
+                    "dave@example.com" => {
+                        Err(crate::message::HandlerError::permanent("Mailbox full"))
+                    }
+                    address => {
+                        delivered.push(address.to_string());
+                        Ok(())
+                    }
+                })
+                .collect()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_partial_delivery() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = crate::storage::SqliteStore::new(temp_file.path())
+            .await
+            .unwrap();
+        let handler = std::sync::Arc::new(PartialHandler::default());
+        let log: std::sync::Arc<dyn crate::smtp::delivery::DeliveryLog> =
+            std::sync::Arc::new(store.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = listener.local_addr().unwrap();
+        let server = crate::smtp::Server {
+            handler: handler.clone(),
+            config: crate::smtp::ConfigBuilder::default()
+                .delivery_log(log)
+                .build()
+                .unwrap(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = crate::socket::run(listener, server).await {
+                tracing::error!("SMTP Server Error: {}", e);
+            }
+        });
+
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+
+        let transaction = "HELO example.com\r\nMAIL FROM: <alice@example.net>\r\nRCPT TO: <bob@example.com>\r\nRCPT TO: <carol@example.com>\r\nRCPT TO: <dave@example.com>\r\nDATA\r\nSubject: Hi\r\n\r\nHello\r\n.\r\n";
+        let input = format!("{0}{0}QUIT\r\n", transaction);
+        let accepted = "250 mail.example.com\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n354 enter mail, end with line containing only \".\"\r\n";
+        let expected = format!(
+            "{0}451 4.3.0 Local error in processing\r\n{0}250 Message sent\r\n221 Goodbye\r\n",
+            accepted
+        );
+        stream.write_all(input.as_bytes()).await.unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), expected);
+
+        // Bob got the message once, and Alice hears about Dave.
+        assert_eq!(
+            *handler.delivered.lock().unwrap(),
+            ["bob@example.com", "carol@example.com"]
+        );
+        let queued = store.claim_outbound(i64::MAX, 0).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(
+            (queued[0].sender.as_str(), queued[0].recipient.as_str()),
+            ("", "alice@example.net")
+        );
+        let report = String::from_utf8_lossy(&queued[0].message);
+        assert!(report.contains("Final-Recipient: rfc822; dave@example.com\r\n"));
+        assert!(report.contains("<dave@example.com>: Mailbox full\r\n"));
+        assert!(report.contains("\r\nSubject: Hi\r\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_lmtp_replies_per_recipient() {
+        let config = crate::smtp::ConfigBuilder::default()
+            .lmtp(true)
+            .build()
+            .unwrap();
+        let server_address = start_server_with(config).await;
+
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).await.unwrap();
+
+        let input = b"LHLO example.com\r\nMAIL FROM: <alice@example.net>\r\nRCPT TO: <bob@example.com>\r\nRCPT TO: <eve@example.com>\r\nRCPT TO: <postmaster@example.com>\r\nDATA\r\nHello\r\n.\r\nQUIT\r\n";
+        let expected = format!(
+            "250-mail.example.com\r\n250-PIPELINING\r\n250 SIZE {}\r\n250 OK\r\n250 OK\r\n550 5.1.1 No such user\r\n250 OK\r\n354 enter mail, end with line containing only \".\"\r\n250 Message sent\r\n250 Message sent\r\n221 Goodbye\r\n",
+            crate::smtp::config::DEFAULT_MAX_MESSAGE_SIZE
+        );
+        stream.write_all(input).await.unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), expected);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn test_pipeline_message() {
         crate::logging::setup();
@@ -162,4 +555,29 @@ mod tests {
         tracing::debug!("Read: {:?}", output);
         assert_eq!(output, expected);
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocklisted_client() {
+        use crate::dnsbl::{Blocklists, ListKind, Zone};
+        let mut zone = Zone::default();
+        zone.insert(
+            "1.0.0.127.zen.example",
+            std::net::Ipv4Addr::new(127, 0, 0, 2),
+        );
+        let blocklists = Blocklists::new(std::sync::Arc::new(zone))
+            .with_list(ListKind::Address, "zen.example".parse().unwrap());
+        let config = crate::smtp::ConfigBuilder::default()
+            .blocklists(std::sync::Arc::new(blocklists))
+            .build()
+            .unwrap();
+        let server_address = start_server_with(config).await;
+
+        let mut stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buffer),
+            "554 5.7.1 Service unavailable; client host [127.0.0.1] blocked using zen.example\r\n"
+        );
+    }
 }
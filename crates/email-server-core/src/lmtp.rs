@@ -0,0 +1,385 @@
+use crate::message::{self, HandlerError, Message};
+use crate::smtp::address;
+use async_trait::async_trait;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+#[derive(Debug)]
+pub enum LmtpError {
+    IoError(std::io::Error),
+    /// The server answered a command with something other than success.
+    Reply(String),
+}
+
+impl Display for LmtpError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            LmtpError::IoError(e) => write!(f, "IoError: {}", e),
+            LmtpError::Reply(reply) => write!(f, "Reply: {}", reply),
+        }
+    }
+}
+
+impl Error for LmtpError {}
+
+impl From<std::io::Error> for LmtpError {
+    fn from(e: std::io::Error) -> Self {
+        LmtpError::IoError(e)
+    }
+}
+
+impl From<LmtpError> for HandlerError {
+    fn from(e: LmtpError) -> Self {
+        match &e {
+            LmtpError::Reply(reply) if reply.starts_with('5') => HandlerError::permanent(e),
+            _ => HandlerError::temporary(e),
+        }
+    }
+}
+
+/// Where the LMTP server listens: `host:port`, or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LmtpAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl FromStr for LmtpAddress {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(LmtpAddress::Unix(path.into()));
+        }
+        Ok(LmtpAddress::Tcp(s.to_string()))
+    }
+}
+
+/// Delivers messages to an external mailbox store over LMTP (RFC 2033), or
+/// relays them to a smarthost over SMTP.
+#[derive(Debug, Clone)]
+pub struct LmtpHandler {
+    address: LmtpAddress,
+    hostname: String,
+    /// Speak SMTP, where the server replies once after the message instead
+    /// of once per recipient.
+    smtp: bool,
+}
+
+impl LmtpHandler {
+    pub fn new(address: LmtpAddress, hostname: impl Into<String>) -> Self {
+        Self {
+            address,
+            hostname: hostname.into(),
+            smtp: false,
+        }
+    }
+
+    /// Relays to an SMTP server that accepts mail from this host without
+    /// authentication.
+    pub fn smtp(address: LmtpAddress, hostname: impl Into<String>) -> Self {
+        Self {
+            smtp: true,
+            ..Self::new(address, hostname)
+        }
+    }
+
+    async fn deliver(&self, message: &Message) -> Result<Vec<Result<(), HandlerError>>, LmtpError> {
+        match &self.address {
+            LmtpAddress::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr).await?;
+                self.session(stream, message).await
+            }
+            #[cfg(unix)]
+            LmtpAddress::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                self.session(stream, message).await
+            }
+        }
+    }
+
+    async fn session<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        message: &Message,
+    ) -> Result<Vec<Result<(), HandlerError>>, LmtpError> {
+        let mut stream = BufReader::new(stream);
+        expect_success(read_reply(&mut stream).await?)?;
+
+        let greeting = if self.smtp { "EHLO" } else { "LHLO" };
+        command(&mut stream, &format!("{} {}", greeting, self.hostname)).await?;
+        command(
+            &mut stream,
+            &format!("MAIL FROM:<{}>", address::mailbox(&message.from)),
+        )
+        .await?;
+
+        let mut results = Vec::with_capacity(message.recipients.len());
+        let mut accepted = vec![];
+        for (i, recipient) in message.recipients.iter().enumerate() {
+            let line = format!("RCPT TO:<{}>\r\n", recipient.address);
+            stream.get_mut().write_all(line.as_bytes()).await?;
+            let reply = read_reply(&mut stream).await?;
+            results.push(
+                expect_success(reply)
+                    .map(|_| ())
+                    .map_err(HandlerError::from),
+            );
+            if results[i].is_ok() {
+                accepted.push(i);
+            }
+        }
+        if accepted.is_empty() {
+            let _ = command(&mut stream, "QUIT").await;
+            return Ok(results);
+        }
+
+        stream.get_mut().write_all(b"DATA\r\n").await?;
+        let reply = read_reply(&mut stream).await?;
+        if !reply.starts_with('3') {
+            return Err(LmtpError::Reply(reply));
+        }
+        send_body(stream.get_mut(), message).await?;
+
+        // One reply per accepted recipient, in RCPT order, or a single one
+        // for all of them over SMTP.
+        let mut reply = String::new();
+        for (n, i) in accepted.into_iter().enumerate() {
+            if n == 0 || !self.smtp {
+                reply = read_reply(&mut stream).await?;
+            }
+            results[i] = expect_success(reply.clone())
+                .map(|_| ())
+                .map_err(HandlerError::from);
+        }
+        let _ = command(&mut stream, "QUIT").await;
+        Ok(results)
+    }
+}
+
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    line: &str,
+) -> Result<String, LmtpError> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await?;
+    expect_success(read_reply(stream).await?)
+}
+
+/// Reads a possibly multi-line reply and returns its last line.
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut BufReader<S>) -> Result<String, LmtpError> {
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(LmtpError::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let line = line.trim_end().to_string();
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(line);
+        }
+    }
+}
+
+fn expect_success(reply: String) -> Result<String, LmtpError> {
+    if reply.starts_with('2') {
+        Ok(reply)
+    } else {
+        Err(LmtpError::Reply(reply))
+    }
+}
+
+async fn send_body<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> Result<(), LmtpError> {
+    let mut body = BufReader::new(message.data.reader().await?);
+    let mut line = Vec::new();
+    let mut ends_with_newline = true;
+    loop {
+        line.clear();
+        if body.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        if line.starts_with(b".") {
+            writer.write_all(b".").await?;
+        }
+        writer.write_all(&line).await?;
+        ends_with_newline = line.ends_with(b"\n");
+    }
+    if !ends_with_newline {
+        writer.write_all(b"\r\n").await?;
+    }
+    writer.write_all(b".\r\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[async_trait]
+impl message::Handler for LmtpHandler {
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+        message::summarize(&self.handle_recipients(message).await)
+    }
+
+    async fn handle_recipients(&self, message: &Message) -> Vec<Result<(), HandlerError>> {
+        match self.deliver(message).await {
+            Ok(results) => results,
+            Err(e) => {
+                tracing::error!("LMTP delivery to {:?} failed: {}", self.address, e);
+                let e = HandlerError::from(e);
+                vec![Err(e); message.recipients.len()]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Handler, Recipient};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// A tiny LMTP server that rejects `eve`, tempfails `bob` after DATA,
+    /// and returns the body it received. As an SMTP server it accepts the
+    /// message for everyone with one reply.
+    async fn lmtp_sink(smtp: bool) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let task = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            socket.get_mut().write_all(b"220 sink\r\n").await.unwrap();
+            let mut rcpts = vec![];
+            let mut body = vec![];
+            loop {
+                let mut line = String::new();
+                if socket.read_line(&mut line).await.unwrap() == 0 {
+                    return body;
+                }
+                let reply: &[u8] = if line.starts_with(if smtp { "EHLO" } else { "LHLO" }) {
+                    b"250-sink\r\n250 PIPELINING\r\n"
+                } else if line.starts_with("RCPT") && line.contains("eve@") {
+                    b"550 5.1.1 unknown\r\n"
+                } else if line.starts_with("RCPT") {
+                    rcpts.push(line.clone());
+                    b"250 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    socket.get_mut().write_all(b"354 go\r\n").await.unwrap();
+                    loop {
+                        let mut data = vec![];
+                        socket.read_until(b'\n', &mut data).await.unwrap();
+                        if data == b".\r\n" {
+                            break;
+                        }
+                        body.extend_from_slice(&data);
+                    }
+                    if smtp {
+                        socket.get_mut().write_all(b"250 queued\r\n").await.unwrap();
+                        continue;
+                    }
+                    for rcpt in &rcpts {
+                        let reply: &[u8] = if rcpt.contains("bob@") {
+                            b"452 4.2.2 over quota\r\n"
+                        } else {
+                            b"250 2.0.0 delivered\r\n"
+                        };
+                        socket.get_mut().write_all(reply).await.unwrap();
+                    }
+                    continue;
+                } else if line.starts_with("QUIT") {
+                    socket.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                    let _ = socket.read_to_end(&mut vec![]).await;
+                    return body;
+                } else {
+                    b"250 ok\r\n"
+                };
+                socket.get_mut().write_all(reply).await.unwrap();
+            }
+        });
+        (addr, task)
+    }
+
+    #[tokio::test]
+    async fn test_per_recipient_delivery() {
+        let (addr, sink) = lmtp_sink(false).await;
+        let handler = LmtpHandler::new(addr.parse().unwrap(), "mx.example.com");
+        let message = Message {
+            from: "<carol@example.net>".to_string(),
+            recipients: vec![
+                Recipient::new("alice@example.com"),
+                Recipient::new("eve@example.com"),
+                Recipient::new("bob@example.com"),
+            ],
+            data: b"Subject: Hi\r\n\r\n.hidden\r\nbye\r\n"[..].into(),
+            ..Default::default()
+        };
+
+        let results = handler.handle_recipients(&message).await;
+        assert!(results[0].is_ok());
+        assert!(!results[1].as_ref().unwrap_err().is_temporary());
+        assert!(results[2].as_ref().unwrap_err().is_temporary());
+        assert!(message::summarize(&results).unwrap_err().is_temporary());
+
+        assert_eq!(
+            sink.await.unwrap(),
+            b"Subject: Hi\r\n\r\n..hidden\r\nbye\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_smtp_relay() {
+        let (addr, sink) = lmtp_sink(true).await;
+        let handler = LmtpHandler::smtp(addr.parse().unwrap(), "mx.example.com");
+        let message = Message {
+            recipients: vec![
+                Recipient::new("alice@example.net"),
+                Recipient::new("eve@example.net"),
+                Recipient::new("bob@example.net"),
+            ],
+            data: b"Subject: Hi\r\n\r\nbye\r\n"[..].into(),
+            ..Default::default()
+        };
+
+        let results = handler.handle_recipients(&message).await;
+        assert!(results[0].is_ok());
+        assert!(!results[1].as_ref().unwrap_err().is_temporary());
+        assert!(results[2].is_ok());
+        assert_eq!(sink.await.unwrap(), b"Subject: Hi\r\n\r\nbye\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_server_tempfails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let handler = LmtpHandler::new(addr.parse().unwrap(), "mx.example.com");
+        let message = Message {
+            recipients: vec![Recipient::new("alice@example.com")],
+            ..Default::default()
+        };
+        assert!(handler
+            .handle_message(&message)
+            .await
+            .unwrap_err()
+            .is_temporary());
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            "127.0.0.1:24".parse::<LmtpAddress>().unwrap(),
+            LmtpAddress::Tcp("127.0.0.1:24".to_string())
+        );
+        assert_eq!(
+            "unix:/run/dovecot/lmtp".parse::<LmtpAddress>().unwrap(),
+            LmtpAddress::Unix("/run/dovecot/lmtp".into())
+        );
+    }
+}
@@ -1,25 +1,208 @@
+use crate::body::Body;
+use crate::milter::Milters;
 use async_trait::async_trait;
 use derive_builder::Builder;
+use futures::future::join_all;
 use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncRead;
 
-#[derive(Default, Builder, Debug, Clone)]
+#[derive(Default, Builder, Debug)]
+#[builder(pattern = "owned")]
 pub struct Message {
     pub sender_domain: String,
+    /// Whether the client authenticated before starting this transaction.
+    pub authenticated: bool,
+    /// Address of the connected client, when known.
+    #[builder(default)]
+    pub client: Option<IpAddr>,
     pub from: String,
     pub to: Vec<String>,
-    pub data: Vec<u8>,
+    /// Final delivery targets after alias and forwarding resolution.
+    pub recipients: Vec<Recipient>,
+    pub data: Body,
+    /// The content filters following the session, which have seen the
+    /// envelope already.
+    #[builder(default)]
+    pub milters: Option<Arc<tokio::sync::Mutex<Milters>>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    pub address: String,
+    /// Subaddress of `user+tag@domain`, kept for filtering.
+    pub tag: Option<String>,
+    /// Indices into `Message::to` of the envelope recipients that resolved
+    /// to this one.
+    pub origins: Vec<usize>,
+    /// Classified as spam: kept in the Junk folder instead of the inbox.
+    pub junk: bool,
+    /// Held by a content filter: kept in the Quarantine folder, unfiltered.
+    pub quarantined: bool,
+    /// Not a local mailbox: relayed through the outbound queue instead of
+    /// stored.
+    pub forward: bool,
+}
+
+impl Recipient {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            tag: None,
+            origins: vec![],
+            junk: false,
+            quarantined: false,
+            forward: false,
+        }
+    }
+}
+
+impl Message {
+    /// A copy of the transaction for some of its recipients, with other
+    /// content, for handlers that rewrite messages.
+    pub fn copy_with(&self, recipients: Vec<Recipient>, data: Vec<u8>) -> Message {
+        self.copy_with_body(recipients, Body::from(data))
+    }
+
+    /// Like [`Self::copy_with`], with `header` followed by what `rest`
+    /// yields, spooled as this message's body was.
+    pub async fn copy_streamed(
+        &self,
+        recipients: Vec<Recipient>,
+        header: &[u8],
+        rest: impl AsyncRead + Unpin,
+    ) -> std::io::Result<Message> {
+        let mut data = Body::new(self.data.threshold());
+        data.write(header).await?;
+        data.write_from(rest).await?;
+        data.finish().await?;
+        Ok(self.copy_with_body(recipients, data))
+    }
+
+    fn copy_with_body(&self, recipients: Vec<Recipient>, data: Body) -> Message {
+        Message {
+            sender_domain: self.sender_domain.clone(),
+            authenticated: self.authenticated,
+            client: self.client,
+            from: self.from.clone(),
+            to: self.to.clone(),
+            recipients,
+            data,
+            milters: None,
+        }
+    }
+
+    /// Folds per-recipient results (in `recipients` order) into one result
+    /// for each envelope recipient in `to`.
+    pub fn results_by_rcpt(
+        &self,
+        results: &[Result<(), HandlerError>],
+    ) -> Vec<Result<(), HandlerError>> {
+        (0..self.to.len())
+            .map(|rcpt| {
+                let results: Vec<_> = self
+                    .recipients
+                    .iter()
+                    .zip(results)
+                    .filter(|(recipient, _)| recipient.origins.contains(&rcpt))
+                    .map(|(_, result)| result.clone())
+                    .collect();
+                summarize(&results)
+            })
+            .collect()
+    }
+}
+
+/// Why a handler could not take a message. Temporary failures are reported
+/// with a 4xx so the client retries; permanent ones with a 5xx.
+#[derive(Debug, Clone)]
+pub enum HandlerError {
+    Temporary(Arc<dyn Error + Send + Sync>),
+    Permanent(Arc<dyn Error + Send + Sync>),
+}
+
+impl HandlerError {
+    pub fn temporary<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> Self {
+        HandlerError::Temporary(err.into().into())
+    }
+
+    pub fn permanent<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> Self {
+        HandlerError::Permanent(err.into().into())
+    }
+
+    pub fn is_temporary(&self) -> bool {
+        matches!(self, HandlerError::Temporary(_))
+    }
+
+    /// What went wrong, without whether it is temporary.
+    pub fn reason(&self) -> String {
+        match self {
+            HandlerError::Temporary(e) | HandlerError::Permanent(e) => e.to_string(),
+        }
+    }
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            HandlerError::Temporary(e) => write!(f, "Temporary: {}", e),
+            HandlerError::Permanent(e) => write!(f, "Permanent: {}", e),
+        }
+    }
+}
+
+impl Error for HandlerError {}
+
+impl From<std::io::Error> for HandlerError {
+    fn from(e: std::io::Error) -> Self {
+        HandlerError::temporary(e)
+    }
+}
+
+impl From<sqlx::Error> for HandlerError {
+    fn from(e: sqlx::Error) -> Self {
+        HandlerError::temporary(e)
+    }
+}
+
+/// Combines the results for several recipients of one transaction. Any
+/// temporary failure wins so the client retries; a permanent failure only
+/// counts if nobody received the message.
+pub fn summarize(results: &[Result<(), HandlerError>]) -> Result<(), HandlerError> {
+    if let Some(Err(e)) = results
+        .iter()
+        .find(|r| matches!(r, Err(e) if e.is_temporary()))
+    {
+        return Err(e.clone());
+    }
+    match results.iter().find(|r| r.is_ok()) {
+        Some(_) => Ok(()),
+        None => results.first().cloned().unwrap_or(Ok(())),
+    }
 }
 
 #[async_trait]
 pub trait Handler {
-    async fn handle_message(&self, message: Message) -> Result<(), Box<dyn Error>>;
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError>;
+
+    /// Delivers `message` and reports the outcome for each entry of
+    /// `message.recipients`, so one failing mailbox does not fail the
+    /// others.
+    async fn handle_recipients(&self, message: &Message) -> Vec<Result<(), HandlerError>> {
+        let result = self.handle_message(message).await;
+        vec![result; message.recipients.len()]
+    }
 }
 
 pub struct PrintHandler;
 
 #[async_trait]
 impl Handler for PrintHandler {
-    async fn handle_message(&self, message: Message) -> Result<(), Box<dyn Error>> {
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
         tracing::debug!(
             "Received message from {} to {} with {} bytes of data",
             message.from,
@@ -30,20 +213,280 @@ impl Handler for PrintHandler {
     }
 }
 
+/// Whether a child handler of a [`MultiHandler`] can fail the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Failures are reported to the client.
+    Required,
+    /// Failures are logged and otherwise ignored, for side channels that
+    /// must never cause mail to be rejected.
+    BestEffort,
+}
+
+/// Several handlers failed; the first is the one the client is told about.
+#[derive(Debug, Clone)]
+pub struct AggregateError(pub Vec<HandlerError>);
+
+impl Display for AggregateError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for AggregateError {}
+
+/// Folds the errors of several handlers into one, temporary if any of them
+/// is.
+fn aggregate(mut errors: Vec<HandlerError>) -> Result<(), HandlerError> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ if errors.iter().any(HandlerError::is_temporary) => {
+            Err(HandlerError::temporary(AggregateError(errors)))
+        }
+        _ => Err(HandlerError::permanent(AggregateError(errors))),
+    }
+}
+
+#[derive(Debug)]
+struct TimedOut(Duration);
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "TimedOut: {:?}", self.0)
+    }
+}
+
+impl Error for TimedOut {}
+
+struct Child {
+    handler: Box<dyn Handler + Send + Sync>,
+    policy: Policy,
+}
+
+/// Runs several handlers concurrently on the same message. Every handler
+/// runs even if another fails, and the errors of the required ones are
+/// aggregated.
+#[derive(Default)]
 pub struct MultiHandler {
-    handlers: Vec<Box<dyn Handler + Send + Sync>>,
+    handlers: Vec<Child>,
+    timeout: Option<Duration>,
 }
 
+/// Combines `handlers`, all of them required.
 pub fn multi_handler(handlers: Vec<Box<dyn Handler + Send + Sync>>) -> MultiHandler {
-    MultiHandler { handlers }
+    handlers
+        .into_iter()
+        .fold(MultiHandler::default(), MultiHandler::required)
+}
+
+impl MultiHandler {
+    pub fn required(self, handler: Box<dyn Handler + Send + Sync>) -> Self {
+        self.with_policy(handler, Policy::Required)
+    }
+
+    pub fn best_effort(self, handler: Box<dyn Handler + Send + Sync>) -> Self {
+        self.with_policy(handler, Policy::BestEffort)
+    }
+
+    pub fn with_policy(mut self, handler: Box<dyn Handler + Send + Sync>, policy: Policy) -> Self {
+        self.handlers.push(Child { handler, policy });
+        self
+    }
+
+    /// Fails a handler with a temporary error if it takes longer than
+    /// `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    async fn limit<T>(&self, f: impl Future<Output = T>) -> Result<T, HandlerError> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, f)
+                .await
+                .map_err(|_| HandlerError::temporary(TimedOut(timeout))),
+            None => Ok(f.await),
+        }
+    }
 }
 
 #[async_trait]
 impl Handler for MultiHandler {
-    async fn handle_message(&self, message: Message) -> Result<(), Box<dyn Error>> {
-        for handler in &self.handlers {
-            handler.handle_message(message.clone()).await?;
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+        let outcomes = join_all(self.handlers.iter().map(|child| async move {
+            let result = self
+                .limit(child.handler.handle_message(message))
+                .await
+                .and_then(|r| r);
+            (child.policy, result)
+        }))
+        .await;
+
+        let mut errors = vec![];
+        for (policy, result) in outcomes {
+            match (policy, result) {
+                (_, Ok(())) => {}
+                (Policy::Required, Err(e)) => errors.push(e),
+                (Policy::BestEffort, Err(e)) => {
+                    tracing::warn!("Ignoring best-effort handler failure: {}", e)
+                }
+            }
         }
-        Ok(())
+        aggregate(errors)
+    }
+
+    async fn handle_recipients(&self, message: &Message) -> Vec<Result<(), HandlerError>> {
+        let outcomes = join_all(self.handlers.iter().map(|child| async move {
+            let results = self
+                .limit(child.handler.handle_recipients(message))
+                .await
+                .unwrap_or_else(|e| vec![Err(e); message.recipients.len()]);
+            (child.policy, results)
+        }))
+        .await;
+
+        let mut errors = vec![vec![]; message.recipients.len()];
+        for (policy, results) in outcomes {
+            for (errors, result) in errors.iter_mut().zip(results) {
+                match (policy, result) {
+                    (_, Ok(())) => {}
+                    (Policy::Required, Err(e)) => errors.push(e),
+                    (Policy::BestEffort, Err(e)) => {
+                        tracing::warn!("Ignoring best-effort handler failure: {}", e)
+                    }
+                }
+            }
+        }
+        errors.into_iter().map(aggregate).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts its calls and answers with `result` after `delay`.
+    struct Stub {
+        calls: Arc<AtomicUsize>,
+        result: Result<(), HandlerError>,
+        delay: Duration,
+    }
+
+    fn stub(result: Result<(), HandlerError>) -> (Box<Stub>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let stub = Stub {
+            calls: calls.clone(),
+            result,
+            delay: Duration::ZERO,
+        };
+        (Box::new(stub), calls)
+    }
+
+    #[async_trait]
+    impl Handler for Stub {
+        async fn handle_message(&self, _message: &Message) -> Result<(), HandlerError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.result.clone()
+        }
+    }
+
+    fn message(to: &[&str], recipients: &[(&str, &[usize])]) -> Message {
+        Message {
+            to: to.iter().map(|t| t.to_string()).collect(),
+            recipients: recipients
+                .iter()
+                .map(|(address, origins)| Recipient {
+                    origins: origins.to_vec(),
+                    ..Recipient::new(*address)
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let temporary = || Err(HandlerError::temporary("locked"));
+        let permanent = || Err(HandlerError::permanent("over quota"));
+        assert!(summarize(&[Ok(()), Ok(())]).is_ok());
+        assert!(summarize(&[Ok(()), permanent()]).is_ok());
+        assert!(!summarize(&[permanent(), permanent()])
+            .unwrap_err()
+            .is_temporary());
+        assert!(summarize(&[Ok(()), permanent(), temporary()])
+            .unwrap_err()
+            .is_temporary());
+    }
+
+    #[test]
+    fn test_results_by_rcpt() {
+        // The second RCPT is an alias for both mailboxes.
+        let message = message(
+            &[
+                "<alice@example.com>",
+                "<team@example.com>",
+                "<bob@example.com>",
+            ],
+            &[("alice@example.com", &[0, 1]), ("bob@example.com", &[1, 2])],
+        );
+        let results = message.results_by_rcpt(&[Ok(()), Err(HandlerError::temporary("locked"))]);
+        assert!(results[0].is_ok());
+        assert!(results[1].as_ref().unwrap_err().is_temporary());
+        assert!(results[2].as_ref().unwrap_err().is_temporary());
+    }
+
+    #[tokio::test]
+    async fn test_multi_handler_runs_every_handler() {
+        let (webhook, webhook_calls) = stub(Err(HandlerError::temporary("down")));
+        let (store, store_calls) = stub(Ok(()));
+        let handler = MultiHandler::default().best_effort(webhook).required(store);
+        let message = message(&["<bob@example.com>"], &[("bob@example.com", &[0])]);
+
+        assert!(handler.handle_message(&message).await.is_ok());
+        assert!(handler.handle_recipients(&message).await[0].is_ok());
+        assert_eq!(webhook_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(store_calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_multi_handler_aggregates_errors() {
+        let (first, _) = stub(Err(HandlerError::permanent("rejected")));
+        let (second, _) = stub(Err(HandlerError::temporary("locked")));
+        let handler = multi_handler(vec![first, second]);
+
+        let e = handler
+            .handle_message(&Message::default())
+            .await
+            .unwrap_err();
+        assert!(e.is_temporary());
+        assert_eq!(
+            e.to_string(),
+            "Temporary: Permanent: rejected; Temporary: locked"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multi_handler_timeout() {
+        let (mut slow, _) = stub(Ok(()));
+        slow.delay = Duration::from_secs(10);
+        let (fast, _) = stub(Ok(()));
+        let handler = MultiHandler::default()
+            .required(fast)
+            .required(slow)
+            .timeout(Duration::from_millis(20));
+        assert!(handler
+            .handle_message(&Message::default())
+            .await
+            .unwrap_err()
+            .is_temporary());
     }
 }
@@ -0,0 +1,1238 @@
+//! A client of the Sendmail milter protocol, which hands mail to external
+//! content filters such as virus scanners. The filters follow an SMTP
+//! session stage by stage, so that their reply to the connection, `HELO`,
+//! `MAIL` or `RCPT` is the reply to it; the content is passed once it is
+//! in. Mail that did not come through the session has its envelope
+//! replayed.
+
+use crate::message::{self, Handler, HandlerError, Message, Recipient};
+use crate::smtp::{address, status};
+use async_trait::async_trait;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The folder mail quarantined by a filter is kept in.
+pub const QUARANTINE: &str = "Quarantine";
+
+const VERSION: u32 = 6;
+/// Modifications filters may make (`SMFIF_*`): add, change and delete
+/// headers, replace the body, quarantine.
+const ACTIONS: u32 = 0x01 | 0x02 | 0x10 | 0x20;
+
+// Steps filters may ask to leave out (`SMFIP_NO*`), or to not reply to
+// (`SMFIP_NR_*`).
+const NO_CONNECT: u32 = 0x1;
+const NO_HELO: u32 = 0x2;
+const NO_MAIL: u32 = 0x4;
+const NO_RCPT: u32 = 0x8;
+const NO_BODY: u32 = 0x10;
+const NO_HEADERS: u32 = 0x20;
+const NO_EOH: u32 = 0x40;
+const NR_HEADER: u32 = 0x80;
+const NO_UNKNOWN: u32 = 0x100;
+const NO_DATA: u32 = 0x200;
+const SKIP: u32 = 0x400;
+const NR_CONNECT: u32 = 0x1000;
+const NR_HELO: u32 = 0x2000;
+const NR_MAIL: u32 = 0x4000;
+const NR_RCPT: u32 = 0x8000;
+const NR_DATA: u32 = 0x10000;
+const NR_UNKNOWN: u32 = 0x20000;
+const NR_EOH: u32 = 0x40000;
+const NR_BODY: u32 = 0x80000;
+const PROTOCOL: u32 = NO_CONNECT
+    | NO_HELO
+    | NO_MAIL
+    | NO_RCPT
+    | NO_BODY
+    | NO_HEADERS
+    | NO_EOH
+    | NR_HEADER
+    | NO_UNKNOWN
+    | NO_DATA
+    | SKIP
+    | NR_CONNECT
+    | NR_HELO
+    | NR_MAIL
+    | NR_RCPT
+    | NR_DATA
+    | NR_UNKNOWN
+    | NR_EOH
+    | NR_BODY;
+
+/// Largest packet accepted from a filter.
+const MAX_PACKET: usize = 1024 * 1024;
+/// Largest body chunk sent to a filter.
+const BODY_CHUNK: usize = 65535;
+
+#[derive(Debug)]
+pub enum MilterError {
+    IoError(std::io::Error),
+    /// The filter broke the protocol.
+    Protocol(String),
+    Timeout,
+    /// The filter refused the message or a recipient.
+    Rejected(String),
+}
+
+impl Display for MilterError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            MilterError::IoError(e) => write!(f, "IoError: {}", e),
+            MilterError::Protocol(e) => write!(f, "Protocol: {}", e),
+            MilterError::Timeout => write!(f, "Timeout"),
+            MilterError::Rejected(reply) => write!(f, "Rejected: {}", reply),
+        }
+    }
+}
+
+impl Error for MilterError {}
+
+impl From<std::io::Error> for MilterError {
+    fn from(e: std::io::Error) -> Self {
+        MilterError::IoError(e)
+    }
+}
+
+/// Where a filter listens: `host:port`, Sendmail's `inet:port@host`, or
+/// `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MilterAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl FromStr for MilterAddress {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:").or_else(|| s.strip_prefix("local:")) {
+            return Ok(MilterAddress::Unix(path.into()));
+        }
+        if let Some((port, host)) = s.strip_prefix("inet:").and_then(|s| s.split_once('@')) {
+            return Ok(MilterAddress::Tcp(format!("{}:{}", host, port)));
+        }
+        if let Some((port, host)) = s.strip_prefix("inet6:").and_then(|s| s.split_once('@')) {
+            return Ok(MilterAddress::Tcp(format!("[{}]:{}", host, port)));
+        }
+        Ok(MilterAddress::Tcp(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MilterConfig {
+    /// The filters, consulted in order.
+    pub milters: Vec<MilterAddress>,
+    /// How long a filter may take over a command or a message.
+    pub timeout: Duration,
+    /// Accept mail when a filter cannot be reached or fails, instead of
+    /// deferring it.
+    pub accept_on_failure: bool,
+}
+
+impl Default for MilterConfig {
+    fn default() -> Self {
+        Self {
+            milters: vec![],
+            timeout: Duration::from_secs(60),
+            accept_on_failure: false,
+        }
+    }
+}
+
+/// What a filter answered to a stage.
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Continue,
+    /// Accept the message without further filtering.
+    Accept,
+    /// Accept the message, but drop it.
+    Discard,
+    Reject(String),
+    TempFail(String),
+    /// Send no more of the body.
+    Skip,
+}
+
+impl Reply {
+    fn parse(command: u8, data: &[u8]) -> Result<Self, MilterError> {
+        Ok(match command {
+            b'c' => Reply::Continue,
+            b'a' => Reply::Accept,
+            b'd' => Reply::Discard,
+            b'r' => Reply::Reject("Rejected by content filter".to_string()),
+            b't' => Reply::TempFail("Deferred by content filter".to_string()),
+            b's' => Reply::Skip,
+            b'y' => {
+                let text = strings(data).into_iter().next().unwrap_or_default();
+                if text.starts_with('4') {
+                    Reply::TempFail(text)
+                } else {
+                    Reply::Reject(text)
+                }
+            }
+            _ => {
+                return Err(MilterError::Protocol(format!(
+                    "unexpected reply {:?}",
+                    command as char
+                )))
+            }
+        })
+    }
+
+    fn error(&self) -> Option<HandlerError> {
+        match self {
+            Reply::Reject(text) => {
+                Some(HandlerError::permanent(MilterError::Rejected(text.clone())))
+            }
+            Reply::TempFail(text) => {
+                Some(HandlerError::temporary(MilterError::Rejected(text.clone())))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The NUL-terminated strings of a packet.
+fn strings(data: &[u8]) -> Vec<String> {
+    let data = data.strip_suffix(b"\0").unwrap_or(data);
+    data.split(|&b| b == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// `name: value` header fields, values without the space after the colon
+/// and with their folding kept.
+fn split_message(data: &[u8]) -> (Vec<(String, String)>, &[u8]) {
+    let mut headers: Vec<(String, String)> = vec![];
+    let mut offset = 0;
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        offset += line.len();
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        let text = String::from_utf8_lossy(line);
+        match headers.last_mut() {
+            Some((_, value)) if line.starts_with(b" ") || line.starts_with(b"\t") => {
+                value.push_str(&text)
+            }
+            _ => {
+                let (name, value) = text.split_once(':').unwrap_or((&text, ""));
+                headers.push((name.to_string(), value.trim_start().to_string()));
+            }
+        }
+    }
+    for (_, value) in &mut headers {
+        value.truncate(value.trim_end_matches(['\r', '\n']).len());
+    }
+    (headers, &data[offset..])
+}
+
+/// A message from `headers` and `body`, with all line breaks in headers
+/// as CRLF.
+fn join_message(headers: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    for (name, value) in headers {
+        let value = value.replace("\r\n", "\n").replace('\n', "\r\n");
+        data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    data.extend_from_slice(b"\r\n");
+    data.extend_from_slice(body);
+    data
+}
+
+/// How a filter decided over the content of a message.
+#[derive(Debug)]
+struct Outcome {
+    action: Reply,
+    /// The headers, if the filter changed them.
+    headers: Option<Vec<(String, String)>>,
+    /// The body, if the filter replaced it.
+    body: Option<Vec<u8>>,
+    quarantine: Option<String>,
+}
+
+impl Outcome {
+    fn new(action: Reply) -> Self {
+        Self {
+            action,
+            headers: None,
+            body: None,
+            quarantine: None,
+        }
+    }
+}
+
+/// A connection to a filter that is not tied to its transport.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+struct Session<S> {
+    stream: S,
+    /// The steps the filter left out or does not reply to.
+    protocol: u32,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    async fn negotiate(stream: S) -> Result<Self, MilterError> {
+        let mut session = Self {
+            stream,
+            protocol: 0,
+        };
+        let mut data = VERSION.to_be_bytes().to_vec();
+        data.extend_from_slice(&ACTIONS.to_be_bytes());
+        data.extend_from_slice(&PROTOCOL.to_be_bytes());
+        session.send(b'O', &data).await?;
+        let (command, data) = session.read().await?;
+        if command != b'O' || data.len() < 12 {
+            return Err(MilterError::Protocol("invalid negotiation".to_string()));
+        }
+        let word = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        if word(0) < 2 {
+            return Err(MilterError::Protocol(format!("version {}", word(0))));
+        }
+        session.protocol = word(8) & PROTOCOL;
+        Ok(session)
+    }
+
+    async fn send(&mut self, command: u8, data: &[u8]) -> Result<(), MilterError> {
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+        packet.push(command);
+        packet.extend_from_slice(data);
+        self.stream.write_all(&packet).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<(u8, Vec<u8>), MilterError> {
+        let len = self.stream.read_u32().await? as usize;
+        if len == 0 || len > MAX_PACKET {
+            return Err(MilterError::Protocol(format!("packet of {} bytes", len)));
+        }
+        let command = self.stream.read_u8().await?;
+        let mut data = vec![0; len - 1];
+        self.stream.read_exact(&mut data).await?;
+        Ok((command, data))
+    }
+
+    /// The reply to a command, after any progress reports.
+    async fn reply(&mut self) -> Result<Reply, MilterError> {
+        loop {
+            let (command, data) = self.read().await?;
+            if command != b'p' {
+                return Reply::parse(command, &data);
+            }
+        }
+    }
+
+    /// Sends a stage unless the filter left it out, and waits for the
+    /// reply unless the filter gives none.
+    async fn stage(&mut self, stage: &Stage<'_>) -> Result<Reply, MilterError> {
+        let (command, data, left_out, no_reply) = match stage {
+            Stage::Connect(client) => (b'C', connect_info(*client), NO_CONNECT, NR_CONNECT),
+            Stage::Helo(domain) => (b'H', format!("{}\0", domain).into(), NO_HELO, NR_HELO),
+            Stage::Mail(from) => {
+                let from = format!("<{}>\0", address::mailbox(from));
+                (b'M', from.into(), NO_MAIL, NR_MAIL)
+            }
+            Stage::Rcpt(to) => {
+                let to = format!("<{}>\0", address::mailbox(to));
+                (b'R', to.into(), NO_RCPT, NR_RCPT)
+            }
+        };
+        self.step(command, &data, left_out, no_reply).await
+    }
+
+    async fn step(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        left_out: u32,
+        no_reply: u32,
+    ) -> Result<Reply, MilterError> {
+        if self.protocol & left_out != 0 {
+            return Ok(Reply::Continue);
+        }
+        self.send(command, data).await?;
+        if self.protocol & no_reply != 0 {
+            return Ok(Reply::Continue);
+        }
+        self.reply().await
+    }
+
+    /// Sends the content of the message, `headers` and what `body` yields,
+    /// and collects the filter's changes to it. A transaction given up
+    /// before its end is aborted, so that the session can take the next.
+    async fn content(
+        &mut self,
+        headers: &[(String, String)],
+        body: impl AsyncRead + Unpin,
+    ) -> Result<Outcome, MilterError> {
+        let outcome = self.send_content(headers, body).await?;
+        if let Some(outcome) = outcome {
+            self.send(b'A', &[]).await?;
+            return Ok(outcome);
+        }
+        self.end_of_message(headers).await
+    }
+
+    /// Returns the outcome if the filter decided before the end.
+    async fn send_content(
+        &mut self,
+        headers: &[(String, String)],
+        mut body: impl AsyncRead + Unpin,
+    ) -> Result<Option<Outcome>, MilterError> {
+        macro_rules! step {
+            ($command:expr, $data:expr, $left_out:expr, $no_reply:expr) => {
+                match self.step($command, $data, $left_out, $no_reply).await? {
+                    Reply::Continue | Reply::Skip => {}
+                    reply => return Ok(Some(Outcome::new(reply))),
+                }
+            };
+        }
+
+        step!(b'T', &[], NO_DATA, NR_DATA);
+        for (name, value) in headers {
+            let field = format!("{}\0{}\0", name, value);
+            step!(b'L', field.as_bytes(), NO_HEADERS, NR_HEADER);
+        }
+        step!(b'N', &[], NO_EOH, NR_EOH);
+        let mut chunk = vec![0; BODY_CHUNK];
+        loop {
+            let n = body.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            match self.step(b'B', &chunk[..n], NO_BODY, NR_BODY).await? {
+                Reply::Continue => {}
+                Reply::Skip => return Ok(None),
+                reply => return Ok(Some(Outcome::new(reply))),
+            }
+        }
+    }
+
+    async fn end_of_message(
+        &mut self,
+        headers: &[(String, String)],
+    ) -> Result<Outcome, MilterError> {
+        self.send(b'E', &[]).await?;
+        let mut headers = headers.to_vec();
+        let mut changed = false;
+        let mut new_body: Option<Vec<u8>> = None;
+        let mut quarantine = None;
+        let action = loop {
+            let (command, data) = self.read().await?;
+            match command {
+                b'p' => {}
+                b'h' => {
+                    let field = strings(&data);
+                    if let [name, value] = &field[..] {
+                        headers.push((name.clone(), value.clone()));
+                        changed = true;
+                    }
+                }
+                b'i' | b'm' if data.len() >= 4 => {
+                    let index = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+                    let field = strings(&data[4..]);
+                    if let [name, value] = &field[..] {
+                        if command == b'i' {
+                            headers.insert(index.min(headers.len()), (name.clone(), value.clone()));
+                        } else {
+                            change_header(&mut headers, name, index, value);
+                        }
+                        changed = true;
+                    }
+                }
+                b'b' => new_body
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(&data),
+                b'q' => quarantine = strings(&data).into_iter().next(),
+                b'+' | b'-' | b'2' | b'e' | b'f' => {
+                    tracing::warn!("Ignoring unoffered milter action {:?}", command as char)
+                }
+                _ => break Reply::parse(command, &data)?,
+            }
+        };
+        Ok(Outcome {
+            action,
+            headers: changed.then_some(headers),
+            body: new_body,
+            quarantine,
+        })
+    }
+}
+
+/// The `index`th (from 1) `name` header gets `value`, or is removed for an
+/// empty one. A missing header is added.
+fn change_header(headers: &mut Vec<(String, String)>, name: &str, index: usize, value: &str) {
+    let position = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, (n, _))| n.eq_ignore_ascii_case(name))
+        .nth(index.max(1) - 1)
+        .map(|(i, _)| i);
+    match position {
+        Some(i) if value.is_empty() => {
+            headers.remove(i);
+        }
+        Some(i) => headers[i].1 = value.to_string(),
+        None if !value.is_empty() => headers.push((name.to_string(), value.to_string())),
+        None => {}
+    }
+}
+
+fn connect_info(client: Option<IpAddr>) -> Vec<u8> {
+    let Some(ip) = client else {
+        return b"localhost\0U".to_vec();
+    };
+    let mut data = format!("[{}]\0", ip).into_bytes();
+    data.push(if ip.is_ipv4() { b'4' } else { b'6' });
+    data.extend_from_slice(&0u16.to_be_bytes());
+    data.extend_from_slice(format!("{}\0", ip).as_bytes());
+    data
+}
+
+/// The message as the filters left it so far. Only what they changed is
+/// held in memory; the rest is read from the received body.
+struct Content {
+    /// The received header, passed on as it is unless `changed`.
+    header: Vec<u8>,
+    headers: Vec<(String, String)>,
+    changed: bool,
+    /// A body that replaced the received one.
+    body: Option<Vec<u8>>,
+}
+
+impl Content {
+    /// Passes the content through one filter.
+    async fn pass(
+        &self,
+        session: &mut Session<Box<dyn Stream>>,
+        message: &Message,
+    ) -> Result<Outcome, MilterError> {
+        match &self.body {
+            Some(body) => session.content(&self.headers, &body[..]).await,
+            None => {
+                let (_, body) = message.data.split_header().await?;
+                session.content(&self.headers, body).await
+            }
+        }
+    }
+}
+
+/// A stage of an SMTP session, forwarded to the filters as it happens.
+pub(crate) enum Stage<'a> {
+    Connect(Option<IpAddr>),
+    Helo(&'a str),
+    Mail(&'a str),
+    Rcpt(&'a str),
+}
+
+struct Filter {
+    address: MilterAddress,
+    session: Session<Box<dyn Stream>>,
+    /// Accepted the connection, and sees no more of it.
+    accepted: bool,
+    /// Accepted the transaction, or has seen all of it.
+    done: bool,
+    /// Is in a transaction that has to be aborted when it ends early.
+    open: bool,
+}
+
+/// The filters of one SMTP connection, which follow it stage by stage.
+pub struct Milters {
+    config: MilterConfig,
+    filters: Vec<Filter>,
+    /// A filter failed, and mail is deferred until the connection ends.
+    failed: bool,
+    /// A filter discarded the transaction.
+    discard: bool,
+}
+
+impl std::fmt::Debug for Milters {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let addresses: Vec<_> = self.filters.iter().map(|filter| &filter.address).collect();
+        f.debug_struct("Milters")
+            .field("filters", &addresses)
+            .field("failed", &self.failed)
+            .field("discard", &self.discard)
+            .finish()
+    }
+}
+
+impl Milters {
+    /// Opens a session with each filter, as the server named `hostname`.
+    pub(crate) async fn connect(config: &MilterConfig, hostname: &str) -> Self {
+        let mut milters = Self {
+            config: config.clone(),
+            filters: vec![],
+            failed: false,
+            discard: false,
+        };
+        for address in &config.milters {
+            let opened = tokio::time::timeout(config.timeout, open(address, hostname)).await;
+            match opened.unwrap_or(Err(MilterError::Timeout)) {
+                Ok(session) => milters.filters.push(Filter {
+                    address: address.clone(),
+                    session,
+                    accepted: false,
+                    done: false,
+                    open: false,
+                }),
+                Err(e) => milters.fail(address, e),
+            }
+        }
+        milters
+    }
+
+    /// Gives up on a filter, deferring mail unless it is accepted without.
+    fn fail(&mut self, address: &MilterAddress, e: MilterError) {
+        if self.config.accept_on_failure {
+            tracing::warn!("Skipping milter {:?}: {}", address, e);
+        } else {
+            tracing::error!("Milter {:?} failed: {}", address, e);
+            self.failed = true;
+        }
+    }
+
+    /// Forwards `stage` to the filters, returning the reply refusing its
+    /// command if one of them did. A refused `MAIL` ends the transaction.
+    pub(crate) async fn stage(&mut self, stage: Stage<'_>) -> Option<status::Code> {
+        let code = match self.forward(&stage).await {
+            Reply::Reject(text) => {
+                tracing::info!("Content filter rejected {}", text);
+                status::Code::FilterRejected
+            }
+            Reply::TempFail(text) => {
+                tracing::info!("Content filter deferred {}", text);
+                status::Code::FilterDeferred
+            }
+            _ => return None,
+        };
+        if let Stage::Mail(_) = stage {
+            self.reset().await;
+        }
+        Some(code)
+    }
+
+    /// The first refusal of `stage` by a filter, or what they agreed on.
+    async fn forward(&mut self, stage: &Stage<'_>) -> Reply {
+        let transaction = matches!(stage, Stage::Mail(_) | Stage::Rcpt(_));
+        if self.failed {
+            return unavailable();
+        }
+        if transaction && self.discard {
+            return Reply::Continue;
+        }
+        let mut i = 0;
+        while i < self.filters.len() {
+            let filter = &mut self.filters[i];
+            if filter.accepted || (transaction && filter.done) {
+                i += 1;
+                continue;
+            }
+            filter.open |= matches!(stage, Stage::Mail(_));
+            let reply =
+                tokio::time::timeout(self.config.timeout, filter.session.stage(stage)).await;
+            match reply.unwrap_or(Err(MilterError::Timeout)) {
+                Ok(Reply::Continue | Reply::Skip) => {}
+                Ok(Reply::Accept) => match stage {
+                    Stage::Connect(_) | Stage::Helo(_) => filter.accepted = true,
+                    Stage::Mail(_) => filter.done = true,
+                    // Only the recipient is accepted.
+                    Stage::Rcpt(_) => {}
+                },
+                Ok(Reply::Discard) => {
+                    tracing::info!("Milter {:?} discarded the message", filter.address);
+                    self.discard = true;
+                    return Reply::Continue;
+                }
+                Ok(reply) => return reply,
+                Err(e) => {
+                    let filter = self.filters.remove(i);
+                    self.fail(&filter.address, e);
+                    if self.failed {
+                        return unavailable();
+                    }
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        Reply::Continue
+    }
+
+    /// Passes the content of `message` through the filters in turn, each
+    /// getting it as the ones before left it. `None` if it was discarded.
+    async fn content(
+        &mut self,
+        message: &Message,
+    ) -> Result<Option<(Content, Option<String>)>, HandlerError> {
+        let header = message.data.split_header().await?.0;
+        let mut content = Content {
+            headers: split_message(&header).0,
+            header,
+            changed: false,
+            body: None,
+        };
+        let mut quarantine = None;
+        let mut i = 0;
+        while i < self.filters.len() {
+            if self.failed {
+                return Err(unavailable().error().unwrap());
+            }
+            if self.discard {
+                return Ok(None);
+            }
+            let filter = &mut self.filters[i];
+            if filter.accepted || filter.done {
+                i += 1;
+                continue;
+            }
+            let filtered = tokio::time::timeout(
+                self.config.timeout,
+                content.pass(&mut filter.session, message),
+            )
+            .await;
+            let outcome = match filtered.unwrap_or(Err(MilterError::Timeout)) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    let filter = self.filters.remove(i);
+                    self.fail(&filter.address, e);
+                    continue;
+                }
+            };
+            filter.done = true;
+            filter.open = false;
+            match outcome.action {
+                Reply::Discard => {
+                    tracing::info!("Milter {:?} discarded the message", filter.address);
+                    return Ok(None);
+                }
+                reply @ (Reply::Reject(_) | Reply::TempFail(_)) => {
+                    return Err(reply.error().unwrap())
+                }
+                Reply::Continue | Reply::Accept | Reply::Skip => {}
+            }
+            if let Some(headers) = outcome.headers {
+                content.headers = headers;
+                content.changed = true;
+            }
+            if let Some(body) = outcome.body {
+                content.body = Some(body);
+            }
+            if outcome.quarantine.is_some() {
+                quarantine = outcome.quarantine;
+            }
+            i += 1;
+        }
+        if self.failed {
+            return Err(unavailable().error().unwrap());
+        }
+        Ok(Some((content, quarantine)))
+    }
+
+    /// Ends the transaction, aborting it with the filters still in it.
+    pub(crate) async fn reset(&mut self) {
+        let mut i = 0;
+        while i < self.filters.len() {
+            let filter = &mut self.filters[i];
+            filter.done = false;
+            if std::mem::take(&mut filter.open) {
+                if let Err(e) = filter.session.send(b'A', &[]).await {
+                    let filter = self.filters.remove(i);
+                    self.fail(&filter.address, e);
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        self.discard = false;
+    }
+
+    /// Closes the sessions, which the filters may have ended already.
+    pub(crate) async fn quit(&mut self) {
+        for mut filter in self.filters.drain(..) {
+            let _ = filter.session.send(b'Q', &[]).await;
+        }
+    }
+}
+
+async fn open(
+    address: &MilterAddress,
+    hostname: &str,
+) -> Result<Session<Box<dyn Stream>>, MilterError> {
+    let stream: Box<dyn Stream> = match address {
+        MilterAddress::Tcp(addr) => Box::new(tokio::net::TcpStream::connect(addr).await?),
+        #[cfg(unix)]
+        MilterAddress::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+    };
+    let mut session = Session::negotiate(stream).await?;
+    session
+        .send(b'D', format!("Cj\0{}\0", hostname).as_bytes())
+        .await?;
+    Ok(session)
+}
+
+fn unavailable() -> Reply {
+    Reply::TempFail("Content filter unavailable".to_string())
+}
+
+/// Passes mail through the configured filters before handing it to
+/// `inner`, as they changed it, to the recipients they accepted.
+pub struct MilterHandler {
+    config: MilterConfig,
+    hostname: String,
+    inner: Box<dyn Handler + Send + Sync>,
+}
+
+impl MilterHandler {
+    pub fn new(
+        config: MilterConfig,
+        hostname: impl Into<String>,
+        inner: Box<dyn Handler + Send + Sync>,
+    ) -> Self {
+        Self {
+            config,
+            hostname: hostname.into(),
+            inner,
+        }
+    }
+
+    /// Replays the envelope of `message`, which did not pass the filters as
+    /// it came in, returning the refusals of its envelope recipients.
+    async fn replay(
+        &self,
+        milters: &mut Milters,
+        message: &Message,
+    ) -> Result<Vec<Option<HandlerError>>, HandlerError> {
+        let mut stages = vec![Stage::Connect(message.client)];
+        if !message.sender_domain.is_empty() {
+            stages.push(Stage::Helo(&message.sender_domain));
+        }
+        stages.push(Stage::Mail(&message.from));
+        for stage in stages {
+            if let Some(e) = milters.forward(&stage).await.error() {
+                return Err(e);
+            }
+        }
+        let mut refused = vec![];
+        for to in &message.to {
+            refused.push(milters.forward(&Stage::Rcpt(to)).await.error());
+        }
+        Ok(refused)
+    }
+
+    /// Passes `message` through the filters, and on to `inner` except for
+    /// recipients whose envelope recipients were all `refused`.
+    async fn pass(
+        &self,
+        milters: &mut Milters,
+        message: &Message,
+        refused: &[Option<HandlerError>],
+    ) -> Vec<Result<(), HandlerError>> {
+        let mut results = vec![Ok(()); message.recipients.len()];
+        let mut passed = vec![];
+        for (i, recipient) in message.recipients.iter().enumerate() {
+            let refusal = recipient
+                .origins
+                .iter()
+                .map(|&rcpt| refused.get(rcpt).cloned().flatten())
+                .reduce(|a, b| a.and(b))
+                .flatten();
+            match refusal {
+                Some(e) => results[i] = Err(e),
+                None => passed.push(i),
+            }
+        }
+        if passed.is_empty() {
+            return results;
+        }
+        let (content, quarantine) = match milters.content(message).await {
+            Ok(Some(filtered)) => filtered,
+            Ok(None) => return results,
+            Err(e) => {
+                for &i in &passed {
+                    results[i] = Err(e.clone());
+                }
+                return results;
+            }
+        };
+        let mut header = if content.changed {
+            join_message(&content.headers, &[])
+        } else {
+            content.header
+        };
+        if let Some(reason) = &quarantine {
+            tracing::info!("Quarantining message: {}", reason);
+            let mut quarantined = format!("X-Quarantine-Reason: {}\r\n", reason).into_bytes();
+            quarantined.extend_from_slice(&header);
+            header = quarantined;
+        }
+        let recipients = passed
+            .iter()
+            .map(|&i| Recipient {
+                quarantined: quarantine.is_some() || message.recipients[i].quarantined,
+                ..message.recipients[i].clone()
+            })
+            .collect();
+        let filtered = match content.body {
+            Some(body) => Ok(message.copy_with(recipients, [header, body].concat())),
+            None => match message.data.split_header().await {
+                Ok((_, rest)) => message.copy_streamed(recipients, &header, rest).await,
+                Err(e) => Err(e),
+            },
+        };
+        let inner = match filtered {
+            Ok(filtered) => self.inner.handle_recipients(&filtered).await,
+            Err(e) => vec![Err(e.into()); passed.len()],
+        };
+        for (i, result) in passed.into_iter().zip(inner) {
+            results[i] = result;
+        }
+        results
+    }
+}
+
+#[async_trait]
+impl Handler for MilterHandler {
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+        message::summarize(&self.handle_recipients(message).await)
+    }
+
+    async fn handle_recipients(&self, message: &Message) -> Vec<Result<(), HandlerError>> {
+        // The filters followed the session, and have seen the envelope.
+        if let Some(milters) = &message.milters {
+            return self.pass(&mut *milters.lock().await, message, &[]).await;
+        }
+        let mut milters = Milters::connect(&self.config, &self.hostname).await;
+        let results = match self.replay(&mut milters, message).await {
+            Ok(refused) => self.pass(&mut milters, message, &refused).await,
+            Err(e) => vec![Err(e); message.recipients.len()],
+        };
+        milters.reset().await;
+        milters.quit().await;
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SqliteStore;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A filter answering each command with the packets `respond` gives,
+    /// which yields the commands it got once the client quits.
+    async fn fake_milter<F>(respond: F) -> (MilterAddress, JoinHandle<Vec<(u8, Vec<u8>)>>)
+    where
+        F: Fn(u8, &[u8]) -> Vec<(u8, Vec<u8>)> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = MilterAddress::Tcp(listener.local_addr().unwrap().to_string());
+        let log = tokio::spawn(async move {
+            let mut commands = vec![];
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut session = Session {
+                stream,
+                protocol: 0,
+            };
+            while let Ok((command, data)) = session.read().await {
+                commands.push((command, data.clone()));
+                let replies = match command {
+                    b'O' => {
+                        let mut data = VERSION.to_be_bytes().to_vec();
+                        data.extend_from_slice(&ACTIONS.to_be_bytes());
+                        data.extend_from_slice(&(NO_HELO | NR_HEADER).to_be_bytes());
+                        vec![(b'O', data)]
+                    }
+                    // Headers go unanswered, as negotiated.
+                    b'D' | b'A' | b'L' => vec![],
+                    b'Q' => break,
+                    _ => respond(command, &data),
+                };
+                for (command, data) in replies {
+                    session.send(command, &data).await.unwrap();
+                }
+            }
+            commands
+        });
+        (address, log)
+    }
+
+    fn continues(_: u8, _: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        vec![(b'c', vec![])]
+    }
+
+    fn message(to: &[&str]) -> Message {
+        Message {
+            sender_domain: "mail.example.net".to_string(),
+            client: Some("192.0.2.1".parse().unwrap()),
+            from: "<alice@example.net>".to_string(),
+            to: to.iter().map(|to| format!("<{}>", to)).collect(),
+            recipients: to
+                .iter()
+                .enumerate()
+                .map(|(i, to)| Recipient {
+                    origins: vec![i],
+                    ..Recipient::new(*to)
+                })
+                .collect(),
+            data: b"Subject: Hi\r\nFrom: alice@example.net\r\n\r\nHello\r\n"
+                .as_slice()
+                .into(),
+            ..Default::default()
+        }
+    }
+
+    async fn milter_handler(
+        milters: Vec<MilterAddress>,
+    ) -> (MilterHandler, SqliteStore, tempfile::NamedTempFile) {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let config = MilterConfig {
+            milters,
+            timeout: Duration::from_secs(5),
+            accept_on_failure: false,
+        };
+        let handler = MilterHandler::new(config, "mx.example.com", Box::new(store.clone()));
+        (handler, store, temp_file)
+    }
+
+    #[test]
+    fn test_address() {
+        let parse = |s: &str| s.parse::<MilterAddress>().unwrap();
+        assert_eq!(
+            parse("inet:8891@localhost"),
+            MilterAddress::Tcp("localhost:8891".into())
+        );
+        assert_eq!(
+            parse("inet6:8891@::1"),
+            MilterAddress::Tcp("[::1]:8891".into())
+        );
+        assert_eq!(
+            parse("127.0.0.1:8891"),
+            MilterAddress::Tcp("127.0.0.1:8891".into())
+        );
+        assert_eq!(
+            parse("unix:/run/milter.sock"),
+            MilterAddress::Unix("/run/milter.sock".into())
+        );
+        assert_eq!(
+            parse("local:/run/milter.sock"),
+            MilterAddress::Unix("/run/milter.sock".into())
+        );
+    }
+
+    #[test]
+    fn test_headers() {
+        let data =
+            b"Subject: Hi\r\nReceived: from a\r\n\tby b\r\nX-Two: 1\r\nX-Two: 2\r\n\r\nBody\r\n";
+        let (mut headers, body) = split_message(data);
+        assert_eq!(body, b"Body\r\n");
+        assert_eq!(
+            headers[1],
+            ("Received".to_string(), "from a\r\n\tby b".to_string())
+        );
+        assert_eq!(join_message(&headers, body), data);
+
+        change_header(&mut headers, "x-two", 2, "two");
+        change_header(&mut headers, "Subject", 1, "");
+        change_header(&mut headers, "X-New", 1, "new\n\tfolded");
+        assert_eq!(
+            join_message(&headers, body),
+            b"Received: from a\r\n\tby b\r\nX-Two: 1\r\nX-Two: two\r\nX-New: new\r\n\tfolded\r\n\r\nBody\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stages_and_changes() {
+        let (milter, log) = fake_milter(|command, data| match command {
+            b'R' if data.starts_with(b"<eve@") => vec![(b'r', vec![])],
+            b'E' => {
+                let mut change = 1u32.to_be_bytes().to_vec();
+                change.extend_from_slice(b"Subject\0Changed\0");
+                vec![
+                    (b'p', vec![]),
+                    (b'h', b"X-Scanned\0clean\0".to_vec()),
+                    (b'm', change),
+                    (b'b', b"New body\r\n".to_vec()),
+                    (b'c', vec![]),
+                ]
+            }
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+        let (handler, store, _temp_file) = milter_handler(vec![milter]).await;
+
+        let results = handler
+            .handle_recipients(&message(&["bob@example.com", "eve@example.com"]))
+            .await;
+        assert!(results[0].is_ok());
+        assert!(!results[1].as_ref().unwrap_err().is_temporary());
+
+        let log = log.await.unwrap();
+        let commands: Vec<char> = log.iter().map(|(c, _)| *c as char).collect();
+        // HELO was left out by the filter.
+        assert_eq!(
+            commands,
+            ['O', 'D', 'C', 'M', 'R', 'R', 'T', 'L', 'L', 'N', 'B', 'E', 'Q']
+        );
+        assert_eq!(log[2].1, b"[192.0.2.1]\x004\x00\x00192.0.2.1\x00");
+
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        let data = store.message_data(messages[0].id).await.unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&data),
+            "Subject: Changed\r\nFrom: alice@example.net\r\nX-Scanned: clean\r\n\r\nNew body\r\n"
+        );
+        assert!(store
+            .list_messages("eve@example.com")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_actions() {
+        let replies: [(u8, &[u8]); 3] = [
+            (b'y', b"554 5.7.1 Virus found\0"),
+            (b'y', b"451 4.7.1 Try again\0"),
+            (b't', b""),
+        ];
+        for (command, reply) in replies {
+            let (milter, _) = fake_milter(move |c, _| match c {
+                b'E' => vec![(command, reply.to_vec())],
+                _ => vec![(b'c', vec![])],
+            })
+            .await;
+            let (handler, _, _temp_file) = milter_handler(vec![milter]).await;
+            let result = handler.handle_message(&message(&["bob@example.com"])).await;
+            assert_eq!(
+                result.unwrap_err().is_temporary(),
+                !reply.starts_with(b"5"),
+                "{:?}",
+                reply
+            );
+        }
+
+        // Discarded at MAIL: accepted but not delivered.
+        let (milter, log) = fake_milter(|c, _| match c {
+            b'M' => vec![(b'd', vec![])],
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+        let (handler, store, _temp_file) = milter_handler(vec![milter]).await;
+        handler
+            .handle_message(&message(&["bob@example.com"]))
+            .await
+            .unwrap();
+        assert!(store
+            .list_messages("bob@example.com")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(log.await.unwrap().last().unwrap().0, b'Q');
+
+        // Quarantined by the first filter, and accepted early by the second.
+        let (quarantining, _) = fake_milter(|c, _| match c {
+            b'E' => vec![(b'q', b"Suspicious\0".to_vec()), (b'c', vec![])],
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+        let (accepting, log) = fake_milter(|c, _| match c {
+            b'C' => vec![(b'a', vec![])],
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+        let (handler, store, _temp_file) = milter_handler(vec![quarantining, accepting]).await;
+        handler
+            .handle_message(&message(&["bob@example.com"]))
+            .await
+            .unwrap();
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        assert_eq!(messages[0].folder, QUARANTINE);
+        let data = store.message_data(messages[0].id).await.unwrap().unwrap();
+        assert!(data.starts_with(b"X-Quarantine-Reason: Suspicious\r\nSubject: Hi\r\n"));
+        let commands: Vec<u8> = log.await.unwrap().iter().map(|(c, _)| *c).collect();
+        assert_eq!(commands, b"ODCQ");
+    }
+
+    #[tokio::test]
+    async fn test_live_stages() {
+        let (milter, log) = fake_milter(|command, data| match command {
+            b'M' if data.starts_with(b"<spam@") => vec![(b'r', vec![])],
+            b'R' if data.starts_with(b"<eve@") => vec![(b't', vec![])],
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+        let (handler, store, _temp_file) = milter_handler(vec![milter]).await;
+        let mut milters = Milters::connect(&handler.config, "mx.example.com").await;
+        assert_eq!(milters.stage(Stage::Connect(None)).await, None);
+        assert_eq!(
+            milters.stage(Stage::Mail("<spam@example.org>")).await,
+            Some(status::Code::FilterRejected)
+        );
+        assert_eq!(
+            milters.stage(Stage::Mail("<alice@example.net>")).await,
+            None
+        );
+        assert_eq!(
+            milters.stage(Stage::Rcpt("<eve@example.com>")).await,
+            Some(status::Code::FilterDeferred)
+        );
+        assert_eq!(milters.stage(Stage::Rcpt("<bob@example.com>")).await, None);
+
+        // The envelope is not replayed with the content.
+        let milters = Arc::new(tokio::sync::Mutex::new(milters));
+        let message = Message {
+            milters: Some(milters.clone()),
+            ..message(&["bob@example.com"])
+        };
+        handler.handle_message(&message).await.unwrap();
+        milters.lock().await.reset().await;
+        milters.lock().await.quit().await;
+
+        let commands: Vec<u8> = log.await.unwrap().iter().map(|(c, _)| *c).collect();
+        assert_eq!(commands, b"ODCMAMRRTLLNBEQ");
+        assert_eq!(
+            store.list_messages("bob@example.com").await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let milter = MilterAddress::Tcp(listener.local_addr().unwrap().to_string());
+        drop(listener);
+
+        let (mut handler, store, _temp_file) = milter_handler(vec![milter]).await;
+        let result = handler.handle_message(&message(&["bob@example.com"])).await;
+        assert!(result.unwrap_err().is_temporary());
+
+        handler.config.accept_on_failure = true;
+        let (unchanged, _) = fake_milter(continues).await;
+        handler.config.milters.push(unchanged);
+        handler
+            .handle_message(&message(&["bob@example.com"]))
+            .await
+            .unwrap();
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        let data = store.message_data(messages[0].id).await.unwrap().unwrap();
+        assert_eq!(
+            data,
+            b"Subject: Hi\r\nFrom: alice@example.net\r\n\r\nHello\r\n"
+        );
+    }
+}
@@ -0,0 +1,745 @@
+//! A lenient MIME (RFC 2045-2049) parser over the raw bytes of a message.
+//!
+//! Parsing is lazy: [`Part::parse`] only splits off the header block.
+//! Sub-parts are found the first time [`Part::parts`] is called, and bodies
+//! are only decoded when asked for, so large attachments cost nothing unless
+//! somebody reads them.
+
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use encoding_rs::{Encoding, UTF_8};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent)
+        .with_decode_allow_trailing_bits(true),
+);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    /// The value as it appears in the message, still folded and encoded.
+    pub raw_value: &'a [u8],
+}
+
+impl Header<'_> {
+    /// Returns the unfolded value with RFC 2047 encoded words decoded.
+    pub fn value(&self) -> String {
+        decode_words(&unfold(self.raw_value))
+    }
+}
+
+/// A header value with parameters, such as `Content-Type` or
+/// `Content-Disposition`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldValue {
+    /// The lowercased value before the first `;`.
+    pub value: String,
+    /// Parameters with lowercased names and decoded values.
+    pub params: Vec<(String, String)>,
+}
+
+impl FieldValue {
+    pub fn parse(s: &str) -> Self {
+        let mut fields = split_params(s).into_iter();
+        let value = fields
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let mut params = vec![];
+        let mut extended = vec![];
+        for field in fields {
+            let Some((name, value)) = field.split_once('=') else {
+                continue;
+            };
+            let name = name.trim().to_ascii_lowercase();
+            let value = unquote(value.trim());
+            match name.split_once('*') {
+                // RFC 2231 parameter value continuations and charsets.
+                Some((base, section)) => {
+                    let encoded = section.ends_with('*') || section.is_empty();
+                    let index = section.trim_end_matches('*').parse().unwrap_or(0);
+                    extended.push((base.to_string(), index, encoded, value));
+                }
+                None => params.push((name, decode_words(&value))),
+            }
+        }
+        extended.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        for (base, sections) in group_by_name(extended) {
+            params.retain(|(name, _)| *name != base);
+            params.push((base, decode_extended(&sections)));
+        }
+        Self { value, params }
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// What the structure of a message says about one attachment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Attachment {
+    pub filename: Option<String>,
+    pub content_type: String,
+    /// Decoded size in bytes.
+    pub size: usize,
+}
+
+/// Entities nested deeper than this are not looked into, so that a message
+/// cannot recurse the parser off the stack.
+pub const MAX_DEPTH: usize = 64;
+
+/// One entity of a MIME message. A whole message is its own top-level
+/// part.
+#[derive(Debug)]
+pub struct Part<'a> {
+    headers: Vec<Header<'a>>,
+    body: &'a [u8],
+    content_type: FieldValue,
+    parts: OnceLock<Vec<Part<'a>>>,
+    depth: usize,
+}
+
+impl<'a> Part<'a> {
+    pub fn parse(raw: &'a [u8]) -> Self {
+        Self::parse_nested(raw, 0)
+    }
+
+    fn parse_nested(raw: &'a [u8], depth: usize) -> Self {
+        let (headers, body) = split_headers(raw);
+        let content_type = headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("content-type"))
+            .map(|h| FieldValue::parse(&unfold(h.raw_value)))
+            .filter(|ct| ct.value.contains('/'))
+            .unwrap_or_else(|| FieldValue::parse("text/plain; charset=us-ascii"));
+        Self {
+            headers,
+            body,
+            content_type,
+            parts: OnceLock::new(),
+            depth,
+        }
+    }
+
+    pub fn headers(&self) -> &[Header<'a>] {
+        &self.headers
+    }
+
+    /// Returns the decoded value of the first header called `name`.
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(Header::value)
+    }
+
+    pub fn subject(&self) -> Option<String> {
+        self.header("subject")
+    }
+
+    pub fn content_type(&self) -> &FieldValue {
+        &self.content_type
+    }
+
+    pub fn content_disposition(&self) -> Option<FieldValue> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("content-disposition"))
+            .map(|h| FieldValue::parse(&unfold(h.raw_value)))
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.content_type.value.starts_with("multipart/")
+    }
+
+    /// The children of a multipart, or the message inside a
+    /// `message/rfc822`; empty for everything else, and below
+    /// [`MAX_DEPTH`].
+    pub fn parts(&self) -> &[Part<'a>] {
+        self.parts.get_or_init(|| {
+            let depth = self.depth + 1;
+            if depth > MAX_DEPTH {
+                vec![]
+            } else if self.is_multipart() {
+                match self.content_type.param("boundary") {
+                    Some(boundary) => split_multipart(self.body, boundary)
+                        .into_iter()
+                        .map(|raw| Part::parse_nested(raw, depth))
+                        .collect(),
+                    None => vec![],
+                }
+            } else if self.content_type.value == "message/rfc822" {
+                vec![Part::parse_nested(self.body, depth)]
+            } else {
+                vec![]
+            }
+        })
+    }
+
+    /// Every part without children, depth first.
+    pub fn leaves(&self) -> Vec<&Part<'a>> {
+        if self.parts().is_empty() {
+            return vec![self];
+        }
+        self.parts().iter().flat_map(Part::leaves).collect()
+    }
+
+    /// The body exactly as it appears in the message.
+    pub fn raw_body(&self) -> &'a [u8] {
+        self.body
+    }
+
+    /// The body with its content transfer encoding undone.
+    pub fn body(&self) -> Cow<'a, [u8]> {
+        let encoding = self
+            .header("content-transfer-encoding")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match encoding.trim() {
+            "base64" => {
+                let data: Vec<u8> = self
+                    .body
+                    .iter()
+                    .copied()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                BASE64
+                    .decode(data)
+                    .map(Cow::Owned)
+                    .unwrap_or(Cow::Borrowed(self.body))
+            }
+            "quoted-printable" => Cow::Owned(decode_quoted_printable(self.body, false)),
+            _ => Cow::Borrowed(self.body),
+        }
+    }
+
+    /// The decoded body converted to UTF-8, for `text/*` parts.
+    pub fn text(&self) -> Option<String> {
+        if !self.content_type.value.starts_with("text/") {
+            return None;
+        }
+        let charset = self.content_type.param("charset").unwrap_or("us-ascii");
+        Some(decode_charset(&self.body(), charset))
+    }
+
+    pub fn filename(&self) -> Option<String> {
+        self.content_disposition()
+            .and_then(|d| d.param("filename").map(str::to_string))
+            .or_else(|| self.content_type.param("name").map(str::to_string))
+    }
+
+    /// Whether this is a leaf meant to be saved rather than read inline.
+    pub fn is_attachment(&self) -> bool {
+        if !self.parts().is_empty() {
+            return false;
+        }
+        match self.content_disposition() {
+            Some(d) if d.value == "attachment" => true,
+            _ => !self.content_type.value.starts_with("text/") && self.filename().is_some(),
+        }
+    }
+
+    /// Describes every attachment. Each one is decoded to find its size.
+    pub fn attachments(&self) -> Vec<Attachment> {
+        self.leaves()
+            .into_iter()
+            .filter(|p| p.is_attachment())
+            .map(|p| Attachment {
+                filename: p.filename(),
+                content_type: p.content_type.value.clone(),
+                size: p.body().len(),
+            })
+            .collect()
+    }
+
+    /// The first inline `text/plain` part.
+    pub fn text_body(&self) -> Option<String> {
+        self.first_inline("text/plain")
+    }
+
+    /// The first inline `text/html` part.
+    pub fn html_body(&self) -> Option<String> {
+        self.first_inline("text/html")
+    }
+
+    fn first_inline(&self, content_type: &str) -> Option<String> {
+        self.leaves()
+            .into_iter()
+            .find(|p| p.content_type.value == content_type && !p.is_attachment())
+            .and_then(Part::text)
+    }
+}
+
+/// Returns the next line of `data` starting at `pos`, and the position after
+/// its line ending.
+fn next_line(data: &[u8], pos: usize) -> (&[u8], usize) {
+    let end = data[pos..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map(|i| pos + i + 1)
+        .unwrap_or(data.len());
+    (trim_newline(&data[pos..end]), end)
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn split_headers(raw: &[u8]) -> (Vec<Header<'_>>, &[u8]) {
+    let mut headers: Vec<Header> = vec![];
+    let mut pos = 0;
+    while pos < raw.len() {
+        let (line, end) = next_line(raw, pos);
+        if line.is_empty() {
+            return (headers, &raw[end..]);
+        }
+        if matches!(line[0], b' ' | b'\t') {
+            // A folded continuation of the previous header.
+            if let Some(last) = headers.last_mut() {
+                let start = last.raw_value.as_ptr() as usize - raw.as_ptr() as usize;
+                last.raw_value = &raw[start..pos + line.len()];
+            }
+        } else if let Some((name, colon)) = line.iter().position(|&b| b == b':').and_then(|colon| {
+            let name = std::str::from_utf8(&line[..colon]).ok()?;
+            Some((name, colon))
+        }) {
+            headers.push(Header {
+                name: name.trim(),
+                raw_value: &raw[pos + colon + 1..pos + line.len()],
+            });
+        } else if headers.is_empty() {
+            // No header block at all.
+            return (headers, raw);
+        }
+        pos = end;
+    }
+    (headers, &raw[raw.len()..])
+}
+
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = vec![];
+    let mut start = None;
+    let mut pos = 0;
+    while pos < body.len() {
+        let (line, end) = next_line(body, pos);
+        if let Some(tail) = line.strip_prefix(delimiter.as_bytes()) {
+            let close = tail.starts_with(b"--");
+            if close || tail.iter().all(|b| matches!(b, b' ' | b'\t')) {
+                if let Some(start) = start {
+                    // The line break before a delimiter belongs to it.
+                    let part = &body[start..pos];
+                    let part = part
+                        .strip_suffix(b"\n")
+                        .map(|p| p.strip_suffix(b"\r").unwrap_or(p))
+                        .unwrap_or(part);
+                    parts.push(part);
+                }
+                if close {
+                    return parts;
+                }
+                start = Some(end);
+            }
+        }
+        pos = end;
+    }
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+fn unfold(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw)
+        .chars()
+        .filter(|&c| c != '\r' && c != '\n')
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Splits at `;` outside quoted strings.
+fn split_params(s: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in s.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                fields.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        fields.last_mut().unwrap().push(c);
+    }
+    fields
+}
+
+fn unquote(s: &str) -> String {
+    let Some(inner) = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) else {
+        return s.to_string();
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+type Section = (String, u32, bool, String);
+
+fn group_by_name(sections: Vec<Section>) -> Vec<(String, Vec<Section>)> {
+    let mut groups: Vec<(String, Vec<Section>)> = vec![];
+    for section in sections {
+        match groups.last_mut() {
+            Some((name, group)) if *name == section.0 => group.push(section),
+            _ => groups.push((section.0.clone(), vec![section])),
+        }
+    }
+    groups
+}
+
+/// Joins RFC 2231 sections, the first of which may name a charset as
+/// `charset'language'value`.
+fn decode_extended(sections: &[Section]) -> String {
+    let mut charset = "us-ascii".to_string();
+    let mut bytes = vec![];
+    for (i, (_, _, encoded, value)) in sections.iter().enumerate() {
+        let mut value = value.as_str();
+        if i == 0 && *encoded {
+            let mut fields = value.splitn(3, '\'');
+            if let (Some(cs), Some(_), Some(rest)) = (fields.next(), fields.next(), fields.next()) {
+                if !cs.is_empty() {
+                    charset = cs.to_string();
+                }
+                value = rest;
+            }
+        }
+        if *encoded {
+            bytes.extend(percent_decode(value.as_bytes()));
+        } else {
+            bytes.extend_from_slice(value.as_bytes());
+        }
+    }
+    decode_charset(&bytes, &charset)
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+fn percent_decode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match (
+            data[i],
+            data.get(i + 1).and_then(|&b| hex_value(b)),
+            data.get(i + 2).and_then(|&b| hex_value(b)),
+        ) {
+            (b'%', Some(hi), Some(lo)) => {
+                out.push(hi << 4 | lo);
+                i += 3;
+            }
+            (b, _, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Decodes quoted-printable, or the RFC 2047 "Q" encoding when `q` is set.
+fn decode_quoted_printable(data: &[u8], q: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'=' => {
+                let rest = &data[i + 1..];
+                if let (Some(hi), Some(lo)) = (
+                    rest.first().and_then(|&b| hex_value(b)),
+                    rest.get(1).and_then(|&b| hex_value(b)),
+                ) {
+                    out.push(hi << 4 | lo);
+                    i += 3;
+                } else if rest.starts_with(b"\r\n") {
+                    i += 3;
+                } else if rest.starts_with(b"\n") {
+                    i += 2;
+                } else {
+                    out.push(b'=');
+                    i += 1;
+                }
+            }
+            b'_' if q => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Converts `data` in `charset` to UTF-8, replacing what cannot be decoded.
+pub fn decode_charset(data: &[u8], charset: &str) -> String {
+    Encoding::for_label(charset.trim().as_bytes())
+        .unwrap_or(UTF_8)
+        .decode_without_bom_handling(data)
+        .0
+        .into_owned()
+}
+
+/// The mailboxes in an address list header such as
+/// `Alice <alice@example.com>, "Bob, Jr." <bob@example.com>, team: ;`.
+pub fn addresses(value: &str) -> Vec<String> {
+    let mut items = vec![];
+    let (mut item, mut quoted, mut angle, mut comment) = (String::new(), false, false, 0);
+    for c in value.chars() {
+        match c {
+            '"' if comment == 0 => quoted = !quoted,
+            '(' if !quoted => comment += 1,
+            ')' if !quoted && comment > 0 => {
+                comment -= 1;
+                continue;
+            }
+            '<' if !quoted && comment == 0 => angle = true,
+            '>' if !quoted && comment == 0 => angle = false,
+            // The display name of a group.
+            ':' if !quoted && !angle && comment == 0 => {
+                item.clear();
+                continue;
+            }
+            ',' | ';' if !quoted && !angle && comment == 0 => {
+                items.push(std::mem::take(&mut item));
+                continue;
+            }
+            _ => {}
+        }
+        if comment == 0 && c != '(' {
+            item.push(c);
+        }
+    }
+    items.push(item);
+    items
+        .iter()
+        .map(|item| {
+            // Only the part in angle brackets, or the bare address.
+            let item = match item.rfind('<') {
+                Some(start) => &item[start..],
+                None => item.trim(),
+            };
+            crate::smtp::address::mailbox(item).to_string()
+        })
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// Decodes the RFC 2047 encoded words in a header value. Whitespace between
+/// adjacent encoded words is dropped.
+pub fn decode_words(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match encoded_word(candidate) {
+            Some((decoded, len)) => {
+                if !(after_word && before.trim().is_empty()) {
+                    out.push_str(before);
+                }
+                out.push_str(&decoded);
+                rest = &candidate[len..];
+                after_word = true;
+            }
+            None => {
+                out.push_str(before);
+                out.push_str("=?");
+                rest = &candidate[2..];
+                after_word = false;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Decodes the `=?charset?encoding?text?=` at the start of `s`, returning
+/// the text and how many bytes the word took up.
+fn encoded_word(s: &str) -> Option<(String, usize)> {
+    let inner = s.strip_prefix("=?")?;
+    let (charset, inner) = inner.split_once('?')?;
+    let (encoding, inner) = inner.split_once('?')?;
+    let end = inner.find("?=")?;
+    let text = &inner[..end];
+    if text.contains(char::is_whitespace) || charset.contains(char::is_whitespace) {
+        return None;
+    }
+    let bytes = match encoding {
+        "B" | "b" => BASE64.decode(text).ok()?,
+        "Q" | "q" => decode_quoted_printable(text.as_bytes(), true),
+        _ => return None,
+    };
+    // RFC 2231 allows a language after the charset: `utf-8*en`.
+    let charset = charset.split('*').next().unwrap_or(charset);
+    let len = s.len() - inner.len() + end + 2;
+    Some((decode_charset(&bytes, charset), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addresses() {
+        assert_eq!(
+            addresses("\"Bob, Jr.\" <bob@example.com>, alice@example.net (Alice), team: carol@example.org;"),
+            ["bob@example.com", "alice@example.net", "carol@example.org"]
+        );
+        assert!(addresses("undisclosed-recipients:;").is_empty());
+    }
+
+    const MESSAGE: &[u8] = b"From: =?ISO-8859-1?Q?Andr=E9?= <andre@example.com>\r\n\
+Subject: =?UTF-8?B?SGVsbG8s?=\r\n =?UTF-8?Q?_w=C3=B6rld?=\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+preamble\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=inner\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain; charset=iso-8859-1\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Caf=E9 au lait, a very long line that is soft=\r\n\
+\x20broken\r\n\
+--inner\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+\r\n\
+<p>Caf\xc3\xa9</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=\"ignored.pdf\"\r\n\
+Content-Disposition: attachment;\r\n\
+\x20filename*=UTF-8''r%C3%A9sum%C3%A9.pdf\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0x\r\n\
+LjQ=\r\n\
+--outer--\r\n\
+epilogue\r\n";
+
+    #[test]
+    fn test_headers() {
+        let message = Part::parse(MESSAGE);
+        assert_eq!(
+            message.header("from").unwrap(),
+            "Andr\u{e9} <andre@example.com>"
+        );
+        assert_eq!(message.subject().unwrap(), "Hello, w\u{f6}rld");
+        assert_eq!(message.headers().len(), 4);
+        assert_eq!(message.content_type().value, "multipart/mixed");
+        assert_eq!(message.content_type().param("boundary"), Some("outer"));
+    }
+
+    #[test]
+    fn test_multipart_tree() {
+        let message = Part::parse(MESSAGE);
+        assert_eq!(message.parts().len(), 2);
+        assert_eq!(message.parts()[0].parts().len(), 2);
+        assert_eq!(message.leaves().len(), 3);
+        assert_eq!(
+            message.text_body().unwrap(),
+            "Caf\u{e9} au lait, a very long line that is soft broken"
+        );
+        assert_eq!(message.html_body().unwrap(), "<p>Caf\u{e9}</p>");
+    }
+
+    #[test]
+    fn test_attachments() {
+        let message = Part::parse(MESSAGE);
+        assert_eq!(
+            message.attachments(),
+            vec![Attachment {
+                filename: Some("r\u{e9}sum\u{e9}.pdf".to_string()),
+                content_type: "application/pdf".to_string(),
+                size: 8,
+            }]
+        );
+        assert_eq!(&*message.leaves()[2].body(), b"%PDF-1.4");
+    }
+
+    #[test]
+    fn test_deeply_nested() {
+        let mut data = b"Content-Type: text/plain\r\n\r\nHello\r\n".to_vec();
+        for level in 0..3000 {
+            let mut outer =
+                format!("Content-Type: multipart/mixed; boundary=b{level}\r\n\r\n--b{level}\r\n")
+                    .into_bytes();
+            outer.extend_from_slice(&data);
+            outer.extend_from_slice(format!("\r\n--b{level}--\r\n").as_bytes());
+            data = outer;
+        }
+        let message = Part::parse(&data);
+        let leaves = message.leaves();
+        assert_eq!(leaves.len(), 1);
+        assert!(leaves[0].is_multipart());
+        assert!(message.attachments().is_empty());
+        assert_eq!(message.text_body(), None);
+    }
+
+    #[test]
+    fn test_plain_message() {
+        let message = Part::parse(b"Subject: Hi\nX-Folded: a\n\tb\n\nHello\n");
+        assert_eq!(message.header("x-folded").unwrap(), "a\tb");
+        assert!(message.parts().is_empty());
+        assert_eq!(message.text_body().unwrap(), "Hello\n");
+        assert!(message.attachments().is_empty());
+    }
+
+    #[test]
+    fn test_decode_words() {
+        assert_eq!(decode_words("plain text"), "plain text");
+        assert_eq!(decode_words("=?utf-8?q?a_b?= =?utf-8?q?c?="), "a bc");
+        assert_eq!(decode_words("x =?utf-8?b?w6k=?= y"), "x \u{e9} y");
+        assert_eq!(decode_words("=?broken"), "=?broken");
+        assert_eq!(decode_words("=?utf-8*en?q?hi?="), "hi");
+    }
+
+    #[test]
+    fn test_field_value() {
+        let value = FieldValue::parse(
+            "Attachment; filename*0*=utf-8''a%20; filename*1=\"b;c.txt\"; size=3",
+        );
+        assert_eq!(value.value, "attachment");
+        assert_eq!(value.param("filename"), Some("a b;c.txt"));
+        assert_eq!(value.param("SIZE"), Some("3"));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::autocrypt;
+use crate::dsn;
+use crate::lmtp::{LmtpAddress, LmtpHandler};
+use crate::message::{Handler, Message, Recipient};
+use crate::retry::{Backoff, LEASE};
+use crate::storage::{OutboundMessage, SqliteStore};
+use crate::time::unix_time;
+use std::time::Duration;
+
+/// Messages still failing after this many attempts are dropped.
+pub const MAX_ATTEMPTS: i64 = 16;
+
+const BACKOFF: Backoff = Backoff {
+    initial: 60,
+    max: 4 * 60 * 60,
+};
+
+/// Sends the mail queued by Sieve redirects, auto-replies and forwards
+/// through a smarthost, retrying temporary failures with exponential backoff.
+#[derive(Debug, Clone)]
+pub struct Relay {
+    store: SqliteStore,
+    client: LmtpHandler,
+    hostname: String,
+    autocrypt: bool,
+}
+
+impl Relay {
+    pub fn new(store: SqliteStore, address: LmtpAddress, hostname: impl Into<String>) -> Self {
+        let hostname = hostname.into();
+        Self {
+            store,
+            client: LmtpHandler::smtp(address, hostname.clone()),
+            hostname,
+            autocrypt: false,
+        }
+    }
+
+    /// Announces the keys of local senders in `Autocrypt` headers.
+    pub fn with_autocrypt(mut self, autocrypt: bool) -> Self {
+        self.autocrypt = autocrypt;
+        self
+    }
+
+    /// Makes one attempt at every queued message that is due, returning
+    /// how many were sent.
+    pub async fn send_due(&self) -> Result<usize, sqlx::Error> {
+        let now = unix_time();
+        let mut sent = 0;
+        for queued in self.store.claim_outbound(now, now + LEASE).await? {
+            match self.send(&queued).await {
+                Ok(()) => {
+                    self.store.delete_outbound(queued.id).await?;
+                    sent += 1;
+                }
+                Err(e) if !e.is_temporary() || queued.attempts + 1 >= MAX_ATTEMPTS => {
+                    tracing::error!(
+                        "Giving up on mail to {} after {} attempts: {}",
+                        queued.recipient,
+                        queued.attempts + 1,
+                        e
+                    );
+                    let failure = dsn::Failure {
+                        recipient: queued.recipient.clone(),
+                        original_recipient: None,
+                        reason: e.reason(),
+                    };
+                    self.store
+                        .bounce_outbound(&queued, &self.hostname, &failure, now)
+                        .await?;
+                    self.store.delete_outbound(queued.id).await?;
+                }
+                Err(e) => {
+                    tracing::warn!("Relaying mail to {} failed: {}", queued.recipient, e);
+                    let attempts = queued.attempts + 1;
+                    self.store
+                        .reschedule_outbound(queued.id, attempts, now + BACKOFF.after(attempts))
+                        .await?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    async fn send(&self, queued: &OutboundMessage) -> Result<(), crate::message::HandlerError> {
+        let mut data = None;
+        if self.autocrypt {
+            data = autocrypt::announce(&self.store, &queued.message).await;
+        }
+        let message = Message {
+            from: queued.sender.clone(),
+            recipients: vec![Recipient::new(&queued.recipient)],
+            data: data.unwrap_or_else(|| queued.message.clone()).into(),
+            ..Default::default()
+        };
+        self.client.handle_message(&message).await
+    }
+
+    /// Sends due messages every `interval`, forever.
+    pub async fn run(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.send_due().await {
+                tracing::error!("Failed to relay queued mail: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// An SMTP server that answers RCPT with `rcpt_reply` and records the
+    /// envelope and message of every transaction.
+    async fn smtp_sink(rcpt_reply: &'static str) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let task = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            socket.get_mut().write_all(b"220 relay\r\n").await.unwrap();
+            let mut received = vec![];
+            loop {
+                let mut line = String::new();
+                if socket.read_line(&mut line).await.unwrap() == 0 {
+                    return received;
+                }
+                let reply = if line.starts_with("RCPT") {
+                    received.push(line.trim_end().to_string());
+                    rcpt_reply
+                } else if line.starts_with("MAIL") {
+                    received.push(line.trim_end().to_string());
+                    "250 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    socket.get_mut().write_all(b"354 go\r\n").await.unwrap();
+                    let mut data = String::new();
+                    while data != ".\r\n" {
+                        data.clear();
+                        socket.read_line(&mut data).await.unwrap();
+                    }
+                    "250 queued\r\n"
+                } else if line.starts_with("QUIT") {
+                    socket.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                    return received;
+                } else {
+                    "250 ok\r\n"
+                };
+                socket.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        (addr, task)
+    }
+
+    async fn relay(addr: &str) -> (Relay, SqliteStore, tempfile::NamedTempFile) {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let relay = Relay::new(store.clone(), addr.parse().unwrap(), "mx.example.com");
+        (relay, store, temp_file)
+    }
+
+    #[tokio::test]
+    async fn test_send_due() {
+        let (addr, sink) = smtp_sink("250 ok\r\n").await;
+        let (relay, store, _file) = relay(&addr).await;
+        let recipients = ["alice@example.net".to_string()];
+        store
+            .enqueue_outbound("", &recipients, b"Subject: Away\r\n\r\nBack soon\r\n", 0)
+            .await
+            .unwrap();
+
+        assert_eq!(relay.send_due().await.unwrap(), 1);
+        assert_eq!(
+            sink.await.unwrap(),
+            ["MAIL FROM:<>", "RCPT TO:<alice@example.net>"]
+        );
+        assert!(store.claim_outbound(i64::MAX, 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failures() {
+        let (addr, _sink) = smtp_sink("451 4.3.0 try later\r\n").await;
+        let (relay, store, _file) = relay(&addr).await;
+        let recipients = ["alice@example.net".to_string()];
+        store
+            .enqueue_outbound("bob@example.com", &recipients, b"Hi\r\n", 0)
+            .await
+            .unwrap();
+        assert_eq!(relay.send_due().await.unwrap(), 0);
+        let queued = store.claim_outbound(i64::MAX, 0).await.unwrap();
+        assert_eq!(queued[0].attempts, 1);
+
+        // A permanent failure drops the message and bounces it.
+        let (addr, _sink) = smtp_sink("550 5.1.1 unknown\r\n").await;
+        let relay = Relay::new(store.clone(), addr.parse().unwrap(), "mx.example.com");
+        store.reschedule_outbound(queued[0].id, 1, 0).await.unwrap();
+        assert_eq!(relay.send_due().await.unwrap(), 0);
+        let queued = store.claim_outbound(i64::MAX, 0).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(
+            (queued[0].sender.as_str(), queued[0].recipient.as_str()),
+            ("", "bob@example.com")
+        );
+        let report = String::from_utf8(queued[0].message.clone()).unwrap();
+        assert!(report.contains("Final-Recipient: rfc822; alice@example.net"));
+        assert!(report.contains("MAILER-DAEMON@mx.example.com"));
+
+        // Bounces are not bounced.
+        let (addr, _sink) = smtp_sink("550 5.1.1 unknown\r\n").await;
+        let relay = Relay::new(store.clone(), addr.parse().unwrap(), "mx.example.com");
+        store.reschedule_outbound(queued[0].id, 1, 0).await.unwrap();
+        assert_eq!(relay.send_due().await.unwrap(), 0);
+        assert!(store.claim_outbound(i64::MAX, 0).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(BACKOFF.after(1), 60);
+        assert_eq!(BACKOFF.after(2), 120);
+        assert_eq!(BACKOFF.after(MAX_ATTEMPTS), BACKOFF.max);
+    }
+}
@@ -0,0 +1,890 @@
+use crate::message::{self, Handler, HandlerError, Message};
+use crate::mime;
+use crate::storage::SqliteStore;
+use aes::{Aes128, Aes192, Aes256};
+use aes_kw::Kek;
+use async_trait::async_trait;
+use base64::Engine;
+use cfb_mode::cipher::{AsyncStreamCipher, KeyIvInit};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rsa::{BigUint, Pkcs1v15Encrypt, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha384, Sha512};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use tokio::io::AsyncReadExt;
+
+const TAG_PKESK: u8 = 1;
+const TAG_SIGNATURE: u8 = 2;
+const TAG_PUBLIC_KEY: u8 = 6;
+const TAG_USER_ID: u8 = 13;
+const TAG_PUBLIC_SUBKEY: u8 = 14;
+const TAG_LITERAL: u8 = 11;
+const TAG_SEIPD: u8 = 18;
+const TAG_MDC: u8 = 19;
+
+const ALGO_RSA: u8 = 1;
+const ALGO_RSA_ENCRYPT: u8 = 2;
+const ALGO_ECDH: u8 = 18;
+const CIPHER_AES128: u8 = 7;
+const CIPHER_AES192: u8 = 8;
+const CIPHER_AES256: u8 = 9;
+const HASH_SHA256: u8 = 8;
+const HASH_SHA384: u8 = 9;
+const HASH_SHA512: u8 = 10;
+/// Key flags that allow encrypting communications or storage.
+const FLAGS_ENCRYPT: u8 = 0x04 | 0x08;
+const CURVE25519_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x97, 0x55, 0x01, 0x05, 0x01];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PgpError {
+    /// Not a well-formed OpenPGP key.
+    Malformed(&'static str),
+    /// The key has no subkey this server can encrypt to.
+    NoEncryptionKey,
+    Crypto(String),
+}
+
+impl Display for PgpError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            PgpError::Malformed(what) => write!(f, "Malformed: {}", what),
+            PgpError::NoEncryptionKey => write!(f, "NoEncryptionKey"),
+            PgpError::Crypto(e) => write!(f, "Crypto: {}", e),
+        }
+    }
+}
+
+impl Error for PgpError {}
+
+impl From<PgpError> for HandlerError {
+    fn from(e: PgpError) -> Self {
+        HandlerError::temporary(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Material {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    Cv25519 {
+        point: [u8; 32],
+        hash: u8,
+        cipher: u8,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EncryptionKey {
+    fingerprint: [u8; 20],
+    material: Material,
+}
+
+impl EncryptionKey {
+    fn key_id(&self) -> &[u8] {
+        &self.fingerprint[12..]
+    }
+}
+
+/// A transferable OpenPGP public key (RFC 4880), reduced to what is needed
+/// to encrypt to it. Signatures are not verified: keys are trusted as
+/// registered by the administrator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    bytes: Vec<u8>,
+    fingerprint: [u8; 20],
+    user_ids: Vec<String>,
+    encryption_key: EncryptionKey,
+}
+
+impl PublicKey {
+    /// Parses a binary or ASCII armored key.
+    pub fn parse(data: &[u8]) -> Result<Self, PgpError> {
+        let bytes = match data.trim_ascii_start().starts_with(b"-----BEGIN") {
+            true => dearmor(data)?,
+            false => data.to_vec(),
+        };
+        let mut primary: Option<[u8; 20]> = None;
+        let mut user_ids = vec![];
+        // Every key in order, with its key flags once a signature supplies
+        // them and whether it was revoked.
+        let mut keys: Vec<(Option<EncryptionKey>, Option<u8>, bool)> = vec![];
+        for (tag, body) in packets(&bytes)? {
+            match tag {
+                TAG_PUBLIC_KEY | TAG_PUBLIC_SUBKEY => {
+                    if tag == TAG_PUBLIC_KEY && primary.is_some() {
+                        // Another key follows in a keyring; only the first counts.
+                        break;
+                    }
+                    let fingerprint = fingerprint(body);
+                    if tag == TAG_PUBLIC_KEY {
+                        primary = Some(fingerprint);
+                    }
+                    let key = parse_material(body)?.map(|material| EncryptionKey {
+                        fingerprint,
+                        material,
+                    });
+                    keys.push((key, None, false));
+                }
+                TAG_USER_ID if primary.is_some() => {
+                    user_ids.push(String::from_utf8_lossy(body).into_owned());
+                }
+                TAG_SIGNATURE => match (keys.last_mut(), parse_signature(body)) {
+                    (Some(key), Some(Signature::Revocation)) => key.2 = true,
+                    (Some(key), Some(Signature::Flags(flags))) => key.1 = Some(flags),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        let fingerprint = primary.ok_or(PgpError::Malformed("no public key packet"))?;
+        let usable = |(key, flags, revoked): &(Option<EncryptionKey>, Option<u8>, bool)| {
+            let allowed = flags.is_none_or(|flags| flags & FLAGS_ENCRYPT != 0);
+            key.clone().filter(|_| allowed && !revoked)
+        };
+        // Subkeys are preferred; the primary key is the fallback.
+        let encryption_key = keys
+            .iter()
+            .skip(1)
+            .find_map(usable)
+            .or_else(|| keys.first().and_then(usable))
+            .ok_or(PgpError::NoEncryptionKey)?;
+        Ok(Self {
+            bytes,
+            fingerprint,
+            user_ids,
+            encryption_key,
+        })
+    }
+
+    /// The key in binary form, as published over WKD.
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The uppercase hex v4 fingerprint of the primary key.
+    pub fn fingerprint(&self) -> String {
+        hex::encode_upper(self.fingerprint)
+    }
+
+    pub fn user_ids(&self) -> &[String] {
+        &self.user_ids
+    }
+
+    /// Encrypts `data` to this key as an ASCII armored OpenPGP message.
+    pub fn encrypt(&self, data: &[u8]) -> Result<String, PgpError> {
+        let mut session_key = [0; 32];
+        OsRng.fill_bytes(&mut session_key);
+        let mut message = self.session_key_packet(&session_key)?;
+
+        // Built and encrypted in place, as the data may be large.
+        let literal_len = 6 + data.len();
+        let mut plaintext = Vec::with_capacity(18 + 6 + literal_len + 22);
+        plaintext.resize(18, 0);
+        OsRng.fill_bytes(&mut plaintext[..16]);
+        plaintext.copy_within(14..16, 16);
+        packet_header(&mut plaintext, TAG_LITERAL, literal_len);
+        plaintext.extend_from_slice(&[b'b', 0, 0, 0, 0, 0]);
+        plaintext.extend_from_slice(data);
+        plaintext.extend_from_slice(&[0xc0 | TAG_MDC, 20]);
+        let mdc = Sha1::digest(&plaintext);
+        plaintext.extend_from_slice(&mdc);
+        cfb_mode::Encryptor::<Aes256>::new(&session_key.into(), &[0; 16].into())
+            .encrypt(&mut plaintext);
+        packet_header(&mut message, TAG_SEIPD, 1 + plaintext.len());
+        message.push(1);
+        message.extend_from_slice(&plaintext);
+        drop(plaintext);
+        Ok(armor(&message))
+    }
+
+    fn session_key_packet(&self, session_key: &[u8; 32]) -> Result<Vec<u8>, PgpError> {
+        let mut m = vec![CIPHER_AES256];
+        m.extend_from_slice(session_key);
+        let checksum = session_key
+            .iter()
+            .map(|&b| b as u16)
+            .fold(0, u16::wrapping_add);
+        m.extend_from_slice(&checksum.to_be_bytes());
+
+        let key = &self.encryption_key;
+        let mut body = vec![3];
+        body.extend_from_slice(key.key_id());
+        match &key.material {
+            Material::Rsa { n, e } => {
+                let public = RsaPublicKey::new_with_max_size(
+                    BigUint::from_bytes_be(n),
+                    BigUint::from_bytes_be(e),
+                    16384,
+                )
+                .map_err(|e| PgpError::Crypto(e.to_string()))?;
+                let encrypted = public
+                    .encrypt(&mut OsRng, Pkcs1v15Encrypt, &m)
+                    .map_err(|e| PgpError::Crypto(e.to_string()))?;
+                body.push(ALGO_RSA);
+                body.extend(mpi(&encrypted));
+            }
+            Material::Cv25519 {
+                point,
+                hash,
+                cipher,
+            } => {
+                let ephemeral = x25519_dalek::EphemeralSecret::random_from_rng(OsRng);
+                let mut ephemeral_point = vec![0x40];
+                ephemeral_point
+                    .extend_from_slice(x25519_dalek::PublicKey::from(&ephemeral).as_bytes());
+                let shared = ephemeral.diffie_hellman(&x25519_dalek::PublicKey::from(*point));
+
+                let mut param = vec![CURVE25519_OID.len() as u8];
+                param.extend_from_slice(CURVE25519_OID);
+                param.extend_from_slice(&[ALGO_ECDH, 3, 1, *hash, *cipher]);
+                param.extend_from_slice(b"Anonymous Sender    ");
+                param.extend_from_slice(&key.fingerprint);
+                let kek = kdf(*hash, shared.as_bytes(), &param)?;
+
+                // PKCS#5 padding to a multiple of eight bytes.
+                let pad = 8 - m.len() % 8;
+                m.extend(std::iter::repeat_n(pad as u8, pad));
+                let wrapped = key_wrap(*cipher, &kek, &m)?;
+                body.push(ALGO_ECDH);
+                body.extend(mpi(&ephemeral_point));
+                body.push(wrapped.len() as u8);
+                body.extend(wrapped);
+            }
+        }
+        Ok(packet(TAG_PKESK, &body))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = PgpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s.as_bytes())
+    }
+}
+
+fn fingerprint(body: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update([0x99]);
+    hasher.update((body.len() as u16).to_be_bytes());
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+/// Reads the public key material of a v4 key packet, or `None` for keys
+/// that cannot encrypt.
+fn parse_material(body: &[u8]) -> Result<Option<Material>, PgpError> {
+    let malformed = PgpError::Malformed("truncated key packet");
+    if body.first() != Some(&4) {
+        return Ok(None);
+    }
+    let (&algo, mut rest) = body
+        .get(5..)
+        .and_then(|b| b.split_first())
+        .ok_or(malformed)?;
+    match algo {
+        ALGO_RSA | ALGO_RSA_ENCRYPT => {
+            let n = read_mpi(&mut rest)?.to_vec();
+            let e = read_mpi(&mut rest)?.to_vec();
+            Ok(Some(Material::Rsa { n, e }))
+        }
+        ALGO_ECDH => {
+            let (&oid_len, after) = rest
+                .split_first()
+                .ok_or(PgpError::Malformed("truncated key packet"))?;
+            let oid = after
+                .get(..oid_len as usize)
+                .ok_or(PgpError::Malformed("truncated key packet"))?;
+            if oid != CURVE25519_OID {
+                return Ok(None);
+            }
+            rest = &after[oid_len as usize..];
+            let point = read_mpi(&mut rest)?;
+            let point = point
+                .strip_prefix(&[0x40])
+                .and_then(|p| <[u8; 32]>::try_from(p).ok())
+                .ok_or(PgpError::Malformed("bad Curve25519 point"))?;
+            match rest {
+                [3, 1, hash, cipher, ..] => Ok(Some(Material::Cv25519 {
+                    point,
+                    hash: *hash,
+                    cipher: *cipher,
+                })),
+                _ => Err(PgpError::Malformed("bad ECDH parameters")),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+enum Signature {
+    Revocation,
+    Flags(u8),
+    Other,
+}
+
+/// Reads what matters for choosing a key from a v4 signature: whether it
+/// revokes, and the key flags of a self-signature.
+fn parse_signature(body: &[u8]) -> Option<Signature> {
+    let [4, kind, _, _, len_hi, len_lo, rest @ ..] = body else {
+        return None;
+    };
+    if matches!(kind, 0x20 | 0x28) {
+        return Some(Signature::Revocation);
+    }
+    let mut hashed = rest.get(..u16::from_be_bytes([*len_hi, *len_lo]) as usize)?;
+    while !hashed.is_empty() {
+        let (len, header) = match hashed[0] {
+            0..=191 => (hashed[0] as usize, 1),
+            192..=254 => (
+                ((hashed[0] as usize - 192) << 8) + *hashed.get(1)? as usize + 192,
+                2,
+            ),
+            255 => (
+                u32::from_be_bytes(hashed.get(1..5)?.try_into().ok()?) as usize,
+                5,
+            ),
+        };
+        let subpacket = hashed.get(header..header + len)?;
+        // The top bit of the type only marks the subpacket critical.
+        if let [kind, flags, ..] = subpacket {
+            if kind & 0x7f == 27 {
+                return Some(Signature::Flags(*flags));
+            }
+        }
+        hashed = &hashed[header + len..];
+    }
+    Some(Signature::Other)
+}
+
+/// Splits `data` into `(tag, body)` packets, in old or new format.
+fn packets(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, PgpError> {
+    let truncated = || PgpError::Malformed("truncated packet");
+    let mut packets = vec![];
+    while let Some((&header, rest)) = data.split_first() {
+        if header & 0x80 == 0 {
+            return Err(PgpError::Malformed("bad packet header"));
+        }
+        let (tag, len, rest) = if header & 0x40 != 0 {
+            let (len, rest) = match rest {
+                [a @ 0..=191, rest @ ..] => (*a as usize, rest),
+                [a @ 192..=223, b, rest @ ..] => {
+                    (((*a as usize - 192) << 8) + *b as usize + 192, rest)
+                }
+                [255, a, b, c, d, rest @ ..] => {
+                    (u32::from_be_bytes([*a, *b, *c, *d]) as usize, rest)
+                }
+                [224..=254, ..] => return Err(PgpError::Malformed("partial body length")),
+                _ => return Err(truncated()),
+            };
+            (header & 0x3f, len, rest)
+        } else {
+            let (len, rest) = match (header & 3, rest) {
+                (0, [a, rest @ ..]) => (*a as usize, rest),
+                (1, [a, b, rest @ ..]) => (u16::from_be_bytes([*a, *b]) as usize, rest),
+                (2, [a, b, c, d, rest @ ..]) => {
+                    (u32::from_be_bytes([*a, *b, *c, *d]) as usize, rest)
+                }
+                (3, rest) => (rest.len(), rest),
+                _ => return Err(truncated()),
+            };
+            ((header >> 2) & 0x0f, len, rest)
+        };
+        let body = rest.get(..len).ok_or_else(truncated)?;
+        packets.push((tag, body));
+        data = &rest[len..];
+    }
+    Ok(packets)
+}
+
+/// Encodes a new format packet with a definite length.
+fn packet(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![];
+    packet_header(&mut packet, tag, body.len());
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Appends the header of a new-format packet whose body is `len` bytes.
+fn packet_header(out: &mut Vec<u8>, tag: u8, len: usize) {
+    out.push(0xc0 | tag);
+    match len {
+        0..=191 => out.push(len as u8),
+        192..=8383 => {
+            let len = len - 192;
+            out.extend_from_slice(&[(len >> 8) as u8 + 192, len as u8]);
+        }
+        _ => {
+            out.push(255);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn read_mpi<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], PgpError> {
+    let truncated = PgpError::Malformed("truncated MPI");
+    let [hi, lo, rest @ ..] = *data else {
+        return Err(truncated);
+    };
+    let len = (u16::from_be_bytes([*hi, *lo]) as usize).div_ceil(8);
+    let value = rest.get(..len).ok_or(truncated)?;
+    *data = &rest[len..];
+    Ok(value)
+}
+
+fn mpi(value: &[u8]) -> Vec<u8> {
+    let start = value.iter().position(|&b| b != 0).unwrap_or(value.len());
+    let value = &value[start..];
+    let bits = match value.first() {
+        Some(first) => (value.len() - 1) * 8 + (8 - first.leading_zeros() as usize),
+        None => 0,
+    };
+    let mut mpi = (bits as u16).to_be_bytes().to_vec();
+    mpi.extend_from_slice(value);
+    mpi
+}
+
+/// The ECDH key derivation of RFC 6637 section 7.
+fn kdf(hash: u8, shared: &[u8], param: &[u8]) -> Result<Vec<u8>, PgpError> {
+    fn digest<D: Digest>(shared: &[u8], param: &[u8]) -> Vec<u8> {
+        D::new()
+            .chain_update([0, 0, 0, 1])
+            .chain_update(shared)
+            .chain_update(param)
+            .finalize()
+            .to_vec()
+    }
+    match hash {
+        HASH_SHA256 => Ok(digest::<Sha256>(shared, param)),
+        HASH_SHA384 => Ok(digest::<Sha384>(shared, param)),
+        HASH_SHA512 => Ok(digest::<Sha512>(shared, param)),
+        _ => Err(PgpError::Crypto(format!("unsupported KDF hash {}", hash))),
+    }
+}
+
+/// RFC 3394 AES key wrap with a key of the size `cipher` asks for.
+fn key_wrap(cipher: u8, kek: &[u8], data: &[u8]) -> Result<Vec<u8>, PgpError> {
+    let wrapped = match cipher {
+        CIPHER_AES128 => Kek::<Aes128>::try_from(&kek[..16]).and_then(|k| k.wrap_vec(data)),
+        CIPHER_AES192 => Kek::<Aes192>::try_from(&kek[..24]).and_then(|k| k.wrap_vec(data)),
+        CIPHER_AES256 => Kek::<Aes256>::try_from(&kek[..32]).and_then(|k| k.wrap_vec(data)),
+        _ => {
+            return Err(PgpError::Crypto(format!(
+                "unsupported KEK cipher {}",
+                cipher
+            )))
+        }
+    };
+    wrapped.map_err(|e| PgpError::Crypto(e.to_string()))
+}
+
+/// The CRC-24 armor checksum of RFC 4880 section 6.1.
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xb704ce;
+    for &byte in data {
+        crc ^= (byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= 0x1864cfb;
+            }
+        }
+    }
+    crc & 0xffffff
+}
+
+fn armor(data: &[u8]) -> String {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let mut armored = String::from("-----BEGIN PGP MESSAGE-----\r\n\r\n");
+    for chunk in data.chunks(48) {
+        armored.push_str(&engine.encode(chunk));
+        armored.push_str("\r\n");
+    }
+    armored.push('=');
+    armored.push_str(&engine.encode(&crc24(data).to_be_bytes()[1..]));
+    armored.push_str("\r\n-----END PGP MESSAGE-----\r\n");
+    armored
+}
+
+fn dearmor(data: &[u8]) -> Result<Vec<u8>, PgpError> {
+    let text = std::str::from_utf8(data).map_err(|_| PgpError::Malformed("armor"))?;
+    let mut lines = text
+        .lines()
+        .map(str::trim)
+        .skip_while(|l| !l.starts_with("-----BEGIN"));
+    lines.next();
+    // Armor headers end at the first blank line.
+    let mut encoded = String::new();
+    for line in lines.skip_while(|l| !l.is_empty()) {
+        if line.starts_with('=') || line.starts_with("-----END") {
+            break;
+        }
+        encoded.push_str(line);
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| PgpError::Malformed("armor"))
+}
+
+/// Whether the message is already encrypted, with PGP/MIME, inline PGP or
+/// S/MIME.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    let part = mime::Part::parse(data);
+    let content_type = part.content_type().value.as_str();
+    matches!(
+        content_type,
+        "multipart/encrypted"
+            | "application/pgp-encrypted"
+            | "application/pkcs7-mime"
+            | "application/x-pkcs7-mime"
+    ) || (!part.is_multipart()
+        && part
+            .raw_body()
+            .trim_ascii_start()
+            .starts_with(b"-----BEGIN PGP MESSAGE-----"))
+}
+
+/// Wraps `data` into a PGP/MIME (RFC 3156) message encrypted to `key`. The
+/// content headers move into the encrypted part; the others stay outside
+/// so the message can still be routed and listed.
+pub fn encrypt_message(data: &[u8], key: &PublicKey) -> Result<Vec<u8>, PgpError> {
+    let part = mime::Part::parse(data);
+    let mut outer = vec![];
+    let mut inner = vec![];
+    for header in part.headers() {
+        let name = header.name.to_ascii_lowercase();
+        if name == "mime-version" {
+            continue;
+        }
+        let target = match name.starts_with("content-") {
+            true => &mut inner,
+            false => &mut outer,
+        };
+        target.extend_from_slice(header.name.as_bytes());
+        target.push(b':');
+        target.extend_from_slice(header.raw_value);
+        target.extend_from_slice(b"\r\n");
+    }
+    inner.extend_from_slice(b"\r\n");
+    inner.extend_from_slice(part.raw_body());
+    let armored = key.encrypt(&inner)?;
+    drop(inner);
+
+    let boundary = format!("=_pgp_{}", uuid::Uuid::new_v4().simple());
+    outer.extend_from_slice(
+        format!(
+            "MIME-Version: 1.0\r\n\
+             Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\";\r\n\
+             \tboundary=\"{boundary}\"\r\n\
+             \r\n\
+             This is an OpenPGP/MIME encrypted message (RFC 3156).\r\n\
+             --{boundary}\r\n\
+             Content-Type: application/pgp-encrypted\r\n\
+             Content-Description: PGP/MIME version identification\r\n\
+             \r\n\
+             Version: 1\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\
+             Content-Description: OpenPGP encrypted message\r\n\
+             Content-Disposition: inline; filename=\"encrypted.asc\"\r\n\
+             \r\n"
+        )
+        .as_bytes(),
+    );
+    outer.extend_from_slice(armored.as_bytes());
+    outer.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    Ok(outer)
+}
+
+/// Encrypts mail for recipients with a registered OpenPGP key before
+/// passing it on to `inner`. Mail that is already encrypted is passed on
+/// as it is.
+pub struct PgpHandler {
+    store: SqliteStore,
+    inner: Box<dyn Handler + Send + Sync>,
+}
+
+impl PgpHandler {
+    pub fn new(store: SqliteStore, inner: Box<dyn Handler + Send + Sync>) -> Self {
+        Self { store, inner }
+    }
+
+    /// Whether the message is already encrypted, judged by its header and
+    /// the start of its body.
+    async fn starts_encrypted(&self, message: &Message) -> std::io::Result<bool> {
+        let (mut head, rest) = message.data.split_header().await?;
+        rest.take(1024).read_to_end(&mut head).await?;
+        Ok(is_encrypted(&head))
+    }
+}
+
+fn encrypt_with(data: &[u8], key: &[u8]) -> Result<Vec<u8>, HandlerError> {
+    Ok(encrypt_message(data, &PublicKey::parse(key)?)?)
+}
+
+#[async_trait]
+impl Handler for PgpHandler {
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+        message::summarize(&self.handle_recipients(message).await)
+    }
+
+    async fn handle_recipients(&self, message: &Message) -> Vec<Result<(), HandlerError>> {
+        let mut results = vec![Ok(()); message.recipients.len()];
+        let mut keys = vec![None; message.recipients.len()];
+        for (i, recipient) in message.recipients.iter().enumerate() {
+            match self.store.pgp_key(&recipient.address).await {
+                Ok(key) => keys[i] = key,
+                Err(e) => results[i] = Err(e.into()),
+            }
+        }
+        // The message is only loaded when there is someone to encrypt it to.
+        if results.iter().all(Result::is_ok) && keys.iter().all(Option::is_none) {
+            return self.inner.handle_recipients(message).await;
+        }
+        match self.starts_encrypted(message).await {
+            Ok(true) => return self.inner.handle_recipients(message).await,
+            Ok(false) => {}
+            Err(e) => return vec![Err(e.into()); message.recipients.len()],
+        }
+
+        let mut plain = vec![];
+        for (i, recipient) in message.recipients.iter().enumerate() {
+            if results[i].is_err() {
+                continue;
+            }
+            let Some(key) = &keys[i] else {
+                plain.push(i);
+                continue;
+            };
+            // Loaded for each recipient, so that only the encrypted copy is
+            // held while it is passed on.
+            let encrypted = match message.data.to_vec().await {
+                Ok(data) => encrypt_with(&data, key),
+                Err(e) => Err(e.into()),
+            };
+            match encrypted {
+                Ok(encrypted) => {
+                    let single = message.copy_with(vec![recipient.clone()], encrypted);
+                    results[i] = message::summarize(&self.inner.handle_recipients(&single).await);
+                }
+                Err(e) => results[i] = Err(e),
+            }
+        }
+        if !plain.is_empty() {
+            let recipients = plain
+                .iter()
+                .map(|&i| message.recipients[i].clone())
+                .collect();
+            let rest = async {
+                let (header, rest) = message.data.split_header().await?;
+                message.copy_streamed(recipients, &header, rest).await
+            };
+            let inner = match rest.await {
+                Ok(rest) => self.inner.handle_recipients(&rest).await,
+                Err(e) => vec![Err(HandlerError::from(e)); plain.len()],
+            };
+            for (i, result) in plain.into_iter().zip(inner) {
+                results[i] = result;
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Recipient;
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+    use std::sync::{Arc, Mutex};
+
+    /// A bare key: a v4 RSA primary key and a user ID, without signatures.
+    fn rsa_key(private: &RsaPrivateKey) -> Vec<u8> {
+        let mut body = vec![4, 0, 0, 0, 0, ALGO_RSA];
+        body.extend(mpi(&private.n().to_bytes_be()));
+        body.extend(mpi(&private.e().to_bytes_be()));
+        let mut key = packet(TAG_PUBLIC_KEY, &body);
+        key.extend(packet(TAG_USER_ID, b"Bob <bob@example.com>"));
+        key
+    }
+
+    /// Decrypts what [`PublicKey::encrypt`] produced for an RSA key.
+    fn decrypt(private: &RsaPrivateKey, armored: &str) -> Vec<u8> {
+        let data = dearmor(armored.as_bytes()).unwrap();
+        let outer = packets(&data).unwrap();
+        let (TAG_PKESK, pkesk) = outer[0] else {
+            panic!("no session key packet");
+        };
+        let mut rest = &pkesk[10..];
+        let m = private
+            .decrypt(Pkcs1v15Encrypt, read_mpi(&mut rest).unwrap())
+            .unwrap();
+        assert_eq!(m[0], CIPHER_AES256);
+        let session_key: [u8; 32] = m[1..33].try_into().unwrap();
+
+        let (TAG_SEIPD, seipd) = outer[1] else {
+            panic!("no encrypted data packet");
+        };
+        let mut plaintext = seipd[1..].to_vec();
+        cfb_mode::Decryptor::<Aes256>::new(&session_key.into(), &[0; 16].into())
+            .decrypt(&mut plaintext);
+        assert_eq!(plaintext[14..16], plaintext[16..18]);
+        let (body, mdc) = plaintext.split_at(plaintext.len() - 20);
+        assert_eq!(Sha1::digest(body).as_slice(), mdc);
+        let (TAG_LITERAL, literal) = packets(&body[18..body.len() - 2]).unwrap()[0] else {
+            panic!("no literal data packet");
+        };
+        literal[6..].to_vec()
+    }
+
+    fn private_key() -> RsaPrivateKey {
+        RsaPrivateKey::new(&mut OsRng, 1024).unwrap()
+    }
+
+    #[test]
+    fn test_encrypt_rsa() {
+        let private = private_key();
+        let key = PublicKey::parse(&rsa_key(&private)).unwrap();
+        assert_eq!(key.user_ids(), ["Bob <bob@example.com>"]);
+        assert_eq!(key.fingerprint().len(), 40);
+
+        let armored = key.encrypt(b"Hello, Bob").unwrap();
+        assert!(armored.starts_with("-----BEGIN PGP MESSAGE-----\r\n"));
+        assert_eq!(decrypt(&private, &armored), b"Hello, Bob");
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert!(matches!(
+            PublicKey::parse(b"\x99\x00"),
+            Err(PgpError::Malformed(_))
+        ));
+        // A key the server cannot encrypt to (EdDSA) and no subkey.
+        let mut body = vec![4, 0, 0, 0, 0, 22];
+        body.extend_from_slice(&[9, 0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01]);
+        body.extend(mpi(&[0x40; 33]));
+        assert_eq!(
+            PublicKey::parse(&packet(TAG_PUBLIC_KEY, &body)),
+            Err(PgpError::NoEncryptionKey)
+        );
+
+        // Armored keys round trip to the same binary key.
+        let binary = rsa_key(&private_key());
+        let engine = base64::engine::general_purpose::STANDARD;
+        let armored = format!(
+            "-----BEGIN PGP PUBLIC KEY BLOCK-----\nComment: test\n\n{}\n=AAAA\n-----END PGP PUBLIC KEY BLOCK-----\n",
+            engine.encode(&binary)
+        );
+        let key: PublicKey = armored.parse().unwrap();
+        assert_eq!(key.to_bytes(), binary);
+    }
+
+    #[test]
+    fn test_parse_gpg_key() {
+        // Exported by GnuPG: an Ed25519 signing key with a Cv25519 subkey.
+        let key = PublicKey::parse(include_bytes!("../tests/data/bob.asc")).unwrap();
+        assert_eq!(
+            key.fingerprint(),
+            "6657200D47DE3303ED02698A2232B1F3B6FAF13D"
+        );
+        assert_eq!(key.user_ids(), ["Bob <bob@example.com>"]);
+        assert!(matches!(
+            key.encryption_key.material,
+            Material::Cv25519 { .. }
+        ));
+        assert_ne!(key.encryption_key.fingerprint, key.fingerprint);
+        assert!(key.encrypt(b"hello").is_ok());
+    }
+
+    #[test]
+    fn test_crc24() {
+        // The checksum of an empty armor body.
+        assert_eq!(crc24(b""), 0xb704ce);
+        assert_eq!(crc24(b"123456789"), 0x21cf02);
+    }
+
+    #[test]
+    fn test_is_encrypted() {
+        assert!(is_encrypted(
+            b"Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\"\r\n\r\n"
+        ));
+        assert!(is_encrypted(
+            b"Subject: hi\r\n\r\n-----BEGIN PGP MESSAGE-----\r\n"
+        ));
+        assert!(!is_encrypted(b"Subject: hi\r\n\r\nHello\r\n"));
+    }
+
+    #[derive(Default)]
+    struct Capture(Mutex<Vec<(Vec<String>, Vec<u8>)>>);
+
+    #[async_trait]
+    impl Handler for Arc<Capture> {
+        async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+            let recipients = message
+                .recipients
+                .iter()
+                .map(|r| r.address.clone())
+                .collect();
+            let data = message.data.to_vec().await?;
+            self.0.lock().unwrap().push((recipients, data));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let private = private_key();
+        store
+            .set_pgp_key("bob@example.com", &rsa_key(&private))
+            .await
+            .unwrap();
+        let capture = Arc::new(Capture::default());
+        let handler = PgpHandler::new(store, Box::new(capture.clone()));
+
+        let data = b"Subject: Lunch\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nNoon?\r\n";
+        let message = Message {
+            from: "carol@example.net".to_string(),
+            recipients: vec![
+                Recipient::new("alice@example.com"),
+                Recipient::new("bob@example.com"),
+            ],
+            data: data[..].into(),
+            ..Default::default()
+        };
+        assert_eq!(handler.handle_recipients(&message).await.len(), 2);
+        let delivered = capture.0.lock().unwrap().clone();
+        assert_eq!(delivered.len(), 2);
+        let (to_bob, encrypted) = &delivered[0];
+        assert_eq!(to_bob, &["bob@example.com"]);
+        let part = mime::Part::parse(encrypted);
+        assert_eq!(part.subject().as_deref(), Some("Lunch"));
+        assert_eq!(part.content_type().value, "multipart/encrypted");
+        let armored = String::from_utf8(part.parts()[1].body().to_vec()).unwrap();
+        assert_eq!(
+            decrypt(&private, &armored),
+            b"Content-Type: text/plain; charset=utf-8\r\n\r\nNoon?\r\n"
+        );
+        assert_eq!(
+            delivered[1],
+            (vec!["alice@example.com".to_string()], data.to_vec())
+        );
+
+        // Already encrypted mail is passed on untouched.
+        let message = Message {
+            recipients: vec![Recipient::new("bob@example.com")],
+            data: encrypted[..].into(),
+            ..Default::default()
+        };
+        handler.handle_message(&message).await.unwrap();
+        assert_eq!(capture.0.lock().unwrap()[2].1, *encrypted);
+    }
+}
@@ -0,0 +1,35 @@
+use crate::message::Recipient;
+use async_trait::async_trait;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum ResolveError {
+    Loop(String),
+    BoxError(Box<dyn Error + Send + Sync>),
+}
+
+impl ResolveError {
+    pub fn boxed<E: Error + Send + Sync + 'static>(err: E) -> Self {
+        ResolveError::BoxError(Box::new(err))
+    }
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            ResolveError::Loop(address) => write!(f, "Loop: {}", address),
+            ResolveError::BoxError(e) => write!(f, "BoxError: {}", e),
+        }
+    }
+}
+
+impl Error for ResolveError {}
+
+/// Expands an accepted envelope recipient into the mailboxes (or foreign
+/// forwarding addresses) the message is finally delivered to. An empty
+/// result means the address does not exist.
+#[async_trait]
+pub trait AddressResolver: Send + Sync + std::fmt::Debug {
+    async fn resolve(&self, address: &str) -> Result<Vec<Recipient>, ResolveError>;
+}
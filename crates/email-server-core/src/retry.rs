@@ -0,0 +1,23 @@
+//! Retrying work that failed for now, such as relayed mail and webhook
+//! deliveries: the store leases a due attempt to one worker, and failures
+//! wait longer each time.
+
+/// How long a claimed attempt is hidden from other workers while it is
+/// being made.
+pub const LEASE: i64 = 10 * 60;
+
+/// Waits that double with each failed attempt, from `initial` seconds up
+/// to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: i64,
+    pub max: i64,
+}
+
+impl Backoff {
+    /// Seconds to wait before the next attempt after `attempts` failures.
+    pub fn after(&self, attempts: i64) -> i64 {
+        let exponent = (attempts - 1).clamp(0, 20) as u32;
+        (self.initial << exponent).min(self.max)
+    }
+}
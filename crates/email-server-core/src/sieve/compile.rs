@@ -0,0 +1,897 @@
+use super::parser::{Argument, Node};
+use super::{parser, Script, SieveError, EXTENSIONS};
+use crate::smtp::address;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Command {
+    /// The `if` and `elsif` branches in order, then the `else` block.
+    If(Vec<(Test, Vec<Command>)>, Vec<Command>),
+    Stop,
+    Keep {
+        flags: Option<Vec<String>>,
+    },
+    Discard,
+    FileInto {
+        folder: String,
+        copy: bool,
+        flags: Option<Vec<String>>,
+    },
+    Redirect {
+        address: String,
+        copy: bool,
+    },
+    Reject(String),
+    Vacation(Vacation),
+    Set {
+        name: String,
+        value: String,
+        /// In the order they apply.
+        modifiers: Vec<Modifier>,
+    },
+    Flags {
+        action: FlagAction,
+        /// Names a variable instead of the internal flags.
+        variable: Option<String>,
+        flags: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Vacation {
+    pub days: Option<u64>,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    pub addresses: Vec<String>,
+    pub mime: bool,
+    pub handle: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FlagAction {
+    Set,
+    Add,
+    Remove,
+}
+
+/// A `set` modifier (RFC 5229).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Modifier {
+    Lower,
+    Upper,
+    LowerFirst,
+    UpperFirst,
+    QuoteWildcard,
+    Length,
+}
+
+impl Modifier {
+    fn precedence(self) -> u8 {
+        match self {
+            Modifier::Lower | Modifier::Upper => 40,
+            Modifier::LowerFirst | Modifier::UpperFirst => 30,
+            Modifier::QuoteWildcard => 20,
+            Modifier::Length => 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Test {
+    True,
+    False,
+    Not(Box<Test>),
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Exists(Vec<String>),
+    Size {
+        over: bool,
+        limit: u64,
+    },
+    Header {
+        names: Vec<String>,
+        keys: Vec<String>,
+        matcher: Matcher,
+    },
+    Address {
+        part: AddressPart,
+        names: Vec<String>,
+        keys: Vec<String>,
+        matcher: Matcher,
+    },
+    Envelope {
+        part: AddressPart,
+        names: Vec<String>,
+        keys: Vec<String>,
+        matcher: Matcher,
+    },
+    Body {
+        transform: BodyTransform,
+        keys: Vec<String>,
+        matcher: Matcher,
+    },
+    String {
+        sources: Vec<String>,
+        keys: Vec<String>,
+        matcher: Matcher,
+    },
+    HasFlag {
+        variables: Vec<String>,
+        keys: Vec<String>,
+        matcher: Matcher,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum BodyTransform {
+    Raw,
+    Text,
+    /// Only parts whose type starts with one of these; `""` means all.
+    Content(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Matcher {
+    pub kind: MatchType,
+    pub comparator: Comparator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MatchType {
+    Is,
+    Contains,
+    Matches,
+    Value(Relation),
+    Count(Relation),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Relation {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Comparator {
+    Octet,
+    AsciiCasemap,
+    AsciiNumeric,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+    User,
+    Detail,
+}
+
+/// What follows a tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Nothing,
+    String,
+    StringList,
+    Number,
+}
+
+const MATCH_TAGS: &[(&str, Kind)] = &[
+    ("is", Kind::Nothing),
+    ("contains", Kind::Nothing),
+    ("matches", Kind::Nothing),
+    ("value", Kind::String),
+    ("count", Kind::String),
+    ("comparator", Kind::String),
+];
+
+const ADDRESS_TAGS: &[(&str, Kind)] = &[
+    ("all", Kind::Nothing),
+    ("localpart", Kind::Nothing),
+    ("domain", Kind::Nothing),
+    ("user", Kind::Nothing),
+    ("detail", Kind::Nothing),
+];
+
+/// The arguments of one command or test, split into tags and positional
+/// arguments.
+struct Args<'a> {
+    node: &'a Node,
+    tags: Vec<(String, Argument)>,
+    positional: Vec<Argument>,
+}
+
+impl<'a> Args<'a> {
+    fn new(node: &'a Node, known: &[&[(&str, Kind)]]) -> Result<Self, SieveError> {
+        let mut args = Args {
+            node,
+            tags: vec![],
+            positional: vec![],
+        };
+        let mut arguments = node.arguments.iter();
+        while let Some(argument) = arguments.next() {
+            let Argument::Tag(tag) = argument else {
+                args.positional.push(argument.clone());
+                continue;
+            };
+            if !args.positional.is_empty() {
+                return Err(args.error(format!(":{} must come before other arguments", tag)));
+            }
+            let Some(&(_, kind)) = known.iter().flat_map(|k| k.iter()).find(|(t, _)| t == tag)
+            else {
+                return Err(args.error(format!("unknown tag :{} for {}", tag, node.name)));
+            };
+            if args.tags.iter().any(|(t, _)| t == tag) {
+                return Err(args.error(format!(":{} given twice", tag)));
+            }
+            if kind == Kind::Nothing {
+                args.tags.push((tag.clone(), argument.clone()));
+                continue;
+            }
+            let value = match (kind, arguments.next()) {
+                (Kind::String, Some(v @ Argument::String(_)))
+                | (Kind::Number, Some(v @ Argument::Number(_))) => v.clone(),
+                (Kind::StringList, Some(Argument::String(s))) => {
+                    Argument::StringList(vec![s.clone()])
+                }
+                (Kind::StringList, Some(v @ Argument::StringList(_))) => v.clone(),
+                _ => return Err(args.error(format!(":{} is missing its value", tag))),
+            };
+            args.tags.push((tag.clone(), value));
+        }
+        Ok(args)
+    }
+
+    fn error(&self, message: impl Into<String>) -> SieveError {
+        SieveError::new(self.node.line, message)
+    }
+
+    fn has(&self, tag: &str) -> bool {
+        self.tags.iter().any(|(t, _)| t == tag)
+    }
+
+    fn tag(&self, tag: &str) -> Option<&Argument> {
+        self.tags.iter().find(|(t, _)| t == tag).map(|(_, v)| v)
+    }
+
+    fn tag_string(&self, tag: &str) -> Option<String> {
+        match self.tag(tag) {
+            Some(Argument::String(s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    fn tag_strings(&self, tag: &str) -> Option<Vec<String>> {
+        match self.tag(tag) {
+            Some(Argument::StringList(list)) => Some(list.clone()),
+            _ => None,
+        }
+    }
+
+    /// At most one of `tags` was given; returns it.
+    fn one_of(&self, tags: &[(&str, Kind)]) -> Result<Option<&str>, SieveError> {
+        let mut found = self
+            .tags
+            .iter()
+            .filter(|(t, _)| tags.iter().any(|(k, _)| k == t));
+        let first = found.next();
+        if let Some((other, _)) = found.next() {
+            return Err(self.error(format!(":{} conflicts with another tag", other)));
+        }
+        Ok(first.map(|(t, _)| t.as_str()))
+    }
+
+    /// Checks the number of positional arguments.
+    fn count(&self, expected: std::ops::RangeInclusive<usize>) -> Result<(), SieveError> {
+        if !expected.contains(&self.positional.len()) {
+            return Err(self.error(format!("wrong number of arguments for {}", self.node.name)));
+        }
+        Ok(())
+    }
+
+    fn string(&self, i: usize) -> Result<String, SieveError> {
+        match &self.positional[i] {
+            Argument::String(s) => Ok(s.clone()),
+            _ => Err(self.error(format!("{} expects a string", self.node.name))),
+        }
+    }
+
+    fn strings(&self, i: usize) -> Result<Vec<String>, SieveError> {
+        match &self.positional[i] {
+            Argument::String(s) => Ok(vec![s.clone()]),
+            Argument::StringList(list) => Ok(list.clone()),
+            _ => Err(self.error(format!("{} expects a string list", self.node.name))),
+        }
+    }
+
+    fn no_tests(&self) -> Result<(), SieveError> {
+        match self.node.tests.is_empty() {
+            true => Ok(()),
+            false => Err(self.error(format!("{} takes no tests", self.node.name))),
+        }
+    }
+}
+
+struct Compiler {
+    required: HashSet<String>,
+}
+
+impl Compiler {
+    fn need(&self, extension: &str, line: usize) -> Result<(), SieveError> {
+        match self.required.contains(extension) {
+            true => Ok(()),
+            false => Err(SieveError::new(
+                line,
+                format!("missing require \"{}\"", extension),
+            )),
+        }
+    }
+
+    fn block(&self, commands: &[parser::Command]) -> Result<Vec<Command>, SieveError> {
+        let mut compiled = vec![];
+        let mut commands = commands.iter().peekable();
+        while let Some(command) = commands.next() {
+            let node = &command.node;
+            match node.name.as_str() {
+                "require" => {
+                    return Err(SieveError::new(
+                        node.line,
+                        "require must come before other commands",
+                    ))
+                }
+                "if" => {
+                    let mut branches = vec![self.branch(command)?];
+                    let mut otherwise = vec![];
+                    while let Some(next) = commands.peek() {
+                        match next.node.name.as_str() {
+                            "elsif" => branches.push(self.branch(next)?),
+                            "else" => {
+                                let args = Args::new(&next.node, &[])?;
+                                args.count(0..=0)?;
+                                args.no_tests()?;
+                                otherwise = self.block(self.required_block(next)?)?;
+                                commands.next();
+                                break;
+                            }
+                            _ => break,
+                        }
+                        commands.next();
+                    }
+                    compiled.push(Command::If(branches, otherwise));
+                }
+                "elsif" | "else" => {
+                    return Err(SieveError::new(
+                        node.line,
+                        format!("{} without if", node.name),
+                    ))
+                }
+                _ => {
+                    if command.block.is_some() {
+                        return Err(SieveError::new(
+                            node.line,
+                            format!("{} takes no block", node.name),
+                        ));
+                    }
+                    compiled.push(self.command(node)?);
+                }
+            }
+        }
+        Ok(compiled)
+    }
+
+    fn required_block<'c>(
+        &self,
+        command: &'c parser::Command,
+    ) -> Result<&'c [parser::Command], SieveError> {
+        command.block.as_deref().ok_or_else(|| {
+            SieveError::new(
+                command.node.line,
+                format!("{} needs a block", command.node.name),
+            )
+        })
+    }
+
+    fn branch(&self, command: &parser::Command) -> Result<(Test, Vec<Command>), SieveError> {
+        let node = &command.node;
+        let args = Args::new(node, &[])?;
+        args.count(0..=0)?;
+        let [test] = &node.tests[..] else {
+            return Err(args.error(format!("{} needs one test", node.name)));
+        };
+        let block = self.block(self.required_block(command)?)?;
+        Ok((self.test(test)?, block))
+    }
+
+    fn command(&self, node: &Node) -> Result<Command, SieveError> {
+        let line = node.line;
+        let flags_tag: &[(&str, Kind)] = &[("flags", Kind::StringList)];
+        let copy_tag: &[(&str, Kind)] = &[("copy", Kind::Nothing)];
+        let command = match node.name.as_str() {
+            "stop" | "discard" => {
+                let args = Args::new(node, &[])?;
+                args.count(0..=0)?;
+                args.no_tests()?;
+                match node.name.as_str() {
+                    "stop" => Command::Stop,
+                    _ => Command::Discard,
+                }
+            }
+            "keep" => {
+                let args = Args::new(node, &[flags_tag])?;
+                args.count(0..=0)?;
+                args.no_tests()?;
+                self.flags_tag(&args)?;
+                Command::Keep {
+                    flags: args.tag_strings("flags"),
+                }
+            }
+            "fileinto" => {
+                self.need("fileinto", line)?;
+                let args = Args::new(node, &[flags_tag, copy_tag])?;
+                args.count(1..=1)?;
+                args.no_tests()?;
+                self.flags_tag(&args)?;
+                self.copy_tag(&args)?;
+                Command::FileInto {
+                    folder: args.string(0)?,
+                    copy: args.has("copy"),
+                    flags: args.tag_strings("flags"),
+                }
+            }
+            "redirect" => {
+                let args = Args::new(node, &[copy_tag])?;
+                args.count(1..=1)?;
+                args.no_tests()?;
+                self.copy_tag(&args)?;
+                let target = args.string(0)?;
+                let dynamic = self.required.contains("variables") && target.contains("${");
+                if !dynamic && address::split(&target).is_none() {
+                    return Err(args.error(format!("invalid redirect address {}", target)));
+                }
+                Command::Redirect {
+                    address: target,
+                    copy: args.has("copy"),
+                }
+            }
+            "reject" => {
+                self.need("reject", line)?;
+                let args = Args::new(node, &[])?;
+                args.count(1..=1)?;
+                args.no_tests()?;
+                Command::Reject(args.string(0)?)
+            }
+            "vacation" => {
+                self.need("vacation", line)?;
+                let args = Args::new(
+                    node,
+                    &[&[
+                        ("days", Kind::Number),
+                        ("subject", Kind::String),
+                        ("from", Kind::String),
+                        ("addresses", Kind::StringList),
+                        ("mime", Kind::Nothing),
+                        ("handle", Kind::String),
+                    ]],
+                )?;
+                args.count(1..=1)?;
+                args.no_tests()?;
+                Command::Vacation(Vacation {
+                    days: match args.tag("days") {
+                        Some(Argument::Number(days)) => Some(*days),
+                        _ => None,
+                    },
+                    subject: args.tag_string("subject"),
+                    from: args.tag_string("from"),
+                    addresses: args.tag_strings("addresses").unwrap_or_default(),
+                    mime: args.has("mime"),
+                    handle: args.tag_string("handle"),
+                    reason: args.string(0)?,
+                })
+            }
+            "set" => {
+                self.need("variables", line)?;
+                let args = Args::new(
+                    node,
+                    &[&[
+                        ("lower", Kind::Nothing),
+                        ("upper", Kind::Nothing),
+                        ("lowerfirst", Kind::Nothing),
+                        ("upperfirst", Kind::Nothing),
+                        ("quotewildcard", Kind::Nothing),
+                        ("length", Kind::Nothing),
+                    ]],
+                )?;
+                args.count(2..=2)?;
+                args.no_tests()?;
+                let mut modifiers: Vec<Modifier> = args
+                    .tags
+                    .iter()
+                    .map(|(tag, _)| match tag.as_str() {
+                        "lower" => Modifier::Lower,
+                        "upper" => Modifier::Upper,
+                        "lowerfirst" => Modifier::LowerFirst,
+                        "upperfirst" => Modifier::UpperFirst,
+                        "quotewildcard" => Modifier::QuoteWildcard,
+                        _ => Modifier::Length,
+                    })
+                    .collect();
+                modifiers.sort_by_key(|m| std::cmp::Reverse(m.precedence()));
+                if modifiers
+                    .windows(2)
+                    .any(|w| w[0].precedence() == w[1].precedence())
+                {
+                    return Err(args.error("conflicting modifiers for set"));
+                }
+                let name = args.string(0)?;
+                variable_name(&args, &name)?;
+                Command::Set {
+                    name: name.to_ascii_lowercase(),
+                    value: args.string(1)?,
+                    modifiers,
+                }
+            }
+            "setflag" | "addflag" | "removeflag" => {
+                self.need("imap4flags", line)?;
+                let args = Args::new(node, &[])?;
+                args.count(1..=2)?;
+                args.no_tests()?;
+                let variable = match args.positional.len() {
+                    2 => {
+                        let name = args.string(0)?;
+                        variable_name(&args, &name)?;
+                        Some(name.to_ascii_lowercase())
+                    }
+                    _ => None,
+                };
+                Command::Flags {
+                    action: match node.name.as_str() {
+                        "setflag" => FlagAction::Set,
+                        "addflag" => FlagAction::Add,
+                        _ => FlagAction::Remove,
+                    },
+                    variable,
+                    flags: args.strings(args.positional.len() - 1)?,
+                }
+            }
+            name => return Err(SieveError::new(line, format!("unknown command {}", name))),
+        };
+        Ok(command)
+    }
+
+    fn flags_tag(&self, args: &Args) -> Result<(), SieveError> {
+        match args.has("flags") {
+            true => self.need("imap4flags", args.node.line),
+            false => Ok(()),
+        }
+    }
+
+    fn copy_tag(&self, args: &Args) -> Result<(), SieveError> {
+        match args.has("copy") {
+            true => self.need("copy", args.node.line),
+            false => Ok(()),
+        }
+    }
+
+    fn test(&self, node: &Node) -> Result<Test, SieveError> {
+        let line = node.line;
+        let test = match node.name.as_str() {
+            "true" | "false" => {
+                let args = Args::new(node, &[])?;
+                args.count(0..=0)?;
+                args.no_tests()?;
+                match node.name.as_str() {
+                    "true" => Test::True,
+                    _ => Test::False,
+                }
+            }
+            "not" => {
+                let args = Args::new(node, &[])?;
+                args.count(0..=0)?;
+                let [test] = &node.tests[..] else {
+                    return Err(args.error("not needs one test"));
+                };
+                Test::Not(Box::new(self.test(test)?))
+            }
+            "allof" | "anyof" => {
+                let args = Args::new(node, &[])?;
+                args.count(0..=0)?;
+                if node.tests.is_empty() {
+                    return Err(args.error(format!("{} needs a test list", node.name)));
+                }
+                let tests = node
+                    .tests
+                    .iter()
+                    .map(|t| self.test(t))
+                    .collect::<Result<_, _>>()?;
+                match node.name.as_str() {
+                    "allof" => Test::AllOf(tests),
+                    _ => Test::AnyOf(tests),
+                }
+            }
+            "exists" => {
+                let args = Args::new(node, &[])?;
+                args.count(1..=1)?;
+                args.no_tests()?;
+                Test::Exists(args.strings(0)?)
+            }
+            "size" => {
+                let args = Args::new(
+                    node,
+                    &[&[("over", Kind::Nothing), ("under", Kind::Nothing)]],
+                )?;
+                args.count(1..=1)?;
+                args.no_tests()?;
+                let over =
+                    match args.one_of(&[("over", Kind::Nothing), ("under", Kind::Nothing)])? {
+                        Some(tag) => tag == "over",
+                        None => return Err(args.error("size needs :over or :under")),
+                    };
+                let Argument::Number(limit) = args.positional[0] else {
+                    return Err(args.error("size expects a number"));
+                };
+                Test::Size { over, limit }
+            }
+            "header" => {
+                let args = Args::new(node, &[MATCH_TAGS])?;
+                args.count(2..=2)?;
+                args.no_tests()?;
+                Test::Header {
+                    matcher: self.matcher(&args)?,
+                    names: args.strings(0)?,
+                    keys: args.strings(1)?,
+                }
+            }
+            "address" | "envelope" => {
+                if node.name == "envelope" {
+                    self.need("envelope", line)?;
+                }
+                let args = Args::new(node, &[MATCH_TAGS, ADDRESS_TAGS])?;
+                args.count(2..=2)?;
+                args.no_tests()?;
+                let part = match args.one_of(ADDRESS_TAGS)? {
+                    None | Some("all") => AddressPart::All,
+                    Some("localpart") => AddressPart::LocalPart,
+                    Some("domain") => AddressPart::Domain,
+                    Some("user") => {
+                        self.need("subaddress", line)?;
+                        AddressPart::User
+                    }
+                    Some(_) => {
+                        self.need("subaddress", line)?;
+                        AddressPart::Detail
+                    }
+                };
+                let (matcher, names, keys) =
+                    (self.matcher(&args)?, args.strings(0)?, args.strings(1)?);
+                if node.name == "address" {
+                    Test::Address {
+                        part,
+                        names,
+                        keys,
+                        matcher,
+                    }
+                } else {
+                    if let Some(name) = names
+                        .iter()
+                        .find(|n| !n.eq_ignore_ascii_case("from") && !n.eq_ignore_ascii_case("to"))
+                    {
+                        return Err(args.error(format!("unsupported envelope part {}", name)));
+                    }
+                    Test::Envelope {
+                        part,
+                        names,
+                        keys,
+                        matcher,
+                    }
+                }
+            }
+            "body" => {
+                self.need("body", line)?;
+                let transforms: &[(&str, Kind)] = &[
+                    ("raw", Kind::Nothing),
+                    ("text", Kind::Nothing),
+                    ("content", Kind::StringList),
+                ];
+                let args = Args::new(node, &[MATCH_TAGS, transforms])?;
+                args.count(1..=1)?;
+                args.no_tests()?;
+                let transform = match args.one_of(transforms)? {
+                    Some("raw") => BodyTransform::Raw,
+                    Some("content") => {
+                        BodyTransform::Content(args.tag_strings("content").unwrap_or_default())
+                    }
+                    _ => BodyTransform::Text,
+                };
+                Test::Body {
+                    transform,
+                    matcher: self.matcher(&args)?,
+                    keys: args.strings(0)?,
+                }
+            }
+            "string" => {
+                self.need("variables", line)?;
+                let args = Args::new(node, &[MATCH_TAGS])?;
+                args.count(2..=2)?;
+                args.no_tests()?;
+                Test::String {
+                    matcher: self.matcher(&args)?,
+                    sources: args.strings(0)?,
+                    keys: args.strings(1)?,
+                }
+            }
+            "hasflag" => {
+                self.need("imap4flags", line)?;
+                let args = Args::new(node, &[MATCH_TAGS])?;
+                args.count(1..=2)?;
+                args.no_tests()?;
+                let variables = match args.positional.len() {
+                    2 => args.strings(0)?,
+                    _ => vec![],
+                };
+                for name in &variables {
+                    variable_name(&args, name)?;
+                }
+                Test::HasFlag {
+                    matcher: self.matcher(&args)?,
+                    variables: variables.iter().map(|v| v.to_ascii_lowercase()).collect(),
+                    keys: args.strings(args.positional.len() - 1)?,
+                }
+            }
+            name => return Err(SieveError::new(line, format!("unknown test {}", name))),
+        };
+        Ok(test)
+    }
+
+    fn matcher(&self, args: &Args) -> Result<Matcher, SieveError> {
+        let line = args.node.line;
+        let relation = |tag: &str| -> Result<Relation, SieveError> {
+            self.need("relational", line)?;
+            let value = args.tag_string(tag).unwrap_or_default();
+            Ok(match value.to_ascii_lowercase().as_str() {
+                "gt" => Relation::Gt,
+                "ge" => Relation::Ge,
+                "lt" => Relation::Lt,
+                "le" => Relation::Le,
+                "eq" => Relation::Eq,
+                "ne" => Relation::Ne,
+                _ => return Err(args.error(format!("unknown relation {}", value))),
+            })
+        };
+        let kind = match args.one_of(&MATCH_TAGS[..5])? {
+            None | Some("is") => MatchType::Is,
+            Some("contains") => MatchType::Contains,
+            Some("matches") => MatchType::Matches,
+            Some("value") => MatchType::Value(relation("value")?),
+            Some(_) => MatchType::Count(relation("count")?),
+        };
+        let comparator = match args.tag_string("comparator").as_deref() {
+            None | Some("i;ascii-casemap") => Comparator::AsciiCasemap,
+            Some("i;octet") => Comparator::Octet,
+            Some("i;ascii-numeric") => {
+                self.need("comparator-i;ascii-numeric", line)?;
+                if matches!(kind, MatchType::Contains | MatchType::Matches) {
+                    return Err(args.error("i;ascii-numeric only supports :is and relations"));
+                }
+                Comparator::AsciiNumeric
+            }
+            Some(name) => return Err(args.error(format!("unknown comparator {}", name))),
+        };
+        Ok(Matcher { kind, comparator })
+    }
+}
+
+fn variable_name(args: &Args, name: &str) -> Result<(), SieveError> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(args.error(format!("invalid variable name {}", name))),
+    }
+}
+
+pub(super) fn compile(commands: &[parser::Command]) -> Result<Script, SieveError> {
+    let mut compiler = Compiler {
+        required: HashSet::new(),
+    };
+    let start = commands
+        .iter()
+        .position(|c| c.node.name != "require")
+        .unwrap_or(commands.len());
+    for command in &commands[..start] {
+        let args = Args::new(&command.node, &[])?;
+        args.count(1..=1)?;
+        args.no_tests()?;
+        if command.block.is_some() {
+            return Err(args.error("require takes no block"));
+        }
+        for extension in args.strings(0)? {
+            if !EXTENSIONS.contains(&extension.as_str()) {
+                return Err(args.error(format!("unsupported extension {}", extension)));
+            }
+            compiler.required.insert(extension);
+        }
+    }
+    Ok(Script {
+        commands: compiler.block(&commands[start..])?,
+        variables: compiler.required.contains("variables"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile() {
+        let script = Script::compile(
+            r#"require ["fileinto", "imap4flags", "relational", "comparator-i;ascii-numeric"];
+               if header :value "ge" :comparator "i;ascii-numeric" "x-spam-score" "5" {
+                   fileinto :flags "\\Seen" "Junk";
+               } elsif not exists "subject" {
+                   discard;
+               } else {
+                   keep;
+               }"#,
+        )
+        .unwrap();
+        let [Command::If(branches, otherwise)] = &script.commands[..] else {
+            panic!("{:?}", script.commands);
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(
+            branches[0].0,
+            Test::Header {
+                names: vec!["x-spam-score".to_string()],
+                keys: vec!["5".to_string()],
+                matcher: Matcher {
+                    kind: MatchType::Value(Relation::Ge),
+                    comparator: Comparator::AsciiNumeric,
+                },
+            }
+        );
+        assert_eq!(
+            branches[0].1,
+            [Command::FileInto {
+                folder: "Junk".to_string(),
+                copy: false,
+                flags: Some(vec!["\\Seen".to_string()]),
+            }]
+        );
+        assert_eq!(otherwise, &[Command::Keep { flags: None }]);
+        assert!(!script.variables);
+    }
+
+    #[test]
+    fn test_errors() {
+        for (script, message) in [
+            ("fileinto \"x\";", "missing require \"fileinto\""),
+            ("require \"foo\";", "unsupported extension foo"),
+            ("keep;\nrequire \"body\";", "require must come before other commands"),
+            ("frobnicate;", "unknown command frobnicate"),
+            ("if true { keep; }\nelse;", "else needs a block"),
+            ("elsif true { keep; }", "elsif without if"),
+            ("if header :frob \"a\" \"b\" { }", "unknown tag :frob for header"),
+            ("if header :is :contains \"a\" \"b\" { }", ":contains conflicts with another tag"),
+            ("if size 10 { }", "size needs :over or :under"),
+            ("redirect \"nobody\";", "invalid redirect address nobody"),
+            ("require \"reject\";\nreject;", "wrong number of arguments for reject"),
+            ("if address :detail \"to\" \"x\" { }", "missing require \"subaddress\""),
+            (
+                "require \"variables\";\nset \"1a\" \"x\";",
+                "invalid variable name 1a",
+            ),
+            (
+                "require \"comparator-i;ascii-numeric\";\nif header :contains :comparator \"i;ascii-numeric\" \"a\" \"1\" { }",
+                "i;ascii-numeric only supports :is and relations",
+            ),
+        ] {
+            let e = Script::compile(script).unwrap_err();
+            assert_eq!(e.message, message, "{}", script);
+        }
+    }
+}
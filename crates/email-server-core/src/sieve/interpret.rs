@@ -0,0 +1,777 @@
+use super::compile::{
+    AddressPart, BodyTransform, Command, Comparator, FlagAction, MatchType, Matcher, Modifier,
+    Relation, Test,
+};
+use super::{Delivery, Envelope, Outcome, Script, Vacation, INBOX};
+use crate::mime;
+use crate::smtp::address;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// A script may not forward one message to more addresses than this.
+pub const MAX_REDIRECTS: usize = 4;
+
+const DEFAULT_VACATION_DAYS: u64 = 7;
+
+/// Backtracking steps one `:matches` comparison may take before it is
+/// treated as not matching.
+const MATCH_BUDGET: usize = 100_000;
+
+struct Interpreter<'a> {
+    script: &'a Script,
+    envelope: &'a Envelope<'a>,
+    message: mime::Part<'a>,
+    size: usize,
+    variables: HashMap<String, String>,
+    /// `${0}` to `${9}` from the last successful `:matches`.
+    matches: Vec<String>,
+    /// The internal variable of `imap4flags`.
+    flags: Vec<String>,
+    implicit_keep: bool,
+    outcome: Outcome,
+}
+
+pub(super) fn run(script: &Script, envelope: &Envelope, data: &[u8]) -> Result<Outcome, String> {
+    let mut interpreter = Interpreter {
+        script,
+        envelope,
+        message: mime::Part::parse(data),
+        size: data.len(),
+        variables: HashMap::new(),
+        matches: vec![],
+        flags: vec![],
+        implicit_keep: true,
+        outcome: Outcome::default(),
+    };
+    interpreter.block(&script.commands)?;
+    interpreter.finish()
+}
+
+impl Interpreter<'_> {
+    /// Runs `commands`, returning `true` once `stop` was reached.
+    fn block(&mut self, commands: &[Command]) -> Result<bool, String> {
+        for command in commands {
+            if self.command(command)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn command(&mut self, command: &Command) -> Result<bool, String> {
+        match command {
+            Command::If(branches, otherwise) => {
+                for (test, block) in branches {
+                    if self.test(test) {
+                        return self.block(block);
+                    }
+                }
+                return self.block(otherwise);
+            }
+            Command::Stop => return Ok(true),
+            Command::Keep { flags } => {
+                let flags = self.delivery_flags(flags.as_deref());
+                self.deliver(INBOX.to_string(), flags);
+                self.implicit_keep = false;
+            }
+            Command::Discard => self.implicit_keep = false,
+            Command::FileInto {
+                folder,
+                copy,
+                flags,
+            } => {
+                let flags = self.delivery_flags(flags.as_deref());
+                let folder = self.expand(folder);
+                self.deliver(folder, flags);
+                self.implicit_keep &= *copy;
+            }
+            Command::Redirect { address, copy } => {
+                let target = self.expand(address);
+                if address::split(&target).is_none() {
+                    return Err(format!("invalid redirect address {}", target));
+                }
+                let redirects = &mut self.outcome.redirects;
+                if !redirects.iter().any(|r| r.eq_ignore_ascii_case(&target)) {
+                    if redirects.len() == MAX_REDIRECTS {
+                        return Err("too many redirects".to_string());
+                    }
+                    redirects.push(target);
+                }
+                self.implicit_keep &= *copy;
+            }
+            Command::Reject(reason) => {
+                if self.outcome.reject.is_some() {
+                    return Err("reject used twice".to_string());
+                }
+                self.outcome.reject = Some(self.expand(reason));
+                self.implicit_keep = false;
+            }
+            Command::Vacation(vacation) => {
+                if self.outcome.vacation.is_some() {
+                    return Err("vacation used twice".to_string());
+                }
+                let reason = self.expand(&vacation.reason);
+                let subject = vacation.subject.as_ref().map(|s| self.expand(s));
+                let from = vacation.from.as_ref().map(|s| self.expand(s));
+                let handle = match &vacation.handle {
+                    Some(handle) => self.expand(handle),
+                    // RFC 5230: the same arguments share one rate limit.
+                    None => hex::encode(Sha256::digest(format!(
+                        "{}\0{}\0{}\0{}",
+                        subject.as_deref().unwrap_or_default(),
+                        from.as_deref().unwrap_or_default(),
+                        vacation.mime,
+                        reason
+                    ))),
+                };
+                self.outcome.vacation = Some(Vacation {
+                    reason,
+                    days: vacation.days.unwrap_or(DEFAULT_VACATION_DAYS).max(1),
+                    subject,
+                    from,
+                    addresses: vacation.addresses.iter().map(|a| self.expand(a)).collect(),
+                    mime: vacation.mime,
+                    handle,
+                });
+            }
+            Command::Set {
+                name,
+                value,
+                modifiers,
+            } => {
+                let value = modifiers
+                    .iter()
+                    .fold(self.expand(value), |value, m| modify(*m, value));
+                self.variables.insert(name.clone(), value);
+            }
+            Command::Flags {
+                action,
+                variable,
+                flags,
+            } => {
+                let given = self.flag_list(flags);
+                let mut current = match variable {
+                    Some(name) => split_flags(self.variables.get(name).map_or("", |v| v)),
+                    None => std::mem::take(&mut self.flags),
+                };
+                match action {
+                    FlagAction::Set => current = vec![],
+                    FlagAction::Add => {}
+                    FlagAction::Remove => {
+                        current.retain(|f| !given.iter().any(|g| g.eq_ignore_ascii_case(f)));
+                    }
+                }
+                if *action != FlagAction::Remove {
+                    for flag in given {
+                        if !current.iter().any(|f| f.eq_ignore_ascii_case(&flag)) {
+                            current.push(flag);
+                        }
+                    }
+                }
+                match variable {
+                    Some(name) => {
+                        self.variables.insert(name.clone(), current.join(" "));
+                    }
+                    None => self.flags = current,
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn finish(mut self) -> Result<Outcome, String> {
+        if self.implicit_keep {
+            let flags = self.flags.clone();
+            self.deliver(INBOX.to_string(), flags);
+        }
+        let outcome = self.outcome;
+        if outcome.reject.is_some()
+            && (!outcome.deliveries.is_empty()
+                || !outcome.redirects.is_empty()
+                || outcome.vacation.is_some())
+        {
+            return Err("reject cannot be combined with other actions".to_string());
+        }
+        Ok(outcome)
+    }
+
+    /// Files the message into `folder`, once.
+    fn deliver(&mut self, folder: String, flags: Vec<String>) {
+        let deliveries = &mut self.outcome.deliveries;
+        if !deliveries.iter().any(|d| d.folder == folder) {
+            deliveries.push(Delivery { folder, flags });
+        }
+    }
+
+    fn delivery_flags(&self, flags: Option<&[String]>) -> Vec<String> {
+        match flags {
+            Some(flags) => self.flag_list(flags),
+            None => self.flags.clone(),
+        }
+    }
+
+    /// Flags given as strings, each of which may hold several.
+    fn flag_list(&self, flags: &[String]) -> Vec<String> {
+        let mut list: Vec<String> = vec![];
+        for flag in flags.iter().flat_map(|f| split_flags(&self.expand(f))) {
+            if !list.iter().any(|f| f.eq_ignore_ascii_case(&flag)) {
+                list.push(flag);
+            }
+        }
+        list
+    }
+
+    /// Replaces `${name}` and `${n}` in `s` if the script uses variables.
+    fn expand(&self, s: &str) -> String {
+        if !self.script.variables {
+            return s.to_string();
+        }
+        let mut expanded = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(end) = rest.find('}') else {
+                break;
+            };
+            let name = &rest[2..end];
+            if let Ok(n) = name.parse::<usize>() {
+                expanded.push_str(self.matches.get(n).map_or("", |m| m));
+            } else if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+            {
+                let value = self.variables.get(&name.to_ascii_lowercase());
+                expanded.push_str(value.map_or("", |v| v));
+            } else {
+                // Not a variable reference, so it stands for itself.
+                expanded.push_str("${");
+                rest = &rest[2..];
+                continue;
+            }
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+
+    fn test(&mut self, test: &Test) -> bool {
+        match test {
+            Test::True => true,
+            Test::False => false,
+            Test::Not(test) => !self.test(test),
+            Test::AllOf(tests) => tests.iter().all(|t| self.test(t)),
+            Test::AnyOf(tests) => tests.iter().any(|t| self.test(t)),
+            Test::Exists(names) => names.iter().all(|name| {
+                let name = self.expand(name);
+                self.message
+                    .headers()
+                    .iter()
+                    .any(|h| h.name.eq_ignore_ascii_case(&name))
+            }),
+            Test::Size { over, limit } => match over {
+                true => self.size as u64 > *limit,
+                false => (self.size as u64) < *limit,
+            },
+            Test::Header {
+                names,
+                keys,
+                matcher,
+            } => {
+                let values = self.header_values(names);
+                self.compare(matcher, &values, keys)
+            }
+            Test::Address {
+                part,
+                names,
+                keys,
+                matcher,
+            } => {
+                let values: Vec<String> = self
+                    .header_values(names)
+                    .iter()
+                    .flat_map(|v| mime::addresses(v))
+                    .filter_map(|a| address_part(&a, *part))
+                    .collect();
+                self.compare(matcher, &values, keys)
+            }
+            Test::Envelope {
+                part,
+                names,
+                keys,
+                matcher,
+            } => {
+                let values: Vec<String> = names
+                    .iter()
+                    .map(|name| match name.eq_ignore_ascii_case("from") {
+                        true => self.envelope.from,
+                        false => self.envelope.to,
+                    })
+                    .filter_map(|a| match (a.is_empty(), part) {
+                        // The null reverse path only has an empty :all.
+                        (true, AddressPart::All) => Some(String::new()),
+                        (true, _) => None,
+                        (false, _) => address_part(a, *part),
+                    })
+                    .collect();
+                self.compare(matcher, &values, keys)
+            }
+            Test::Body {
+                transform,
+                keys,
+                matcher,
+            } => {
+                let values = self.body_values(transform);
+                self.compare(matcher, &values, keys)
+            }
+            Test::String {
+                sources,
+                keys,
+                matcher,
+            } => {
+                let values: Vec<String> = sources.iter().map(|s| self.expand(s)).collect();
+                self.compare(matcher, &values, keys)
+            }
+            Test::HasFlag {
+                variables,
+                keys,
+                matcher,
+            } => {
+                let values: Vec<String> = match variables.is_empty() {
+                    true => self.flags.clone(),
+                    false => variables
+                        .iter()
+                        .flat_map(|v| split_flags(self.variables.get(v).map_or("", |v| v)))
+                        .collect(),
+                };
+                let keys: Vec<String> = keys
+                    .iter()
+                    .flat_map(|k| split_flags(&self.expand(k)))
+                    .collect();
+                self.compare(matcher, &values, &keys)
+            }
+        }
+    }
+
+    fn header_values(&self, names: &[String]) -> Vec<String> {
+        names
+            .iter()
+            .flat_map(|name| {
+                let name = self.expand(name);
+                self.message
+                    .headers()
+                    .iter()
+                    .filter(move |h| h.name.eq_ignore_ascii_case(&name))
+                    .map(|h| h.value())
+            })
+            .collect()
+    }
+
+    fn body_values(&self, transform: &BodyTransform) -> Vec<String> {
+        match transform {
+            BodyTransform::Raw => {
+                vec![String::from_utf8_lossy(self.message.raw_body()).into_owned()]
+            }
+            BodyTransform::Text => self
+                .message
+                .leaves()
+                .into_iter()
+                .filter(|p| !p.is_attachment())
+                .filter_map(|p| p.text())
+                .collect(),
+            BodyTransform::Content(types) => {
+                let types: Vec<String> = types
+                    .iter()
+                    .map(|t| self.expand(t).to_ascii_lowercase())
+                    .collect();
+                self.message
+                    .leaves()
+                    .into_iter()
+                    .filter(|p| {
+                        let content_type = &p.content_type().value;
+                        types.iter().any(|t| {
+                            t.is_empty()
+                                || content_type == t
+                                || content_type.split('/').next() == Some(t.as_str())
+                        })
+                    })
+                    .map(|p| {
+                        p.text()
+                            .unwrap_or_else(|| String::from_utf8_lossy(&p.body()).into_owned())
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Whether any value matches any key, recording `:matches` captures.
+    fn compare(&mut self, matcher: &Matcher, values: &[String], keys: &[String]) -> bool {
+        let keys: Vec<String> = keys.iter().map(|k| self.expand(k)).collect();
+        let comparator = matcher.comparator;
+        match matcher.kind {
+            MatchType::Count(relation) => {
+                let count = values.iter().filter(|v| !v.is_empty()).count().to_string();
+                keys.iter()
+                    .any(|k| relation.holds(order(Comparator::AsciiNumeric, &count, k)))
+            }
+            MatchType::Value(relation) => values
+                .iter()
+                .any(|v| keys.iter().any(|k| relation.holds(order(comparator, v, k)))),
+            MatchType::Is => values
+                .iter()
+                .any(|v| keys.iter().any(|k| order(comparator, v, k).is_eq())),
+            MatchType::Contains => values.iter().any(|v| {
+                keys.iter().any(|k| match comparator {
+                    Comparator::AsciiCasemap => {
+                        v.to_ascii_lowercase().contains(&k.to_ascii_lowercase())
+                    }
+                    _ => v.contains(k.as_str()),
+                })
+            }),
+            MatchType::Matches => {
+                for value in values {
+                    for key in &keys {
+                        if let Some(captures) = glob(comparator, key, value) {
+                            if self.script.variables {
+                                self.matches = captures;
+                            }
+                            return true;
+                        }
+                    }
+                }
+                false
+            }
+        }
+    }
+}
+
+impl Relation {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Relation::Gt => ordering.is_gt(),
+            Relation::Ge => ordering.is_ge(),
+            Relation::Lt => ordering.is_lt(),
+            Relation::Le => ordering.is_le(),
+            Relation::Eq => ordering.is_eq(),
+            Relation::Ne => ordering.is_ne(),
+        }
+    }
+}
+
+/// Orders `value` against `key` under a comparator.
+fn order(comparator: Comparator, value: &str, key: &str) -> Ordering {
+    match comparator {
+        Comparator::Octet => value.cmp(key),
+        Comparator::AsciiCasemap => value.to_ascii_lowercase().cmp(&key.to_ascii_lowercase()),
+        Comparator::AsciiNumeric => {
+            // Strings not starting with a digit count as positive infinity.
+            let number = |s: &str| {
+                let digits =
+                    &s[..s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len()];
+                (!digits.is_empty()).then(|| {
+                    let digits = digits.trim_start_matches('0');
+                    (digits.len(), digits.to_string())
+                })
+            };
+            match (number(value), number(key)) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Glob {
+    Star,
+    Any,
+    Char(char),
+}
+
+/// Matches `value` against a `:matches` pattern, returning the whole value
+/// and the text each wildcard matched.
+fn glob(comparator: Comparator, pattern: &str, value: &str) -> Option<Vec<String>> {
+    let mut tokens = vec![];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' => Glob::Star,
+            '?' => Glob::Any,
+            '\\' => Glob::Char(chars.next().unwrap_or('\\')),
+            c => Glob::Char(c),
+        });
+    }
+    let text: Vec<char> = value.chars().collect();
+    let mut captures = vec![value.to_string()];
+    let mut budget = MATCH_BUDGET;
+    glob_at(comparator, &tokens, &text, &mut captures, &mut budget).then_some(captures)
+}
+
+fn glob_at(
+    comparator: Comparator,
+    pattern: &[Glob],
+    text: &[char],
+    captures: &mut Vec<String>,
+    budget: &mut usize,
+) -> bool {
+    if *budget == 0 {
+        return false;
+    }
+    *budget -= 1;
+    let Some((first, rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+    match first {
+        Glob::Char(c) => match text.split_first() {
+            Some((t, text)) => {
+                let equal = match comparator {
+                    Comparator::AsciiCasemap => c.eq_ignore_ascii_case(t),
+                    _ => c == t,
+                };
+                equal && glob_at(comparator, rest, text, captures, budget)
+            }
+            None => false,
+        },
+        Glob::Any => {
+            let Some((t, text)) = text.split_first() else {
+                return false;
+            };
+            captures.push(t.to_string());
+            if glob_at(comparator, rest, text, captures, budget) {
+                return true;
+            }
+            captures.pop();
+            false
+        }
+        // As short as possible, so the leftmost star matches least.
+        Glob::Star => {
+            for i in 0..=text.len() {
+                captures.push(text[..i].iter().collect());
+                if glob_at(comparator, rest, &text[i..], captures, budget) {
+                    return true;
+                }
+                captures.pop();
+            }
+            false
+        }
+    }
+}
+
+fn modify(modifier: Modifier, value: String) -> String {
+    let mut chars = value.chars();
+    match modifier {
+        Modifier::Lower => value.to_lowercase(),
+        Modifier::Upper => value.to_uppercase(),
+        Modifier::LowerFirst => chars
+            .next()
+            .map_or_else(String::new, |c| c.to_lowercase().chain(chars).collect()),
+        Modifier::UpperFirst => chars
+            .next()
+            .map_or_else(String::new, |c| c.to_uppercase().chain(chars).collect()),
+        Modifier::QuoteWildcard => value
+            .chars()
+            .flat_map(|c| match c {
+                '*' | '?' | '\\' => vec!['\\', c],
+                c => vec![c],
+            })
+            .collect(),
+        Modifier::Length => value.chars().count().to_string(),
+    }
+}
+
+fn split_flags(s: &str) -> Vec<String> {
+    s.split_whitespace().map(str::to_string).collect()
+}
+
+fn address_part(address: &str, part: AddressPart) -> Option<String> {
+    let (local, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+    let part = match part {
+        AddressPart::All => address,
+        AddressPart::LocalPart => local,
+        AddressPart::Domain => domain,
+        AddressPart::User => local.split_once('+').map_or(local, |(user, _)| user),
+        AddressPart::Detail => local.split_once('+')?.1,
+    };
+    Some(part.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: \"Alice, A.\" <Alice@Example.net>\r\n\
+To: bob+lists@example.com, team: carol@example.com;\r\n\
+Subject: [rust-users] Weekly digest\r\n\
+X-Spam-Score: 12\r\n\
+List-Id: <rust-users.example.org>\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Hello from the list\r\n";
+
+    fn run(script: &str) -> Outcome {
+        let envelope = Envelope {
+            from: "alice@example.net",
+            to: "bob+lists@example.com",
+        };
+        Script::compile(script).unwrap().run(&envelope, MESSAGE)
+    }
+
+    fn delivery(folder: &str, flags: &[&str]) -> Delivery {
+        Delivery {
+            folder: folder.to_string(),
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_actions() {
+        assert_eq!(run(""), Outcome::keep());
+        assert_eq!(run("discard;"), Outcome::default());
+        assert_eq!(
+            run("require \"fileinto\";\nfileinto \"Lists\"; fileinto \"Lists\";").deliveries,
+            [delivery("Lists", &[])]
+        );
+        let outcome = run("require \"copy\";\nredirect :copy \"carol@example.com\";");
+        assert_eq!(outcome.deliveries, [delivery(INBOX, &[])]);
+        assert_eq!(outcome.redirects, ["carol@example.com"]);
+        let outcome = run("require \"reject\";\nreject \"go away\";");
+        assert_eq!(outcome.reject.as_deref(), Some("go away"));
+        assert!(outcome.deliveries.is_empty());
+        // Rejecting what was also filed is an error, so the message is kept.
+        assert_eq!(
+            run("require [\"reject\", \"fileinto\"];\nfileinto \"A\"; reject \"no\";"),
+            Outcome::keep()
+        );
+        assert_eq!(run("stop; discard;"), Outcome::keep());
+    }
+
+    #[test]
+    fn test_tests() {
+        for (test, expected) in [
+            ("header :contains \"subject\" \"RUST-users\"", true),
+            (
+                "header :is :comparator \"i;octet\" \"subject\" \"[rust-users] weekly digest\"",
+                false,
+            ),
+            ("address :domain \"from\" \"example.net\"", true),
+            ("address :all \"to\" \"carol@example.com\"", true),
+            ("address :localpart \"from\" \"alice, a.\"", false),
+            ("address :user \"to\" \"bob\"", true),
+            ("address :detail \"to\" \"lists\"", true),
+            ("envelope :detail \"to\" \"lists\"", true),
+            ("envelope :is \"from\" \"ALICE@example.net\"", true),
+            ("exists [\"list-id\", \"subject\"]", true),
+            ("exists [\"list-id\", \"x-missing\"]", false),
+            ("size :over 100", true),
+            ("size :under 100", false),
+            ("body :contains \"from the list\"", true),
+            ("body :raw :contains \"digest\"", false),
+            (
+                "header :value \"gt\" :comparator \"i;ascii-numeric\" \"x-spam-score\" \"9\"",
+                true,
+            ),
+            ("header :value \"gt\" \"x-spam-score\" \"9\"", false),
+            (
+                "address :count \"eq\" :comparator \"i;ascii-numeric\" \"to\" \"2\"",
+                true,
+            ),
+            ("header :matches \"subject\" \"[*] *digest\"", true),
+            ("anyof (false, not true)", false),
+            ("allof (true, not false)", true),
+        ] {
+            let script = format!(
+                "require [\"envelope\", \"body\", \"subaddress\", \"relational\", \
+                 \"comparator-i;ascii-numeric\"];\nif {} {{ discard; }}",
+                test
+            );
+            assert_eq!(run(&script).deliveries.is_empty(), expected, "{}", test);
+        }
+    }
+
+    #[test]
+    fn test_variables() {
+        let outcome = run(r#"require ["variables", "fileinto"];
+            if header :matches "subject" "[*] *" {
+                set :lower :upperfirst "list" "${1}";
+                fileinto "Lists/${list}";
+            }
+            set "x" "${unknown}${}${1";
+            if string :is "${x}" "${}${1" { fileinto "Literal"; }
+            set :length "n" "four";
+            if string :is "${n}" "4" { fileinto "Length"; }"#);
+        assert_eq!(
+            outcome.deliveries,
+            [
+                delivery("Lists/Rust-users", &[]),
+                delivery("Literal", &[]),
+                delivery("Length", &[])
+            ]
+        );
+        // Without the extension strings are left alone.
+        assert_eq!(
+            run("require \"fileinto\";\nfileinto \"${1}\";").deliveries,
+            [delivery("${1}", &[])]
+        );
+    }
+
+    #[test]
+    fn test_flags() {
+        let outcome = run(r#"require ["imap4flags", "fileinto", "variables"];
+            setflag "\\Flagged \\Seen";
+            removeflag "\\seen";
+            addflag "mine" "$Label1";
+            if hasflag :is "\\flagged" { fileinto "Flagged"; }
+            if hasflag "mine" "$label1" { fileinto :flags "\\Answered" "Answered"; }"#);
+        assert_eq!(
+            outcome.deliveries,
+            [
+                delivery("Flagged", &["\\Flagged"]),
+                delivery("Answered", &["\\Answered"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vacation() {
+        let outcome = run(r#"require "vacation";
+            vacation :days 0 :subject "Away" :addresses ["bob@example.org"] "Back soon";"#);
+        let vacation = outcome.vacation.unwrap();
+        assert_eq!(vacation.days, 1);
+        assert_eq!(vacation.subject.as_deref(), Some("Away"));
+        assert_eq!(vacation.reason, "Back soon");
+        assert_eq!(vacation.addresses, ["bob@example.org"]);
+        assert_eq!(vacation.handle.len(), 64);
+        assert_eq!(outcome.deliveries, [delivery(INBOX, &[])]);
+    }
+
+    #[test]
+    fn test_redirect_limit() {
+        let redirects: String = (0..=MAX_REDIRECTS)
+            .map(|i| format!("redirect \"user{}@example.org\";\n", i))
+            .collect();
+        assert_eq!(run(&redirects), Outcome::keep());
+    }
+
+    #[test]
+    fn test_glob() {
+        let matches = |pattern: &str, value: &str| glob(Comparator::AsciiCasemap, pattern, value);
+        assert_eq!(
+            matches("*@*.com", "bob@mail.example.com"),
+            Some(vec![
+                "bob@mail.example.com".to_string(),
+                "bob".to_string(),
+                "mail.example".to_string()
+            ])
+        );
+        assert_eq!(matches("a?c", "ABC").unwrap()[1], "B");
+        assert_eq!(matches("\\*", "*"), Some(vec!["*".to_string()]));
+        assert_eq!(matches("\\*", "x"), None);
+        // Gives up instead of backtracking forever.
+        assert!(matches(&"*a".repeat(30), &format!("{}b", "a".repeat(40))).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+//! Server-side mail filtering with Sieve (RFC 5228) and the `fileinto`,
+//! `reject`, `envelope`, `body`, `variables`, `vacation`, `imap4flags`,
+//! `relational`, `subaddress` and `copy` extensions.
+
+mod compile;
+mod interpret;
+mod parser;
+
+pub use interpret::MAX_REDIRECTS;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// Extensions a script may `require`.
+pub const EXTENSIONS: &[&str] = &[
+    "fileinto",
+    "reject",
+    "envelope",
+    "body",
+    "variables",
+    "vacation",
+    "imap4flags",
+    "relational",
+    "subaddress",
+    "copy",
+    "comparator-i;ascii-numeric",
+];
+
+/// The folder mail is kept in unless a script files it elsewhere.
+pub const INBOX: &str = "INBOX";
+
+/// A script that does not compile, with the line the problem is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SieveError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl SieveError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for SieveError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for SieveError {}
+
+/// A validated script, ready to run against messages.
+#[derive(Debug, Clone)]
+pub struct Script {
+    commands: Vec<compile::Command>,
+    /// Whether `${...}` in strings is expanded, i.e. `variables` was
+    /// required.
+    variables: bool,
+}
+
+impl Script {
+    /// Parses and checks a script: unknown commands, tests and tags,
+    /// wrong arguments and extensions used without `require` are all
+    /// errors here rather than when mail arrives.
+    pub fn compile(src: &str) -> Result<Self, SieveError> {
+        compile::compile(&parser::parse(src)?)
+    }
+
+    /// Runs the script for one recipient of a message. Errors at run time,
+    /// such as conflicting actions, fall back to keeping the message.
+    pub fn run(&self, envelope: &Envelope, data: &[u8]) -> Outcome {
+        interpret::run(self, envelope, data).unwrap_or_else(|e| {
+            tracing::warn!("sieve script failed for {}: {}", envelope.to, e);
+            Outcome::keep()
+        })
+    }
+}
+
+/// The SMTP envelope of the message a script runs on.
+#[derive(Debug, Clone, Copy)]
+pub struct Envelope<'a> {
+    /// The reverse path, empty for bounces.
+    pub from: &'a str,
+    /// The address the message was sent to, including any `+detail`.
+    pub to: &'a str,
+}
+
+/// What to do with a message after its script ran.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// Folders to store the message in; empty if it is not kept.
+    pub deliveries: Vec<Delivery>,
+    /// Addresses to forward the message to.
+    pub redirects: Vec<String>,
+    /// Refuse the message with this reason.
+    pub reject: Option<String>,
+    pub vacation: Option<Vacation>,
+}
+
+impl Outcome {
+    /// The implicit keep: the inbox, with no flags.
+    pub fn keep() -> Self {
+        Self {
+            deliveries: vec![Delivery {
+                folder: INBOX.to_string(),
+                flags: vec![],
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub folder: String,
+    /// IMAP flags such as `\Seen` to store the message with.
+    pub flags: Vec<String>,
+}
+
+/// An auto-reply requested by the `vacation` command (RFC 5230). Whether it
+/// is sent is still up to [`crate::vacation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vacation {
+    pub reason: String,
+    /// Minimum time between replies to the same sender, in days.
+    pub days: u64,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    /// Other addresses of the recipient, besides the envelope one.
+    pub addresses: Vec<String>,
+    /// The reason is a MIME part rather than plain text.
+    pub mime: bool,
+    /// Replies with the same handle share one rate limit.
+    pub handle: String,
+}
@@ -0,0 +1,382 @@
+use super::SieveError;
+
+/// An argument as written, before the command it belongs to gives it a
+/// meaning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Argument {
+    String(String),
+    StringList(Vec<String>),
+    Number(u64),
+    /// A `:tag`, lowercased and without the colon.
+    Tag(String),
+}
+
+/// A test, or a command without its trailing `;` or block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Node {
+    pub name: String,
+    pub arguments: Vec<Argument>,
+    pub tests: Vec<Node>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Command {
+    pub node: Node,
+    pub block: Option<Vec<Command>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Tag(String),
+    Number(u64),
+    String(String),
+    Punct(char),
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn error(&self, message: impl Into<String>) -> SieveError {
+        SieveError::new(self.line, message)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.line += self.src[self.pos..self.pos + n].matches('\n').count();
+        self.pos += n;
+    }
+
+    fn skip_space(&mut self) -> Result<(), SieveError> {
+        loop {
+            let rest = self.rest();
+            if let Some(c) = rest.chars().next().filter(|c| c.is_ascii_whitespace()) {
+                self.advance(c.len_utf8());
+            } else if rest.starts_with('#') {
+                self.advance(rest.find('\n').unwrap_or(rest.len()));
+            } else if rest.starts_with("/*") {
+                let end = rest
+                    .find("*/")
+                    .ok_or_else(|| self.error("unterminated comment"))?;
+                self.advance(end + 2);
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<(Token, usize)>, SieveError> {
+        self.skip_space()?;
+        let rest = self.rest();
+        let line = self.line;
+        let Some(c) = rest.chars().next() else {
+            return Ok(None);
+        };
+        let token = match c {
+            '"' => self.quoted()?,
+            ':' => {
+                let name = identifier(&rest[1..]);
+                if name.is_empty() {
+                    return Err(self.error("expected a tag after ':'"));
+                }
+                self.advance(1 + name.len());
+                Token::Tag(name.to_ascii_lowercase())
+            }
+            '0'..='9' => {
+                let digits =
+                    rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                let value: u64 = rest[..digits]
+                    .parse()
+                    .map_err(|_| self.error("number too large"))?;
+                let (multiplier, len) = match rest[digits..].chars().next() {
+                    Some('K' | 'k') => (1 << 10, 1),
+                    Some('M' | 'm') => (1 << 20, 1),
+                    Some('G' | 'g') => (1 << 30, 1),
+                    _ => (1, 0),
+                };
+                self.advance(digits + len);
+                Token::Number(
+                    value
+                        .checked_mul(multiplier)
+                        .ok_or_else(|| self.error("number too large"))?,
+                )
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let name = identifier(rest);
+                if rest[name.len()..].starts_with(':') && name.eq_ignore_ascii_case("text") {
+                    self.multiline()?
+                } else {
+                    self.advance(name.len());
+                    Token::Identifier(name.to_ascii_lowercase())
+                }
+            }
+            '[' | ']' | '(' | ')' | '{' | '}' | ',' | ';' => {
+                self.advance(1);
+                Token::Punct(c)
+            }
+            c => return Err(self.error(format!("unexpected character '{}'", c))),
+        };
+        Ok(Some((token, line)))
+    }
+
+    fn quoted(&mut self) -> Result<Token, SieveError> {
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.advance(i + 1);
+                    return Ok(Token::String(value));
+                }
+                // Any escaped character stands for itself.
+                '\\' => {
+                    if let Some((_, c)) = chars.next() {
+                        value.push(c);
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    /// A `text:` string, ended by a line holding only a dot.
+    fn multiline(&mut self) -> Result<Token, SieveError> {
+        let rest = self.rest();
+        let start = rest
+            .find('\n')
+            .ok_or_else(|| self.error("expected a line break after text:"))?;
+        let mut value = String::new();
+        let mut offset = start + 1;
+        loop {
+            let line_end = rest[offset..]
+                .find('\n')
+                .map(|i| offset + i + 1)
+                .ok_or_else(|| self.error("unterminated text: string"))?;
+            let line = &rest[offset..line_end];
+            offset = line_end;
+            let content = line.trim_end_matches(['\r', '\n']);
+            if content == "." {
+                break;
+            }
+            // Dot-stuffing, as in SMTP.
+            value.push_str(line.strip_prefix("..").map_or(line, |_| &line[1..]));
+        }
+        self.advance(offset);
+        Ok(Token::String(value))
+    }
+}
+
+fn identifier(s: &str) -> &str {
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    &s[..end]
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<(Token, usize)>,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Result<Option<&Token>, SieveError> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next()?;
+        }
+        Ok(self.peeked.as_ref().map(|(token, _)| token))
+    }
+
+    fn take(&mut self) -> Result<Option<(Token, usize)>, SieveError> {
+        self.peek()?;
+        Ok(self.peeked.take())
+    }
+
+    fn line(&self) -> usize {
+        self.peeked
+            .as_ref()
+            .map_or(self.lexer.line, |(_, line)| *line)
+    }
+
+    fn error(&self, message: impl Into<String>) -> SieveError {
+        SieveError::new(self.line(), message)
+    }
+
+    fn commands(&mut self, nested: bool) -> Result<Vec<Command>, SieveError> {
+        let mut commands = vec![];
+        loop {
+            match self.peek()? {
+                None if nested => return Err(self.error("expected '}'")),
+                None => return Ok(commands),
+                Some(Token::Punct('}')) if nested => {
+                    self.take()?;
+                    return Ok(commands);
+                }
+                Some(Token::Identifier(_)) => commands.push(self.command()?),
+                Some(_) => return Err(self.error("expected a command")),
+            }
+        }
+    }
+
+    fn command(&mut self) -> Result<Command, SieveError> {
+        let node = self.node()?;
+        let block = match self.take()? {
+            Some((Token::Punct(';'), _)) => None,
+            Some((Token::Punct('{'), _)) => Some(self.commands(true)?),
+            _ => return Err(self.error(format!("expected ';' after {}", node.name))),
+        };
+        Ok(Command { node, block })
+    }
+
+    /// An identifier with its arguments and tests.
+    fn node(&mut self) -> Result<Node, SieveError> {
+        let Some((Token::Identifier(name), line)) = self.take()? else {
+            return Err(self.error("expected an identifier"));
+        };
+        let mut arguments = vec![];
+        loop {
+            let argument = match self.peek()? {
+                Some(Token::String(_)) => {
+                    let Some((Token::String(s), _)) = self.take()? else {
+                        unreachable!()
+                    };
+                    Argument::String(s)
+                }
+                Some(Token::Number(n)) => {
+                    let n = *n;
+                    self.take()?;
+                    Argument::Number(n)
+                }
+                Some(Token::Tag(_)) => {
+                    let Some((Token::Tag(tag), _)) = self.take()? else {
+                        unreachable!()
+                    };
+                    Argument::Tag(tag)
+                }
+                Some(Token::Punct('[')) => {
+                    self.take()?;
+                    Argument::StringList(self.string_list()?)
+                }
+                _ => break,
+            };
+            arguments.push(argument);
+        }
+        let tests = match self.peek()? {
+            Some(Token::Identifier(_)) => vec![self.node()?],
+            Some(Token::Punct('(')) => {
+                self.take()?;
+                let mut tests = vec![self.node()?];
+                loop {
+                    match self.take()? {
+                        Some((Token::Punct(','), _)) => tests.push(self.node()?),
+                        Some((Token::Punct(')'), _)) => break,
+                        _ => return Err(self.error("expected ',' or ')' in test list")),
+                    }
+                }
+                tests
+            }
+            _ => vec![],
+        };
+        Ok(Node {
+            name,
+            arguments,
+            tests,
+            line,
+        })
+    }
+
+    fn string_list(&mut self) -> Result<Vec<String>, SieveError> {
+        let mut strings = vec![];
+        loop {
+            match self.take()? {
+                Some((Token::String(s), _)) => strings.push(s),
+                _ => return Err(self.error("expected a string in string list")),
+            }
+            match self.take()? {
+                Some((Token::Punct(','), _)) => {}
+                Some((Token::Punct(']'), _)) => return Ok(strings),
+                _ => return Err(self.error("expected ',' or ']' in string list")),
+            }
+        }
+    }
+}
+
+/// Parses a script into its commands, without checking what they mean.
+pub(super) fn parse(src: &str) -> Result<Vec<Command>, SieveError> {
+    let mut parser = Parser {
+        lexer: Lexer {
+            src,
+            pos: 0,
+            line: 1,
+        },
+        peeked: None,
+    };
+    parser.commands(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = "require [\"fileinto\", \"body\"];\r\n\
+                      # a comment\r\n\
+                      if anyof (header :contains \"subject\" \"x\", size :over 1K) {\r\n\
+                      \x20   fileinto \"Junk\"; /* another */\r\n\
+                      } else { keep; }\r\n";
+        let commands = parse(script).unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(
+            commands[0].node.arguments,
+            [Argument::StringList(vec![
+                "fileinto".to_string(),
+                "body".to_string()
+            ])]
+        );
+        let test = &commands[1].node.tests[0];
+        assert_eq!(test.name, "anyof");
+        assert_eq!(test.tests[1].arguments[1], Argument::Number(1024));
+        assert_eq!(test.tests[1].line, 3);
+        let block = commands[1].block.as_ref().unwrap();
+        assert_eq!(
+            block[0].node.arguments[0],
+            Argument::String("Junk".to_string())
+        );
+        assert_eq!(commands[2].node.name, "else");
+    }
+
+    #[test]
+    fn test_strings() {
+        let commands = parse("set \"a\" \"q\\\"\\\\\";\nset \"b\" text:\n..x\nline\n.\n;").unwrap();
+        assert_eq!(
+            commands[0].node.arguments[1],
+            Argument::String("q\"\\".to_string())
+        );
+        assert_eq!(
+            commands[1].node.arguments[1],
+            Argument::String(".x\nline\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        for (script, line) in [
+            ("keep", 1),
+            ("if true {\nkeep;\n", 3),
+            ("fileinto \"x", 1),
+            ("\n\nkeep [\"a\" \"b\"];", 3),
+            ("/* open", 1),
+        ] {
+            assert_eq!(parse(script).unwrap_err().line, line, "{}", script);
+        }
+    }
+}
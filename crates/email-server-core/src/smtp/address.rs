@@ -0,0 +1,40 @@
+/// Extracts the mailbox from a `MAIL FROM`/`RCPT TO` argument such as
+/// `Bob <bob@example.com>` or `<bob@example.com> SIZE=100`.
+pub fn mailbox(path: &str) -> &str {
+    let path = path.trim();
+    match (path.find('<'), path.find('>')) {
+        (Some(start), Some(end)) if start < end => &path[start + 1..end],
+        _ => path.split_whitespace().next().unwrap_or_default(),
+    }
+}
+
+/// Splits a mailbox into its local part and lowercased domain.
+pub fn split(mailbox: &str) -> Option<(&str, String)> {
+    let (local, domain) = mailbox.rsplit_once('@')?;
+    if local.is_empty() || domain.is_empty() {
+        return None;
+    }
+    Some((local, domain.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mailbox() {
+        assert_eq!(mailbox("Bob <bob@example.com>"), "bob@example.com");
+        assert_eq!(mailbox(" <bob@example.com> SIZE=100"), "bob@example.com");
+        assert_eq!(mailbox("bob@example.com"), "bob@example.com");
+        assert_eq!(mailbox("<>"), "");
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split("Bob@Example.COM"),
+            Some(("Bob", "example.com".to_string()))
+        );
+        assert_eq!(split("postmaster"), None);
+    }
+}
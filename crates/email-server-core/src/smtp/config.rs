@@ -0,0 +1,168 @@
+use crate::body::DEFAULT_SPOOL_THRESHOLD;
+use crate::dnsbl::Blocklists;
+use crate::lmtp::LmtpAddress;
+use crate::milter::MilterConfig;
+use crate::resolver::AddressResolver;
+use crate::smtp::delivery::DeliveryLog;
+use crate::smtp::greylist::{GreylistConfig, GreylistStore};
+use crate::smtp::validator::{HeloValidator, NoopValidator, RecipientValidator};
+use crate::spam::SpamConfig;
+use crate::srs::Srs;
+use crate::storage::RecoveryKey;
+use derive_builder::Builder;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 25 * 1024 * 1024;
+
+/// Longest command line, with its CRLF (RFC 5321 4.5.3.1.4).
+const MAX_COMMAND_LINE: usize = 512;
+
+/// Settings shared by every state of an SMTP session.
+#[derive(Builder, Clone, Debug)]
+#[builder(default)]
+pub struct Config {
+    /// Name the server gives itself when it connects to other servers and
+    /// in the bounces it writes.
+    #[builder(setter(into))]
+    pub(crate) hostname: String,
+    #[builder(setter(into))]
+    pub(crate) helo_validator: Arc<dyn HeloValidator>,
+    #[builder(setter(into, strip_option))]
+    pub(crate) srs: Option<Arc<Srs>>,
+    #[builder(setter(into, strip_option))]
+    pub(crate) recipient_validator: Option<Arc<dyn RecipientValidator>>,
+    #[builder(setter(into, strip_option))]
+    pub(crate) resolver: Option<Arc<dyn AddressResolver>>,
+    /// Largest accepted message in bytes, advertised as `SIZE`; zero means
+    /// no limit.
+    pub(crate) max_message_size: usize,
+    /// Message bodies larger than this are spooled to a temporary file.
+    pub(crate) spool_threshold: usize,
+    /// Speak LMTP (RFC 2033) instead of SMTP, replying once per recipient
+    /// after the message.
+    pub(crate) lmtp: bool,
+    /// Deliver accepted mail over LMTP instead of into the SQLite store.
+    #[builder(setter(into, strip_option))]
+    pub(crate) lmtp_delivery: Option<LmtpAddress>,
+    /// Smarthost (`host:port`) that mail queued by Sieve redirects,
+    /// auto-replies and forwards is relayed through. Without it, recipients
+    /// that would be forwarded to other domains are deferred.
+    #[builder(setter(into, strip_option))]
+    pub(crate) relay_host: Option<LmtpAddress>,
+    /// Temporarily reject all recipients, e.g. while the store is migrated.
+    pub(crate) maintenance: bool,
+    /// Also post every accepted message to this URL.
+    #[builder(setter(into, strip_option))]
+    pub(crate) webhook_url: Option<String>,
+    /// Key for the HMAC signature on webhook requests.
+    #[builder(setter(into))]
+    pub(crate) webhook_secret: String,
+    /// Keep message bodies as files under this directory instead of in the
+    /// database.
+    #[builder(setter(into, strip_option))]
+    pub(crate) blob_path: Option<PathBuf>,
+    /// Encrypts mail on delivery for users who enabled encryption. Without
+    /// it their mail is deferred.
+    #[builder(setter(into, strip_option))]
+    pub(crate) recovery_key: Option<RecoveryKey>,
+    /// Encrypt mail to recipients with a registered OpenPGP key before it
+    /// is delivered.
+    pub(crate) pgp_encryption: bool,
+    /// Learn peers' keys from the Autocrypt headers of inbound mail, and
+    /// announce local users' keys in the mail relayed for them.
+    pub(crate) autocrypt: bool,
+    /// Score incoming mail for spam, keeping it in the Junk folder or
+    /// refusing it above the configured scores.
+    #[builder(setter(into, strip_option))]
+    pub(crate) spam: Option<SpamConfig>,
+    /// Refuse clients and sender domains listed on DNS blocklists.
+    #[builder(setter(into, strip_option))]
+    pub(crate) blocklists: Option<Arc<Blocklists>>,
+    /// Defer the first attempt of unauthenticated clients to deliver from
+    /// a sender to a recipient.
+    #[builder(setter(into, strip_option))]
+    pub(crate) greylist: Option<GreylistConfig>,
+    #[builder(setter(into, strip_option))]
+    pub(crate) greylist_store: Option<Arc<dyn GreylistStore>>,
+    /// Pass mail through external content filters over the milter
+    /// protocol before it is delivered.
+    #[builder(setter(into, strip_option))]
+    pub(crate) milter: Option<MilterConfig>,
+    /// Bounces recipients that failed for good once others got the
+    /// message, and spares those that got it a repeat when the client
+    /// retries for the others.
+    #[builder(setter(into, strip_option))]
+    pub(crate) delivery_log: Option<Arc<dyn DeliveryLog>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hostname: "localhost".to_string(),
+            helo_validator: Arc::new(NoopValidator),
+            srs: None,
+            recipient_validator: None,
+            resolver: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            spool_threshold: DEFAULT_SPOOL_THRESHOLD,
+            lmtp: false,
+            lmtp_delivery: None,
+            relay_host: None,
+            maintenance: false,
+            webhook_url: None,
+            webhook_secret: String::new(),
+            blob_path: None,
+            recovery_key: None,
+            pgp_encryption: false,
+            autocrypt: false,
+            spam: None,
+            blocklists: None,
+            greylist: None,
+            greylist_store: None,
+            milter: None,
+            delivery_log: None,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn exceeds_size(&self, size: usize) -> bool {
+        self.max_message_size != 0 && size > self.max_message_size
+    }
+
+    /// Whether the HELO or sender `domain` is listed on the blocklists.
+    pub(crate) async fn domain_blocked(&self, domain: &str) -> bool {
+        let Some(blocklists) = &self.blocklists else {
+            return false;
+        };
+        match blocklists.check_domain(domain).await {
+            Some(listing) => {
+                tracing::info!("{} is listed on {}", domain, listing.zones.join(", "));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether mail for recipients elsewhere, such as foreign alias
+    /// targets, can be forwarded: the store queues it and the relay sends
+    /// it on.
+    pub(crate) fn forwards(&self) -> bool {
+        self.relay_host.is_some() && self.lmtp_delivery.is_none()
+    }
+
+    /// Command lines longer than this are discarded by the codec, which
+    /// counts the CR but not the LF.
+    pub(crate) fn max_command_length(&self) -> usize {
+        MAX_COMMAND_LINE - 1
+    }
+
+    /// Body lines longer than this are discarded by the codec.
+    pub(crate) fn max_line_length(&self) -> usize {
+        match self.max_message_size {
+            0 => usize::MAX,
+            max => max.saturating_add(1),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! What one SMTP reply cannot say about a transaction that reached only
+//! some of its recipients.
+
+use crate::message::Message;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+/// Remembers partial deliveries and queues bounces for the SMTP server.
+#[async_trait]
+pub trait DeliveryLog: Send + Sync + std::fmt::Debug {
+    /// The recipients that already got the message with `digest` in an
+    /// attempt the client was told to retry.
+    async fn delivered(&self, digest: &str) -> Result<Vec<String>, sqlx::Error>;
+
+    /// Remembers that `recipients` got the message with `digest` at `now`
+    /// (seconds since the epoch).
+    async fn record_delivered(
+        &self,
+        digest: &str,
+        recipients: &[String],
+        now: i64,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Queues the bounce `report` to `sender`.
+    async fn bounce(&self, sender: &str, report: &[u8], now: i64) -> Result<(), sqlx::Error>;
+}
+
+/// Identifies a message across retries by its envelope sender and content.
+pub async fn digest(message: &Message) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(message.from.as_bytes());
+    hasher.update([0]);
+    let mut reader = message.data.reader().await?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
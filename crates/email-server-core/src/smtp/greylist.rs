@@ -0,0 +1,108 @@
+//! Greylisting: the first attempt to deliver from a client network, sender
+//! and recipient not seen before is deferred. Real mail servers retry after
+//! a while; much spamware never does.
+
+use async_trait::async_trait;
+pub use ipnet::IpNet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GreylistConfig {
+    /// How long a client must wait before its retry is accepted.
+    pub delay: Duration,
+    /// How long after the first attempt a retry is still accepted.
+    pub retry_window: Duration,
+    /// How long passed triplets and whitelisted clients are remembered
+    /// since they were last seen.
+    pub lifetime: Duration,
+    /// Clients that passed this many times are no longer greylisted; zero
+    /// never whitelists them.
+    pub auto_whitelist: u32,
+    /// Clients that are never greylisted, e.g. the networks of large
+    /// providers that retry from other addresses.
+    pub allow: Vec<IpNet>,
+}
+
+impl Default for GreylistConfig {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(5 * 60),
+            retry_window: Duration::from_secs(2 * 24 * 60 * 60),
+            lifetime: Duration::from_secs(36 * 24 * 60 * 60),
+            auto_whitelist: 5,
+            allow: vec![],
+        }
+    }
+}
+
+impl GreylistConfig {
+    pub fn allows(&self, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        self.allow.iter().any(|net| net.contains(&client))
+    }
+}
+
+/// What a delivery attempt is known by. Clients are taken by their /24 or
+/// /64, as big senders retry from neighbouring addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Triplet {
+    pub network: IpNet,
+    pub sender: String,
+    pub recipient: String,
+}
+
+impl Triplet {
+    pub fn new(client: IpAddr, sender: &str, recipient: &str) -> Self {
+        let client = client.to_canonical();
+        let prefix = if client.is_ipv4() { 24 } else { 64 };
+        Self {
+            network: IpNet::new(client, prefix)
+                .expect("prefix fits the address")
+                .trunc(),
+            sender: sender.to_ascii_lowercase(),
+            recipient: recipient.to_ascii_lowercase(),
+        }
+    }
+}
+
+/// Remembers delivery attempts.
+#[async_trait]
+pub trait GreylistStore: Send + Sync + std::fmt::Debug {
+    /// Records an attempt at `now` (seconds since the epoch), returning
+    /// whether it is accepted.
+    async fn attempt(
+        &self,
+        triplet: &Triplet,
+        config: &GreylistConfig,
+        now: i64,
+    ) -> Result<bool, sqlx::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triplet() {
+        let triplet = Triplet::new(
+            "192.0.2.77".parse().unwrap(),
+            "Alice@Example.net",
+            "bob@example.com",
+        );
+        assert_eq!(triplet.network.to_string(), "192.0.2.0/24");
+        assert_eq!(triplet.sender, "alice@example.net");
+        let mapped = Triplet::new("::ffff:192.0.2.1".parse().unwrap(), "", "bob@example.com");
+        assert_eq!(mapped.network, triplet.network);
+        let v6 = Triplet::new("2001:db8:1:2:3::4".parse().unwrap(), "", "bob@example.com");
+        assert_eq!(v6.network.to_string(), "2001:db8:1:2::/64");
+
+        let config = GreylistConfig {
+            allow: vec!["198.51.100.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(config.allows("198.51.100.9".parse().unwrap()));
+        assert!(config.allows("::ffff:198.51.100.9".parse().unwrap()));
+        assert!(!config.allows("192.0.2.1".parse().unwrap()));
+    }
+}
@@ -1,3 +1,8 @@
+pub mod address;
+pub mod config;
+pub use config::{Config, ConfigBuilder};
+pub mod delivery;
+pub mod greylist;
 pub mod server;
 pub use server::Server;
 pub mod state;
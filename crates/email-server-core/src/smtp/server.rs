@@ -1,12 +1,19 @@
+use crate::dsn;
 use crate::message::{self, Message};
-use crate::smtp::{state, status};
+use crate::milter::{Milters, Stage};
+use crate::smtp::delivery::{self, DeliveryLog};
+use crate::smtp::{address, state, status, Config};
 use crate::socket::{SocketError, SocketHandler};
+use crate::time::unix_time;
 use async_trait::async_trait;
+use bytes::BytesMut;
 use futures::StreamExt;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio::sync::Mutex;
+use tokio_util::codec::{Decoder, FramedRead, LinesCodec, LinesCodecError};
 
 macro_rules! outln {
     ($stream:expr, $msg:expr) => {
@@ -17,9 +24,43 @@ macro_rules! outln {
     };
 }
 
+/// Yields `None` for a line over the length limit instead of failing, so the
+/// session carries on with the next line.
+struct SmtpLinesCodec(LinesCodec);
+
+impl Decoder for SmtpLinesCodec {
+    type Item = Option<String>;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.0.decode(buf) {
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(None)),
+            line => line.map(|line| line.map(Some)),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.0.decode_eof(buf) {
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(None)),
+            line => line.map(|line| line.map(Some)),
+        }
+    }
+}
+
+impl SmtpLinesCodec {
+    /// Lets the following lines be up to `max_length` long. Only called
+    /// between lines, when the codec holds no part of one.
+    fn limit(&mut self, max_length: usize) {
+        if self.0.max_length() != max_length {
+            self.0 = LinesCodec::new_with_max_length(max_length);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Server {
     pub(crate) handler: Arc<dyn message::Handler + Sync + Send>,
+    pub(crate) config: Config,
 }
 
 #[async_trait]
@@ -58,36 +99,231 @@ impl SocketHandler for Server {
 // 530 5.7.1 Authentication required
 
 impl Server {
+    /// The banner refusing a client listed on the blocklists, in place of
+    /// the greeting.
+    async fn blocked(&self, stream: &TcpStream) -> Option<String> {
+        let blocklists = self.config.blocklists.as_ref()?;
+        let ip = stream.peer_addr().ok()?.ip();
+        let listing = blocklists.check_address(ip).await?;
+        let zones = listing.zones.join(", ");
+        tracing::info!("Refusing {} listed on {}", ip, zones);
+        Some(format!(
+            "554 5.7.1 Service unavailable; client host [{}] blocked using {}",
+            ip, zones
+        ))
+    }
+
+    /// The content filters following the session of `client`, or the
+    /// banner refusing it in place of the greeting.
+    async fn milters(
+        &self,
+        client: Option<IpAddr>,
+    ) -> Result<Option<Arc<Mutex<Milters>>>, &'static str> {
+        let Some(config) = &self.config.milter else {
+            return Ok(None);
+        };
+        let mut milters = Milters::connect(config, &self.config.hostname).await;
+        match milters.stage(Stage::Connect(client)).await {
+            None => Ok(Some(Arc::new(Mutex::new(milters)))),
+            Some(code) => {
+                milters.quit().await;
+                Err(match code {
+                    status::Code::FilterDeferred => "421 4.7.0 Content filter unavailable",
+                    _ => "554 5.7.1 Connection rejected by content filter",
+                })
+            }
+        }
+    }
+
+    /// Hands a completed SMTP transaction to the handlers. Its one reply
+    /// cannot tell which recipients failed, so once anybody got the
+    /// message, recipients that failed for good are bounced, and those who
+    /// got it before the client was told to retry are skipped on the retry.
+    async fn deliver(&self, message: &mut Message) -> status::Code {
+        let Some(log) = &self.config.delivery_log else {
+            let results = self.handler.handle_recipients(message).await;
+            return reply(message::summarize(&results));
+        };
+        let now = unix_time();
+        let digest = match delivery::digest(message).await {
+            Ok(digest) => digest,
+            Err(e) => {
+                tracing::error!("Failed to read message: {}", e);
+                return status::Code::LocalError;
+            }
+        };
+        let resumed = match log.delivered(&digest).await {
+            Ok(delivered) => {
+                let before = message.recipients.len();
+                message
+                    .recipients
+                    .retain(|r| !delivered.contains(&r.address));
+                message.recipients.len() < before
+            }
+            Err(e) => {
+                tracing::error!("Failed to look up earlier deliveries: {}", e);
+                return status::Code::LocalError;
+            }
+        };
+        let results = match message.recipients.is_empty() {
+            true => vec![],
+            false => self.handler.handle_recipients(message).await,
+        };
+
+        let mut delivered = vec![];
+        let mut failures = vec![];
+        let mut deferred = None;
+        for (recipient, result) in message.recipients.iter().zip(&results) {
+            match result {
+                Ok(()) => delivered.push(recipient.address.clone()),
+                Err(e) if e.is_temporary() => deferred = Some(e.clone()),
+                Err(e) => failures.push(dsn::Failure {
+                    recipient: recipient.address.clone(),
+                    original_recipient: recipient
+                        .origins
+                        .first()
+                        .and_then(|&i| message.to.get(i))
+                        .map(|to| address::mailbox(to).to_string())
+                        .filter(|to| !to.eq_ignore_ascii_case(&recipient.address)),
+                    reason: e.reason(),
+                }),
+            }
+        }
+        // Nobody has the message, so the client may retry or bounce it.
+        if delivered.is_empty() && !resumed {
+            return reply(message::summarize(&results));
+        }
+        if let Some(e) = deferred {
+            if let Err(e) = log.record_delivered(&digest, &delivered, now).await {
+                tracing::error!("Failed to record deliveries, retries repeat them: {}", e);
+            }
+            return reply(Err(e));
+        }
+        if !failures.is_empty() {
+            if let Err(e) = self.bounce(log.as_ref(), message, &failures, now).await {
+                tracing::error!("Failed to queue bounce: {}", e);
+                // Retried, the message only goes to those who failed.
+                if let Err(e) = log.record_delivered(&digest, &delivered, now).await {
+                    tracing::error!("Failed to record deliveries, retries repeat them: {}", e);
+                }
+                return status::Code::LocalError;
+            }
+        }
+        status::Code::MessageSent
+    }
+
+    /// Tells the sender of `message` about `failures`. Bounces are never
+    /// bounced.
+    async fn bounce(
+        &self,
+        log: &dyn DeliveryLog,
+        message: &Message,
+        failures: &[dsn::Failure],
+        now: i64,
+    ) -> Result<(), message::HandlerError> {
+        let sender = address::mailbox(&message.from);
+        if sender.is_empty() {
+            tracing::info!("Not bouncing mail from the null sender");
+            return Ok(());
+        }
+        let header = dsn::original_header(message.data.reader().await?).await?;
+        let report = dsn::compose(&self.config.hostname, sender, failures, &header, now);
+        Ok(log.bounce(sender, &report, now).await?)
+    }
+
     async fn handle_tls_connection(&mut self, mut stream: TcpStream) -> Result<(), SocketError> {
+        if let Some(banner) = self.blocked(&stream).await {
+            outln!(stream, banner);
+            return Ok(());
+        }
+        let client = stream.peer_addr().ok().map(|addr| addr.ip());
+        let milters = match self.milters(client).await {
+            Ok(milters) => milters,
+            Err(banner) => {
+                outln!(stream, banner);
+                return Ok(());
+            }
+        };
         outln!(stream, status::Code::ServiceReady);
 
         let (reader, mut writer) = stream.into_split();
-        let mut lines = FramedRead::new(reader, LinesCodec::new());
+        let mut lines = FramedRead::new(
+            reader,
+            SmtpLinesCodec(LinesCodec::new_with_max_length(
+                self.config.max_command_length(),
+            )),
+        );
 
-        let mut message = Message::default();
-        let mut state = state::new_state();
+        let mut message = Message {
+            client,
+            milters: milters.clone(),
+            ..Default::default()
+        };
+        let mut state = state::with_config(self.config.clone());
 
         while let Some(line) = lines.next().await {
-            let line = line.map_err(SocketError::boxed)?;
+            let Some(line) = line.map_err(SocketError::boxed)? else {
+                if state.is_collecting_data() {
+                    state.overflow();
+                } else {
+                    outln!(writer, status::Code::LineTooLong);
+                }
+                continue;
+            };
             tracing::debug!("state = {:?}; received: {:?}", state, line);
 
-            if let (Some(output), next_state) = state.process(line.as_bytes(), &mut message).await {
+            let (output, next_state) = state.process(line.as_bytes(), &mut message).await;
+            if let Some(output) = output {
                 outln!(writer, output);
-                if let Some(next_state) = next_state {
-                    state = next_state;
-                } else {
-                    break;
-                }
             }
+            match next_state {
+                Some(next_state) => state = next_state,
+                None => break,
+            }
+            // Only the message may have lines longer than a command.
+            lines.decoder_mut().limit(match state.is_collecting_data() {
+                true => self.config.max_line_length(),
+                false => self.config.max_command_length(),
+            });
 
+            // The message is only acknowledged once the handlers have it.
             if state.is_message_completed() {
-                if let Err(e) = self.handler.handle_message(message).await {
-                    tracing::error!("Error handling message: {:?}", e);
+                if self.config.lmtp {
+                    let results = self.handler.handle_recipients(&message).await;
+                    for result in message.results_by_rcpt(&results) {
+                        outln!(writer, reply(result));
+                    }
+                } else {
+                    outln!(writer, self.deliver(&mut message).await);
+                }
+                if let Some(milters) = &milters {
+                    milters.lock().await.reset().await;
                 }
-                message = Message::default();
+                message = Message {
+                    client,
+                    milters: milters.clone(),
+                    ..Default::default()
+                };
             }
         }
 
+        if let Some(milters) = &milters {
+            milters.lock().await.quit().await;
+        }
         Ok(())
     }
 }
+
+fn reply(result: Result<(), message::HandlerError>) -> status::Code {
+    match result {
+        Ok(()) => status::Code::MessageSent,
+        Err(e) => {
+            tracing::error!("Error handling message: {}", e);
+            if e.is_temporary() {
+                status::Code::LocalError
+            } else {
+                status::Code::TransactionFailed
+            }
+        }
+    }
+}
@@ -1,11 +1,15 @@
 use async_trait::async_trait;
 
-use crate::message::Message;
-use crate::smtp::status;
+use crate::body::Body;
+use crate::message::{Message, Recipient};
+use crate::milter::Stage;
+use crate::resolver::ResolveError;
+use crate::smtp::greylist::Triplet;
+use crate::smtp::validator::RecipientVerdict;
+use crate::smtp::{address, status, Config};
+use crate::srs::Srs;
+use crate::time::unix_time;
 use std::fmt::Debug;
-use std::sync::Arc;
-
-use super::validator::{HeloValidator, NoopValidator};
 
 #[async_trait]
 pub trait SmtpState: Send + Debug {
@@ -24,7 +28,7 @@ pub trait SmtpState: Send + Debug {
             (Some(status::Code::Goodbye), None)
         } else if self.is_message_completed() {
             // reset the state
-            let mut init = new_state();
+            let mut init = self.reset();
             init.process_line(line, message).await
         } else {
             self.process_line(line, message).await
@@ -37,22 +41,24 @@ pub trait SmtpState: Send + Debug {
     fn is_message_completed(&self) -> bool {
         false
     }
+    fn reset(&self) -> Box<dyn SmtpState> {
+        new_state()
+    }
+    /// Called when a line longer than the session allows was discarded.
+    fn overflow(&mut self) {}
 }
 
 pub fn new_state() -> Box<dyn SmtpState + Send> {
     Box::new(InitState::default())
 }
 
-#[derive(Debug)]
-pub struct InitState {
-    validator: Arc<dyn HeloValidator>,
+pub fn with_config(config: Config) -> Box<dyn SmtpState + Send> {
+    Box::new(InitState { config })
 }
-impl Default for InitState {
-    fn default() -> Self {
-        Self {
-            validator: Arc::new(NoopValidator),
-        }
-    }
+
+#[derive(Default, Debug)]
+pub struct InitState {
+    config: Config,
 }
 #[async_trait]
 impl SmtpState for InitState {
@@ -61,25 +67,60 @@ impl SmtpState for InitState {
         line: &[u8],
         message: &mut Message,
     ) -> (Option<status::Code>, Option<Box<dyn SmtpState>>) {
-        if line.starts_with(b"HELO") {
-            let sender_domain = String::from_utf8_lossy(&line[5..]).trim().to_string();
-            if self.validator.valid(&sender_domain).await {
+        // RFC 2033: LMTP clients greet with LHLO, and only with LHLO.
+        let greeting = if self.config.lmtp {
+            line.starts_with(b"LHLO")
+        } else {
+            line.starts_with(b"HELO") || line.starts_with(b"EHLO")
+        };
+        if greeting {
+            let domain = line.get(5..).unwrap_or_default();
+            let sender_domain = String::from_utf8_lossy(domain).trim().to_string();
+            let next = Box::new(MailState {
+                config: self.config.clone(),
+            });
+            let reply = if !line.starts_with(b"HELO") {
+                status::Code::Ehlo(self.config.max_message_size)
+            } else {
+                status::Code::Helo
+            };
+            if self.config.domain_blocked(&sender_domain).await {
+                return (
+                    Some(status::Code::DomainBlocked),
+                    Some(Box::new(InitState {
+                        config: self.config.clone(),
+                    })),
+                );
+            }
+            if let Some(code) = filter(message, Stage::Helo(&sender_domain)).await {
+                return (
+                    Some(code),
+                    Some(Box::new(InitState {
+                        config: self.config.clone(),
+                    })),
+                );
+            }
+            if self.config.helo_validator.valid(&sender_domain).await {
                 message.sender_domain = sender_domain;
-                return (Some(status::Code::Helo), Some(Box::new(MailState)));
+                return (Some(reply), Some(next));
             }
             // TODO: need to auth or starttls
-            (Some(status::Code::Helo), Some(Box::new(MailState)))
+            (Some(reply), Some(next))
         } else {
             (
                 Some(status::Code::BadSequence),
-                Some(Box::new(InitState::default())),
+                Some(Box::new(InitState {
+                    config: self.config.clone(),
+                })),
             )
         }
     }
 }
 
 #[derive(Default, Debug)]
-pub struct MailState;
+pub struct MailState {
+    config: Config,
+}
 #[async_trait]
 impl SmtpState for MailState {
     async fn process_line(
@@ -88,8 +129,35 @@ impl SmtpState for MailState {
         message: &mut Message,
     ) -> (Option<status::Code>, Option<Box<dyn SmtpState>>) {
         if line.starts_with(b"MAIL FROM:") {
-            message.from = String::from_utf8_lossy(&line[10..]).trim().to_string();
-            (Some(status::Code::Ok), Some(Box::new(RcptState)))
+            let args = String::from_utf8_lossy(&line[10..]);
+            let (from, params) = split_params(&args);
+            // Refused senders may try again, e.g. with a smaller message.
+            let retry = Box::new(MailState {
+                config: self.config.clone(),
+            });
+            match declared_size(params) {
+                Ok(Some(size)) if self.config.exceeds_size(size) => {
+                    return (Some(status::Code::MessageTooBig), Some(retry));
+                }
+                Ok(_) => {}
+                Err(code) => return (Some(code), Some(retry)),
+            }
+            let domain = address::split(address::mailbox(from)).map(|(_, domain)| domain);
+            if let Some(domain) = domain {
+                if self.config.domain_blocked(&domain).await {
+                    return (Some(status::Code::DomainBlocked), Some(retry));
+                }
+            }
+            if let Some(code) = filter(message, Stage::Mail(from)).await {
+                return (Some(code), Some(retry));
+            }
+            message.from = from.to_string();
+            (
+                Some(status::Code::Ok),
+                Some(Box::new(RcptState {
+                    config: self.config.clone(),
+                })),
+            )
         } else {
             (Some(status::Code::BadSequence), None)
         }
@@ -97,7 +165,113 @@ impl SmtpState for MailState {
 }
 
 #[derive(Default, Debug)]
-pub struct RcptState;
+pub struct RcptState {
+    config: Config,
+}
+impl RcptState {
+    /// Defers `mailbox` if some of its `recipients` are elsewhere but
+    /// nothing could forward mail to them.
+    fn forwardable(
+        &self,
+        mailbox: &str,
+        recipients: Vec<Recipient>,
+    ) -> Result<Vec<Recipient>, status::Code> {
+        if recipients.iter().any(|r| r.forward) && !self.config.forwards() {
+            tracing::warn!(
+                "deferring {}: forwarding needs a relay host and the local store",
+                mailbox
+            );
+            return Err(status::Code::TryAgainLater);
+        }
+        Ok(recipients)
+    }
+
+    async fn accept_recipient(
+        &self,
+        path: &str,
+        message: &Message,
+    ) -> Result<Vec<Recipient>, status::Code> {
+        if self.config.maintenance {
+            return Err(status::Code::TryAgainLater);
+        }
+
+        let mailbox = address::mailbox(path);
+        // Bounces to addresses we rewrote with SRS are sent back to the
+        // original sender, provided the rewrite is ours and still fresh.
+        match &self.config.srs {
+            Some(srs) if Srs::is_srs(mailbox) && srs.is_local(mailbox) => {
+                return match srs.reverse(mailbox) {
+                    Ok(original) => self.forwardable(
+                        mailbox,
+                        vec![Recipient {
+                            forward: true,
+                            ..Recipient::new(original)
+                        }],
+                    ),
+                    Err(e) => {
+                        tracing::info!("rejecting SRS recipient {}: {}", mailbox, e);
+                        Err(status::Code::NoSuchUser)
+                    }
+                };
+            }
+            _ => {}
+        }
+
+        if let Some(validator) = &self.config.recipient_validator {
+            match validator.check(message, mailbox).await {
+                RecipientVerdict::Accept => {}
+                RecipientVerdict::UnknownUser => return Err(status::Code::NoSuchUser),
+                RecipientVerdict::RelayDenied => return Err(status::Code::RelayDenied),
+                RecipientVerdict::RoutingLoop => return Err(status::Code::RoutingLoop),
+                RecipientVerdict::TempFail => return Err(status::Code::TryAgainLater),
+            }
+        }
+
+        if self.greylisted(mailbox, message).await {
+            return Err(status::Code::Greylisted);
+        }
+
+        let Some(resolver) = &self.config.resolver else {
+            return Ok(vec![Recipient::new(mailbox)]);
+        };
+        match resolver.resolve(mailbox).await {
+            Ok(recipients) if recipients.is_empty() => Err(status::Code::NoSuchUser),
+            Ok(recipients) => self.forwardable(mailbox, recipients),
+            Err(ResolveError::Loop(address)) => {
+                tracing::warn!("aliases of {} loop at {}", mailbox, address);
+                Err(status::Code::RoutingLoop)
+            }
+            Err(e) => {
+                tracing::error!("failed to resolve recipient {}: {}", mailbox, e);
+                Err(status::Code::TryAgainLater)
+            }
+        }
+    }
+
+    /// Whether the attempt to deliver to `mailbox` must be retried later.
+    /// Greylisting fails open, so that a broken store does not defer mail.
+    async fn greylisted(&self, mailbox: &str, message: &Message) -> bool {
+        let (Some(config), Some(store), Some(client)) = (
+            &self.config.greylist,
+            &self.config.greylist_store,
+            message.client,
+        ) else {
+            return false;
+        };
+        if message.authenticated || config.allows(client) {
+            return false;
+        }
+        let triplet = Triplet::new(client, address::mailbox(&message.from), mailbox);
+        let now = unix_time();
+        match store.attempt(&triplet, config, now).await {
+            Ok(accepted) => !accepted,
+            Err(e) => {
+                tracing::error!("failed to greylist {:?}: {}", triplet, e);
+                false
+            }
+        }
+    }
+}
 #[async_trait]
 impl SmtpState for RcptState {
     async fn process_line(
@@ -105,15 +279,50 @@ impl SmtpState for RcptState {
         line: &[u8],
         message: &mut Message,
     ) -> (Option<status::Code>, Option<Box<dyn SmtpState>>) {
+        let next = Box::new(RcptState {
+            config: self.config.clone(),
+        });
         if line.starts_with(b"RCPT TO:") {
-            message
-                .to
-                .push(String::from_utf8_lossy(&line[8..]).trim().to_string());
-            (Some(status::Code::Ok), Some(Box::new(RcptState)))
+            let path = String::from_utf8_lossy(&line[8..]).trim().to_string();
+            let accepted = match self.accept_recipient(&path, message).await {
+                Ok(recipients) => match filter(message, Stage::Rcpt(&path)).await {
+                    Some(code) => Err(code),
+                    None => Ok(recipients),
+                },
+                Err(code) => Err(code),
+            };
+            match accepted {
+                Ok(recipients) => {
+                    let rcpt = message.to.len();
+                    message.to.push(path);
+                    for mut recipient in recipients {
+                        match message
+                            .recipients
+                            .iter_mut()
+                            .find(|r| r.address == recipient.address && r.tag == recipient.tag)
+                        {
+                            Some(existing) => existing.origins.push(rcpt),
+                            None => {
+                                recipient.origins.push(rcpt);
+                                message.recipients.push(recipient);
+                            }
+                        }
+                    }
+                    (Some(status::Code::Ok), Some(next))
+                }
+                Err(code) => (Some(code), Some(next)),
+            }
         } else if line == b"DATA" {
+            if message.recipients.is_empty() {
+                return (Some(status::Code::NoValidRecipients), Some(next));
+            }
+            message.data = Body::new(self.config.spool_threshold);
             (
                 Some(status::Code::EnterMessage),
-                Some(Box::new(DataCollectState)),
+                Some(Box::new(DataCollectState {
+                    config: self.config.clone(),
+                    rejection: None,
+                })),
             )
         } else {
             (Some(status::Code::BadSequence), None)
@@ -122,7 +331,35 @@ impl SmtpState for RcptState {
 }
 
 #[derive(Default, Debug)]
-pub struct DataCollectState;
+pub struct DataCollectState {
+    config: Config,
+    /// Set once the transaction has failed, e.g. the body passed the size
+    /// limit; the rest is discarded and the code is sent at the final dot.
+    rejection: Option<status::Code>,
+}
+impl DataCollectState {
+    async fn append(&mut self, line: &[u8], message: &mut Message) {
+        // RFC 5321 4.5.2: remove the leading dot added by the client.
+        let line = line.strip_prefix(b".").unwrap_or(line);
+        if self
+            .config
+            .exceeds_size(message.data.len().saturating_add(line.len() + 2))
+        {
+            self.rejection = Some(status::Code::MessageTooBig);
+        }
+        if self.rejection.is_some() {
+            return;
+        }
+        let written = match message.data.write(line).await {
+            Ok(()) => message.data.write(b"\r\n").await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            tracing::error!("failed to spool message body: {}", e);
+            self.rejection = Some(status::Code::LocalError);
+        }
+    }
+}
 #[async_trait]
 impl SmtpState for DataCollectState {
     async fn process_line(
@@ -130,20 +367,95 @@ impl SmtpState for DataCollectState {
         line: &[u8],
         message: &mut Message,
     ) -> (Option<status::Code>, Option<Box<dyn SmtpState>>) {
-        if line == b"." {
-            (
-                Some(status::Code::MessageSent),
-                Some(Box::new(MessageCompleted)),
-            )
-        } else {
-            message.data.extend_from_slice(line);
-            (None, Some(Box::new(DataCollectState)))
+        if line != b"." {
+            self.append(line, message).await;
+            return (
+                None,
+                Some(Box::new(DataCollectState {
+                    config: self.config.clone(),
+                    rejection: self.rejection,
+                })),
+            );
+        }
+
+        if self.rejection.is_none() {
+            if let Err(e) = message.data.finish().await {
+                tracing::error!("failed to spool message body: {}", e);
+                self.rejection = Some(status::Code::LocalError);
+            }
+        }
+        match self.rejection {
+            Some(code) => {
+                // Keep the session but drop the transaction.
+                if let Some(milters) = &message.milters {
+                    milters.lock().await.reset().await;
+                }
+                *message = Message {
+                    sender_domain: std::mem::take(&mut message.sender_domain),
+                    authenticated: message.authenticated,
+                    client: message.client,
+                    milters: message.milters.take(),
+                    ..Default::default()
+                };
+                (
+                    Some(code),
+                    Some(Box::new(MailState {
+                        config: self.config.clone(),
+                    })),
+                )
+            }
+            // The reply depends on the handlers, so the server sends it.
+            None => (
+                None,
+                Some(Box::new(MessageCompleted {
+                    config: self.config.clone(),
+                })),
+            ),
+        }
+    }
+    fn is_collecting_data(&self) -> bool {
+        true
+    }
+    fn overflow(&mut self) {
+        self.rejection = Some(status::Code::MessageTooBig);
+    }
+}
+
+/// Forwards `stage` to the content filters following the session, with
+/// the reply refusing it if they did.
+async fn filter(message: &Message, stage: Stage<'_>) -> Option<status::Code> {
+    let milters = message.milters.as_ref()?;
+    milters.lock().await.stage(stage).await
+}
+
+/// Splits `MAIL FROM` arguments into the path and its ESMTP parameters.
+fn split_params(args: &str) -> (&str, &str) {
+    let args = args.trim();
+    match args.find('>') {
+        Some(end) => (&args[..=end], args[end + 1..].trim()),
+        None => (args, ""),
+    }
+}
+
+/// Parses the RFC 1870 `SIZE=` parameter, if present.
+fn declared_size(params: &str) -> Result<Option<usize>, status::Code> {
+    for param in params.split_whitespace() {
+        if let Some((key, value)) = param.split_once('=') {
+            if key.eq_ignore_ascii_case("SIZE") {
+                return value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| status::Code::SyntaxError);
+            }
         }
     }
+    Ok(None)
 }
 
 #[derive(Default, Debug)]
-pub struct MessageCompleted;
+pub struct MessageCompleted {
+    config: Config,
+}
 #[async_trait]
 impl SmtpState for MessageCompleted {
     async fn process_line(
@@ -156,6 +468,9 @@ impl SmtpState for MessageCompleted {
     fn is_message_completed(&self) -> bool {
         true
     }
+    fn reset(&self) -> Box<dyn SmtpState> {
+        with_config(self.config.clone())
+    }
 }
 
 #[cfg(test)]
@@ -173,10 +488,37 @@ mod tests {
         assert!(next.is_some());
     }
 
+    #[tokio::test]
+    async fn test_init_state_bare_ehlo() {
+        let mut msg = Message::default();
+        let mut state = InitState::default();
+        let (resp, next) = state.process_line(b"EHLO", &mut msg).await;
+        assert!(matches!(resp, Some(status::Code::Ehlo(_))));
+        assert_eq!(msg.sender_domain, "");
+        assert!(next.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_init_state_lhlo() {
+        let mut msg = Message::default();
+        let mut state = InitState {
+            config: crate::smtp::ConfigBuilder::default()
+                .lmtp(true)
+                .build()
+                .unwrap(),
+        };
+        let (resp, _) = state.process_line(b"HELO example.com", &mut msg).await;
+        assert_eq!(resp, Some(status::Code::BadSequence));
+        let (resp, next) = state.process_line(b"LHLO example.com", &mut msg).await;
+        assert!(matches!(resp, Some(status::Code::Ehlo(_))));
+        assert_eq!(msg.sender_domain, "example.com");
+        assert!(next.is_some());
+    }
+
     #[tokio::test]
     async fn test_mail_state_from() {
         let mut msg = Message::default();
-        let mut state = MailState {};
+        let mut state = MailState::default();
         let (resp, next) = state
             .process_line(b"MAIL FROM: <sender@example>", &mut msg)
             .await;
@@ -188,28 +530,48 @@ mod tests {
     #[tokio::test]
     async fn test_rcpt_state_to() {
         let mut msg = Message::default();
-        let mut state = RcptState {};
+        let mut state = RcptState::default();
         let (resp, next) = state
             .process_line(b"RCPT TO: <recipient@example>", &mut msg)
             .await;
         assert_eq!(resp, Some(status::Code::Ok));
         assert_eq!(msg.to, vec!["<recipient@example>".to_string()]);
+        assert_eq!(msg.recipients.len(), 1);
+        assert_eq!(msg.recipients[0].address, "recipient@example");
+        assert_eq!(msg.recipients[0].origins, vec![0]);
         assert!(next.is_some());
     }
 
     #[tokio::test]
     async fn test_data_state() {
-        let mut msg = Message::default();
-        let mut state = RcptState {};
+        let mut msg = Message {
+            recipients: vec![Recipient::new("recipient@example")],
+            ..Default::default()
+        };
+        let mut state = RcptState::default();
         let (resp, next) = state.process_line(b"DATA", &mut msg).await;
         assert_eq!(resp, Some(status::Code::EnterMessage));
         assert!(next.is_some());
     }
 
+    #[tokio::test]
+    async fn test_data_state_without_recipients() {
+        let mut msg = Message::default();
+        let mut state = RcptState::default();
+        let (resp, next) = state.process_line(b"DATA", &mut msg).await;
+        assert_eq!(resp, Some(status::Code::NoValidRecipients));
+        // Still waiting for a recipient.
+        let (resp, _) = next
+            .unwrap()
+            .process_line(b"RCPT TO: <recipient@example>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Ok));
+    }
+
     #[tokio::test]
     async fn test_data_collect_state() {
         let mut msg = Message::default();
-        let mut state = DataCollectState {};
+        let mut state = DataCollectState::default();
         let (resp, next) = state.process_line(b"Hello", &mut msg).await;
         assert!(resp.is_none());
         assert!(next.is_some());
@@ -217,17 +579,292 @@ mod tests {
         assert!(resp.is_none());
         assert!(next.is_some());
         let (resp, next) = state.process_line(b".", &mut msg).await;
-        assert_eq!(resp, Some(status::Code::MessageSent));
-        assert!(next.is_some());
+        assert!(resp.is_none());
+        assert!(next.unwrap().is_message_completed());
+    }
+
+    #[tokio::test]
+    async fn test_data_collect_state_body() {
+        let mut msg = Message::default();
+        let mut state = DataCollectState::default();
+        for line in [&b"Subject: Test"[..], b"", b"..leading dot", b"."] {
+            state.process_line(line, &mut msg).await;
+        }
+        assert_eq!(
+            msg.data.to_vec().await.unwrap(),
+            b"Subject: Test\r\n\r\n.leading dot\r\n"
+        );
     }
 
     #[tokio::test]
     async fn test_done_state() {
         let mut msg = Message::default();
-        let mut state = MessageCompleted {};
+        let mut state = MessageCompleted::default();
         let (resp, next) = state.process_line(b"QUIT", &mut msg).await;
         assert_eq!(resp, Some(status::Code::BadSequence));
         assert!(next.is_none());
         assert!(state.is_message_completed());
     }
+
+    #[tokio::test]
+    async fn test_rcpt_state_srs_bounce() {
+        let srs = std::sync::Arc::new(Srs::new("secret", "forwarder.example"));
+        let rewritten = srs.forward("alice@gmail.com");
+        let mut msg = Message::default();
+        let mut state = RcptState {
+            config: crate::smtp::ConfigBuilder::default()
+                .srs(srs.clone())
+                .build()
+                .unwrap(),
+        };
+
+        // Nothing could send the bounce on.
+        let line = format!("RCPT TO: <{}>", rewritten);
+        let (resp, _) = state.process_line(line.as_bytes(), &mut msg).await;
+        assert_eq!(resp, Some(status::Code::TryAgainLater));
+        assert!(msg.recipients.is_empty());
+
+        state.config = crate::smtp::ConfigBuilder::default()
+            .srs(srs)
+            .relay_host(
+                "127.0.0.1:2525"
+                    .parse::<crate::lmtp::LmtpAddress>()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let (resp, _) = state.process_line(line.as_bytes(), &mut msg).await;
+        assert_eq!(resp, Some(status::Code::Ok));
+        assert_eq!(msg.recipients.len(), 1);
+        assert_eq!(msg.recipients[0].address, "alice@gmail.com");
+
+        let forged = format!("RCPT TO: <{}>", rewritten.replace("alice", "mallory"));
+        let (resp, next) = state.process_line(forged.as_bytes(), &mut msg).await;
+        assert_eq!(resp, Some(status::Code::NoSuchUser));
+        assert!(next.is_some());
+        assert_eq!(msg.to.len(), 1);
+    }
+
+    #[derive(Debug)]
+    struct LocalOnly;
+    #[async_trait]
+    impl crate::smtp::validator::RecipientValidator for LocalOnly {
+        async fn check(&self, message: &Message, recipient: &str) -> RecipientVerdict {
+            match recipient {
+                "bob@example.com" => RecipientVerdict::Accept,
+                r if r.ends_with("@example.com") => RecipientVerdict::UnknownUser,
+                _ if message.authenticated => RecipientVerdict::Accept,
+                _ => RecipientVerdict::RelayDenied,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_state_validation() {
+        let mut msg = Message::default();
+        let mut state = RcptState {
+            config: crate::smtp::ConfigBuilder::default()
+                .recipient_validator(std::sync::Arc::new(LocalOnly) as std::sync::Arc<_>)
+                .build()
+                .unwrap(),
+        };
+
+        let (resp, _) = state
+            .process_line(b"RCPT TO: <bob@example.com>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Ok));
+        let (resp, _) = state
+            .process_line(b"RCPT TO: <eve@example.com>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::NoSuchUser));
+        let (resp, next) = state
+            .process_line(b"RCPT TO: <carol@elsewhere.test>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::RelayDenied));
+        assert!(next.is_some());
+        assert_eq!(msg.to, vec!["<bob@example.com>".to_string()]);
+
+        msg.authenticated = true;
+        let (resp, _) = state
+            .process_line(b"RCPT TO: <carol@elsewhere.test>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Ok));
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_state_maintenance() {
+        let mut msg = Message::default();
+        let mut state = RcptState {
+            config: crate::smtp::ConfigBuilder::default()
+                .maintenance(true)
+                .build()
+                .unwrap(),
+        };
+        let (resp, _) = state
+            .process_line(b"RCPT TO: <bob@example.com>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::TryAgainLater));
+        assert!(msg.to.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ehlo_advertises_size() {
+        let mut msg = Message::default();
+        let mut state = InitState {
+            config: crate::smtp::ConfigBuilder::default()
+                .max_message_size(1000usize)
+                .build()
+                .unwrap(),
+        };
+        let (resp, _) = state.process_line(b"EHLO example.com", &mut msg).await;
+        assert_eq!(resp, Some(status::Code::Ehlo(1000)));
+        assert!(resp.unwrap().to_string().ends_with("250 SIZE 1000"));
+        assert_eq!(msg.sender_domain, "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_mail_state_declared_size() {
+        let config = crate::smtp::ConfigBuilder::default()
+            .max_message_size(1000usize)
+            .build()
+            .unwrap();
+        let mut msg = Message::default();
+        let mut state = MailState {
+            config: config.clone(),
+        };
+        let (resp, _) = state
+            .process_line(b"MAIL FROM: <sender@example> SIZE=500", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Ok));
+        assert_eq!(msg.from, "<sender@example>");
+
+        let mut msg = Message::default();
+        let (resp, next) = state
+            .process_line(b"MAIL FROM: <sender@example> SIZE=5000", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::MessageTooBig));
+        assert!(msg.from.is_empty());
+        let (resp, next) = next
+            .unwrap()
+            .process_line(b"MAIL FROM: <sender@example> SIZE=x", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::SyntaxError));
+        assert!(next.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_data_collect_state_oversized() {
+        let mut msg = Message {
+            sender_domain: "example.com".to_string(),
+            to: vec!["<bob@example.com>".to_string()],
+            ..Default::default()
+        };
+        let mut state = DataCollectState {
+            config: crate::smtp::ConfigBuilder::default()
+                .max_message_size(8usize)
+                .build()
+                .unwrap(),
+            rejection: None,
+        };
+        let (resp, _) = state.process_line(b"Hello", &mut msg).await;
+        assert!(resp.is_none());
+        let (resp, _) = state.process_line(b"World", &mut msg).await;
+        assert!(resp.is_none());
+        assert_eq!(msg.data.to_vec().await.unwrap(), b"Hello\r\n");
+        let (resp, next) = state.process_line(b".", &mut msg).await;
+        assert_eq!(resp, Some(status::Code::MessageTooBig));
+        assert!(!next.unwrap().is_message_completed());
+        assert!(msg.to.is_empty());
+        assert!(msg.data.is_empty());
+        assert_eq!(msg.sender_domain, "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_blocklisted_domains() {
+        use crate::dnsbl::{Blocklists, ListKind, Zone};
+        let mut zone = Zone::default();
+        zone.insert(
+            "spam.example.dbl.example",
+            std::net::Ipv4Addr::new(127, 0, 1, 2),
+        );
+        let blocklists = Blocklists::new(std::sync::Arc::new(zone))
+            .with_list(ListKind::Domain, "dbl.example".parse().unwrap());
+        let config = crate::smtp::ConfigBuilder::default()
+            .blocklists(std::sync::Arc::new(blocklists))
+            .build()
+            .unwrap();
+
+        let mut msg = Message::default();
+        let mut state = InitState {
+            config: config.clone(),
+        };
+        let (resp, next) = state.process_line(b"HELO spam.example", &mut msg).await;
+        assert_eq!(resp, Some(status::Code::DomainBlocked));
+        assert!(msg.sender_domain.is_empty());
+        let (resp, _) = next
+            .unwrap()
+            .process_line(b"HELO mail.example.net", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Helo));
+
+        let mut state = MailState { config };
+        let (resp, next) = state
+            .process_line(b"MAIL FROM: <alice@Spam.Example>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::DomainBlocked));
+        assert!(msg.from.is_empty());
+        let (resp, _) = next
+            .unwrap()
+            .process_line(b"MAIL FROM: <alice@example.net>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Ok));
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_state_greylisting() {
+        use crate::smtp::greylist::GreylistConfig;
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = crate::storage::SqliteStore::new(temp_file.path())
+            .await
+            .unwrap();
+        let mut state = RcptState {
+            config: crate::smtp::ConfigBuilder::default()
+                .greylist(GreylistConfig {
+                    allow: vec!["198.51.100.0/24".parse().unwrap()],
+                    ..Default::default()
+                })
+                .greylist_store(std::sync::Arc::new(store) as std::sync::Arc<_>)
+                .build()
+                .unwrap(),
+        };
+        let mut msg = Message {
+            client: Some("192.0.2.1".parse().unwrap()),
+            from: "<alice@example.net>".to_string(),
+            ..Default::default()
+        };
+        let (resp, next) = state
+            .process_line(b"RCPT TO: <bob@example.com>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Greylisted));
+        assert!(next.is_some());
+        assert!(msg.to.is_empty());
+        // Too early to retry.
+        let (resp, _) = state
+            .process_line(b"RCPT TO: <bob@example.com>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Greylisted));
+
+        msg.authenticated = true;
+        let (resp, _) = state
+            .process_line(b"RCPT TO: <bob@example.com>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Ok));
+
+        msg.authenticated = false;
+        msg.client = Some("198.51.100.7".parse().unwrap());
+        let (resp, _) = state
+            .process_line(b"RCPT TO: <bob@example.com>", &mut msg)
+            .await;
+        assert_eq!(resp, Some(status::Code::Ok));
+    }
 }
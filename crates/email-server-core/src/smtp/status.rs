@@ -10,8 +10,23 @@ pub enum Code {
     Goodbye,
     BadSequence,
     Helo,
+    Ehlo(usize),
     EnterMessage,
     MessageSent,
+    NoSuchUser,
+    RelayDenied,
+    RoutingLoop,
+    DomainBlocked,
+    TryAgainLater,
+    Greylisted,
+    MessageTooBig,
+    SyntaxError,
+    LineTooLong,
+    LocalError,
+    TransactionFailed,
+    NoValidRecipients,
+    FilterRejected,
+    FilterDeferred,
 }
 
 impl Display for Code {
@@ -21,12 +36,34 @@ impl Display for Code {
             Code::StartTLS => write!(f, "220 Start TLS"),
             Code::Goodbye => write!(f, "221 Goodbye"),
             Code::Helo => write!(f, "250 mail.example.com"),
+            Code::Ehlo(size) => write!(
+                f,
+                "250-mail.example.com\r\n250-PIPELINING\r\n250 SIZE {}",
+                size
+            ),
             Code::Ok => write!(f, "250 OK"),
             Code::MessageSent => write!(f, "250 Message sent"),
             Code::EnterMessage => write!(f, "354 enter mail, end with line containing only \".\""),
+            Code::LineTooLong => write!(f, "500 5.5.2 Line too long"),
+            Code::SyntaxError => write!(f, "501 5.5.4 Syntax error in parameters"),
             Code::BadSequence => write!(f, "503 Bad sequence of commands"),
             Code::EncRequired => write!(f, "530 Encryption required"),
             Code::AuthRequired => write!(f, "530 Authentication required"),
+            Code::LocalError => write!(f, "451 4.3.0 Local error in processing"),
+            Code::TryAgainLater => write!(f, "451 4.3.2 Try again later"),
+            Code::Greylisted => write!(f, "451 4.7.1 Greylisted, try again later"),
+            Code::FilterDeferred => write!(f, "451 4.7.1 Deferred by content filter"),
+            Code::NoSuchUser => write!(f, "550 5.1.1 No such user"),
+            Code::MessageTooBig => write!(
+                f,
+                "552 5.3.4 Message size exceeds fixed maximum message size"
+            ),
+            Code::RoutingLoop => write!(f, "550 5.4.6 Routing loop detected"),
+            Code::FilterRejected => write!(f, "550 5.7.1 Rejected by content filter"),
+            Code::RelayDenied => write!(f, "554 5.7.1 Relay access denied"),
+            Code::DomainBlocked => write!(f, "554 5.7.1 Domain listed on a DNS blocklist"),
+            Code::TransactionFailed => write!(f, "554 5.3.0 Transaction failed"),
+            Code::NoValidRecipients => write!(f, "554 5.5.1 No valid recipients"),
         }
     }
 }
@@ -1,3 +1,4 @@
+use crate::message::Message;
 use async_trait::async_trait;
 use hickory_resolver::{
     config::{ResolverConfig, ResolverOpts},
@@ -9,6 +10,23 @@ pub trait HeloValidator: Send + Sync + std::fmt::Debug {
     async fn valid(&self, domain: &str) -> bool;
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RecipientVerdict {
+    Accept,
+    UnknownUser,
+    RelayDenied,
+    /// Aliases that lead back to themselves.
+    RoutingLoop,
+    TempFail,
+}
+
+/// Decides whether a `RCPT TO` address is accepted for the session that
+/// `message` belongs to.
+#[async_trait]
+pub trait RecipientValidator: Send + Sync + std::fmt::Debug {
+    async fn check(&self, message: &Message, recipient: &str) -> RecipientVerdict;
+}
+
 #[derive(Debug, Default)]
 pub struct NoopValidator;
 #[async_trait]
@@ -0,0 +1,161 @@
+use crate::mime;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+/// Only this many distinct tokens of a message are looked at.
+const MAX_TOKENS: usize = 1000;
+/// How many of the most telling tokens decide.
+const INTERESTING: usize = 15;
+/// Robinson's weight of the assumption that a rarely seen token is neutral.
+const STRENGTH: f64 = 1.0;
+
+/// What a message was learned as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Spam,
+    Ham,
+}
+
+impl Class {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Class::Spam => "spam",
+            Class::Ham => "ham",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "spam" => Some(Class::Spam),
+            "ham" => Some(Class::Ham),
+            _ => None,
+        }
+    }
+}
+
+/// In how many learned spam and ham messages something was seen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub spam: u64,
+    pub ham: u64,
+}
+
+/// The distinct tokens of a message: words of the subject, sender and text,
+/// and the hosts of links. They are hashed, so that the counts kept for
+/// each user do not spell out their mail.
+pub fn tokens(message: &mime::Part) -> Vec<i64> {
+    let mut words = vec![];
+    if let Some(subject) = message.subject() {
+        words.extend(split(&subject).map(|w| format!("subject:{}", w)));
+    }
+    if let Some(from) = message.header("from") {
+        words.extend(split(&from).map(|w| format!("from:{}", w)));
+    }
+    let (body, links) = match message.text_body() {
+        Some(text) => (text.clone(), text),
+        None => {
+            let html = message.html_body().unwrap_or_default();
+            (strip_tags(&html), html)
+        }
+    };
+    words.extend(split(&body));
+    words.extend(
+        super::url_hosts(&links)
+            .into_iter()
+            .map(|host| format!("url:{}", host)),
+    );
+
+    let mut seen = HashSet::new();
+    words
+        .into_iter()
+        .map(|word| hash(&word))
+        .filter(|&token| seen.insert(token))
+        .take(MAX_TOKENS)
+        .collect()
+}
+
+fn split(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || matches!(c, '$' | '\'' | '-')))
+        .filter(|word| (3..=20).contains(&word.chars().count()))
+        .map(str::to_lowercase)
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+fn hash(word: &str) -> i64 {
+    let digest = Sha256::digest(word.as_bytes());
+    i64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// The probability that a message is spam, from the counts of its tokens
+/// and of all learned messages. Each token's probability is smoothed after
+/// Robinson and the most telling ones are combined naively.
+pub fn probability(tokens: &[Counts], learned: Counts) -> f64 {
+    let mut telling: Vec<f64> = tokens
+        .iter()
+        .filter(|c| c.spam + c.ham > 0)
+        .map(|c| {
+            let spam = c.spam as f64 / learned.spam.max(1) as f64;
+            let ham = c.ham as f64 / learned.ham.max(1) as f64;
+            let seen = (c.spam + c.ham) as f64;
+            (STRENGTH * 0.5 + seen * spam / (spam + ham)) / (STRENGTH + seen)
+        })
+        .collect();
+    telling.sort_by(|a, b| (b - 0.5).abs().total_cmp(&(a - 0.5).abs()));
+    telling.truncate(INTERESTING);
+    let log_odds: f64 = telling.iter().map(|p| (p / (1.0 - p)).ln()).sum();
+    1.0 / (1.0 + (-log_odds).exp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let message = mime::Part::parse(
+            b"Subject: Cheap pills\r\nContent-Type: text/html\r\n\r\n<p>Buy <b>cheap</b> pills at <a href=\"http://Pills.example/x\">us</a></p>\r\n",
+        );
+        let tokens = tokens(&message);
+        for word in [
+            "subject:cheap",
+            "subject:pills",
+            "buy",
+            "cheap",
+            "url:pills.example",
+        ] {
+            assert!(tokens.contains(&hash(word)), "{}", word);
+        }
+        // Tags and short words are not tokens, and repeats count once.
+        assert!(!tokens.contains(&hash("href")));
+        assert!(!tokens.contains(&hash("at")));
+        assert_eq!(tokens.len(), 6);
+    }
+
+    #[test]
+    fn test_probability() {
+        let learned = Counts { spam: 10, ham: 10 };
+        let spammy = Counts { spam: 9, ham: 0 };
+        let hammy = Counts { spam: 0, ham: 9 };
+        let neutral = Counts { spam: 5, ham: 5 };
+        assert!(probability(&[spammy, spammy, neutral], learned) > 0.99);
+        assert!(probability(&[hammy, neutral], learned) < 0.1);
+        assert_eq!(probability(&[neutral, Counts::default()], learned), 0.5);
+        assert_eq!(probability(&[], learned), 0.5);
+    }
+}
@@ -0,0 +1,338 @@
+//! Spam scoring of incoming mail. Rules on the envelope, headers and links
+//! are combined with a naive Bayes classifier that each user trains by
+//! moving mail into and out of their Junk folder.
+
+mod bayes;
+mod rules;
+
+pub use bayes::{probability, tokens, Class, Counts};
+pub use rules::Hit;
+
+use crate::message::{self, Handler, HandlerError, Message, Recipient};
+use crate::mime;
+use crate::storage::SqliteStore;
+use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
+
+/// The folder mail classified as spam is kept in.
+pub const JUNK: &str = "Junk";
+
+/// Points the classifier adds for certain spam, or takes off for certain
+/// ham.
+const BAYES_WEIGHT: f64 = 5.0;
+
+/// Only the start of a message is scored, so that a large attachment is
+/// never read into memory for the little it adds to the rules.
+const MAX_SCAN_SIZE: u64 = 512 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpamConfig {
+    /// Mail scoring at least this much goes to the Junk folder.
+    pub junk_score: f64,
+    /// Mail scoring at least this much for every recipient is refused.
+    /// Recipients it scores that much for while others take it keep it in
+    /// their Junk folder, as the refusal could only be bounced to them.
+    pub reject_score: Option<f64>,
+    /// The `Authentication-Results` of this server (RFC 8601) are trusted
+    /// for SPF, DKIM and DMARC results, e.g. those of an MTA in front.
+    pub authserv_id: Option<String>,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        Self {
+            junk_score: 5.0,
+            reject_score: None,
+            authserv_id: None,
+        }
+    }
+}
+
+/// The hosts of the `http` and `https` links in `text`, lowercased.
+fn url_hosts(text: &str) -> Vec<String> {
+    let text = text.to_ascii_lowercase();
+    let mut hosts = vec![];
+    let mut rest = text.as_str();
+    while let Some(i) = rest.find("http") {
+        rest = &rest[i + 4..];
+        let Some(url) = rest
+            .strip_prefix("://")
+            .or_else(|| rest.strip_prefix("s://"))
+        else {
+            continue;
+        };
+        let end = url
+            .find(|c: char| c.is_whitespace() || "/?#:\"'<>()".contains(c))
+            .unwrap_or(url.len());
+        if end > 0 {
+            hosts.push(url[..end].to_string());
+        }
+        rest = &url[end..];
+    }
+    hosts
+}
+
+/// The headers that report a score, in the style of SpamAssassin.
+fn spam_headers(score: f64, junk_score: f64, hits: &[Hit]) -> String {
+    let names: Vec<_> = hits.iter().map(|h| h.name).collect();
+    let junk = score >= junk_score;
+    format!(
+        "{}X-Spam-Score: {:.1}\r\nX-Spam-Status: {}, score={:.1} required={:.1} tests={}\r\n",
+        if junk { "X-Spam-Flag: YES\r\n" } else { "" },
+        score,
+        if junk { "Yes" } else { "No" },
+        score,
+        junk_score,
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(",")
+        }
+    )
+}
+
+/// `data` with `headers` on top, and without any `X-Spam-` headers the
+/// sender put in to pass for scored mail.
+fn with_headers(data: &[u8], headers: &str) -> Vec<u8> {
+    let mut out = headers.as_bytes().to_vec();
+    out.reserve(data.len());
+    let mut skipping = false;
+    let mut offset = 0;
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        offset += line.len();
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            skipping = line.len() >= 7 && line[..7].eq_ignore_ascii_case(b"x-spam-");
+        }
+        if !skipping {
+            out.extend_from_slice(line);
+        }
+    }
+    out.extend_from_slice(&data[offset..]);
+    out
+}
+
+/// Scores mail for each recipient before passing it on to `inner` with the
+/// score in an `X-Spam-Score` header. Spam is marked for the Junk folder,
+/// or refused when it is above the reject score for all recipients. Mail
+/// from authenticated clients is not scored.
+pub struct SpamHandler {
+    store: SqliteStore,
+    config: SpamConfig,
+    inner: Box<dyn Handler + Send + Sync>,
+}
+
+impl SpamHandler {
+    pub fn new(
+        store: SqliteStore,
+        config: SpamConfig,
+        inner: Box<dyn Handler + Send + Sync>,
+    ) -> Self {
+        Self {
+            store,
+            config,
+            inner,
+        }
+    }
+
+    /// The rule hits plus what the classifier of `recipient` makes of
+    /// `tokens`, once it learned enough.
+    async fn hits(&self, recipient: &Recipient, rules: &[Hit], tokens: &[i64]) -> Vec<Hit> {
+        let mut hits = rules.to_vec();
+        match self
+            .store
+            .spam_probability(&recipient.address, tokens)
+            .await
+        {
+            Ok(Some(p)) => hits.push(Hit {
+                name: "BAYES",
+                score: (BAYES_WEIGHT * (2.0 * p - 1.0) * 10.0).round() / 10.0,
+            }),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Classifying mail to {} failed: {}", recipient.address, e),
+        }
+        hits
+    }
+}
+
+#[async_trait]
+impl Handler for SpamHandler {
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+        message::summarize(&self.handle_recipients(message).await)
+    }
+
+    async fn handle_recipients(&self, message: &Message) -> Vec<Result<(), HandlerError>> {
+        if message.authenticated {
+            return self.inner.handle_recipients(message).await;
+        }
+        // The rules need the message, but only until it is scored.
+        let (rules, tokens) = {
+            let mut data = vec![];
+            let read = async {
+                let reader = message.data.reader().await?;
+                reader.take(MAX_SCAN_SIZE).read_to_end(&mut data).await
+            };
+            if let Err(e) = read.await {
+                return vec![Err(e.into()); message.recipients.len()];
+            }
+            let part = mime::Part::parse(&data);
+            let authserv_id = self.config.authserv_id.as_deref();
+            (rules::check(message, &part, authserv_id), tokens(&part))
+        };
+
+        let mut scores = vec![];
+        for recipient in &message.recipients {
+            let hits = self.hits(recipient, &rules, &tokens).await;
+            let score = hits.iter().fold(0.0, |score, h| score + h.score);
+            scores.push((score, hits));
+        }
+        let rejected = |score: f64| self.config.reject_score.is_some_and(|r| score >= r);
+        // Refusing some recipients once others took the message would bounce
+        // it to the sender, whose address spam usually forges.
+        if scores.iter().all(|(score, _)| rejected(*score)) {
+            let lowest = scores
+                .iter()
+                .map(|(score, _)| *score)
+                .fold(f64::MAX, f64::min);
+            tracing::info!("Rejecting mail as spam: {:.1}", lowest);
+            let e = HandlerError::permanent("Message rejected as spam");
+            return vec![Err(e); message.recipients.len()];
+        }
+
+        let mut results = vec![Ok(()); message.recipients.len()];
+        // Recipients whose mail gets the same headers are passed on together.
+        let mut groups: Vec<(String, bool, Vec<usize>)> = vec![];
+        for (i, (score, hits)) in scores.into_iter().enumerate() {
+            let junk = score >= self.config.junk_score || rejected(score);
+            let headers = spam_headers(score, self.config.junk_score, &hits);
+            match groups.iter_mut().find(|(h, _, _)| *h == headers) {
+                Some((_, _, indices)) => indices.push(i),
+                None => groups.push((headers, junk, vec![i])),
+            }
+        }
+        for (headers, junk, indices) in groups {
+            let recipients = indices
+                .iter()
+                .map(|&i| Recipient {
+                    junk: junk || message.recipients[i].junk,
+                    ..message.recipients[i].clone()
+                })
+                .collect();
+            let scored = async {
+                let (header, rest) = message.data.split_header().await?;
+                message
+                    .copy_streamed(recipients, &with_headers(&header, &headers), rest)
+                    .await
+            };
+            let inner = match scored.await {
+                Ok(scored) => self.inner.handle_recipients(&scored).await,
+                Err(e) => vec![Err(HandlerError::from(e)); indices.len()],
+            };
+            for (i, result) in indices.into_iter().zip(inner) {
+                results[i] = result;
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_hosts() {
+        assert_eq!(
+            url_hosts(
+                "See HTTPS://Example.net/a, http://[2001:db8::1]/ and http://192.0.2.1:8080."
+            ),
+            ["example.net", "[2001", "192.0.2.1"]
+        );
+        assert!(url_hosts("http:// https").is_empty());
+    }
+
+    #[test]
+    fn test_with_headers() {
+        let data = b"X-Spam-Flag: YES\r\nSubject: Hi\r\nX-Spam-Status: No,\r\n tests=none\r\n\r\nX-Spam-Score: body\r\n";
+        assert_eq!(
+            with_headers(data, "X-Spam-Score: 0.0\r\n"),
+            b"X-Spam-Score: 0.0\r\nSubject: Hi\r\n\r\nX-Spam-Score: body\r\n"
+        );
+    }
+
+    async fn deliver(handler: &SpamHandler, to: &str, data: &str) -> Result<(), HandlerError> {
+        let message = Message {
+            sender_domain: "mail.example.net".to_string(),
+            from: "<alice@example.net>".to_string(),
+            recipients: vec![Recipient::new(to)],
+            data: data.as_bytes().into(),
+            ..Default::default()
+        };
+        handler.handle_message(&message).await
+    }
+
+    #[tokio::test]
+    async fn test_handler() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let config = SpamConfig {
+            junk_score: 3.0,
+            reject_score: Some(7.0),
+            authserv_id: None,
+        };
+        let handler = SpamHandler::new(store.clone(), config, Box::new(store.clone()));
+
+        let ham = "From: alice@example.net\r\nDate: Tue, 14 Nov 2023 22:13:20 +0000\r\nMessage-ID: <1@example.net>\r\nSubject: Lunch\r\n\r\nNoon?\r\n";
+        deliver(&handler, "bob@example.com", ham).await.unwrap();
+        let spam = "X-Spam-Score: -100\r\nSubject: FREE MONEY NOW!!!\r\n\r\nClick\r\n";
+        deliver(&handler, "bob@example.com", spam).await.unwrap();
+        let worse = "Subject: FREE MONEY NOW!!!\r\nContent-Type: text/html\r\n\r\n<a href=\"http://192.0.2.1/\">x</a>\r\n";
+        assert!(deliver(&handler, "bob@example.com", worse).await.is_err());
+
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        let folders: Vec<_> = messages.iter().map(|m| m.folder.as_str()).collect();
+        assert_eq!(folders, ["INBOX", JUNK]);
+        let data = store.message_data(messages[1].id).await.unwrap().unwrap();
+        let part = mime::Part::parse(&data);
+        assert_eq!(part.header("x-spam-flag").unwrap(), "YES");
+        assert_eq!(part.header("x-spam-score").unwrap(), "5.5");
+        let data = store.message_data(messages[0].id).await.unwrap().unwrap();
+        let part = mime::Part::parse(&data);
+        assert_eq!(part.header("x-spam-score").unwrap(), "0.0");
+        assert!(part.header("x-spam-flag").is_none());
+
+        // Once carol takes it, bob gets it in the Junk folder instead.
+        let ham = tokens(&mime::Part::parse(worse.as_bytes()));
+        for _ in 0..10 {
+            store
+                .learn("carol@example.com", &ham, Class::Ham)
+                .await
+                .unwrap();
+            store
+                .learn("carol@example.com", &[], Class::Spam)
+                .await
+                .unwrap();
+        }
+        let message = Message {
+            recipients: vec![
+                Recipient::new("bob@example.com"),
+                Recipient::new("carol@example.com"),
+            ],
+            data: worse.as_bytes().into(),
+            ..Default::default()
+        };
+        handler.handle_message(&message).await.unwrap();
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        assert_eq!(messages[2].folder, JUNK);
+        assert_eq!(
+            store
+                .list_messages("carol@example.com")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use crate::message::Message;
+use crate::mime;
+use std::net::Ipv4Addr;
+
+/// Results in `Authentication-Results` that are scored: method, result,
+/// rule and points.
+const AUTH_RULES: &[(&str, &str, &str, f64)] = &[
+    ("spf", "pass", "SPF_PASS", -0.5),
+    ("spf", "softfail", "SPF_SOFTFAIL", 1.0),
+    ("spf", "fail", "SPF_FAIL", 2.5),
+    ("dkim", "pass", "DKIM_PASS", -0.5),
+    ("dkim", "fail", "DKIM_FAIL", 2.0),
+    ("dmarc", "pass", "DMARC_PASS", -1.0),
+    ("dmarc", "fail", "DMARC_FAIL", 3.5),
+];
+
+/// A rule that matched, and the points it adds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub name: &'static str,
+    pub score: f64,
+}
+
+/// The rules that match `message`. Authentication results are only taken
+/// from headers stamped by the trusted `authserv_id`, as anyone can add
+/// such headers before the mail reaches us.
+pub fn check(message: &Message, part: &mime::Part, authserv_id: Option<&str>) -> Vec<Hit> {
+    let mut hits = vec![];
+    if let Some(authserv_id) = authserv_id {
+        authentication_results(part, authserv_id, &mut hits);
+    }
+    helo(&message.sender_domain, &mut hits);
+    headers(part, &mut hits);
+    urls(part, &mut hits);
+    hits
+}
+
+fn hit(hits: &mut Vec<Hit>, name: &'static str, score: f64) {
+    hits.push(Hit { name, score });
+}
+
+fn authentication_results(part: &mime::Part, authserv_id: &str, hits: &mut Vec<Hit>) {
+    let stamped = part
+        .headers()
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("authentication-results"))
+        .map(|h| h.value())
+        .filter(|value| {
+            let id = value.split(';').next().unwrap_or_default();
+            id.split_whitespace()
+                .next()
+                .is_some_and(|id| id.eq_ignore_ascii_case(authserv_id))
+        });
+    for value in stamped {
+        for result in value.split(';').skip(1) {
+            let Some((method, result)) = result
+                .split_whitespace()
+                .next()
+                .and_then(|r| r.split_once('='))
+            else {
+                continue;
+            };
+            let method = method.split('/').next().unwrap_or_default();
+            let rule = AUTH_RULES.iter().find(|(m, r, _, _)| {
+                m.eq_ignore_ascii_case(method) && r.eq_ignore_ascii_case(result)
+            });
+            if let Some(&(_, _, name, score)) = rule {
+                if !hits.iter().any(|h| h.name == name) {
+                    hit(hits, name, score);
+                }
+            }
+        }
+    }
+}
+
+/// The greeting is only kept when the HELO validator accepted it.
+fn helo(helo: &str, hits: &mut Vec<Hit>) {
+    if helo.is_empty() {
+        hit(hits, "HELO_INVALID", 1.5);
+    } else if helo.starts_with('[') {
+        hit(hits, "HELO_IP_LITERAL", 0.5);
+    } else if !helo.contains('.') {
+        hit(hits, "HELO_NO_DOT", 1.0);
+    }
+}
+
+fn headers(part: &mime::Part, hits: &mut Vec<Hit>) {
+    if part.header("date").is_none() {
+        hit(hits, "MISSING_DATE", 1.0);
+    }
+    if part.header("message-id").is_none() {
+        hit(hits, "MISSING_MESSAGE_ID", 1.0);
+    }
+    match part.header("from") {
+        None => hit(hits, "MISSING_FROM", 2.0),
+        // `"support@bank.example" <x@elsewhere.example>`
+        Some(from) => {
+            let name = from.split_once('<').map_or("", |(name, _)| name);
+            let address = mime::addresses(&from)
+                .into_iter()
+                .next()
+                .unwrap_or_default();
+            if name.contains('@') && !name.to_lowercase().contains(&address.to_lowercase()) {
+                hit(hits, "FROM_NAME_MISMATCH", 1.5);
+            }
+        }
+    }
+    let subject = part.subject().unwrap_or_default();
+    let letters: Vec<char> = subject.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.len() >= 10 && !letters.iter().any(|c| c.is_lowercase()) {
+        hit(hits, "SUBJECT_ALL_CAPS", 1.0);
+    }
+    if subject.matches('!').count() >= 3 {
+        hit(hits, "SUBJECT_EXCLAMATIONS", 0.5);
+    }
+    if part.html_body().is_some() && part.text_body().is_none() {
+        hit(hits, "HTML_ONLY", 0.5);
+    }
+}
+
+fn urls(part: &mime::Part, hits: &mut Vec<Hit>) {
+    let text = [part.text_body(), part.html_body()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("\n");
+    let hosts = super::url_hosts(&text);
+    if hosts.len() > 25 {
+        hit(hits, "URL_FLOOD", 2.0);
+    } else if hosts.len() > 10 {
+        hit(hits, "URL_MANY", 1.0);
+    }
+    if hosts
+        .iter()
+        .any(|host| host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok())
+    {
+        hit(hits, "URL_IP_HOST", 1.5);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(helo: &str, data: &str, authserv_id: Option<&str>) -> Vec<&'static str> {
+        let message = Message {
+            sender_domain: helo.to_string(),
+            ..Default::default()
+        };
+        check(&message, &mime::Part::parse(data.as_bytes()), authserv_id)
+            .iter()
+            .map(|h| h.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let clean = "From: Alice <alice@example.net>\r\nDate: Tue, 14 Nov 2023 22:13:20 +0000\r\nMessage-ID: <1@example.net>\r\nSubject: Lunch\r\n\r\nNoon at https://example.net/menu?\r\n";
+        assert!(names("mail.example.net", clean, None).is_empty());
+        assert_eq!(names("", clean, None), ["HELO_INVALID"]);
+        assert_eq!(names("[192.0.2.1]", clean, None), ["HELO_IP_LITERAL"]);
+        assert_eq!(names("localhost", clean, None), ["HELO_NO_DOT"]);
+
+        let spam = "From: \"support@bank.example\" <x@elsewhere.example>\r\nSubject: ACT NOW!!! YOUR ACCOUNT\r\nContent-Type: text/html\r\n\r\n<a href=\"http://192.0.2.7/login\">here</a>\r\n";
+        assert_eq!(
+            names("mx.example", spam, None),
+            [
+                "MISSING_DATE",
+                "MISSING_MESSAGE_ID",
+                "FROM_NAME_MISMATCH",
+                "SUBJECT_ALL_CAPS",
+                "SUBJECT_EXCLAMATIONS",
+                "HTML_ONLY",
+                "URL_IP_HOST"
+            ]
+        );
+
+        let links = "https://a.example/ ".repeat(11);
+        let many = format!(
+            "{}\r\n\r\n{}\r\n",
+            clean.split("\r\n\r\n").next().unwrap(),
+            links
+        );
+        assert_eq!(names("mx.example", &many, None), ["URL_MANY"]);
+    }
+
+    #[test]
+    fn test_authentication_results() {
+        let data = "Authentication-Results: mx.example.com;\r\n spf=fail smtp.mailfrom=example.net;\r\n dkim=pass header.d=example.net; dmarc=fail (p=reject)\r\nAuthentication-Results: evil.example; dmarc=pass\r\nFrom: alice@example.net\r\nDate: Tue, 14 Nov 2023 22:13:20 +0000\r\nMessage-ID: <1@example.net>\r\n\r\nHi\r\n";
+        assert_eq!(
+            names("mx.example", data, Some("mx.example.com")),
+            ["SPF_FAIL", "DKIM_PASS", "DMARC_FAIL"]
+        );
+        assert!(names("mx.example", data, None).is_empty());
+        assert_eq!(
+            names("mx.example", data, Some("evil.example")),
+            ["DMARC_PASS"]
+        );
+    }
+}
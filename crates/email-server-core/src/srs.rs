@@ -0,0 +1,270 @@
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HASH_LENGTH: usize = 4;
+const MAX_AGE_DAYS: u64 = 21;
+const TIMESTAMP_PRECISION: u64 = 60 * 60 * 24;
+const TIMESTAMP_SLOTS: u64 = 1024;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SrsError {
+    NotSrs,
+    Malformed,
+    BadHash,
+    Expired,
+}
+
+impl Display for SrsError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            SrsError::NotSrs => write!(f, "NotSrs"),
+            SrsError::Malformed => write!(f, "Malformed"),
+            SrsError::BadHash => write!(f, "BadHash"),
+            SrsError::Expired => write!(f, "Expired"),
+        }
+    }
+}
+
+impl Error for SrsError {}
+
+/// Sender Rewriting Scheme: rewrites envelope senders of forwarded mail so
+/// they pass SPF at the destination, and reverses bounces sent back to them.
+#[derive(Clone)]
+pub struct Srs {
+    secret: Vec<u8>,
+    domain: String,
+}
+
+impl std::fmt::Debug for Srs {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("Srs").field("domain", &self.domain).finish()
+    }
+}
+
+impl Srs {
+    pub fn new(secret: impl Into<Vec<u8>>, domain: impl Into<String>) -> Self {
+        Self {
+            secret: secret.into(),
+            domain: domain.into(),
+        }
+    }
+
+    pub fn is_srs(address: &str) -> bool {
+        let local = address.split('@').next().unwrap_or_default();
+        match local.get(..5) {
+            Some(prefix) => {
+                prefix.eq_ignore_ascii_case("SRS0=") || prefix.eq_ignore_ascii_case("SRS1=")
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `address` is under the domain we rewrite senders into.
+    pub fn is_local(&self, address: &str) -> bool {
+        address
+            .rsplit_once('@')
+            .is_some_and(|(_, domain)| domain.eq_ignore_ascii_case(&self.domain))
+    }
+
+    /// Rewrites `sender` so that it belongs to our domain. Senders that
+    /// are already ours are returned unchanged.
+    pub fn forward(&self, sender: &str) -> String {
+        self.forward_at(sender, now_days())
+    }
+
+    fn forward_at(&self, sender: &str, days: u64) -> String {
+        let Some((local, domain)) = sender.rsplit_once('@') else {
+            return sender.to_string();
+        };
+        if domain.eq_ignore_ascii_case(&self.domain) {
+            return sender.to_string();
+        }
+
+        let prefix = local.get(..5).map(|p| p.to_ascii_uppercase());
+        match prefix.as_deref() {
+            // SRS0=HHH=TT=domain=local@srs0domain
+            // -> SRS1=HHH=srs0domain==HHH=TT=domain=local@ourdomain
+            Some("SRS0=") => {
+                let rest = &local[4..];
+                let hash = self.hash(&[domain, rest]);
+                format!("SRS1={}={}={}@{}", hash, domain, rest, self.domain)
+            }
+            // SRS1=HHH=srs0domain==... keeps pointing at the original
+            // forwarder, only the outer hash is ours.
+            Some("SRS1=") => match local[5..].split_once('=') {
+                Some((_, tail)) => match tail.split_once('=') {
+                    Some((srs0_domain, rest)) => {
+                        let hash = self.hash(&[srs0_domain, rest]);
+                        format!("SRS1={}={}={}@{}", hash, srs0_domain, rest, self.domain)
+                    }
+                    None => sender.to_string(),
+                },
+                None => sender.to_string(),
+            },
+            _ => {
+                let timestamp = encode_timestamp(days);
+                let hash = self.hash(&[&timestamp, domain, local]);
+                format!(
+                    "SRS0={}={}={}={}@{}",
+                    hash, timestamp, domain, local, self.domain
+                )
+            }
+        }
+    }
+
+    /// Reverses an address produced by [`Srs::forward`], returning the
+    /// address the bounce should be delivered to.
+    pub fn reverse(&self, address: &str) -> Result<String, SrsError> {
+        self.reverse_at(address, now_days())
+    }
+
+    fn reverse_at(&self, address: &str, days: u64) -> Result<String, SrsError> {
+        if !Self::is_srs(address) {
+            return Err(SrsError::NotSrs);
+        }
+        let local = address.rsplit_once('@').map_or(address, |(l, _)| l);
+
+        if local[..5].eq_ignore_ascii_case("SRS1=") {
+            let (hash, tail) = local[5..].split_once('=').ok_or(SrsError::Malformed)?;
+            let (srs0_domain, rest) = tail.split_once('=').ok_or(SrsError::Malformed)?;
+            if !rest.starts_with('=') {
+                return Err(SrsError::Malformed);
+            }
+            self.check_hash(hash, &[srs0_domain, rest])?;
+            return Ok(format!("SRS0{}@{}", rest, srs0_domain));
+        }
+
+        let mut parts = local[5..].splitn(4, '=');
+        let hash = parts.next().ok_or(SrsError::Malformed)?;
+        let timestamp = parts.next().ok_or(SrsError::Malformed)?;
+        let domain = parts.next().ok_or(SrsError::Malformed)?;
+        let user = parts.next().ok_or(SrsError::Malformed)?;
+        if domain.is_empty() || user.is_empty() {
+            return Err(SrsError::Malformed);
+        }
+        self.check_hash(hash, &[timestamp, domain, user])?;
+        check_timestamp(timestamp, days)?;
+        Ok(format!("{}@{}", user, domain))
+    }
+
+    fn hash(&self, parts: &[&str]) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("hmac accepts any key");
+        for part in parts {
+            mac.update(part.to_ascii_lowercase().as_bytes());
+        }
+        let digest = base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+        digest[..HASH_LENGTH].to_string()
+    }
+
+    fn check_hash(&self, hash: &str, parts: &[&str]) -> Result<(), SrsError> {
+        // Mailers may fold the case of the local part, so compare without it.
+        if self.hash(parts).eq_ignore_ascii_case(hash) {
+            Ok(())
+        } else {
+            Err(SrsError::BadHash)
+        }
+    }
+}
+
+fn now_days() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / TIMESTAMP_PRECISION
+}
+
+fn encode_timestamp(days: u64) -> String {
+    let slot = days % TIMESTAMP_SLOTS;
+    [BASE32[(slot >> 5) as usize], BASE32[(slot & 31) as usize]]
+        .iter()
+        .map(|&c| c as char)
+        .collect()
+}
+
+fn check_timestamp(timestamp: &str, days: u64) -> Result<(), SrsError> {
+    if timestamp.len() != 2 {
+        return Err(SrsError::Malformed);
+    }
+    let mut slot = 0;
+    for c in timestamp.bytes() {
+        let value = BASE32
+            .iter()
+            .position(|&b| b == c.to_ascii_uppercase())
+            .ok_or(SrsError::Malformed)?;
+        slot = (slot << 5) | value as u64;
+    }
+    let age = (days % TIMESTAMP_SLOTS + TIMESTAMP_SLOTS - slot) % TIMESTAMP_SLOTS;
+    if age > MAX_AGE_DAYS {
+        return Err(SrsError::Expired);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 20_000;
+
+    #[test]
+    fn test_forward_and_reverse() {
+        let srs = Srs::new("secret", "forwarder.example");
+        let rewritten = srs.forward_at("alice@gmail.com", DAY);
+        assert!(rewritten.starts_with("SRS0="));
+        assert!(rewritten.ends_with("=gmail.com=alice@forwarder.example"));
+        assert_eq!(
+            srs.reverse_at(&rewritten, DAY + 3).unwrap(),
+            "alice@gmail.com"
+        );
+    }
+
+    #[test]
+    fn test_local_sender_unchanged() {
+        let srs = Srs::new("secret", "forwarder.example");
+        assert_eq!(
+            srs.forward_at("bob@forwarder.example", DAY),
+            "bob@forwarder.example"
+        );
+    }
+
+    #[test]
+    fn test_reverse_rejects_tampering() {
+        let srs = Srs::new("secret", "forwarder.example");
+        let rewritten = srs.forward_at("alice@gmail.com", DAY);
+        let forged = rewritten.replace("alice", "mallory");
+        assert_eq!(srs.reverse_at(&forged, DAY), Err(SrsError::BadHash));
+
+        let other = Srs::new("other", "forwarder.example");
+        assert_eq!(other.reverse_at(&rewritten, DAY), Err(SrsError::BadHash));
+    }
+
+    #[test]
+    fn test_reverse_rejects_expired() {
+        let srs = Srs::new("secret", "forwarder.example");
+        let rewritten = srs.forward_at("alice@gmail.com", DAY);
+        assert_eq!(
+            srs.reverse_at(&rewritten, DAY + MAX_AGE_DAYS + 1),
+            Err(SrsError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_srs1_round_trip() {
+        let first = Srs::new("first", "first.example");
+        let second = Srs::new("second", "second.example");
+        let srs0 = first.forward_at("alice@gmail.com", DAY);
+        let srs1 = second.forward_at(&srs0, DAY);
+        assert!(srs1.starts_with("SRS1="));
+        assert!(srs1.ends_with("@second.example"));
+
+        let back = second.reverse_at(&srs1, DAY).unwrap();
+        assert_eq!(back, srs0);
+        assert_eq!(first.reverse_at(&back, DAY).unwrap(), "alice@gmail.com");
+    }
+}
@@ -0,0 +1,297 @@
+use super::SqliteStore;
+use crate::message::Recipient;
+use crate::resolver::{AddressResolver, ResolveError};
+use crate::smtp::address;
+use async_trait::async_trait;
+
+const MAX_DEPTH: usize = 10;
+
+impl SqliteStore {
+    pub async fn add_alias(&self, address: &str, target: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR IGNORE INTO aliases (address, target) VALUES (?, ?)")
+            .bind(address.to_ascii_lowercase())
+            .bind(target.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a single target of `address`, or all of them.
+    pub async fn remove_alias(
+        &self,
+        address: &str,
+        target: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM aliases WHERE address = ? AND (? IS NULL OR target = ?)")
+            .bind(address.to_ascii_lowercase())
+            .bind(target.map(str::to_ascii_lowercase))
+            .bind(target.map(str::to_ascii_lowercase))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_aliases(&self) -> Result<Vec<(String, String)>, sqlx::Error> {
+        sqlx::query_as("SELECT address, target FROM aliases ORDER BY address, target")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn set_catch_all(&self, domain: &str, target: &str) -> Result<(), sqlx::Error> {
+        let address = format!("@{}", domain);
+        self.remove_alias(&address, None).await?;
+        self.add_alias(&address, target).await
+    }
+
+    pub async fn remove_catch_all(&self, domain: &str) -> Result<(), sqlx::Error> {
+        self.remove_alias(&format!("@{}", domain), None).await
+    }
+
+    pub async fn add_domain_alias(&self, name: &str, target: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR REPLACE INTO domain_aliases (name, target) VALUES (?, ?)")
+            .bind(name.to_ascii_lowercase())
+            .bind(target.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_domain_alias(&self, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM domain_aliases WHERE name = ?")
+            .bind(name.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_domain_aliases(&self) -> Result<Vec<(String, String)>, sqlx::Error> {
+        sqlx::query_as("SELECT name, target FROM domain_aliases ORDER BY name")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Follows domain aliases until a domain that is not an alias.
+    pub async fn canonical_domain(&self, domain: &str) -> Result<String, ResolveError> {
+        let mut domain = domain.to_ascii_lowercase();
+        for _ in 0..MAX_DEPTH {
+            let target: Option<String> =
+                sqlx::query_scalar("SELECT target FROM domain_aliases WHERE name = ?")
+                    .bind(&domain)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(ResolveError::boxed)?;
+            match target {
+                Some(target) => domain = target,
+                None => return Ok(domain),
+            }
+        }
+        Err(ResolveError::Loop(domain))
+    }
+
+    async fn alias_targets(&self, address: &str) -> Result<Vec<String>, ResolveError> {
+        sqlx::query_scalar("SELECT target FROM aliases WHERE address = ? ORDER BY target")
+            .bind(address)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(ResolveError::boxed)
+    }
+
+    /// Resolves one address a single step: either it is final, or it
+    /// expands into further addresses to resolve.
+    async fn resolve_step(&self, address: &str, tag: Option<String>) -> Result<Step, ResolveError> {
+        let Some((local, domain)) = address::split(address) else {
+            return Ok(Step::Expand(vec![]));
+        };
+        let local = local.to_ascii_lowercase();
+        let domain = self.canonical_domain(&domain).await?;
+        let address = format!("{}@{}", local, domain);
+
+        if !self
+            .is_local_domain(&domain)
+            .await
+            .map_err(ResolveError::boxed)?
+        {
+            return Ok(Step::Final(Recipient {
+                tag,
+                forward: true,
+                ..Recipient::new(address)
+            }));
+        }
+
+        let targets = self.alias_targets(&address).await?;
+        if !targets.is_empty() {
+            return Ok(Step::Expand(
+                targets.into_iter().map(|t| (t, tag.clone())).collect(),
+            ));
+        }
+        if self
+            .is_local_user(&address)
+            .await
+            .map_err(ResolveError::boxed)?
+        {
+            return Ok(Step::Final(Recipient {
+                tag,
+                ..Recipient::new(address)
+            }));
+        }
+        if let Some((user, subaddress)) = local.split_once('+') {
+            if !user.is_empty() {
+                let base = format!("{}@{}", user, domain);
+                return Ok(Step::Expand(vec![(base, Some(subaddress.to_string()))]));
+            }
+        }
+        let catch_all = self.alias_targets(&format!("@{}", domain)).await?;
+        if !catch_all.is_empty() {
+            return Ok(Step::Expand(
+                catch_all.into_iter().map(|t| (t, None)).collect(),
+            ));
+        }
+        Ok(Step::Expand(vec![]))
+    }
+}
+
+enum Step {
+    Final(Recipient),
+    Expand(Vec<(String, Option<String>)>),
+}
+
+#[async_trait]
+impl AddressResolver for SqliteStore {
+    async fn resolve(&self, address: &str) -> Result<Vec<Recipient>, ResolveError> {
+        let mut recipients: Vec<Recipient> = vec![];
+        // Each pending address carries the chain of aliases that led to it,
+        // so a cycle is detected without rejecting diamonds.
+        let mut pending = vec![(address.to_ascii_lowercase(), None, vec![])];
+        while let Some((address, tag, path)) = pending.pop() {
+            if path.contains(&address) || path.len() > MAX_DEPTH {
+                return Err(ResolveError::Loop(address));
+            }
+            match self.resolve_step(&address, tag).await? {
+                Step::Final(recipient) => {
+                    if !recipients.contains(&recipient) {
+                        recipients.push(recipient);
+                    }
+                }
+                Step::Expand(targets) => {
+                    let mut path = path.clone();
+                    path.push(address);
+                    for (target, tag) in targets.into_iter().rev() {
+                        pending.push((target, tag, path.clone()));
+                    }
+                }
+            }
+        }
+        Ok(recipients)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store() -> (SqliteStore, tempfile::NamedTempFile) {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        store.add_domain("example.com").await.unwrap();
+        store.add_user("alice@example.com").await.unwrap();
+        store.add_user("bob@example.com").await.unwrap();
+        (store, temp_file)
+    }
+
+    fn recipient(address: &str, tag: Option<&str>) -> Recipient {
+        Recipient {
+            tag: tag.map(str::to_string),
+            ..Recipient::new(address)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nested_aliases() {
+        let (store, _file) = store().await;
+        store
+            .add_alias("team@example.com", "alice@example.com")
+            .await
+            .unwrap();
+        store
+            .add_alias("team@example.com", "ops@example.com")
+            .await
+            .unwrap();
+        store
+            .add_alias("ops@example.com", "bob@example.com")
+            .await
+            .unwrap();
+        store
+            .add_alias("ops@example.com", "carol@elsewhere.test")
+            .await
+            .unwrap();
+        store
+            .add_alias("ops@example.com", "alice@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.resolve("Team@Example.com").await.unwrap(),
+            vec![
+                recipient("alice@example.com", None),
+                recipient("bob@example.com", None),
+                Recipient {
+                    forward: true,
+                    ..recipient("carol@elsewhere.test", None)
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_alias_loop() {
+        let (store, _file) = store().await;
+        store
+            .add_alias("a@example.com", "b@example.com")
+            .await
+            .unwrap();
+        store
+            .add_alias("b@example.com", "a@example.com")
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.resolve("a@example.com").await,
+            Err(ResolveError::Loop(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_plus_addressing_and_domain_alias() {
+        let (store, _file) = store().await;
+        store
+            .add_domain_alias("example.org", "example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            store.resolve("alice+lists@example.org").await.unwrap(),
+            vec![recipient("alice@example.com", Some("lists"))]
+        );
+        assert!(store
+            .resolve("nobody+x@example.com")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_catch_all() {
+        let (store, _file) = store().await;
+        assert!(store.resolve("sales@example.com").await.unwrap().is_empty());
+        store
+            .set_catch_all("example.com", "bob@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            store.resolve("sales@example.com").await.unwrap(),
+            vec![recipient("bob@example.com", None)]
+        );
+        assert_eq!(
+            store.resolve("alice@example.com").await.unwrap(),
+            vec![recipient("alice@example.com", None)]
+        );
+    }
+}
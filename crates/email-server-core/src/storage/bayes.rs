@@ -0,0 +1,221 @@
+use super::{BlobError, SqliteStore};
+use crate::mime;
+use crate::spam::{self, Class, Counts, JUNK};
+use sqlx::{QueryBuilder, Row};
+
+/// The classifier only speaks up once a user taught it this many messages
+/// of each class.
+const MIN_LEARNED: u64 = 10;
+
+impl SqliteStore {
+    /// Adds `delta` to the counts of `class` for `tokens` and for all
+    /// learned messages of `owner`.
+    async fn count_tokens(
+        &self,
+        owner: &str,
+        tokens: &[i64],
+        class: Class,
+        delta: i64,
+    ) -> Result<(), sqlx::Error> {
+        let column = class.as_str();
+        let owner = owner.to_ascii_lowercase();
+        let mut tx = self.pool.begin().await?;
+        for token in tokens {
+            sqlx::query(&format!(
+                r#"
+                   INSERT INTO bayes_tokens (owner, token, {column}) VALUES (?, ?, MAX(?, 0))
+                   ON CONFLICT (owner, token) DO UPDATE SET {column} = MAX({column} + ?, 0)
+                   "#
+            ))
+            .bind(&owner)
+            .bind(token)
+            .bind(delta)
+            .bind(delta)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query(&format!(
+            r#"
+               INSERT INTO bayes_totals (owner, {column}) VALUES (?, MAX(?, 0))
+               ON CONFLICT (owner) DO UPDATE SET {column} = MAX({column} + ?, 0)
+               "#
+        ))
+        .bind(&owner)
+        .bind(delta)
+        .bind(delta)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+
+    /// Teaches the classifier of `owner` that a message with `tokens` is of
+    /// `class`.
+    pub async fn learn(
+        &self,
+        owner: &str,
+        tokens: &[i64],
+        class: Class,
+    ) -> Result<(), sqlx::Error> {
+        self.count_tokens(owner, tokens, class, 1).await
+    }
+
+    /// How likely the classifier of `owner` finds a message with `tokens`
+    /// to be spam, or `None` while it has not learned enough.
+    pub async fn spam_probability(
+        &self,
+        owner: &str,
+        tokens: &[i64],
+    ) -> Result<Option<f64>, sqlx::Error> {
+        let owner = owner.to_ascii_lowercase();
+        let learned: Option<(i64, i64)> =
+            sqlx::query_as("SELECT spam, ham FROM bayes_totals WHERE owner = ?")
+                .bind(&owner)
+                .fetch_optional(&self.pool)
+                .await?;
+        let learned = match learned {
+            Some((spam, ham)) => Counts {
+                spam: spam as u64,
+                ham: ham as u64,
+            },
+            None => return Ok(None),
+        };
+        if learned.spam < MIN_LEARNED || learned.ham < MIN_LEARNED {
+            return Ok(None);
+        }
+        if tokens.is_empty() {
+            return Ok(Some(spam::probability(&[], learned)));
+        }
+
+        let mut query = QueryBuilder::new("SELECT spam, ham FROM bayes_tokens WHERE owner = ");
+        query.push_bind(&owner).push(" AND token IN (");
+        let mut separated = query.separated(", ");
+        for token in tokens {
+            separated.push_bind(token);
+        }
+        separated.push_unseparated(")");
+        let counts: Vec<_> = query
+            .build()
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| Counts {
+                spam: row.get::<i64, _>(0) as u64,
+                ham: row.get::<i64, _>(1) as u64,
+            })
+            .collect();
+        Ok(Some(spam::probability(&counts, learned)))
+    }
+
+    /// Moves a stored message to `folder`. Moving mail into the Junk folder
+    /// teaches the owner's classifier it is spam, and moving it out that it
+    /// is ham, undoing what it was learned as before. Encrypted mail cannot
+    /// be read to learn from. Returns whether the message exists.
+    pub async fn move_message(&self, id: i64, folder: &str) -> Result<bool, BlobError> {
+        let row: Option<(String, String, Option<String>)> =
+            sqlx::query_as("SELECT to_addrs, folder, learned FROM messages WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some((owner, previous, learned)) = row else {
+            return Ok(false);
+        };
+        sqlx::query("UPDATE messages SET folder = ? WHERE id = ?")
+            .bind(folder)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        let class = match (previous == JUNK, folder == JUNK) {
+            (false, true) => Class::Spam,
+            (true, false) => Class::Ham,
+            _ => return Ok(true),
+        };
+        let learned = learned.as_deref().and_then(Class::parse);
+        if learned == Some(class) {
+            return Ok(true);
+        }
+        let Some((data, false)) = self.stored_data(id).await? else {
+            return Ok(true);
+        };
+        let tokens = spam::tokens(&mime::Part::parse(&data));
+        if let Some(learned) = learned {
+            self.count_tokens(&owner, &tokens, learned, -1).await?;
+        }
+        self.learn(&owner, &tokens, class).await?;
+        sqlx::query("UPDATE messages SET learned = ? WHERE id = ?")
+            .bind(class.as_str())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Handler, Message, Recipient};
+
+    #[tokio::test]
+    async fn test_bayes() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let owner = "bob@example.com";
+        let spam = spam::tokens(&mime::Part::parse(
+            b"Subject: Cheap pills\r\n\r\nBuy cheap pills today\r\n",
+        ));
+        let ham = spam::tokens(&mime::Part::parse(
+            b"Subject: Lunch plans\r\n\r\nShall we meet today\r\n",
+        ));
+
+        assert_eq!(store.spam_probability(owner, &spam).await.unwrap(), None);
+        for _ in 0..MIN_LEARNED {
+            store.learn(owner, &spam, Class::Spam).await.unwrap();
+            store
+                .learn("Bob@Example.com", &ham, Class::Ham)
+                .await
+                .unwrap();
+        }
+        assert!(store.spam_probability(owner, &spam).await.unwrap().unwrap() > 0.99);
+        assert!(store.spam_probability(owner, &ham).await.unwrap().unwrap() < 0.01);
+        assert_eq!(store.spam_probability(owner, &[]).await.unwrap(), Some(0.5));
+        assert_eq!(
+            store
+                .spam_probability("carol@example.com", &spam)
+                .await
+                .unwrap(),
+            None
+        );
+
+        let message = Message {
+            recipients: vec![Recipient::new(owner)],
+            data: b"Subject: Cheap pills\r\n\r\nOrder now\r\n"
+                .as_slice()
+                .into(),
+            ..Default::default()
+        };
+        store.handle_message(&message).await.unwrap();
+        let id = store.list_messages(owner).await.unwrap()[0].id;
+        let learned = |store: SqliteStore| async move {
+            let row: (i64, i64) = sqlx::query_as("SELECT spam, ham FROM bayes_totals")
+                .fetch_one(&store.pool)
+                .await
+                .unwrap();
+            row
+        };
+
+        assert!(store.move_message(id, JUNK).await.unwrap());
+        assert_eq!(learned(store.clone()).await, (11, 10));
+        // Moving it back unlearns it as spam and learns it as ham.
+        assert!(store.move_message(id, "INBOX").await.unwrap());
+        assert_eq!(learned(store.clone()).await, (10, 11));
+        // Moves between other folders teach nothing.
+        assert!(store.move_message(id, "Archive").await.unwrap());
+        assert_eq!(learned(store.clone()).await, (10, 11));
+        assert_eq!(
+            store.list_messages(owner).await.unwrap()[0].folder,
+            "Archive"
+        );
+        assert!(!store.move_message(id + 1, JUNK).await.unwrap());
+    }
+}
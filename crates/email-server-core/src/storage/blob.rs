@@ -0,0 +1,735 @@
+use super::SqliteStore;
+use crate::message::HandlerError;
+use crate::mime;
+use crate::time::unix_time;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqlitePool;
+use sqlx::{Row, SqliteConnection};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Leaf parts with a body at least this large get a blob of their own, so
+/// that the same attachment in different messages is stored once.
+pub const LARGE_PART_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum BlobError {
+    IoError(std::io::Error),
+    SqlError(sqlx::Error),
+    /// A blob is referenced but missing from the backend.
+    Missing(BlobId),
+    /// A blob's content no longer matches its hash.
+    Corrupt(BlobId),
+    /// Not the hex SHA-256 a [`BlobId`] is made of.
+    InvalidId(String),
+    /// The message is encrypted; see [`SqliteStore::decrypt_message`].
+    Encrypted(i64),
+}
+
+impl Display for BlobError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            BlobError::IoError(e) => write!(f, "IoError: {}", e),
+            BlobError::SqlError(e) => write!(f, "SqlError: {}", e),
+            BlobError::Missing(id) => write!(f, "Missing: {}", id),
+            BlobError::Corrupt(id) => write!(f, "Corrupt: {}", id),
+            BlobError::InvalidId(id) => write!(f, "InvalidId: {}", id),
+            BlobError::Encrypted(id) => write!(f, "Encrypted: {}", id),
+        }
+    }
+}
+
+impl Error for BlobError {}
+
+impl From<std::io::Error> for BlobError {
+    fn from(e: std::io::Error) -> Self {
+        BlobError::IoError(e)
+    }
+}
+
+impl From<sqlx::Error> for BlobError {
+    fn from(e: sqlx::Error) -> Self {
+        BlobError::SqlError(e)
+    }
+}
+
+impl From<BlobError> for HandlerError {
+    fn from(e: BlobError) -> Self {
+        HandlerError::temporary(e)
+    }
+}
+
+/// The lowercase hex SHA-256 of a blob's content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlobId(String);
+
+impl BlobId {
+    pub fn of(data: &[u8]) -> Self {
+        Self(hex::encode(Sha256::digest(data)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for BlobId {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for BlobId {
+    type Err = BlobError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            Ok(Self(s.to_string()))
+        } else {
+            Err(BlobError::InvalidId(s.to_string()))
+        }
+    }
+}
+
+/// Where blob bytes live. Backends only store bytes; reference counts are
+/// kept by [`SqliteStore`] whatever the backend.
+#[async_trait]
+pub trait BlobStore: Debug + Send + Sync {
+    /// Stores `data` under `id`. Storing an existing blob again is a no-op.
+    async fn put(&self, id: &BlobId, data: &[u8]) -> Result<(), BlobError>;
+
+    async fn get(&self, id: &BlobId) -> Result<Option<Vec<u8>>, BlobError>;
+
+    async fn delete(&self, id: &BlobId) -> Result<(), BlobError>;
+
+    async fn list(&self) -> Result<Vec<BlobId>, BlobError>;
+
+    /// Whether the blobs live in the `blobs` table of the store's own
+    /// database. Migrations and garbage collection then write them
+    /// through their open transaction instead.
+    fn in_database(&self) -> bool {
+        false
+    }
+}
+
+/// Keeps blobs in the `blobs` table of the store's own database.
+#[derive(Debug, Clone)]
+pub struct SqliteBlobStore {
+    pool: SqlitePool,
+}
+
+impl SqliteBlobStore {
+    pub(super) fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl BlobStore for SqliteBlobStore {
+    async fn put(&self, id: &BlobId, data: &[u8]) -> Result<(), BlobError> {
+        sqlx::query("INSERT OR IGNORE INTO blobs (id, data) VALUES (?, ?)")
+            .bind(id.as_str())
+            .bind(data)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &BlobId) -> Result<Option<Vec<u8>>, BlobError> {
+        Ok(sqlx::query_scalar("SELECT data FROM blobs WHERE id = ?")
+            .bind(id.as_str())
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn delete(&self, id: &BlobId) -> Result<(), BlobError> {
+        sqlx::query("DELETE FROM blobs WHERE id = ?")
+            .bind(id.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<BlobId>, BlobError> {
+        let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM blobs")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(ids.into_iter().map(BlobId).collect())
+    }
+
+    fn in_database(&self) -> bool {
+        true
+    }
+}
+
+/// Keeps blobs as files named by their hash under `root/ab/abcdef...`.
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, id: &BlobId) -> PathBuf {
+        self.root.join(&id.as_str()[..2]).join(id.as_str())
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, id: &BlobId, data: &[u8]) -> Result<(), BlobError> {
+        let path = self.path(id);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+        let dir = path.parent().expect("blob paths have a parent");
+        tokio::fs::create_dir_all(dir).await?;
+        // Written aside and renamed so readers never see a partial blob.
+        let temp = tempfile::NamedTempFile::new_in(dir)?;
+        tokio::fs::write(temp.path(), data).await?;
+        temp.persist(&path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &BlobId) -> Result<Option<Vec<u8>>, BlobError> {
+        match tokio::fs::read(self.path(id)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, id: &BlobId) -> Result<(), BlobError> {
+        match tokio::fs::remove_file(self.path(id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<BlobId>, BlobError> {
+        let mut ids = vec![];
+        let mut dirs = match tokio::fs::read_dir(&self.root).await {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                if let Some(id) = file.file_name().to_str().and_then(|n| n.parse().ok()) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+}
+
+/// A message with its large leaf bodies cut out, and where they go back.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct Split<'a> {
+    pub skeleton: Vec<u8>,
+    pub parts: Vec<(usize, &'a [u8])>,
+}
+
+impl<'a> Split<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        let message = mime::Part::parse(data);
+        let mut split = Split::default();
+        let mut copied = 0;
+        for leaf in message.leaves() {
+            let body = leaf.raw_body();
+            if body.len() < LARGE_PART_SIZE {
+                continue;
+            }
+            let start = body.as_ptr() as usize - data.as_ptr() as usize;
+            split.skeleton.extend_from_slice(&data[copied..start]);
+            split.parts.push((split.skeleton.len(), body));
+            copied = start + body.len();
+        }
+        split.skeleton.extend_from_slice(&data[copied..]);
+        split
+    }
+}
+
+/// Splices the parts back into the skeleton.
+fn join(skeleton: &[u8], parts: &[(usize, Vec<u8>)]) -> Vec<u8> {
+    let mut data =
+        Vec::with_capacity(skeleton.len() + parts.iter().map(|p| p.1.len()).sum::<usize>());
+    let mut copied = 0;
+    for (position, part) in parts {
+        data.extend_from_slice(&skeleton[copied..*position]);
+        data.extend_from_slice(part);
+        copied = *position;
+    }
+    data.extend_from_slice(&skeleton[copied..]);
+    data
+}
+
+/// The blobs a message was stored as.
+#[derive(Debug, Clone)]
+pub(super) struct StoredBlobs {
+    pub skeleton: BlobId,
+    pub parts: Vec<(usize, BlobId)>,
+}
+
+impl SqliteStore {
+    /// Writes the blobs of a message. They are unreferenced until a row of
+    /// `messages` takes them with [`Self::reference_blobs`].
+    pub(super) async fn put_blobs(&self, data: &[u8]) -> Result<StoredBlobs, BlobError> {
+        let split = Split::new(data);
+        let skeleton = self.put_blob(&split.skeleton).await?;
+        let mut parts = Vec::with_capacity(split.parts.len());
+        for (position, part) in split.parts {
+            parts.push((position, self.put_blob(part).await?));
+        }
+        Ok(StoredBlobs { skeleton, parts })
+    }
+
+    pub(super) async fn put_blob(&self, data: &[u8]) -> Result<BlobId, BlobError> {
+        let mut conn = self.pool.acquire().await?;
+        self.put_blob_on(&mut conn, data).await
+    }
+
+    /// Like [`Self::put_blob`], but through `conn`, which may hold an open
+    /// transaction.
+    pub(super) async fn put_blob_on(
+        &self,
+        conn: &mut SqliteConnection,
+        data: &[u8],
+    ) -> Result<BlobId, BlobError> {
+        let id = BlobId::of(data);
+        sqlx::query(
+            r#"
+               INSERT INTO blob_refs (id, refs, touched_at) VALUES (?, 0, ?)
+               ON CONFLICT (id) DO UPDATE SET touched_at = excluded.touched_at
+               "#,
+        )
+        .bind(id.as_str())
+        .bind(unix_time())
+        .execute(&mut *conn)
+        .await?;
+        if self.blobs.in_database() {
+            sqlx::query("INSERT OR IGNORE INTO blobs (id, data) VALUES (?, ?)")
+                .bind(id.as_str())
+                .bind(data)
+                .execute(&mut *conn)
+                .await?;
+        } else {
+            self.blobs.put(&id, data).await?;
+        }
+        Ok(id)
+    }
+
+    /// Points message `id` at its blobs and counts the references.
+    pub(super) async fn reference_blobs(
+        conn: &mut SqliteConnection,
+        id: i64,
+        blobs: &StoredBlobs,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE messages SET blob_id = ? WHERE id = ?")
+            .bind(blobs.skeleton.as_str())
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+        let parts = blobs.parts.iter().map(|(_, blob)| blob);
+        for blob in std::iter::once(&blobs.skeleton).chain(parts) {
+            sqlx::query("UPDATE blob_refs SET refs = refs + 1 WHERE id = ?")
+                .bind(blob.as_str())
+                .execute(&mut *conn)
+                .await?;
+        }
+        for (position, blob) in &blobs.parts {
+            sqlx::query(
+                "INSERT INTO message_parts (message_id, position, blob_id) VALUES (?, ?, ?)",
+            )
+            .bind(id)
+            .bind(*position as i64)
+            .bind(blob.as_str())
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn blob(&self, id: BlobId) -> Result<Vec<u8>, BlobError> {
+        let data = self
+            .blobs
+            .get(&id)
+            .await?
+            .ok_or_else(|| BlobError::Missing(id.clone()))?;
+        if BlobId::of(&data) != id {
+            return Err(BlobError::Corrupt(id));
+        }
+        Ok(data)
+    }
+
+    /// Drops the blob references of message `id`, as deleting it would.
+    pub(super) async fn release_blobs(
+        conn: &mut SqliteConnection,
+        id: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+               UPDATE blob_refs SET refs = refs - 1, touched_at = ?
+               WHERE id = (SELECT blob_id FROM messages WHERE id = ?)
+               "#,
+        )
+        .bind(unix_time())
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query(
+            r#"
+               UPDATE blob_refs SET
+                   refs = refs - (
+                       SELECT COUNT(*) FROM message_parts
+                       WHERE message_id = ? AND blob_id = blob_refs.id
+                   ),
+                   touched_at = ?
+               WHERE id IN (SELECT blob_id FROM message_parts WHERE message_id = ?)
+               "#,
+        )
+        .bind(id)
+        .bind(unix_time())
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query("DELETE FROM message_parts WHERE message_id = ?")
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("UPDATE messages SET blob_id = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the raw message `id` as it was received.
+    pub async fn message_data(&self, id: i64) -> Result<Option<Vec<u8>>, BlobError> {
+        match self.stored_data(id).await? {
+            Some((_, true)) => Err(BlobError::Encrypted(id)),
+            Some((data, false)) => Ok(Some(data)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns message `id` as stored, and whether that is encrypted.
+    pub(super) async fn stored_data(&self, id: i64) -> Result<Option<(Vec<u8>, bool)>, BlobError> {
+        let Some(row) =
+            sqlx::query("SELECT blob_id, message, encrypted FROM messages WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?
+        else {
+            return Ok(None);
+        };
+        let encrypted: bool = row.get(2);
+        let Some(skeleton) = row.get::<Option<String>, _>(0) else {
+            return Ok(Some((row.get(1), encrypted)));
+        };
+        let skeleton = self.blob(skeleton.parse()?).await?;
+
+        let rows = sqlx::query(
+            "SELECT position, blob_id FROM message_parts WHERE message_id = ? ORDER BY position",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut parts = Vec::with_capacity(rows.len());
+        for row in rows {
+            let position: i64 = row.get(0);
+            let blob: String = row.get(1);
+            parts.push((position as usize, self.blob(blob.parse()?).await?));
+        }
+        Ok(Some((join(&skeleton, &parts), encrypted)))
+    }
+
+    /// Deletes blobs that have been unreferenced for longer than `grace`,
+    /// and backend blobs nothing knows about. Returns how many were
+    /// deleted.
+    pub async fn collect_garbage(&self, grace: Duration) -> Result<usize, BlobError> {
+        let _guard = self.gc_lock.write().await;
+        let cutoff = unix_time() - grace.as_secs() as i64;
+        let mut deleted = 0;
+
+        let unreferenced: Vec<String> =
+            sqlx::query_scalar("SELECT id FROM blob_refs WHERE refs <= 0 AND touched_at <= ?")
+                .bind(cutoff)
+                .fetch_all(&self.pool)
+                .await?;
+        for id in unreferenced {
+            let mut tx = self.pool.begin().await?;
+            let claimed =
+                sqlx::query("DELETE FROM blob_refs WHERE id = ? AND refs <= 0 AND touched_at <= ?")
+                    .bind(&id)
+                    .bind(cutoff)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+            if claimed == 1 {
+                self.delete_blob(&mut tx, &id.parse()?).await?;
+                deleted += 1;
+            }
+            tx.commit().await?;
+        }
+
+        // Listed before the known ids are read: a row is always written
+        // before its blob, so anything listed that has no row is an orphan.
+        let listed = self.blobs.list().await?;
+        let known: HashSet<String> = sqlx::query_scalar("SELECT id FROM blob_refs")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .collect();
+        for id in listed {
+            if known.contains(id.as_str()) {
+                continue;
+            }
+            // Claimed with a placeholder row, which fails if a delivery has
+            // written one since.
+            let mut tx = self.pool.begin().await?;
+            let claimed = sqlx::query(
+                "INSERT INTO blob_refs (id, refs, touched_at) VALUES (?, 0, 0) ON CONFLICT DO NOTHING",
+            )
+            .bind(id.as_str())
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            if claimed == 1 {
+                self.delete_blob(&mut tx, &id).await?;
+                sqlx::query("DELETE FROM blob_refs WHERE id = ?")
+                    .bind(id.as_str())
+                    .execute(&mut *tx)
+                    .await?;
+                deleted += 1;
+            }
+            tx.commit().await?;
+        }
+        Ok(deleted)
+    }
+
+    /// Removes blob `id` from the backend while `conn` holds the write lock
+    /// of the database. Deliveries, in this or another process, write the
+    /// row of a blob before the blob itself, so none can find the blob
+    /// present and rely on it until the removal is committed.
+    async fn delete_blob(&self, conn: &mut SqliteConnection, id: &BlobId) -> Result<(), BlobError> {
+        if self.blobs.in_database() {
+            sqlx::query("DELETE FROM blobs WHERE id = ?")
+                .bind(id.as_str())
+                .execute(&mut *conn)
+                .await?;
+            return Ok(());
+        }
+        self.blobs.delete(id).await
+    }
+}
+
+/// Moves the bodies stored inline in `messages.message` into blobs, kept
+/// by the store's configured backend.
+pub(super) fn backfill_blobs<'c>(
+    store: &'c SqliteStore,
+    conn: &'c mut SqliteConnection,
+) -> BoxFuture<'c, Result<(), sqlx::Error>> {
+    Box::pin(async move {
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages")
+            .fetch_all(&mut *conn)
+            .await?;
+        for id in ids {
+            let data: Vec<u8> = sqlx::query_scalar("SELECT message FROM messages WHERE id = ?")
+                .bind(id)
+                .fetch_one(&mut *conn)
+                .await?;
+            let split = Split::new(&data);
+            let put = async {
+                let skeleton = store.put_blob_on(conn, &split.skeleton).await?;
+                let mut parts = Vec::with_capacity(split.parts.len());
+                for (position, part) in &split.parts {
+                    parts.push((*position, store.put_blob_on(conn, part).await?));
+                }
+                Ok::<_, BlobError>(StoredBlobs { skeleton, parts })
+            };
+            let stored = put.await.map_err(|e| match e {
+                BlobError::SqlError(e) => e,
+                e => sqlx::Error::Io(std::io::Error::other(e)),
+            })?;
+            SqliteStore::reference_blobs(conn, id, &stored).await?;
+            sqlx::query("UPDATE messages SET message = x'' WHERE id = ?")
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Handler, Message, Recipient};
+    use std::sync::Arc;
+
+    fn newsletter(attachment: &[u8]) -> Vec<u8> {
+        let mut data = b"Subject: News\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\nHello\r\n\
+--b\r\nContent-Type: application/octet-stream\r\n\r\n"
+            .to_vec();
+        data.extend_from_slice(attachment);
+        data.extend_from_slice(b"\r\n--b--\r\n");
+        data
+    }
+
+    async fn deliver(store: &SqliteStore, data: &[u8], to: &[&str]) {
+        let message = Message {
+            from: "<carol@example.net>".to_string(),
+            recipients: to.iter().map(|t| Recipient::new(*t)).collect(),
+            data: data.into(),
+            ..Default::default()
+        };
+        store.handle_message(&message).await.unwrap();
+    }
+
+    async fn blob_count(store: &SqliteStore) -> usize {
+        store.blobs.list().await.unwrap().len()
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        let attachment = vec![b'x'; LARGE_PART_SIZE];
+        let data = newsletter(&attachment);
+        let split = Split::new(&data);
+        assert_eq!(split.parts.len(), 1);
+        assert_eq!(split.skeleton.len(), data.len() - LARGE_PART_SIZE);
+        let parts: Vec<_> = split.parts.iter().map(|(p, d)| (*p, d.to_vec())).collect();
+        assert_eq!(join(&split.skeleton, &parts), data);
+    }
+
+    async fn test_dedup_and_gc(store: SqliteStore) {
+        let attachment = vec![b'x'; LARGE_PART_SIZE];
+        let first = newsletter(&attachment);
+        let second = String::from_utf8(newsletter(&attachment))
+            .unwrap()
+            .replace("Hello", "Bonjour")
+            .into_bytes();
+
+        // One skeleton for both recipients, one shared attachment.
+        deliver(&store, &first, &["alice@example.com", "bob@example.com"]).await;
+        deliver(&store, &second, &["erin@example.com"]).await;
+        assert_eq!(blob_count(&store).await, 3);
+
+        let alice = store.list_messages("alice@example.com").await.unwrap();
+        assert_eq!(
+            store.message_data(alice[0].id).await.unwrap().unwrap(),
+            first
+        );
+        let erin = store.list_messages("erin@example.com").await.unwrap();
+        assert_eq!(
+            store.message_data(erin[0].id).await.unwrap().unwrap(),
+            second
+        );
+
+        store.delete_message(alice[0].id).await.unwrap();
+        store.delete_message(erin[0].id).await.unwrap();
+        assert_eq!(store.collect_garbage(Duration::ZERO).await.unwrap(), 1);
+        let bob = store.list_messages("bob@example.com").await.unwrap();
+        assert_eq!(store.message_data(bob[0].id).await.unwrap().unwrap(), first);
+
+        store.delete_message(bob[0].id).await.unwrap();
+        // Recently unreferenced blobs are kept for a while.
+        assert_eq!(
+            store
+                .collect_garbage(Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(store.collect_garbage(Duration::ZERO).await.unwrap(), 2);
+        assert_eq!(blob_count(&store).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_blobs() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        test_dedup_and_gc(store).await;
+    }
+
+    #[tokio::test]
+    async fn test_fs_blobs() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::new(temp_file.path())
+            .await
+            .unwrap()
+            .with_blob_store(Arc::new(FsBlobStore::new(temp_dir.path())));
+        test_dedup_and_gc(store.clone()).await;
+
+        // Files without a reference count are orphans.
+        let stray = BlobId::of(b"stray");
+        store.blobs.put(&stray, b"stray").await.unwrap();
+        assert_eq!(store.collect_garbage(Duration::ZERO).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_into_fs_blobs() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(temp_file.path())
+            .await
+            .unwrap()
+            .with_blob_store(Arc::new(FsBlobStore::new(temp_dir.path())));
+        // A message stored before blobs existed.
+        sqlx::raw_sql(include_str!("migrations/0001_initial.sql"))
+            .execute(&store.pool)
+            .await
+            .unwrap();
+        let data = newsletter(&vec![b'x'; LARGE_PART_SIZE]);
+        sqlx::query("INSERT INTO messages (from_addr, to_addrs, message) VALUES (?, ?, ?)")
+            .bind("carol@example.net")
+            .bind("bob@example.com")
+            .bind(&data)
+            .execute(&store.pool)
+            .await
+            .unwrap();
+
+        store.migrate().await.unwrap();
+        let bob = store.list_messages("bob@example.com").await.unwrap();
+        assert_eq!(store.message_data(bob[0].id).await.unwrap().unwrap(), data);
+        assert_eq!(blob_count(&store).await, 2);
+        let in_database: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM blobs")
+            .fetch_one(&store.pool)
+            .await
+            .unwrap();
+        assert_eq!(in_database, 0);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_blob() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        deliver(&store, b"Subject: Hi\r\n\r\nHi\r\n", &["bob@example.com"]).await;
+        sqlx::query("UPDATE blobs SET data = x'00'")
+            .execute(&store.pool)
+            .await
+            .unwrap();
+        let bob = store.list_messages("bob@example.com").await.unwrap();
+        assert!(matches!(
+            store.message_data(bob[0].id).await,
+            Err(BlobError::Corrupt(_))
+        ));
+    }
+}
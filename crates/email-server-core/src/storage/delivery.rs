@@ -0,0 +1,85 @@
+use super::SqliteStore;
+use crate::smtp::delivery::DeliveryLog;
+use async_trait::async_trait;
+
+/// How long partial deliveries are remembered, longer than clients retry.
+const RETENTION: i64 = 7 * 24 * 60 * 60;
+
+#[async_trait]
+impl DeliveryLog for SqliteStore {
+    async fn delivered(&self, digest: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT recipient FROM deliveries WHERE digest = ?")
+            .bind(digest)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn record_delivered(
+        &self,
+        digest: &str,
+        recipients: &[String],
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM deliveries WHERE delivered_at < ?")
+            .bind(now - RETENTION)
+            .execute(&mut *tx)
+            .await?;
+        for recipient in recipients {
+            sqlx::query(
+                r#"
+                   INSERT OR REPLACE INTO deliveries (digest, recipient, delivered_at)
+                   VALUES (?, ?, ?)
+                   "#,
+            )
+            .bind(digest)
+            .bind(recipient)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn bounce(&self, sender: &str, report: &[u8], now: i64) -> Result<(), sqlx::Error> {
+        self.enqueue_outbound("", &[sender.to_string()], report, now)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_delivery_log() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let recipients = ["bob@example.com".to_string()];
+
+        assert!(store.delivered("abc").await.unwrap().is_empty());
+        store
+            .record_delivered("abc", &recipients, 1000)
+            .await
+            .unwrap();
+        assert_eq!(store.delivered("abc").await.unwrap(), recipients);
+        assert!(store.delivered("def").await.unwrap().is_empty());
+
+        // Forgotten once clients stopped retrying.
+        store
+            .record_delivered("def", &[], 1000 + RETENTION + 1)
+            .await
+            .unwrap();
+        assert!(store.delivered("abc").await.unwrap().is_empty());
+
+        store
+            .bounce("alice@example.net", b"Subject: Failed\r\n\r\n", 0)
+            .await
+            .unwrap();
+        let queued = store.claim_outbound(0, 1).await.unwrap();
+        assert_eq!(
+            (queued[0].sender.as_str(), queued[0].recipient.as_str()),
+            ("", "alice@example.net")
+        );
+    }
+}
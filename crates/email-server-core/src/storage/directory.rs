@@ -0,0 +1,150 @@
+use super::SqliteStore;
+use crate::message::Message;
+use crate::resolver::{AddressResolver, ResolveError};
+use crate::smtp::address;
+use crate::smtp::validator::{RecipientValidator, RecipientVerdict};
+use async_trait::async_trait;
+
+impl SqliteStore {
+    pub async fn add_domain(&self, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR IGNORE INTO domains (name) VALUES (?)")
+            .bind(name.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_domain(&self, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM domains WHERE name = ?")
+            .bind(name.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_domains(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT name FROM domains ORDER BY name")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn add_user(&self, address: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR IGNORE INTO users (address) VALUES (?)")
+            .bind(address.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_user(&self, address: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM users WHERE address = ?")
+            .bind(address.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT address FROM users ORDER BY address")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn is_local_domain(&self, domain: &str) -> Result<bool, sqlx::Error> {
+        let found: Option<i64> = sqlx::query_scalar("SELECT 1 FROM domains WHERE name = ?")
+            .bind(domain.to_ascii_lowercase())
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(found.is_some())
+    }
+
+    pub async fn is_local_user(&self, address: &str) -> Result<bool, sqlx::Error> {
+        let found: Option<i64> = sqlx::query_scalar("SELECT 1 FROM users WHERE address = ?")
+            .bind(address.to_ascii_lowercase())
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(found.is_some())
+    }
+
+    async fn verdict(
+        &self,
+        message: &Message,
+        recipient: &str,
+    ) -> Result<RecipientVerdict, ResolveError> {
+        let Some((_, domain)) = address::split(recipient) else {
+            return Ok(RecipientVerdict::UnknownUser);
+        };
+        let domain = self.canonical_domain(&domain).await?;
+        if !self
+            .is_local_domain(&domain)
+            .await
+            .map_err(ResolveError::boxed)?
+        {
+            return Ok(if message.authenticated {
+                RecipientVerdict::Accept
+            } else {
+                RecipientVerdict::RelayDenied
+            });
+        }
+        if self.resolve(recipient).await?.is_empty() {
+            Ok(RecipientVerdict::UnknownUser)
+        } else {
+            Ok(RecipientVerdict::Accept)
+        }
+    }
+}
+
+#[async_trait]
+impl RecipientValidator for SqliteStore {
+    async fn check(&self, message: &Message, recipient: &str) -> RecipientVerdict {
+        match self.verdict(message, recipient).await {
+            Ok(verdict) => verdict,
+            Err(ResolveError::Loop(address)) => {
+                tracing::warn!("aliases of {} loop at {}", recipient, address);
+                RecipientVerdict::RoutingLoop
+            }
+            Err(e) => {
+                tracing::error!("failed to look up recipient {}: {}", recipient, e);
+                RecipientVerdict::TempFail
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recipient_verdicts() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        store.add_domain("Example.com").await.unwrap();
+        store.add_user("bob@example.com").await.unwrap();
+        store
+            .add_alias("postmaster@example.com", "bob@example.com")
+            .await
+            .unwrap();
+        store
+            .add_alias("loop@example.com", "loop@example.com")
+            .await
+            .unwrap();
+
+        let mut message = Message::default();
+        for (recipient, expected) in [
+            ("Bob@EXAMPLE.com", RecipientVerdict::Accept),
+            ("postmaster@example.com", RecipientVerdict::Accept),
+            ("eve@example.com", RecipientVerdict::UnknownUser),
+            ("loop@example.com", RecipientVerdict::RoutingLoop),
+            ("carol@elsewhere.test", RecipientVerdict::RelayDenied),
+        ] {
+            assert_eq!(store.check(&message, recipient).await, expected);
+        }
+
+        message.authenticated = true;
+        assert_eq!(
+            store.check(&message, "carol@elsewhere.test").await,
+            RecipientVerdict::Accept
+        );
+    }
+}
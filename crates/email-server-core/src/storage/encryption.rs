@@ -0,0 +1,608 @@
+use super::blob::{BlobError, StoredBlobs};
+use super::password::{blocking, hash_password};
+use super::SqliteStore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use sqlx::{Row, SqliteConnection};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+/// First byte of everything sealed, so the format can change later.
+const FORMAT_VERSION: u8 = 1;
+const PBKDF2_ITERATIONS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    /// The password does not unwrap the user's key.
+    WrongPassword,
+    /// Encryption needs a recovery key and none was configured.
+    NoRecoveryKey,
+    /// The user has no encryption key.
+    NotEnabled(String),
+    /// Sealed data failed to authenticate.
+    Corrupt,
+    InvalidKey(String),
+    SqlError(sqlx::Error),
+    BlobError(BlobError),
+}
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            EncryptionError::WrongPassword => write!(f, "WrongPassword"),
+            EncryptionError::NoRecoveryKey => write!(f, "NoRecoveryKey"),
+            EncryptionError::NotEnabled(address) => write!(f, "NotEnabled: {}", address),
+            EncryptionError::Corrupt => write!(f, "Corrupt"),
+            EncryptionError::InvalidKey(key) => write!(f, "InvalidKey: {}", key),
+            EncryptionError::SqlError(e) => write!(f, "SqlError: {}", e),
+            EncryptionError::BlobError(e) => write!(f, "BlobError: {}", e),
+        }
+    }
+}
+
+impl Error for EncryptionError {}
+
+impl From<sqlx::Error> for EncryptionError {
+    fn from(e: sqlx::Error) -> Self {
+        EncryptionError::SqlError(e)
+    }
+}
+
+impl From<BlobError> for EncryptionError {
+    fn from(e: BlobError) -> Self {
+        EncryptionError::BlobError(e)
+    }
+}
+
+impl From<EncryptionError> for crate::message::HandlerError {
+    fn from(e: EncryptionError) -> Self {
+        crate::message::HandlerError::temporary(e)
+    }
+}
+
+/// A 256-bit ChaCha20-Poly1305 key. Never printed.
+#[derive(Clone, PartialEq, Eq)]
+struct Key([u8; KEY_LEN]);
+
+impl Key {
+    fn generate() -> Self {
+        let mut key = [0; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .expect("system randomness is available");
+        Self(key)
+    }
+
+    fn from_password(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut key = [0; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(iterations.max(1)).unwrap(),
+            salt,
+            password.as_bytes(),
+            &mut key,
+        );
+        Self(key)
+    }
+
+    fn aead(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &self.0).expect("key length is right"))
+    }
+
+    /// Encrypts with a random nonce: `version || nonce || ciphertext || tag`.
+    fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system randomness is available");
+        // Encrypted in place, as messages may be large.
+        let mut sealed = Vec::with_capacity(1 + NONCE_LEN + plaintext.len() + 16);
+        sealed.push(FORMAT_VERSION);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(plaintext);
+        let tag = self
+            .aead()
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut sealed[1 + NONCE_LEN..],
+            )
+            .expect("plaintext fits the AEAD limits");
+        sealed.extend_from_slice(tag.as_ref());
+        sealed
+    }
+
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let Some((&FORMAT_VERSION, rest)) = sealed.split_first() else {
+            return Err(EncryptionError::Corrupt);
+        };
+        if rest.len() < NONCE_LEN {
+            return Err(EncryptionError::Corrupt);
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let nonce =
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| EncryptionError::Corrupt)?;
+        let mut data = ciphertext.to_vec();
+        let len = self
+            .aead()
+            .open_in_place(nonce, Aad::from(aad), &mut data)
+            .map_err(|_| EncryptionError::Corrupt)?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// The key a user's messages are encrypted with, available after
+/// [`SqliteStore::unlock`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey(Key);
+
+impl Debug for DataKey {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+/// The server-side key every data key is also wrapped with. It lets mail
+/// be encrypted on delivery and passwords be reset, so it must be kept
+/// outside the database.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RecoveryKey(Key);
+
+impl RecoveryKey {
+    pub fn generate() -> Self {
+        Self(Key::generate())
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0 .0)
+    }
+}
+
+impl Debug for RecoveryKey {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str("RecoveryKey(..)")
+    }
+}
+
+impl FromStr for RecoveryKey {
+    type Err = EncryptionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EncryptionError::InvalidKey("expected 64 hex digits".to_string());
+        let bytes = hex::decode(s.trim()).map_err(|_| invalid())?;
+        Ok(Self(Key(bytes.try_into().map_err(|_| invalid())?)))
+    }
+}
+
+impl SqliteStore {
+    /// Lets the store encrypt mail on delivery and reset passwords.
+    pub fn with_recovery_key(mut self, key: RecoveryKey) -> Self {
+        self.recovery_key = Some(std::sync::Arc::new(key));
+        self
+    }
+
+    fn require_recovery_key(&self) -> Result<&RecoveryKey, EncryptionError> {
+        self.recovery_key
+            .as_deref()
+            .ok_or(EncryptionError::NoRecoveryKey)
+    }
+
+    /// Gives `address` a data key protected by `password`, which also
+    /// becomes their login password, and encrypts the mail already stored
+    /// for it. The plaintext blobs are left for [`Self::collect_garbage`].
+    pub async fn enable_encryption(
+        &self,
+        address: &str,
+        password: &str,
+    ) -> Result<(), EncryptionError> {
+        let recovery_key = self.require_recovery_key()?;
+        let address = address.to_ascii_lowercase();
+        let key = DataKey(Key::generate());
+        let (salt, password_wrapped) = wrap_with_password(&address, &key, password).await?;
+        let hashed = hash_password(password).await?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+               INSERT INTO user_keys (address, salt, iterations, password_wrapped, recovery_wrapped)
+               VALUES (?, ?, ?, ?, ?)
+               "#,
+        )
+        .bind(&address)
+        .bind(&salt[..])
+        .bind(PBKDF2_ITERATIONS as i64)
+        .bind(password_wrapped)
+        .bind(recovery_key.0.seal(address.as_bytes(), &key.0 .0))
+        .execute(&mut *tx)
+        .await?;
+        Self::store_password(&mut tx, &address, &hashed).await?;
+        tx.commit().await?;
+        self.encrypt_mailbox(&address, &key).await
+    }
+
+    pub async fn is_encrypted(&self, address: &str) -> Result<bool, sqlx::Error> {
+        let found: Option<String> =
+            sqlx::query_scalar("SELECT address FROM user_keys WHERE address = ?")
+                .bind(address.to_ascii_lowercase())
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(found.is_some())
+    }
+
+    /// Unwraps the data key of `address` with the password given at login.
+    pub async fn unlock(&self, address: &str, password: &str) -> Result<DataKey, EncryptionError> {
+        let address = address.to_ascii_lowercase();
+        let row = sqlx::query(
+            "SELECT salt, iterations, password_wrapped FROM user_keys WHERE address = ?",
+        )
+        .bind(&address)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| EncryptionError::NotEnabled(address.clone()))?;
+        let salt: Vec<u8> = row.get(0);
+        let iterations: i64 = row.get(1);
+        let wrapped: Vec<u8> = row.get(2);
+        let password = password.to_string();
+        let wrapping =
+            blocking(move || Key::from_password(&password, &salt, iterations as u32)).await?;
+        let key = wrapping
+            .open(address.as_bytes(), &wrapped)
+            .map_err(|_| EncryptionError::WrongPassword)?;
+        data_key(key)
+    }
+
+    /// Replaces the password-wrapped data key of `address`, as part of
+    /// [`Self::set_password`].
+    pub(super) async fn store_wrapped_key(
+        conn: &mut SqliteConnection,
+        address: &str,
+        (salt, wrapped): &([u8; SALT_LEN], Vec<u8>),
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE user_keys SET salt = ?, iterations = ?, password_wrapped = ? WHERE address = ?",
+        )
+        .bind(&salt[..])
+        .bind(PBKDF2_ITERATIONS as i64)
+        .bind(wrapped)
+        .bind(address)
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Unwraps the data key of `address` with the recovery key, or returns
+    /// `None` if the user does not encrypt their mail.
+    pub(super) async fn delivery_key(
+        &self,
+        address: &str,
+    ) -> Result<Option<DataKey>, EncryptionError> {
+        if !self.is_encrypted(address).await? {
+            return Ok(None);
+        }
+        self.recover(address).await.map(Some)
+    }
+
+    async fn recover(&self, address: &str) -> Result<DataKey, EncryptionError> {
+        let recovery_key = self.require_recovery_key()?;
+        let address = address.to_ascii_lowercase();
+        let wrapped: Vec<u8> =
+            sqlx::query_scalar("SELECT recovery_wrapped FROM user_keys WHERE address = ?")
+                .bind(&address)
+                .fetch_optional(&self.pool)
+                .await?
+                .ok_or_else(|| EncryptionError::NotEnabled(address.clone()))?;
+        data_key(recovery_key.0.open(address.as_bytes(), &wrapped)?)
+    }
+
+    /// Encrypts `data` as message `id` of `owner`, for storage as a single
+    /// blob written through `conn`.
+    pub(super) async fn put_sealed(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &DataKey,
+        owner: &str,
+        id: i64,
+        data: &[u8],
+    ) -> Result<StoredBlobs, BlobError> {
+        let sealed = key.0.seal(&message_aad(owner, id), data);
+        Ok(StoredBlobs {
+            skeleton: self.put_blob_on(conn, &sealed).await?,
+            parts: vec![],
+        })
+    }
+
+    /// Returns message `id` decrypted with the key of its recipient.
+    pub async fn decrypt_message(
+        &self,
+        id: i64,
+        key: &DataKey,
+    ) -> Result<Option<Vec<u8>>, EncryptionError> {
+        match self.stored_data(id).await? {
+            Some((data, true)) => {
+                let owner: String =
+                    sqlx::query_scalar("SELECT to_addrs FROM messages WHERE id = ?")
+                        .bind(id)
+                        .fetch_one(&self.pool)
+                        .await?;
+                Ok(Some(key.0.open(&message_aad(&owner, id), &data)?))
+            }
+            Some((data, false)) => Ok(Some(data)),
+            None => Ok(None),
+        }
+    }
+
+    /// Seals data waiting in the queue `table` with the recovery key, if
+    /// there is one, returning it and whether it was sealed.
+    pub(super) fn seal_queued(&self, table: &str, data: &[u8]) -> (Vec<u8>, bool) {
+        match &self.recovery_key {
+            Some(key) => (key.0.seal(table.as_bytes(), data), true),
+            None => (data.to_vec(), false),
+        }
+    }
+
+    pub(super) fn open_queued(
+        &self,
+        table: &str,
+        data: Vec<u8>,
+        sealed: bool,
+    ) -> Result<Vec<u8>, EncryptionError> {
+        if !sealed {
+            return Ok(data);
+        }
+        self.require_recovery_key()?.0.open(table.as_bytes(), &data)
+    }
+
+    /// Replaces the plaintext messages of `address` with encrypted ones and
+    /// forgets their searchable metadata.
+    async fn encrypt_mailbox(&self, address: &str, key: &DataKey) -> Result<(), EncryptionError> {
+        let _guard = self.gc_lock.read().await;
+        let ids: Vec<i64> =
+            sqlx::query_scalar("SELECT id FROM messages WHERE to_addrs = ? AND encrypted = 0")
+                .bind(address)
+                .fetch_all(&self.pool)
+                .await?;
+        for id in ids {
+            let Some(data) = self.message_data(id).await? else {
+                continue;
+            };
+            let mut tx = self.pool.begin().await?;
+            let blobs = self.put_sealed(&mut tx, key, address, id, &data).await?;
+            Self::release_blobs(&mut tx, id).await?;
+            Self::reference_blobs(&mut tx, id, &blobs).await?;
+            sqlx::query(
+                r#"
+                   UPDATE messages SET encrypted = 1, subject = NULL, from_display = NULL,
+                       to_display = NULL, cc_display = NULL, message_id = NULL,
+                       in_reply_to = NULL, references_ids = NULL, has_attachment = 0
+                   WHERE id = ?
+                   "#,
+            )
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("DELETE FROM messages_fts WHERE rowid = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Associated data for message blobs, so that a blob cannot be moved to
+/// another message or user. Wrapped keys use the address alone.
+fn message_aad(owner: &str, id: i64) -> Vec<u8> {
+    format!("message:{}:{}", owner, id).into_bytes()
+}
+
+pub(super) async fn wrap_with_password(
+    address: &str,
+    key: &DataKey,
+    password: &str,
+) -> Result<([u8; SALT_LEN], Vec<u8>), sqlx::Error> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .expect("system randomness is available");
+    let (address, key, password) = (address.to_string(), key.clone(), password.to_string());
+    blocking(move || {
+        let wrapping = Key::from_password(&password, &salt, PBKDF2_ITERATIONS);
+        (salt, wrapping.seal(address.as_bytes(), &key.0 .0))
+    })
+    .await
+}
+
+fn data_key(bytes: Vec<u8>) -> Result<DataKey, EncryptionError> {
+    let bytes: [u8; KEY_LEN] = bytes.try_into().map_err(|_| EncryptionError::Corrupt)?;
+    Ok(DataKey(Key(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Handler, Message, Recipient};
+    use std::time::Duration;
+
+    async fn store() -> (SqliteStore, tempfile::NamedTempFile) {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path())
+            .await
+            .unwrap()
+            .with_recovery_key(RecoveryKey::generate());
+        (store, temp_file)
+    }
+
+    async fn deliver(store: &SqliteStore, to: &str) {
+        let message = Message {
+            from: "<carol@example.net>".to_string(),
+            recipients: vec![Recipient::new(to)],
+            data: b"Subject: Secret plans\r\n\r\nMeet at noon\r\n"[..].into(),
+            ..Default::default()
+        };
+        store.handle_message(&message).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_delivery() {
+        let (store, _file) = store().await;
+        deliver(&store, "bob@example.com").await;
+        store
+            .enable_encryption("bob@example.com", "hunter2")
+            .await
+            .unwrap();
+        deliver(&store, "bob@example.com").await;
+
+        // Nothing readable is left once the plaintext is collected.
+        store.collect_garbage(Duration::ZERO).await.unwrap();
+        let blobs: Vec<Vec<u8>> = sqlx::query_scalar("SELECT data FROM blobs")
+            .fetch_all(&store.pool)
+            .await
+            .unwrap();
+        assert!(blobs.iter().all(|b| !b.windows(6).any(|w| w == b"Secret")));
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.metadata.subject.is_none()));
+        let query = "secret".parse().unwrap();
+        assert!(store.search(None, &query).await.unwrap().is_empty());
+        assert!(matches!(
+            store.message_data(messages[0].id).await,
+            Err(BlobError::Encrypted(_))
+        ));
+
+        assert!(matches!(
+            store.unlock("bob@example.com", "wrong").await,
+            Err(EncryptionError::WrongPassword)
+        ));
+        let key = store.unlock("bob@example.com", "hunter2").await.unwrap();
+        for message in messages {
+            let data = store.decrypt_message(message.id, &key).await.unwrap();
+            assert_eq!(
+                data.unwrap(),
+                b"Subject: Secret plans\r\n\r\nMeet at noon\r\n"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_password() {
+        let (store, _file) = store().await;
+        store
+            .enable_encryption("bob@example.com", "hunter2")
+            .await
+            .unwrap();
+        assert!(store
+            .check_password("bob@example.com", "hunter2")
+            .await
+            .unwrap());
+        let key = store.unlock("bob@example.com", "hunter2").await.unwrap();
+
+        // Changing or resetting the login password rewraps the key.
+        store
+            .set_password("bob@example.com", "correct horse")
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .unlock("bob@example.com", "correct horse")
+                .await
+                .unwrap(),
+            key
+        );
+        assert!(matches!(
+            store.unlock("bob@example.com", "hunter2").await,
+            Err(EncryptionError::WrongPassword)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_blobs_are_bound_to_messages() {
+        let (store, _file) = store().await;
+        store
+            .enable_encryption("bob@example.com", "hunter2")
+            .await
+            .unwrap();
+        deliver(&store, "bob@example.com").await;
+        deliver(&store, "bob@example.com").await;
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        let key = store.unlock("bob@example.com", "hunter2").await.unwrap();
+
+        sqlx::query("UPDATE messages SET blob_id = (SELECT blob_id FROM messages WHERE id = ?)")
+            .bind(messages[0].id)
+            .execute(&store.pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.decrypt_message(messages[1].id, &key).await,
+            Err(EncryptionError::Corrupt)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_queues_are_sealed() {
+        let (store, _file) = store().await;
+        let recipients = ["alice@example.net".to_string()];
+        store
+            .enqueue_outbound("", &recipients, b"Secret plans", 0)
+            .await
+            .unwrap();
+        store
+            .enqueue_webhook_retry("http://localhost/", b"Secret plans", 0)
+            .await
+            .unwrap();
+        let stored: Vec<Vec<u8>> = sqlx::query_scalar(
+            "SELECT message FROM outbound_queue UNION ALL SELECT payload FROM webhook_retries",
+        )
+        .fetch_all(&store.pool)
+        .await
+        .unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|b| !b.windows(6).any(|w| w == b"Secret")));
+
+        let queued = store.claim_outbound(0, 0).await.unwrap();
+        assert_eq!(queued[0].message, b"Secret plans");
+        let retries = store.claim_webhook_retries(0, 0).await.unwrap();
+        assert_eq!(retries[0].payload, b"Secret plans");
+    }
+
+    #[tokio::test]
+    async fn test_delivery_needs_recovery_key() {
+        let (store, file) = store().await;
+        store
+            .enable_encryption("bob@example.com", "hunter2")
+            .await
+            .unwrap();
+        let without_key = SqliteStore::new(file.path()).await.unwrap();
+        let message = Message {
+            recipients: vec![Recipient::new("bob@example.com")],
+            data: b"Hi"[..].into(),
+            ..Default::default()
+        };
+        // Mail is deferred rather than stored in plaintext.
+        assert!(without_key
+            .handle_message(&message)
+            .await
+            .unwrap_err()
+            .is_temporary());
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = Key::generate();
+        let sealed = key.seal(b"aad", b"hello");
+        assert_eq!(key.open(b"aad", &sealed).unwrap(), b"hello");
+        assert!(key.open(b"other", &sealed).is_err());
+        assert!(Key::generate().open(b"aad", &sealed).is_err());
+        assert_ne!(sealed, key.seal(b"aad", b"hello"));
+
+        let recovery = RecoveryKey::generate();
+        assert_eq!(recovery.to_hex().parse::<RecoveryKey>().unwrap(), recovery);
+        assert!("abc".parse::<RecoveryKey>().is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use super::SqliteStore;
+use crate::smtp::greylist::{GreylistConfig, GreylistStore, Triplet};
+use async_trait::async_trait;
+
+#[async_trait]
+impl GreylistStore for SqliteStore {
+    async fn attempt(
+        &self,
+        triplet: &Triplet,
+        config: &GreylistConfig,
+        now: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let network = triplet.network.to_string();
+        let delay = config.delay.as_secs() as i64;
+        let retry_window = config.retry_window.as_secs() as i64;
+        let lifetime = config.lifetime.as_secs() as i64;
+        let mut tx = self.pool.begin().await?;
+
+        if config.auto_whitelist > 0 {
+            let whitelisted = sqlx::query(
+                r#"
+                   UPDATE greylist_clients SET last_seen = ?
+                   WHERE network = ? AND passes >= ? AND last_seen >= ?
+                   "#,
+            )
+            .bind(now)
+            .bind(&network)
+            .bind(config.auto_whitelist as i64)
+            .bind(now - lifetime)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                > 0;
+            if whitelisted {
+                tx.commit().await?;
+                return Ok(true);
+            }
+        }
+
+        let seen: Option<(i64, i64, bool)> = sqlx::query_as(
+            r#"
+               SELECT first_seen, last_seen, passed FROM greylist
+               WHERE network = ? AND sender = ? AND recipient = ?
+               "#,
+        )
+        .bind(&network)
+        .bind(&triplet.sender)
+        .bind(&triplet.recipient)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let accepted = match seen {
+            Some((_, last_seen, true)) if now - last_seen <= lifetime => true,
+            Some((first_seen, _, false)) if now - first_seen <= retry_window => {
+                now - first_seen >= delay
+            }
+            // Unknown, or forgotten: start over.
+            _ => {
+                sqlx::query("DELETE FROM greylist WHERE last_seen < ?")
+                    .bind(now - lifetime.max(retry_window))
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(
+                    r#"
+                       INSERT INTO greylist (network, sender, recipient, first_seen, last_seen)
+                       VALUES (?, ?, ?, ?, ?)
+                       ON CONFLICT (network, sender, recipient) DO UPDATE
+                       SET first_seen = excluded.first_seen, last_seen = excluded.last_seen,
+                           passed = 0
+                       "#,
+                )
+                .bind(&network)
+                .bind(&triplet.sender)
+                .bind(&triplet.recipient)
+                .bind(now)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                return Ok(false);
+            }
+        };
+        if !accepted {
+            return Ok(false);
+        }
+
+        let passed = matches!(seen, Some((_, _, false)));
+        sqlx::query(
+            r#"
+               UPDATE greylist SET last_seen = ?, passed = 1
+               WHERE network = ? AND sender = ? AND recipient = ?
+               "#,
+        )
+        .bind(now)
+        .bind(&network)
+        .bind(&triplet.sender)
+        .bind(&triplet.recipient)
+        .execute(&mut *tx)
+        .await?;
+        // A first successful retry counts towards whitelisting the client.
+        if passed {
+            sqlx::query(
+                r#"
+                   INSERT INTO greylist_clients (network, passes, last_seen) VALUES (?, 1, ?)
+                   ON CONFLICT (network) DO UPDATE
+                   SET passes = CASE WHEN last_seen < ? THEN 1 ELSE passes + 1 END,
+                       last_seen = excluded.last_seen
+                   "#,
+            )
+            .bind(&network)
+            .bind(now)
+            .bind(now - lifetime)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_greylist() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let config = GreylistConfig {
+            delay: Duration::from_secs(300),
+            retry_window: Duration::from_secs(3600),
+            lifetime: Duration::from_secs(86400),
+            auto_whitelist: 2,
+            allow: vec![],
+        };
+        let client = "192.0.2.1".parse().unwrap();
+        let first = Triplet::new(client, "alice@example.net", "bob@example.com");
+
+        assert!(!store.attempt(&first, &config, 1000).await.unwrap());
+        // Too early, and it does not restart the delay.
+        assert!(!store.attempt(&first, &config, 1200).await.unwrap());
+        // From a neighbouring address of the same network.
+        let retry = Triplet::new(
+            "192.0.2.2".parse().unwrap(),
+            "alice@example.net",
+            "bob@example.com",
+        );
+        assert!(store.attempt(&retry, &config, 1300).await.unwrap());
+        assert!(store.attempt(&first, &config, 50000).await.unwrap());
+
+        // Retried too late, so greylisted anew.
+        let late = Triplet::new(client, "alice@example.net", "carol@example.com");
+        assert!(!store.attempt(&late, &config, 1000).await.unwrap());
+        assert!(!store.attempt(&late, &config, 5000).await.unwrap());
+        assert!(store.attempt(&late, &config, 5300).await.unwrap());
+
+        // After two passes the client skips greylisting.
+        let whitelisted = Triplet::new(client, "", "dave@example.com");
+        assert!(store.attempt(&whitelisted, &config, 5400).await.unwrap());
+        // But not once it has not been seen for longer than the lifetime.
+        let forgotten = Triplet::new(client, "", "erin@example.com");
+        assert!(!store
+            .attempt(&forgotten, &config, 5400 + 86401)
+            .await
+            .unwrap());
+
+        let other = Triplet::new("198.51.100.1".parse().unwrap(), "", "dave@example.com");
+        assert!(!store.attempt(&other, &config, 5400).await.unwrap());
+    }
+}
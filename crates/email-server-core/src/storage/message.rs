@@ -1,69 +1,348 @@
+use super::blob::{BlobError, BlobStore, SqliteBlobStore, StoredBlobs};
+use super::encryption::{DataKey, RecoveryKey};
+use super::search::SearchDocument;
 use crate::message;
+use crate::mime;
+use crate::sieve::Delivery;
+use crate::smtp::address;
+use crate::srs::Srs;
+use crate::time::unix_time;
 use async_trait::async_trait;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use futures::future::BoxFuture;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqliteRow};
+use sqlx::{Row, SqliteConnection};
 use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
+/// Header fields kept next to each message so that listing a mailbox does
+/// not have to parse every blob.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MessageMetadata {
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub cc: Option<String>,
+    /// Without the angle brackets.
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    /// Space separated message IDs, without angle brackets.
+    pub references: Option<String>,
+}
+
+impl MessageMetadata {
+    pub fn parse(data: &[u8]) -> Self {
+        let message = mime::Part::parse(data);
+        Self {
+            subject: message.subject(),
+            from: message.header("from"),
+            to: message.header("to"),
+            cc: message.header("cc"),
+            message_id: message
+                .header("message-id")
+                .and_then(|v| message_ids(&v).next()),
+            in_reply_to: message
+                .header("in-reply-to")
+                .and_then(|v| message_ids(&v).next()),
+            references: message
+                .header("references")
+                .map(|v| message_ids(&v).collect::<Vec<_>>().join(" "))
+                .filter(|v| !v.is_empty()),
+        }
+    }
+}
+
+fn message_ids(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .map(|id| id.trim_start_matches('<').trim_end_matches('>'))
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+/// A stored message without its body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageSummary {
+    pub id: i64,
+    /// Unix time of delivery; unknown for mail stored before it was
+    /// recorded.
+    pub received_at: Option<i64>,
+    pub size: i64,
+    /// Space separated IMAP flags such as `\Seen`.
+    pub flags: String,
+    /// The folder a Sieve script filed the message into.
+    pub folder: String,
+    pub metadata: MessageMetadata,
+}
+
+#[derive(Clone, Debug)]
 pub struct SqliteStore {
-    pool: SqlitePool,
+    pub(super) pool: SqlitePool,
+    pub(super) blobs: Arc<dyn BlobStore>,
+    /// Held for reading while a delivery writes and references blobs, and
+    /// for writing by garbage collection.
+    pub(super) gc_lock: Arc<RwLock<()>>,
+    /// Unwraps users' data keys to encrypt their mail on delivery.
+    pub(super) recovery_key: Option<Arc<RecoveryKey>>,
+    /// Rewrites foreign senders of mail relayed on their behalf.
+    pub(super) srs: Option<Arc<Srs>>,
 }
 
 impl SqliteStore {
+    /// Opens the database and applies any pending migrations.
     pub async fn new(path: impl AsRef<Path>) -> Result<Self, sqlx::Error> {
+        let this = Self::open(path).await?;
+        this.migrate().await?;
+        Ok(this)
+    }
+
+    /// Opens the database as it is, without migrating it.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, sqlx::Error> {
         let opts = SqliteConnectOptions::default()
             .filename(path)
             .create_if_missing(true);
         let pool = SqlitePool::connect_with(opts).await?;
-        let this = Self { pool };
-        this.initialize_table().await?;
-        Ok(this)
+        Ok(Self {
+            blobs: Arc::new(SqliteBlobStore::new(pool.clone())),
+            pool,
+            gc_lock: Arc::default(),
+            recovery_key: None,
+            srs: None,
+        })
     }
 
-    async fn initialize_table(&self) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            r#"
-               CREATE TABLE IF NOT EXISTS messages (
-                   id INTEGER PRIMARY KEY AUTOINCREMENT,
-                   from_addr TEXT NOT NULL,
-                   to_addrs TEXT NOT NULL,
-                   message BINARY NOT NULL
-               )
-               "#,
-        )
-        .execute(&self.pool)
+    /// Keeps message bodies in `blobs` instead of the database.
+    pub fn with_blob_store(mut self, blobs: Arc<dyn BlobStore>) -> Self {
+        self.blobs = blobs;
+        self
+    }
+
+    /// Lists the messages delivered to `address`, oldest first.
+    pub async fn list_messages(&self, address: &str) -> Result<Vec<MessageSummary>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM messages WHERE to_addrs = ? ORDER BY received_at, id",
+            SUMMARY_COLUMNS
+        ))
+        .bind(address)
+        .fetch_all(&self.pool)
         .await?;
-        Ok(())
+        Ok(rows.iter().map(summary).collect())
     }
 
     async fn create_message(
         &self,
-        from: &str,
-        to: &[String],
-        message: &[u8],
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query(
+        incoming: &Incoming<'_>,
+        recipient: &message::Recipient,
+        content: Content<'_>,
+        delivery: &Delivery,
+    ) -> Result<(), BlobError> {
+        let Incoming {
+            from,
+            data: message,
+            metadata,
+            received_at,
+        } = *incoming;
+        let to = &recipient.address;
+        let encrypted = matches!(content, Content::Sealed(_));
+        // Nothing about the content of encrypted mail is kept in the clear.
+        let document = (!encrypted).then(|| SearchDocument::parse(message, from, to));
+        let query = sqlx::query(
             r#"
-               INSERT INTO messages (from_addr, to_addrs, message)
-               VALUES (?, ?, ?)
+               INSERT INTO messages (from_addr, to_addrs, message, received_at, size,
+                   folder, flags, encrypted, has_attachment, subject, from_display,
+                   to_display, cc_display, message_id, in_reply_to, references_ids)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
                "#,
         )
         .bind(from)
-        .bind(to.join(","))
-        .bind(message)
-        .execute(&self.pool)
-        .await?;
+        .bind(to)
+        .bind(&b""[..])
+        .bind(received_at)
+        .bind(message.len() as i64)
+        .bind(&delivery.folder)
+        .bind(delivery.flags.join(" "))
+        .bind(encrypted)
+        .bind(document.as_ref().is_some_and(|d| d.has_attachment));
+
+        let mut tx = self.pool.begin().await?;
+        let metadata = match encrypted {
+            true => &MessageMetadata::default(),
+            false => metadata,
+        };
+        let id = bind_metadata(query, metadata)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+        if let Some(document) = document {
+            document.insert(id).execute(&mut *tx).await?;
+        }
+        let blobs = match content {
+            Content::Plain(blobs) => blobs.clone(),
+            Content::Sealed(key) => self.put_sealed(&mut tx, key, to, id, message).await?,
+        };
+        Self::reference_blobs(&mut tx, id, &blobs).await?;
+        tx.commit().await?;
         Ok(())
     }
 }
 
+/// How a message is stored for one recipient.
+#[derive(Clone, Copy)]
+enum Content<'a> {
+    /// Blobs shared by every recipient who does not encrypt their mail.
+    Plain(&'a StoredBlobs),
+    /// Sealed with the recipient's key, once per message.
+    Sealed(&'a DataKey),
+}
+
+/// A message being delivered, as stored for each of its recipients.
+#[derive(Clone, Copy)]
+struct Incoming<'a> {
+    from: &'a str,
+    data: &'a [u8],
+    metadata: &'a MessageMetadata,
+    received_at: i64,
+}
+
+/// Fills in the metadata columns of messages stored before they existed.
+pub(super) fn backfill_metadata<'c>(
+    _store: &'c SqliteStore,
+    conn: &'c mut SqliteConnection,
+) -> BoxFuture<'c, Result<(), sqlx::Error>> {
+    Box::pin(async move {
+        // One message at a time, so that a large mailbox is never loaded
+        // at once.
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages")
+            .fetch_all(&mut *conn)
+            .await?;
+        for id in ids {
+            let data: Vec<u8> = sqlx::query_scalar("SELECT message FROM messages WHERE id = ?")
+                .bind(id)
+                .fetch_one(&mut *conn)
+                .await?;
+            let metadata = MessageMetadata::parse(&data);
+            bind_metadata(
+                sqlx::query(
+                    r#"
+                       UPDATE messages SET size = ?, subject = ?, from_display = ?,
+                           to_display = ?, cc_display = ?, message_id = ?,
+                           in_reply_to = ?, references_ids = ?
+                       WHERE id = ?
+                       "#,
+                )
+                .bind(data.len() as i64),
+                &metadata,
+            )
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    })
+}
+
+/// The columns [`summary`] reads, in order.
+pub(super) const SUMMARY_COLUMNS: &str = "id, received_at, size, flags, subject, from_display, \
+     to_display, cc_display, message_id, in_reply_to, references_ids, folder";
+
+pub(super) fn summary(row: &SqliteRow) -> MessageSummary {
+    MessageSummary {
+        id: row.get(0),
+        received_at: row.get(1),
+        size: row.get(2),
+        flags: row.get(3),
+        metadata: MessageMetadata {
+            subject: row.get(4),
+            from: row.get(5),
+            to: row.get(6),
+            cc: row.get(7),
+            message_id: row.get(8),
+            in_reply_to: row.get(9),
+            references: row.get(10),
+        },
+        folder: row.get(11),
+    }
+}
+
+pub(super) type Query<'q> = sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>>;
+
+fn bind_metadata<'q>(query: Query<'q>, metadata: &MessageMetadata) -> Query<'q> {
+    query
+        .bind(metadata.subject.clone())
+        .bind(metadata.from.clone())
+        .bind(metadata.to.clone())
+        .bind(metadata.cc.clone())
+        .bind(metadata.message_id.clone())
+        .bind(metadata.in_reply_to.clone())
+        .bind(metadata.references.clone())
+}
+
 #[async_trait]
 impl message::Handler for SqliteStore {
     async fn handle_message(
         &self,
-        message: message::Message,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.create_message(&message.from, &message.to, &message.data)
-            .await?;
-        Ok(())
+        message: &message::Message,
+    ) -> Result<(), message::HandlerError> {
+        message::summarize(&self.handle_recipients(message).await)
+    }
+
+    async fn handle_recipients(
+        &self,
+        message: &message::Message,
+    ) -> Vec<Result<(), message::HandlerError>> {
+        // Loaded whole: metadata, Sieve and the blob split all parse it.
+        let data = match message.data.to_vec().await {
+            Ok(data) => data,
+            Err(e) => return vec![Err(e.into()); message.recipients.len()],
+        };
+        let metadata = MessageMetadata::parse(&data);
+        let received_at = unix_time();
+        let incoming = Incoming {
+            from: &message.from,
+            data: &data,
+            metadata: &metadata,
+            received_at,
+        };
+        let _guard = self.gc_lock.read().await;
+        // Shared by every recipient who does not encrypt their mail.
+        let mut plain: Option<StoredBlobs> = None;
+        let mut results = Vec::with_capacity(message.recipients.len());
+        for recipient in &message.recipients {
+            let result = async {
+                // Alias targets elsewhere and bounces to rewritten senders
+                // are relayed rather than stored.
+                if recipient.forward {
+                    let sender = self.relay_sender(address::mailbox(&message.from)).await?;
+                    let recipients = std::slice::from_ref(&recipient.address);
+                    self.enqueue_outbound(&sender, recipients, &data, received_at)
+                        .await?;
+                    return Ok(());
+                }
+                let outcome = self.filter(message, recipient, &data).await?;
+                if let Some(reason) = outcome.reject {
+                    return Err(message::HandlerError::permanent(reason));
+                }
+                let key = self.delivery_key(&recipient.address).await?;
+                if !outcome.deliveries.is_empty() {
+                    let content = match &key {
+                        Some(key) => Content::Sealed(key),
+                        None => match &plain {
+                            Some(blobs) => Content::Plain(blobs),
+                            None => Content::Plain(plain.insert(self.put_blobs(&data).await?)),
+                        },
+                    };
+                    for delivery in &outcome.deliveries {
+                        self.create_message(&incoming, recipient, content, delivery)
+                            .await?;
+                    }
+                }
+                self.dispatch(message, recipient, &data, &outcome, received_at)
+                    .await?;
+                Ok::<_, message::HandlerError>(())
+            };
+            results.push(result.await);
+        }
+        results
     }
 }
 
@@ -84,17 +363,19 @@ mod tests {
         let test_message = message::Message {
             sender_domain: "example.com".to_string(),
             from: "alice@example.com".to_string(),
-            to: vec!["bob@example.com".to_string()],
-            data: b"Hello, Bob!".to_vec(),
+            to: vec!["<bob@example.com>".to_string()],
+            recipients: vec![message::Recipient::new("bob@example.com")],
+            data: b"Hello, Bob!"[..].into(),
+            ..Default::default()
         };
 
         // Handle the message (insert into the database)
-        store.handle_message(test_message).await.unwrap();
+        store.handle_message(&test_message).await.unwrap();
 
         // Verify the message was inserted
-        let row: (String, String, Vec<u8>) = sqlx::query_as(
+        let row: (String, String, i64) = sqlx::query_as(
             r#"
-            SELECT from_addr, to_addrs, message FROM messages
+            SELECT from_addr, to_addrs, id FROM messages
             "#,
         )
         .fetch_one(&store.pool)
@@ -103,6 +384,228 @@ mod tests {
 
         assert_eq!(row.0, "alice@example.com");
         assert_eq!(row.1, "bob@example.com");
-        assert_eq!(row.2, b"Hello, Bob!");
+        assert_eq!(
+            store.message_data(row.2).await.unwrap().unwrap(),
+            b"Hello, Bob!"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_metadata() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+
+        let test_message = message::Message {
+            from: "alice@example.com".to_string(),
+            recipients: vec![message::Recipient::new("bob@example.com")],
+            data: b"From: Alice <alice@example.com>\r\n\
+To: Bob <bob@example.com>\r\n\
+Subject: =?utf-8?q?Caf=C3=A9?=\r\n\
+Message-ID: <2@example.com>\r\n\
+In-Reply-To: <1@example.com>\r\n\
+References: <0@example.com>\r\n <1@example.com>\r\n\
+\r\n\
+Hi\r\n"[..]
+                .into(),
+            ..Default::default()
+        };
+        store.handle_message(&test_message).await.unwrap();
+
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].received_at.is_some());
+        assert_eq!(messages[0].size, test_message.data.len() as i64);
+        assert_eq!(messages[0].flags, "");
+        assert_eq!(
+            messages[0].metadata,
+            MessageMetadata {
+                subject: Some("Caf\u{e9}".to_string()),
+                from: Some("Alice <alice@example.com>".to_string()),
+                to: Some("Bob <bob@example.com>".to_string()),
+                cc: None,
+                message_id: Some("2@example.com".to_string()),
+                in_reply_to: Some("1@example.com".to_string()),
+                references: Some("0@example.com 1@example.com".to_string()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sieve_delivery() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let srs = Arc::new(Srs::new("secret", "example.com"));
+        let store = SqliteStore::new(temp_file.path())
+            .await
+            .unwrap()
+            .with_srs(srs.clone());
+        store.add_domain("example.com").await.unwrap();
+        let script = r#"require ["fileinto", "reject", "imap4flags", "vacation", "copy"];
+            if header :contains "subject" "spam" { reject "No thanks"; stop; }
+            if header :contains "subject" "lists" {
+                fileinto :flags "\\Seen" "Lists";
+                redirect :copy "carol@example.org";
+                stop;
+            }
+            vacation :days 3 "Away";"#;
+        store
+            .put_sieve_script("bob@example.com", "main", script)
+            .await
+            .unwrap();
+        store
+            .activate_sieve_script("bob@example.com", Some("main"))
+            .await
+            .unwrap();
+
+        let deliver = |subject: &str| {
+            let data = format!(
+                "From: alice@example.net\r\nTo: bob@example.com\r\nSubject: {}\r\n\r\nHi\r\n",
+                subject
+            );
+            message::Message {
+                from: "<alice@example.net>".to_string(),
+                to: vec!["<bob@example.com>".to_string()],
+                recipients: vec![message::Recipient {
+                    origins: vec![0],
+                    ..message::Recipient::new("bob@example.com")
+                }],
+                data: data.into_bytes().into(),
+                ..Default::default()
+            }
+        };
+        let e = store.handle_message(&deliver("spam")).await.unwrap_err();
+        assert!(!e.is_temporary());
+        assert!(e.to_string().contains("No thanks"));
+        store.handle_message(&deliver("lists")).await.unwrap();
+        store.handle_message(&deliver("hello")).await.unwrap();
+        store.handle_message(&deliver("again")).await.unwrap();
+        // Spam gets no vacation reply.
+        let junk = deliver("hello");
+        let junk = message::Message {
+            from: "<mallory@example.net>".to_string(),
+            recipients: vec![message::Recipient {
+                junk: true,
+                ..junk.recipients[0].clone()
+            }],
+            ..junk
+        };
+        store.handle_message(&junk).await.unwrap();
+
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        let stored: Vec<_> = messages
+            .iter()
+            .map(|m| (m.folder.as_str(), m.flags.as_str()))
+            .collect();
+        assert_eq!(
+            stored,
+            [
+                ("Lists", "\\Seen"),
+                ("INBOX", ""),
+                ("INBOX", ""),
+                ("Junk", "")
+            ]
+        );
+
+        // The redirect, from the rewritten sender, and a single auto-reply
+        // wait to be relayed.
+        let queued = store.claim_outbound(i64::MAX, 0).await.unwrap();
+        let queued: Vec<_> = queued
+            .iter()
+            .map(|q| (q.sender.as_str(), q.recipient.as_str()))
+            .collect();
+        assert_eq!(queued.len(), 2);
+        assert!(queued[0].0.starts_with("SRS0="));
+        assert!(queued[0].0.ends_with("@example.com"));
+        assert_eq!(srs.reverse(queued[0].0).unwrap(), "alice@example.net");
+        assert_eq!(queued[0].1, "carol@example.org");
+        assert_eq!(queued[1], ("", "alice@example.net"));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_alias() {
+        use crate::resolver::AddressResolver;
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let srs = Arc::new(Srs::new("secret", "example.com"));
+        let store = SqliteStore::new(temp_file.path())
+            .await
+            .unwrap()
+            .with_srs(srs.clone());
+        store.add_domain("example.com").await.unwrap();
+        store.add_user("bob@example.com").await.unwrap();
+        store
+            .add_alias("team@example.com", "bob@example.com")
+            .await
+            .unwrap();
+        store
+            .add_alias("team@example.com", "carol@elsewhere.test")
+            .await
+            .unwrap();
+
+        let message = message::Message {
+            from: "<alice@example.net>".to_string(),
+            to: vec!["<team@example.com>".to_string()],
+            recipients: store.resolve("team@example.com").await.unwrap(),
+            data: b"Subject: Hi\r\n\r\nHi\r\n"[..].into(),
+            ..Default::default()
+        };
+        store.handle_message(&message).await.unwrap();
+
+        assert_eq!(
+            store.list_messages("bob@example.com").await.unwrap().len(),
+            1
+        );
+        assert!(store
+            .list_messages("carol@elsewhere.test")
+            .await
+            .unwrap()
+            .is_empty());
+        let queued = store.claim_outbound(i64::MAX, 0).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].recipient, "carol@elsewhere.test");
+        assert_eq!(srs.reverse(&queued[0].sender).unwrap(), "alice@example.net");
+        assert_eq!(queued[0].message, b"Subject: Hi\r\n\r\nHi\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_metadata_migration() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let opts = SqliteConnectOptions::default()
+            .filename(temp_file.path())
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(opts).await.unwrap();
+        sqlx::query(
+            r#"
+               CREATE TABLE messages (
+                   id INTEGER PRIMARY KEY AUTOINCREMENT,
+                   from_addr TEXT NOT NULL,
+                   to_addrs TEXT NOT NULL,
+                   message BINARY NOT NULL
+               )
+               "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO messages (from_addr, to_addrs, message) VALUES (?, ?, ?)")
+            .bind("alice@example.com")
+            .bind("bob@example.com")
+            .bind(&b"Subject: Old\r\n\r\nHi\r\n"[..])
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let messages = store.list_messages("bob@example.com").await.unwrap();
+        assert_eq!(messages[0].received_at, None);
+        assert_eq!(messages[0].size, 20);
+        assert_eq!(messages[0].metadata.subject.as_deref(), Some("Old"));
+        assert_eq!(
+            store.message_data(messages[0].id).await.unwrap().unwrap(),
+            b"Subject: Old\r\n\r\nHi\r\n"
+        );
+
+        // Opening it again leaves the upgraded table alone.
+        drop(store);
+        SqliteStore::new(temp_file.path()).await.unwrap();
     }
 }
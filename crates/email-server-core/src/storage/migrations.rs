@@ -0,0 +1,297 @@
+use super::blob::backfill_blobs;
+use super::message::backfill_metadata;
+use super::message::Query;
+use super::search::backfill_search;
+use super::SqliteStore;
+use crate::time::unix_time;
+use futures::future::BoxFuture;
+use sqlx::{Executor, SqliteConnection};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// Rust code run after a migration's SQL, inside the same transaction.
+type Backfill =
+    for<'c> fn(&'c SqliteStore, &'c mut SqliteConnection) -> BoxFuture<'c, Result<(), sqlx::Error>>;
+
+/// One numbered step of the schema.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    sql: &'static str,
+    backfill: Option<Backfill>,
+}
+
+/// Every migration, in order. Append only: released migrations must never
+/// change.
+pub(super) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial",
+        sql: include_str!("migrations/0001_initial.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 2,
+        description: "message metadata",
+        sql: include_str!("migrations/0002_message_metadata.sql"),
+        backfill: Some(backfill_metadata),
+    },
+    Migration {
+        version: 3,
+        description: "full-text search",
+        sql: include_str!("migrations/0003_search.sql"),
+        backfill: Some(backfill_search),
+    },
+    Migration {
+        version: 4,
+        description: "blob storage",
+        sql: include_str!("migrations/0004_blobs.sql"),
+        backfill: Some(backfill_blobs),
+    },
+    Migration {
+        version: 5,
+        description: "encryption at rest",
+        sql: include_str!("migrations/0005_encryption.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 6,
+        description: "OpenPGP keys",
+        sql: include_str!("migrations/0006_pgp_keys.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 7,
+        description: "Autocrypt peers",
+        sql: include_str!("migrations/0007_autocrypt_peers.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 8,
+        description: "Sieve filtering",
+        sql: include_str!("migrations/0008_sieve.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 9,
+        description: "User passwords",
+        sql: include_str!("migrations/0009_passwords.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 10,
+        description: "Auto-responders",
+        sql: include_str!("migrations/0010_auto_responders.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 11,
+        description: "Spam classifier",
+        sql: include_str!("migrations/0011_spam.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 12,
+        description: "Greylisting",
+        sql: include_str!("migrations/0012_greylist.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 13,
+        description: "Partial deliveries",
+        sql: include_str!("migrations/0013_deliveries.sql"),
+        backfill: None,
+    },
+    Migration {
+        version: 14,
+        description: "Sealed queues",
+        sql: include_str!("migrations/0014_sealed_queues.sql"),
+        backfill: None,
+    },
+];
+
+/// The database was migrated by a newer release than this one.
+#[derive(Debug)]
+pub struct SchemaTooNew {
+    pub found: i64,
+    pub supported: i64,
+}
+
+impl Display for SchemaTooNew {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "SchemaTooNew: database is at version {} but only {} is supported",
+            self.found, self.supported
+        )
+    }
+}
+
+impl Error for SchemaTooNew {}
+
+impl SqliteStore {
+    /// The highest migration applied to the database, zero for a new one.
+    pub async fn schema_version(&self) -> Result<i64, sqlx::Error> {
+        let tracked: Option<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        if tracked.is_none() {
+            return Ok(0);
+        }
+        let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    /// Lists the migrations [`Self::migrate`] would apply, without touching
+    /// the database.
+    pub async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, sqlx::Error> {
+        pending(self.schema_version().await?, MIGRATIONS)
+    }
+
+    /// Brings the schema up to date, one transaction per migration, and
+    /// returns what was applied.
+    pub async fn migrate(&self) -> Result<Vec<&'static Migration>, sqlx::Error> {
+        self.apply(MIGRATIONS).await
+    }
+
+    async fn apply(
+        &self,
+        migrations: &'static [Migration],
+    ) -> Result<Vec<&'static Migration>, sqlx::Error> {
+        let current = self.schema_version().await?;
+        let pending = pending(current, migrations)?;
+        sqlx::query(
+            r#"
+               CREATE TABLE IF NOT EXISTS schema_version (
+                   version INTEGER PRIMARY KEY,
+                   description TEXT NOT NULL,
+                   applied_at INTEGER NOT NULL
+               )
+               "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        for migration in &pending {
+            tracing::info!(
+                "Applying migration {} ({})",
+                migration.version,
+                migration.description
+            );
+            let mut tx = self.pool.begin().await?;
+            run(self, &mut tx, migration).await?;
+            tx.commit().await?;
+        }
+        Ok(pending)
+    }
+}
+
+fn run<'c>(
+    store: &'c SqliteStore,
+    conn: &'c mut SqliteConnection,
+    migration: &'static Migration,
+) -> BoxFuture<'c, Result<(), sqlx::Error>> {
+    Box::pin(async move {
+        conn.execute(sqlx::raw_sql(migration.sql)).await?;
+        if let Some(backfill) = migration.backfill {
+            backfill(store, conn).await?;
+        }
+        record(migration).execute(&mut *conn).await?;
+        Ok(())
+    })
+}
+
+fn pending(
+    current: i64,
+    migrations: &'static [Migration],
+) -> Result<Vec<&'static Migration>, sqlx::Error> {
+    let supported = migrations.last().map(|m| m.version).unwrap_or(0);
+    if current > supported {
+        return Err(sqlx::Error::Configuration(Box::new(SchemaTooNew {
+            found: current,
+            supported,
+        })));
+    }
+    Ok(migrations.iter().filter(|m| m.version > current).collect())
+}
+
+fn record(migration: &Migration) -> Query<'static> {
+    let applied_at = unix_time();
+    sqlx::query("INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)")
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(applied_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_new_database_is_current() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        assert_eq!(store.schema_version().await.unwrap(), 14);
+        assert!(store.pending_migrations().await.unwrap().is_empty());
+        assert!(store.migrate().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_and_legacy_database() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::open(temp_file.path()).await.unwrap();
+        let pending = store.pending_migrations().await.unwrap();
+        assert_eq!(pending.len(), 14);
+        assert_eq!(store.schema_version().await.unwrap(), 0);
+
+        // A database from before migrations were tracked has the initial
+        // layout, which the first migration takes over.
+        sqlx::raw_sql(MIGRATIONS[0].sql)
+            .execute(&store.pool)
+            .await
+            .unwrap();
+        assert_eq!(store.migrate().await.unwrap().len(), 14);
+        assert_eq!(store.schema_version().await.unwrap(), 14);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_newer_database() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        sqlx::query("INSERT INTO schema_version VALUES (99, 'future', 0)")
+            .execute(&store.pool)
+            .await
+            .unwrap();
+        drop(store);
+
+        let Err(sqlx::Error::Configuration(e)) = SqliteStore::new(temp_file.path()).await else {
+            panic!("opened a database from the future");
+        };
+        assert!(e.is::<SchemaTooNew>());
+    }
+
+    #[tokio::test]
+    async fn test_failed_migration_rolls_back() {
+        static BROKEN: &[Migration] = &[Migration {
+            version: 1,
+            description: "broken",
+            sql: "CREATE TABLE t (x INTEGER); INSERT INTO missing VALUES (1);",
+            backfill: None,
+        }];
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::open(temp_file.path()).await.unwrap();
+        assert!(store.apply(BROKEN).await.is_err());
+        assert_eq!(store.schema_version().await.unwrap(), 0);
+        let table: Option<String> =
+            sqlx::query_scalar("SELECT name FROM sqlite_master WHERE name = 't'")
+                .fetch_optional(&store.pool)
+                .await
+                .unwrap();
+        assert_eq!(table, None);
+    }
+}
@@ -0,0 +1,35 @@
+-- The layout created before migrations were tracked, so databases from
+-- that time are taken over as they are.
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    from_addr TEXT NOT NULL,
+    to_addrs TEXT NOT NULL,
+    message BINARY NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS domains (
+    name TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    address TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS aliases (
+    address TEXT NOT NULL,
+    target TEXT NOT NULL,
+    PRIMARY KEY (address, target)
+);
+
+CREATE TABLE IF NOT EXISTS domain_aliases (
+    name TEXT PRIMARY KEY,
+    target TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webhook_retries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    payload BINARY NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL
+);
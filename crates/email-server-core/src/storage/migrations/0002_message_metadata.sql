@@ -0,0 +1,15 @@
+ALTER TABLE messages ADD COLUMN received_at INTEGER;
+ALTER TABLE messages ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE messages ADD COLUMN subject TEXT;
+ALTER TABLE messages ADD COLUMN from_display TEXT;
+ALTER TABLE messages ADD COLUMN to_display TEXT;
+ALTER TABLE messages ADD COLUMN cc_display TEXT;
+ALTER TABLE messages ADD COLUMN message_id TEXT;
+ALTER TABLE messages ADD COLUMN in_reply_to TEXT;
+ALTER TABLE messages ADD COLUMN references_ids TEXT;
+ALTER TABLE messages ADD COLUMN flags TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX messages_mailbox ON messages (to_addrs, received_at);
+CREATE INDEX messages_message_id ON messages (message_id);
+CREATE INDEX messages_in_reply_to ON messages (in_reply_to);
+CREATE INDEX messages_subject ON messages (subject);
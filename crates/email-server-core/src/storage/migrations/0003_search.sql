@@ -0,0 +1,13 @@
+ALTER TABLE messages ADD COLUMN has_attachment INTEGER NOT NULL DEFAULT 0;
+
+-- Indexed text of each message, keyed by `messages.id`.
+CREATE VIRTUAL TABLE messages_fts USING fts5 (
+    subject,
+    sender,
+    recipients,
+    body
+);
+
+CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+    DELETE FROM messages_fts WHERE rowid = old.id;
+END;
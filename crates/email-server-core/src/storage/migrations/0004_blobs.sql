@@ -0,0 +1,39 @@
+-- Bytes of the SQLite blob backend, keyed by SHA-256.
+CREATE TABLE blobs (
+    id TEXT PRIMARY KEY,
+    data BINARY NOT NULL
+);
+
+-- Reference counts for every backend. Rows are touched before a blob is
+-- written so that garbage collection leaves fresh blobs alone.
+CREATE TABLE blob_refs (
+    id TEXT PRIMARY KEY,
+    refs INTEGER NOT NULL DEFAULT 0,
+    touched_at INTEGER NOT NULL
+);
+
+CREATE INDEX blob_refs_unreferenced ON blob_refs (refs, touched_at);
+
+-- The message with its large parts cut out; `message` is left empty.
+ALTER TABLE messages ADD COLUMN blob_id TEXT;
+
+-- Large parts, spliced back into the message blob at `position`.
+CREATE TABLE message_parts (
+    message_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    blob_id TEXT NOT NULL,
+    PRIMARY KEY (message_id, position)
+);
+
+CREATE TRIGGER messages_blobs_delete AFTER DELETE ON messages BEGIN
+    UPDATE blob_refs SET refs = refs - 1, touched_at = unixepoch()
+    WHERE id = old.blob_id;
+    UPDATE blob_refs SET
+        refs = refs - (
+            SELECT COUNT(*) FROM message_parts
+            WHERE message_id = old.id AND blob_id = blob_refs.id
+        ),
+        touched_at = unixepoch()
+    WHERE id IN (SELECT blob_id FROM message_parts WHERE message_id = old.id);
+    DELETE FROM message_parts WHERE message_id = old.id;
+END;
@@ -0,0 +1,13 @@
+-- The data key of each user who encrypts their mail, wrapped once with a
+-- key derived from their password and once with the server's recovery key.
+CREATE TABLE user_keys (
+    address TEXT PRIMARY KEY,
+    salt BINARY NOT NULL,
+    iterations INTEGER NOT NULL,
+    password_wrapped BINARY NOT NULL,
+    recovery_wrapped BINARY NOT NULL
+);
+
+-- Encrypted messages are a single sealed blob with no metadata or search
+-- document.
+ALTER TABLE messages ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- OpenPGP public keys that incoming mail is encrypted to, in binary form.
+CREATE TABLE pgp_keys (
+    address TEXT PRIMARY KEY,
+    key BINARY NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Keys of correspondents learned from Autocrypt headers, per local user.
+CREATE TABLE autocrypt_peers (
+    owner TEXT NOT NULL,
+    address TEXT NOT NULL,
+    key BINARY NOT NULL,
+    prefer_encrypt INTEGER NOT NULL DEFAULT 0,
+    last_seen INTEGER NOT NULL,
+    PRIMARY KEY (owner, address)
+);
@@ -0,0 +1,32 @@
+-- Folders that Sieve scripts file mail into.
+ALTER TABLE messages ADD COLUMN folder TEXT NOT NULL DEFAULT 'INBOX';
+CREATE INDEX messages_folder ON messages (to_addrs, folder);
+
+-- Each user's Sieve scripts; at most one of them is active.
+CREATE TABLE sieve_scripts (
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    script TEXT NOT NULL,
+    active INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (owner, name)
+);
+
+-- Mail generated here, such as redirects and auto-replies, waiting to be
+-- relayed. One row per recipient.
+CREATE TABLE outbound_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sender TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    message BINARY NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL
+);
+
+-- When each user last sent an auto-reply to each sender.
+CREATE TABLE vacation_replies (
+    owner TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    handle TEXT NOT NULL,
+    replied_at INTEGER NOT NULL,
+    PRIMARY KEY (owner, sender, handle)
+);
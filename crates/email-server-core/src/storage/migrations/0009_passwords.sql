@@ -0,0 +1,7 @@
+-- Login passwords of users, as salted PBKDF2-HMAC-SHA256 hashes.
+CREATE TABLE user_passwords (
+    address TEXT PRIMARY KEY,
+    salt BINARY NOT NULL,
+    iterations INTEGER NOT NULL,
+    hash BINARY NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Out-of-office replies that users set up without a Sieve script.
+CREATE TABLE auto_responders (
+    owner TEXT PRIMARY KEY,
+    subject TEXT,
+    body TEXT NOT NULL,
+    days INTEGER NOT NULL
+);
@@ -0,0 +1,18 @@
+-- Per-user naive Bayes counts of hashed tokens, learned from mail moved
+-- into and out of the Junk folder.
+CREATE TABLE bayes_tokens (
+    owner TEXT NOT NULL,
+    token INTEGER NOT NULL,
+    spam INTEGER NOT NULL DEFAULT 0,
+    ham INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (owner, token)
+);
+
+CREATE TABLE bayes_totals (
+    owner TEXT PRIMARY KEY,
+    spam INTEGER NOT NULL DEFAULT 0,
+    ham INTEGER NOT NULL DEFAULT 0
+);
+
+-- What a message was learned as, so that moving it back unlearns it.
+ALTER TABLE messages ADD COLUMN learned TEXT;
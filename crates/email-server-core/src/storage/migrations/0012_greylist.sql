@@ -0,0 +1,19 @@
+-- Delivery attempts by client network, envelope sender and recipient.
+CREATE TABLE greylist (
+    network TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    passed INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (network, sender, recipient)
+);
+
+CREATE INDEX greylist_last_seen ON greylist (last_seen);
+
+-- How often client networks retried successfully, for auto-whitelisting.
+CREATE TABLE greylist_clients (
+    network TEXT PRIMARY KEY,
+    passes INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Recipients that got a message whose other recipients were deferred, so
+-- that the client's retry does not deliver it to them again.
+CREATE TABLE deliveries (
+    digest TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    delivered_at INTEGER NOT NULL,
+    PRIMARY KEY (digest, recipient)
+);
+
+CREATE INDEX deliveries_delivered_at ON deliveries (delivered_at);
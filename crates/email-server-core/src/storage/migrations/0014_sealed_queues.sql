@@ -0,0 +1,4 @@
+-- Queued mail and webhook payloads sealed with the recovery key, so that
+-- they expose no more than encrypted mailboxes do.
+ALTER TABLE outbound_queue ADD COLUMN sealed INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE webhook_retries ADD COLUMN sealed INTEGER NOT NULL DEFAULT 0;
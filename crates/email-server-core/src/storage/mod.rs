@@ -1,3 +1,28 @@
+mod alias;
+mod bayes;
+mod blob;
+mod delivery;
+mod directory;
+mod encryption;
+mod greylist;
 mod message;
+mod migrations;
+mod outbound;
+mod password;
+mod pgp;
+mod queue;
+mod search;
+mod sieve;
+mod vacation;
+mod webhook;
 
-pub use message::SqliteStore;
+pub use blob::{BlobError, BlobId, BlobStore, FsBlobStore, SqliteBlobStore, LARGE_PART_SIZE};
+pub use encryption::{DataKey, EncryptionError, RecoveryKey};
+pub use message::{MessageMetadata, MessageSummary, SqliteStore};
+pub use migrations::{Migration, SchemaTooNew};
+pub use outbound::OutboundMessage;
+pub use pgp::AutocryptPeer;
+pub use search::{SearchError, SearchQuery, Term};
+pub use sieve::ScriptError;
+pub use vacation::AutoResponder;
+pub use webhook::WebhookRetry;
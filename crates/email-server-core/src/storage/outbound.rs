@@ -0,0 +1,148 @@
+use super::SqliteStore;
+use crate::dsn;
+use crate::srs::Srs;
+use std::sync::Arc;
+
+const TABLE: &str = "outbound_queue";
+
+/// A message generated here, such as a redirect or an auto-reply, waiting
+/// to be relayed to one recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundMessage {
+    pub id: i64,
+    /// The envelope sender, empty for auto-replies.
+    pub sender: String,
+    pub recipient: String,
+    pub message: Vec<u8>,
+    pub attempts: i64,
+}
+
+impl SqliteStore {
+    /// Rewrites the senders of mail relayed for foreign domains with `srs`.
+    pub fn with_srs(mut self, srs: Arc<Srs>) -> Self {
+        self.srs = Some(srs);
+        self
+    }
+
+    /// The envelope sender to relay mail from `sender` with. Senders of
+    /// other domains are rewritten with SRS, if configured, so that the
+    /// mail passes SPF at its destination.
+    pub(super) async fn relay_sender(&self, sender: &str) -> Result<String, sqlx::Error> {
+        let (Some(srs), Some((_, domain))) = (&self.srs, sender.rsplit_once('@')) else {
+            return Ok(sender.to_string());
+        };
+        if self.is_local_domain(domain).await? {
+            return Ok(sender.to_string());
+        }
+        Ok(srs.forward(sender))
+    }
+
+    /// Queues a bounce telling the sender of `queued` about `failure`,
+    /// unwrapping senders rewritten with SRS. Mail from the null sender is
+    /// dropped silently.
+    pub async fn bounce_outbound(
+        &self,
+        queued: &OutboundMessage,
+        hostname: &str,
+        failure: &dsn::Failure,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let sender = match &self.srs {
+            Some(srs) if Srs::is_srs(&queued.sender) && srs.is_local(&queued.sender) => {
+                match srs.reverse(&queued.sender) {
+                    Ok(original) => original,
+                    Err(e) => {
+                        tracing::info!("Not bouncing to {}: {}", queued.sender, e);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => queued.sender.clone(),
+        };
+        if sender.is_empty() {
+            return Ok(());
+        }
+        let header = dsn::original_header(&queued.message[..]).await?;
+        let report = dsn::compose(
+            hostname,
+            &sender,
+            std::slice::from_ref(failure),
+            &header,
+            now,
+        );
+        self.enqueue_outbound("", &[sender], &report, now).await
+    }
+
+    /// Queues `message` for each of `recipients`, to be sent at once. It
+    /// is sealed with the recovery key, if there is one.
+    pub async fn enqueue_outbound(
+        &self,
+        sender: &str,
+        recipients: &[String],
+        message: &[u8],
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let (message, sealed) = self.seal_queued(TABLE, message);
+        let mut tx = self.pool.begin().await?;
+        for recipient in recipients {
+            sqlx::query(
+                r#"
+                   INSERT INTO outbound_queue (sender, recipient, message, sealed, next_attempt_at)
+                   VALUES (?, ?, ?, ?, ?)
+                   "#,
+            )
+            .bind(sender)
+            .bind(recipient)
+            .bind(&message)
+            .bind(sealed)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Takes the messages due at `now`, leased until `lease_until`.
+    pub async fn claim_outbound(
+        &self,
+        now: i64,
+        lease_until: i64,
+    ) -> Result<Vec<OutboundMessage>, sqlx::Error> {
+        let columns = "id, sender, recipient, message, sealed, attempts";
+        let rows: Vec<(i64, String, String, Vec<u8>, bool, i64)> =
+            self.claim_queued(TABLE, columns, now, lease_until).await?;
+        let mut claimed = Vec::with_capacity(rows.len());
+        for (id, sender, recipient, message, sealed, attempts) in rows {
+            // Stays queued until the recovery key is configured again.
+            let message = match self.open_queued(TABLE, message, sealed) {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::warn!("Cannot open queued mail to {}: {}", recipient, e);
+                    continue;
+                }
+            };
+            claimed.push(OutboundMessage {
+                id,
+                sender,
+                recipient,
+                message,
+                attempts,
+            });
+        }
+        Ok(claimed)
+    }
+
+    pub async fn reschedule_outbound(
+        &self,
+        id: i64,
+        attempts: i64,
+        next_attempt_at: i64,
+    ) -> Result<(), sqlx::Error> {
+        self.reschedule_queued(TABLE, id, attempts, next_attempt_at)
+            .await
+    }
+
+    pub async fn delete_outbound(&self, id: i64) -> Result<(), sqlx::Error> {
+        self.delete_queued(TABLE, id).await
+    }
+}
@@ -0,0 +1,162 @@
+use super::encryption::wrap_with_password;
+use super::{EncryptionError, SqliteStore};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use sqlx::SqliteConnection;
+use std::num::NonZeroU32;
+
+const HASH_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+
+impl SqliteStore {
+    /// Sets the password `address` logs in with, e.g. to ManageSieve. Users
+    /// who encrypt their mail unlock it with the same password, so their
+    /// data key is rewrapped for it with the recovery key.
+    pub async fn set_password(&self, address: &str, password: &str) -> Result<(), EncryptionError> {
+        let address = address.to_ascii_lowercase();
+        let wrapped = match self.delivery_key(&address).await? {
+            Some(key) => Some(wrap_with_password(&address, &key, password).await?),
+            None => None,
+        };
+        let hashed = hash_password(password).await?;
+        let mut tx = self.pool.begin().await?;
+        if let Some(wrapped) = wrapped {
+            Self::store_wrapped_key(&mut tx, &address, &wrapped).await?;
+        }
+        Self::store_password(&mut tx, &address, &hashed).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub(super) async fn store_password(
+        conn: &mut SqliteConnection,
+        address: &str,
+        (salt, hash): &([u8; SALT_LEN], [u8; HASH_LEN]),
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+               INSERT INTO user_passwords (address, salt, iterations, hash) VALUES (?, ?, ?, ?)
+               ON CONFLICT (address) DO UPDATE
+               SET salt = excluded.salt, iterations = excluded.iterations, hash = excluded.hash
+               "#,
+        )
+        .bind(address)
+        .bind(&salt[..])
+        .bind(PBKDF2_ITERATIONS as i64)
+        .bind(&hash[..])
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_password(&self, address: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM user_passwords WHERE address = ?")
+            .bind(address.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Whether `password` is the one of `address`. Users without a password
+    /// cannot log in, but take as long to refuse as a wrong password so
+    /// that addresses cannot be told apart by timing.
+    pub async fn check_password(&self, address: &str, password: &str) -> Result<bool, sqlx::Error> {
+        let row: Option<(Vec<u8>, i64, Vec<u8>)> =
+            sqlx::query_as("SELECT salt, iterations, hash FROM user_passwords WHERE address = ?")
+                .bind(address.to_ascii_lowercase())
+                .fetch_optional(&self.pool)
+                .await?;
+        let password = password.to_string();
+        let iterations = row
+            .as_ref()
+            .and_then(|(_, iterations, _)| u32::try_from(*iterations).ok())
+            .and_then(NonZeroU32::new);
+        let (Some((salt, _, hash)), Some(iterations)) = (row, iterations) else {
+            // The same work as a real check, against a hash nothing matches.
+            let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+            let (salt, hash) = (vec![0; SALT_LEN], vec![]);
+            blocking(move || verify(&salt, iterations, &password, &hash)).await?;
+            return Ok(false);
+        };
+        blocking(move || verify(&salt, iterations, &password, &hash)).await
+    }
+}
+
+/// Derives the login hash of `password` with a fresh salt.
+pub(super) async fn hash_password(
+    password: &str,
+) -> Result<([u8; SALT_LEN], [u8; HASH_LEN]), sqlx::Error> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .expect("system randomness is available");
+    let password = password.to_string();
+    blocking(move || {
+        let mut hash = [0; HASH_LEN];
+        let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+        let algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+        pbkdf2::derive(algorithm, iterations, &salt, password.as_bytes(), &mut hash);
+        (salt, hash)
+    })
+    .await
+}
+
+fn verify(salt: &[u8], iterations: NonZeroU32, password: &str, hash: &[u8]) -> bool {
+    let algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+    pbkdf2::verify(algorithm, iterations, salt, password.as_bytes(), hash).is_ok()
+}
+
+/// Runs `f` on a blocking thread, since key derivation takes long enough
+/// to stall other connections.
+pub(super) async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, sqlx::Error> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| sqlx::Error::Io(std::io::Error::other(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_passwords() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+
+        assert!(!store.check_password("bob@example.com", "").await.unwrap());
+        store
+            .set_password("Bob@example.com", "hunter2")
+            .await
+            .unwrap();
+        assert!(store
+            .check_password("bob@example.com", "hunter2")
+            .await
+            .unwrap());
+        assert!(!store
+            .check_password("bob@example.com", "hunter3")
+            .await
+            .unwrap());
+
+        store
+            .set_password("bob@example.com", "hunter3")
+            .await
+            .unwrap();
+        assert!(!store
+            .check_password("bob@example.com", "hunter2")
+            .await
+            .unwrap());
+        assert!(store
+            .check_password("bob@example.com", "hunter3")
+            .await
+            .unwrap());
+
+        store.remove_password("bob@example.com").await.unwrap();
+        assert!(!store
+            .check_password("bob@example.com", "hunter3")
+            .await
+            .unwrap());
+    }
+}
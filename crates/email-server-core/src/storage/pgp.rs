@@ -0,0 +1,98 @@
+use super::SqliteStore;
+
+impl SqliteStore {
+    /// Registers the OpenPGP public key mail to `address` is encrypted to,
+    /// replacing any earlier one.
+    pub async fn set_pgp_key(&self, address: &str, key: &[u8]) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR REPLACE INTO pgp_keys (address, key) VALUES (?, ?)")
+            .bind(address.to_ascii_lowercase())
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn pgp_key(&self, address: &str) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        sqlx::query_scalar("SELECT key FROM pgp_keys WHERE address = ?")
+            .bind(address.to_ascii_lowercase())
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn remove_pgp_key(&self, address: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM pgp_keys WHERE address = ?")
+            .bind(address.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_pgp_keys(&self) -> Result<Vec<(String, Vec<u8>)>, sqlx::Error> {
+        sqlx::query_as("SELECT address, key FROM pgp_keys ORDER BY address")
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+/// A correspondent's key, learned from the `Autocrypt` header of mail they
+/// sent to a local user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutocryptPeer {
+    pub address: String,
+    pub key: Vec<u8>,
+    /// The peer asked for encrypted replies (`prefer-encrypt=mutual`).
+    pub prefer_encrypt: bool,
+    /// Unix time of the latest message the key was seen in.
+    pub last_seen: i64,
+}
+
+impl SqliteStore {
+    /// Records `peer` in the keyring of `owner`, unless a newer key for
+    /// the same address is already there.
+    pub async fn update_autocrypt_peer(
+        &self,
+        owner: &str,
+        peer: &AutocryptPeer,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+               INSERT INTO autocrypt_peers (owner, address, key, prefer_encrypt, last_seen)
+               VALUES (?, ?, ?, ?, ?)
+               ON CONFLICT (owner, address) DO UPDATE SET
+                   key = excluded.key,
+                   prefer_encrypt = excluded.prefer_encrypt,
+                   last_seen = excluded.last_seen
+               WHERE excluded.last_seen >= autocrypt_peers.last_seen
+               "#,
+        )
+        .bind(owner.to_ascii_lowercase())
+        .bind(peer.address.to_ascii_lowercase())
+        .bind(&peer.key)
+        .bind(peer.prefer_encrypt)
+        .bind(peer.last_seen)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn autocrypt_peers(&self, owner: &str) -> Result<Vec<AutocryptPeer>, sqlx::Error> {
+        let rows: Vec<(String, Vec<u8>, bool, i64)> = sqlx::query_as(
+            r#"
+               SELECT address, key, prefer_encrypt, last_seen FROM autocrypt_peers
+               WHERE owner = ? ORDER BY address
+               "#,
+        )
+        .bind(owner.to_ascii_lowercase())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(address, key, prefer_encrypt, last_seen)| AutocryptPeer {
+                address,
+                key,
+                prefer_encrypt,
+                last_seen,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,60 @@
+//! Tables of work retried with backoff, such as `outbound_queue`: each row
+//! has an `id`, the `attempts` made so far, and when the next is due.
+
+use super::SqliteStore;
+use sqlx::sqlite::SqliteRow;
+use sqlx::FromRow;
+
+impl SqliteStore {
+    /// Takes the rows of `table` due at `now`, with their `columns`,
+    /// pushing their next attempt out to `lease_until` so that no other
+    /// worker takes them meanwhile.
+    pub(super) async fn claim_queued<T>(
+        &self,
+        table: &str,
+        columns: &str,
+        now: i64,
+        lease_until: i64,
+    ) -> Result<Vec<T>, sqlx::Error>
+    where
+        T: for<'r> FromRow<'r, SqliteRow> + Send + Unpin,
+    {
+        sqlx::query_as(&format!(
+            r#"
+               UPDATE {table} SET next_attempt_at = ?
+               WHERE next_attempt_at <= ?
+               RETURNING {columns}
+               "#
+        ))
+        .bind(lease_until)
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub(super) async fn reschedule_queued(
+        &self,
+        table: &str,
+        id: i64,
+        attempts: i64,
+        next_attempt_at: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "UPDATE {table} SET attempts = ?, next_attempt_at = ? WHERE id = ?"
+        ))
+        .bind(attempts)
+        .bind(next_attempt_at)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub(super) async fn delete_queued(&self, table: &str, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!("DELETE FROM {table} WHERE id = ?"))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
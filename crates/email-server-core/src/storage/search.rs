@@ -0,0 +1,370 @@
+use super::message::{summary, MessageSummary, Query, SUMMARY_COLUMNS};
+use super::SqliteStore;
+use crate::mime;
+use futures::future::BoxFuture;
+use sqlx::{QueryBuilder, Row, Sqlite, SqliteConnection};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum SearchError {
+    /// A `before:` or `after:` date that is not `YYYY-MM-DD`.
+    InvalidDate(String),
+    /// A `has:` term other than `has:attachment`.
+    Unsupported(String),
+    SqlError(sqlx::Error),
+}
+
+impl Display for SearchError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            SearchError::InvalidDate(date) => write!(f, "InvalidDate: {}", date),
+            SearchError::Unsupported(term) => write!(f, "Unsupported: {}", term),
+            SearchError::SqlError(e) => write!(f, "SqlError: {}", e),
+        }
+    }
+}
+
+impl Error for SearchError {}
+
+impl From<sqlx::Error> for SearchError {
+    fn from(e: sqlx::Error) -> Self {
+        SearchError::SqlError(e)
+    }
+}
+
+/// One condition of a [`SearchQuery`]; all of them must match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    /// Words anywhere in the subject, addresses or text body.
+    Text(String),
+    From(String),
+    To(String),
+    Subject(String),
+    HasAttachment,
+    /// Received before the start of this Unix day, in seconds.
+    Before(i64),
+    /// Received on or after the start of this Unix day, in seconds.
+    After(i64),
+}
+
+/// A parsed query such as `from:alice subject:"lunch plans" after:2024-01-31`.
+/// Quoted values are matched as phrases.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub terms: Vec<Term>,
+}
+
+impl FromStr for SearchQuery {
+    type Err = SearchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut terms = vec![];
+        for token in tokenize(s) {
+            let term = match token.split_once(':') {
+                Some(("from", value)) => Term::From(value.to_string()),
+                Some(("to", value)) => Term::To(value.to_string()),
+                Some(("subject", value)) => Term::Subject(value.to_string()),
+                Some(("has", "attachment")) => Term::HasAttachment,
+                Some(("has", _)) => return Err(SearchError::Unsupported(token)),
+                Some(("before", date)) => Term::Before(parse_date(date)?),
+                Some(("after", date)) => Term::After(parse_date(date)?),
+                _ => Term::Text(token),
+            };
+            terms.push(term);
+        }
+        Ok(Self { terms })
+    }
+}
+
+/// Splits at whitespace outside double quotes, dropping the quotes.
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in s.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Parses `YYYY-MM-DD` into the Unix time of its midnight, UTC.
+fn parse_date(date: &str) -> Result<i64, SearchError> {
+    let invalid = || SearchError::InvalidDate(date.to_string());
+    let mut fields = date.splitn(3, '-').map(|f| f.parse::<i64>());
+    let (Some(Ok(y)), Some(Ok(m)), Some(Ok(d))) = (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return Err(invalid());
+    }
+    // Days since the epoch in the proleptic Gregorian calendar.
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Ok((era * 146097 + doe - 719468) * 86400)
+}
+
+/// Quotes `value` as an FTS5 phrase so that it cannot inject query syntax.
+fn phrase(column: Option<&str>, value: &str) -> String {
+    let quoted = format!("\"{}\"", value.replace('"', "\"\""));
+    match column {
+        Some(column) => format!("{} : {}", column, quoted),
+        None => quoted,
+    }
+}
+
+/// The text of a message that goes into the full-text index.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct SearchDocument {
+    pub subject: String,
+    pub sender: String,
+    pub recipients: String,
+    pub body: String,
+    pub has_attachment: bool,
+}
+
+impl SearchDocument {
+    /// Indexes the decoded headers along with the envelope addresses.
+    pub fn parse(data: &[u8], from: &str, to: &str) -> Self {
+        let message = mime::Part::parse(data);
+        let header = |name| message.header(name).unwrap_or_default();
+        Self {
+            subject: header("subject"),
+            sender: format!("{} {}", header("from"), from),
+            recipients: format!("{} {} {}", header("to"), header("cc"), to),
+            body: message
+                .text_body()
+                .or_else(|| message.html_body())
+                .unwrap_or_default(),
+            has_attachment: !message.attachments().is_empty(),
+        }
+    }
+
+    pub fn insert(&self, id: i64) -> Query<'_> {
+        sqlx::query(
+            r#"
+               INSERT INTO messages_fts (rowid, subject, sender, recipients, body)
+               VALUES (?, ?, ?, ?, ?)
+               "#,
+        )
+        .bind(id)
+        .bind(&self.subject)
+        .bind(&self.sender)
+        .bind(&self.recipients)
+        .bind(&self.body)
+    }
+}
+
+/// Indexes the messages stored before search existed.
+pub(super) fn backfill_search<'c>(
+    _store: &'c SqliteStore,
+    conn: &'c mut SqliteConnection,
+) -> BoxFuture<'c, Result<(), sqlx::Error>> {
+    Box::pin(async move {
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages")
+            .fetch_all(&mut *conn)
+            .await?;
+        for id in ids {
+            let row = sqlx::query("SELECT from_addr, to_addrs, message FROM messages WHERE id = ?")
+                .bind(id)
+                .fetch_one(&mut *conn)
+                .await?;
+            let from: String = row.get(0);
+            let to: String = row.get(1);
+            let data: Vec<u8> = row.get(2);
+            let document = SearchDocument::parse(&data, &from, &to);
+            document.insert(id).execute(&mut *conn).await?;
+            sqlx::query("UPDATE messages SET has_attachment = ? WHERE id = ?")
+                .bind(document.has_attachment)
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+impl SqliteStore {
+    /// Finds the messages matching `query`, oldest first, optionally only
+    /// those delivered to `mailbox`.
+    pub async fn search(
+        &self,
+        mailbox: Option<&str>,
+        query: &SearchQuery,
+    ) -> Result<Vec<MessageSummary>, SearchError> {
+        let mut phrases = vec![];
+        let mut sql = QueryBuilder::<Sqlite>::new(format!(
+            "SELECT {} FROM messages WHERE 1 = 1",
+            SUMMARY_COLUMNS
+        ));
+        if let Some(mailbox) = mailbox {
+            sql.push(" AND to_addrs = ").push_bind(mailbox);
+        }
+        for term in &query.terms {
+            match term {
+                Term::Text(text) => phrases.push(phrase(None, text)),
+                Term::From(from) => phrases.push(phrase(Some("sender"), from)),
+                Term::To(to) => phrases.push(phrase(Some("recipients"), to)),
+                Term::Subject(subject) => phrases.push(phrase(Some("subject"), subject)),
+                Term::HasAttachment => {
+                    sql.push(" AND has_attachment = 1");
+                }
+                Term::Before(time) => {
+                    sql.push(" AND received_at < ").push_bind(*time);
+                }
+                Term::After(time) => {
+                    sql.push(" AND received_at >= ").push_bind(*time);
+                }
+            }
+        }
+        if !phrases.is_empty() {
+            sql.push(" AND id IN (SELECT rowid FROM messages_fts WHERE messages_fts MATCH ")
+                .push_bind(phrases.join(" AND "))
+                .push(")");
+        }
+        sql.push(" ORDER BY received_at, id");
+
+        let rows = sql.build().fetch_all(&self.pool).await?;
+        Ok(rows.iter().map(summary).collect())
+    }
+
+    /// Removes a message; the trigger on `messages` drops it from the index.
+    pub async fn delete_message(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM messages WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Handler, Message, Recipient};
+
+    async fn deliver(store: &SqliteStore, to: &str, data: &[u8]) {
+        let message = Message {
+            from: "<carol@example.net>".to_string(),
+            recipients: vec![Recipient::new(to)],
+            data: data.into(),
+            ..Default::default()
+        };
+        store.handle_message(&message).await.unwrap();
+    }
+
+    fn subjects(messages: &[MessageSummary]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter_map(|m| m.metadata.subject.as_deref())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        deliver(
+            &store,
+            "bob@example.com",
+            b"From: Alice <alice@example.com>\r\nSubject: Lunch plans\r\n\r\nTacos on Friday?\r\n",
+        )
+        .await;
+        deliver(
+            &store,
+            "bob@example.com",
+            b"From: Dave <dave@example.org>\r\nSubject: Report\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\nSee the attached report on lunch.\r\n\
+--b\r\nContent-Type: application/pdf\r\nContent-Disposition: attachment; filename=r.pdf\r\n\r\n\
+%PDF\r\n--b--\r\n",
+        )
+        .await;
+        deliver(
+            &store,
+            "erin@example.com",
+            b"From: Alice <alice@example.com>\r\nSubject: Lunch for Erin\r\n\r\nHi\r\n",
+        )
+        .await;
+
+        let search = |mailbox, query: &str| {
+            let store = store.clone();
+            let query = query.parse::<SearchQuery>().unwrap();
+            async move { store.search(mailbox, &query).await.unwrap() }
+        };
+        assert_eq!(
+            subjects(&search(Some("bob@example.com"), "lunch").await),
+            vec!["Lunch plans", "Report"]
+        );
+        assert_eq!(
+            subjects(&search(None, "from:alice@example.com").await),
+            vec!["Lunch plans", "Lunch for Erin"]
+        );
+        assert_eq!(
+            subjects(&search(None, "subject:lunch has:attachment").await),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            subjects(&search(None, "has:attachment").await),
+            vec!["Report"]
+        );
+        assert_eq!(
+            subjects(&search(None, "\"friday tacos\"").await),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            subjects(&search(None, "\"tacos on friday\" after:2020-01-01").await),
+            vec!["Lunch plans"]
+        );
+        assert!(search(None, "before:2020-01-01").await.is_empty());
+        // Query syntax is quoted, not interpreted.
+        assert!(search(None, "lunch\" OR \"x").await.is_empty());
+
+        let report = search(None, "report").await;
+        store.delete_message(report[0].id).await.unwrap();
+        assert!(search(None, "report").await.is_empty());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query: SearchQuery =
+            "from:alice subject:\"lunch plans\" has:attachment after:2024-03-01 tacos"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            query.terms,
+            vec![
+                Term::From("alice".to_string()),
+                Term::Subject("lunch plans".to_string()),
+                Term::HasAttachment,
+                Term::After(1709251200),
+                Term::Text("tacos".to_string()),
+            ]
+        );
+        assert!(matches!(
+            "before:2024-13-01".parse::<SearchQuery>(),
+            Err(SearchError::InvalidDate(_))
+        ));
+        assert!(matches!(
+            "has:stars".parse::<SearchQuery>(),
+            Err(SearchError::Unsupported(_))
+        ));
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+    }
+}
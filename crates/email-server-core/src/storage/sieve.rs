@@ -0,0 +1,342 @@
+use super::SqliteStore;
+use crate::message::{Message, Recipient};
+use crate::milter::QUARANTINE;
+use crate::mime;
+use crate::sieve::{Delivery, Envelope, Outcome, Script, SieveError, INBOX};
+use crate::smtp::address;
+use crate::spam::JUNK;
+use crate::vacation::AutoReply;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The script does not compile.
+    Invalid(SieveError),
+    NotFound(String),
+    /// Another script already has the name.
+    Exists(String),
+    /// The active script cannot be deleted.
+    Active(String),
+    SqlError(sqlx::Error),
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            ScriptError::Invalid(e) => write!(f, "Invalid: {}", e),
+            ScriptError::NotFound(name) => write!(f, "NotFound: {}", name),
+            ScriptError::Exists(name) => write!(f, "Exists: {}", name),
+            ScriptError::Active(name) => write!(f, "Active: {}", name),
+            ScriptError::SqlError(e) => write!(f, "SqlError: {}", e),
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+impl From<sqlx::Error> for ScriptError {
+    fn from(e: sqlx::Error) -> Self {
+        ScriptError::SqlError(e)
+    }
+}
+
+impl From<SieveError> for ScriptError {
+    fn from(e: SieveError) -> Self {
+        ScriptError::Invalid(e)
+    }
+}
+
+impl SqliteStore {
+    /// Stores a Sieve script of `owner`, replacing the one with the same
+    /// name. Scripts that do not compile are refused.
+    pub async fn put_sieve_script(
+        &self,
+        owner: &str,
+        name: &str,
+        script: &str,
+    ) -> Result<(), ScriptError> {
+        Script::compile(script)?;
+        sqlx::query(
+            r#"
+               INSERT INTO sieve_scripts (owner, name, script) VALUES (?, ?, ?)
+               ON CONFLICT (owner, name) DO UPDATE SET script = excluded.script
+               "#,
+        )
+        .bind(owner.to_ascii_lowercase())
+        .bind(name)
+        .bind(script)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn sieve_script(
+        &self,
+        owner: &str,
+        name: &str,
+    ) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT script FROM sieve_scripts WHERE owner = ? AND name = ?")
+            .bind(owner.to_ascii_lowercase())
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// The names of the scripts of `owner`, and whether each is active.
+    pub async fn list_sieve_scripts(
+        &self,
+        owner: &str,
+    ) -> Result<Vec<(String, bool)>, sqlx::Error> {
+        sqlx::query_as("SELECT name, active FROM sieve_scripts WHERE owner = ? ORDER BY name")
+            .bind(owner.to_ascii_lowercase())
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn delete_sieve_script(&self, owner: &str, name: &str) -> Result<(), ScriptError> {
+        match self.script_active(owner, name).await? {
+            None => return Err(ScriptError::NotFound(name.to_string())),
+            Some(true) => return Err(ScriptError::Active(name.to_string())),
+            Some(false) => {}
+        }
+        sqlx::query("DELETE FROM sieve_scripts WHERE owner = ? AND name = ? AND active = 0")
+            .bind(owner.to_ascii_lowercase())
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Renames a script, keeping whether it is active.
+    pub async fn rename_sieve_script(
+        &self,
+        owner: &str,
+        name: &str,
+        new_name: &str,
+    ) -> Result<(), ScriptError> {
+        if self.script_active(owner, name).await?.is_none() {
+            return Err(ScriptError::NotFound(name.to_string()));
+        }
+        if self.script_active(owner, new_name).await?.is_some() {
+            return Err(ScriptError::Exists(new_name.to_string()));
+        }
+        sqlx::query("UPDATE sieve_scripts SET name = ? WHERE owner = ? AND name = ?")
+            .bind(new_name)
+            .bind(owner.to_ascii_lowercase())
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Makes `name` the script run on mail to `owner`, or turns filtering
+    /// off for `None`.
+    pub async fn activate_sieve_script(
+        &self,
+        owner: &str,
+        name: Option<&str>,
+    ) -> Result<(), ScriptError> {
+        if let Some(name) = name {
+            if self.script_active(owner, name).await?.is_none() {
+                return Err(ScriptError::NotFound(name.to_string()));
+            }
+        }
+        sqlx::query("UPDATE sieve_scripts SET active = (name IS ?) WHERE owner = ?")
+            .bind(name)
+            .bind(owner.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn script_active(&self, owner: &str, name: &str) -> Result<Option<bool>, sqlx::Error> {
+        sqlx::query_scalar("SELECT active FROM sieve_scripts WHERE owner = ? AND name = ?")
+            .bind(owner.to_ascii_lowercase())
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// The compiled active script of `owner`. One that no longer compiles
+    /// is skipped, so that mail is still delivered.
+    pub async fn active_sieve_script(&self, owner: &str) -> Result<Option<Script>, sqlx::Error> {
+        let script: Option<(String, String)> =
+            sqlx::query_as("SELECT name, script FROM sieve_scripts WHERE owner = ? AND active = 1")
+                .bind(owner.to_ascii_lowercase())
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some((name, script)) = script else {
+            return Ok(None);
+        };
+        match Script::compile(&script) {
+            Ok(script) => Ok(Some(script)),
+            Err(e) => {
+                tracing::warn!("ignoring sieve script {} of {}: {}", name, owner, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs the active script of `recipient` on `data`, returning where to
+    /// store the message.
+    pub(super) async fn filter(
+        &self,
+        message: &Message,
+        recipient: &Recipient,
+        data: &[u8],
+    ) -> Result<Outcome, sqlx::Error> {
+        if recipient.quarantined {
+            return Ok(Outcome {
+                deliveries: vec![Delivery {
+                    folder: QUARANTINE.to_string(),
+                    flags: vec![],
+                }],
+                ..Default::default()
+            });
+        }
+        let mut outcome = match self.active_sieve_script(&recipient.address).await? {
+            Some(script) => script.run(&envelope(message, recipient), data),
+            None => Outcome::keep(),
+        };
+        // Spam the script did not file elsewhere goes to Junk.
+        if recipient.junk {
+            for delivery in &mut outcome.deliveries {
+                if delivery.folder == INBOX {
+                    delivery.folder = JUNK.to_string();
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Queues the redirects and the auto-reply of `outcome`, or of the
+    /// auto-responder, once the message was stored.
+    pub(super) async fn dispatch(
+        &self,
+        message: &Message,
+        recipient: &Recipient,
+        data: &[u8],
+        outcome: &Outcome,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let envelope = envelope(message, recipient);
+        if !outcome.redirects.is_empty() {
+            let sender = self.relay_sender(envelope.from).await?;
+            self.enqueue_outbound(&sender, &outcome.redirects, data, now)
+                .await?;
+        }
+        let mut addresses = vec![recipient.address.clone(), envelope.to.to_string()];
+        let reply = match &outcome.vacation {
+            // Quarantined and spam mail get no reply, not even from a script.
+            _ if recipient.junk || recipient.quarantined => None,
+            Some(vacation) => {
+                addresses.extend(vacation.addresses.iter().cloned());
+                Some(AutoReply {
+                    owner: recipient.address.clone(),
+                    addresses,
+                    from: vacation
+                        .from
+                        .clone()
+                        .unwrap_or_else(|| recipient.address.clone()),
+                    subject: vacation.subject.clone(),
+                    body: vacation.reason.clone(),
+                    mime: vacation.mime,
+                    handle: vacation.handle.clone(),
+                    interval: vacation.days as i64 * 86400,
+                })
+            }
+            // A script that answers itself overrides the auto-responder, and
+            // discarded mail gets no reply.
+            None if !outcome.deliveries.is_empty() => self
+                .auto_responder(&recipient.address)
+                .await?
+                .map(|responder| responder.auto_reply(&recipient.address, addresses)),
+            None => None,
+        };
+        if let Some(reply) = reply {
+            let part = mime::Part::parse(data);
+            self.send_auto_reply(&reply, envelope.from, &part, now)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The envelope as `recipient` saw it: the `RCPT TO` it was reached by,
+/// before alias resolution.
+fn envelope<'a>(message: &'a Message, recipient: &'a Recipient) -> Envelope<'a> {
+    let to = recipient
+        .origins
+        .first()
+        .and_then(|&i| message.to.get(i))
+        .map_or(recipient.address.as_str(), |to| address::mailbox(to));
+    Envelope {
+        from: address::mailbox(&message.from),
+        to,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scripts() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let owner = "bob@example.com";
+
+        let e = store
+            .put_sieve_script(owner, "bad", "fileinto \"x\";")
+            .await;
+        assert!(matches!(e, Err(ScriptError::Invalid(e)) if e.line == 1));
+        store.put_sieve_script(owner, "a", "keep;").await.unwrap();
+        store
+            .put_sieve_script(owner, "b", "discard;")
+            .await
+            .unwrap();
+        store.put_sieve_script(owner, "b", "stop;").await.unwrap();
+        assert_eq!(
+            store.sieve_script(owner, "b").await.unwrap().as_deref(),
+            Some("stop;")
+        );
+        assert!(store.active_sieve_script(owner).await.unwrap().is_none());
+
+        store.activate_sieve_script(owner, Some("a")).await.unwrap();
+        store
+            .activate_sieve_script("BOB@example.com", Some("b"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.activate_sieve_script(owner, Some("c")).await,
+            Err(ScriptError::NotFound(_))
+        ));
+        assert_eq!(
+            store.list_sieve_scripts(owner).await.unwrap(),
+            [("a".to_string(), false), ("b".to_string(), true)]
+        );
+        assert!(store.active_sieve_script(owner).await.unwrap().is_some());
+
+        assert!(matches!(
+            store.delete_sieve_script(owner, "b").await,
+            Err(ScriptError::Active(_))
+        ));
+        assert!(matches!(
+            store.rename_sieve_script(owner, "b", "a").await,
+            Err(ScriptError::Exists(_))
+        ));
+        store.rename_sieve_script(owner, "b", "c").await.unwrap();
+        assert_eq!(
+            store.list_sieve_scripts(owner).await.unwrap(),
+            [("a".to_string(), false), ("c".to_string(), true)]
+        );
+
+        store.activate_sieve_script(owner, None).await.unwrap();
+        store.delete_sieve_script(owner, "c").await.unwrap();
+        assert_eq!(
+            store.list_sieve_scripts(owner).await.unwrap(),
+            [("a".to_string(), false)]
+        );
+    }
+}
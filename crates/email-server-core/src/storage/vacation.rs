@@ -0,0 +1,212 @@
+use super::SqliteStore;
+use crate::mime;
+use crate::vacation::AutoReply;
+
+/// Replies of the auto-responder are rate limited apart from those of Sieve
+/// scripts.
+const RESPONDER_HANDLE: &str = "auto-responder";
+
+/// An out-of-office reply set up without a Sieve script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoResponder {
+    /// Defaults to the original subject with an `Auto:` prefix.
+    pub subject: Option<String>,
+    pub body: String,
+    /// Days before the same sender is answered again.
+    pub days: u64,
+}
+
+impl AutoResponder {
+    pub(super) fn auto_reply(&self, owner: &str, addresses: Vec<String>) -> AutoReply {
+        AutoReply {
+            owner: owner.to_string(),
+            addresses,
+            from: owner.to_string(),
+            subject: self.subject.clone(),
+            body: self.body.clone(),
+            mime: false,
+            handle: RESPONDER_HANDLE.to_string(),
+            interval: self.days as i64 * 86400,
+        }
+    }
+}
+
+impl SqliteStore {
+    /// Turns on the auto-responder of `owner`, replacing its settings.
+    pub async fn set_auto_responder(
+        &self,
+        owner: &str,
+        responder: &AutoResponder,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+               INSERT INTO auto_responders (owner, subject, body, days) VALUES (?, ?, ?, ?)
+               ON CONFLICT (owner) DO UPDATE
+               SET subject = excluded.subject, body = excluded.body, days = excluded.days
+               "#,
+        )
+        .bind(owner.to_ascii_lowercase())
+        .bind(&responder.subject)
+        .bind(&responder.body)
+        .bind(responder.days as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn auto_responder(&self, owner: &str) -> Result<Option<AutoResponder>, sqlx::Error> {
+        let row: Option<(Option<String>, String, i64)> =
+            sqlx::query_as("SELECT subject, body, days FROM auto_responders WHERE owner = ?")
+                .bind(owner.to_ascii_lowercase())
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(subject, body, days)| AutoResponder {
+            subject,
+            body,
+            days: days.max(0) as u64,
+        }))
+    }
+
+    pub async fn remove_auto_responder(&self, owner: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM auto_responders WHERE owner = ?")
+            .bind(owner.to_ascii_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Queues `reply` to the sender of `message` if RFC 3834 allows it and
+    /// the sender was not already answered within the reply's interval.
+    /// Returns whether a reply was queued.
+    pub async fn send_auto_reply(
+        &self,
+        reply: &AutoReply,
+        sender: &str,
+        message: &mime::Part<'_>,
+        now: i64,
+    ) -> Result<bool, sqlx::Error> {
+        if !reply.may_reply(sender, message)
+            || !self
+                .claim_vacation_reply(&reply.owner, sender, &reply.handle, reply.interval, now)
+                .await?
+        {
+            return Ok(false);
+        }
+        let data = reply.compose(sender, message, now);
+        self.enqueue_outbound("", &[sender.to_string()], &data, now)
+            .await?;
+        Ok(true)
+    }
+
+    /// Records an auto-reply from `owner` to `sender` unless one with the
+    /// same handle went out less than `interval` seconds before `now`.
+    /// Returns whether the reply should be sent.
+    pub async fn claim_vacation_reply(
+        &self,
+        owner: &str,
+        sender: &str,
+        handle: &str,
+        interval: i64,
+        now: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+               INSERT INTO vacation_replies (owner, sender, handle, replied_at)
+               VALUES (?, ?, ?, ?)
+               ON CONFLICT (owner, sender, handle) DO UPDATE SET
+                   replied_at = excluded.replied_at
+               WHERE vacation_replies.replied_at <= ?
+               "#,
+        )
+        .bind(owner.to_ascii_lowercase())
+        .bind(sender.to_ascii_lowercase())
+        .bind(handle)
+        .bind(now)
+        .bind(now - interval)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Handler, Message, Recipient};
+
+    fn message(headers: &str) -> Message {
+        let data = format!(
+            "From: alice@example.net\r\nTo: bob@example.com\r\n{}Subject: Lunch\r\n\r\nHi\r\n",
+            headers
+        );
+        Message {
+            from: "<alice@example.net>".to_string(),
+            to: vec!["<bob@example.com>".to_string()],
+            recipients: vec![Recipient {
+                origins: vec![0],
+                ..Recipient::new("bob@example.com")
+            }],
+            data: data.into_bytes().into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_responder() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let responder = AutoResponder {
+            subject: Some("Out of office".to_string()),
+            body: "Back on Monday.".to_string(),
+            days: 3,
+        };
+        store
+            .set_auto_responder("Bob@example.com", &responder)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.auto_responder("bob@example.com").await.unwrap(),
+            Some(responder)
+        );
+
+        store.handle_message(&message("")).await.unwrap();
+        store.handle_message(&message("")).await.unwrap();
+        store
+            .handle_message(&message("Precedence: bulk\r\n"))
+            .await
+            .unwrap();
+        store
+            .remove_auto_responder("bob@example.com")
+            .await
+            .unwrap();
+        store.handle_message(&message("")).await.unwrap();
+        assert_eq!(
+            store.list_messages("bob@example.com").await.unwrap().len(),
+            4
+        );
+
+        // One reply, within the rate limit and to personal mail only.
+        let queued = store.claim_outbound(i64::MAX, 0).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].sender, "");
+        assert_eq!(queued[0].recipient, "alice@example.net");
+        let reply = mime::Part::parse(&queued[0].message);
+        assert_eq!(reply.subject().unwrap(), "Out of office");
+        assert_eq!(reply.text().unwrap(), "Back on Monday.\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_claim_vacation_reply() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        let claim = |sender: &'static str, handle: &'static str, now| {
+            store.claim_vacation_reply("bob@example.com", sender, handle, 100, now)
+        };
+        assert!(claim("alice@example.net", "a", 1000).await.unwrap());
+        assert!(!claim("Alice@example.net", "a", 1050).await.unwrap());
+        assert!(claim("alice@example.net", "b", 1050).await.unwrap());
+        assert!(claim("carol@example.net", "a", 1050).await.unwrap());
+        assert!(claim("alice@example.net", "a", 1100).await.unwrap());
+        assert!(!claim("alice@example.net", "a", 1199).await.unwrap());
+    }
+}
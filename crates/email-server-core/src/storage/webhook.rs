@@ -0,0 +1,81 @@
+use super::SqliteStore;
+
+const TABLE: &str = "webhook_retries";
+
+/// A webhook delivery that failed and waits for another attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookRetry {
+    pub id: i64,
+    pub url: String,
+    pub payload: Vec<u8>,
+    pub attempts: i64,
+}
+
+impl SqliteStore {
+    /// Records a delivery as attempted once, returning its id. The payload
+    /// is sealed with the recovery key, if there is one.
+    pub async fn enqueue_webhook_retry(
+        &self,
+        url: &str,
+        payload: &[u8],
+        next_attempt_at: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let (payload, sealed) = self.seal_queued(TABLE, payload);
+        sqlx::query_scalar(
+            r#"
+               INSERT INTO webhook_retries (url, payload, sealed, attempts, next_attempt_at)
+               VALUES (?, ?, ?, 1, ?)
+               RETURNING id
+               "#,
+        )
+        .bind(url)
+        .bind(payload)
+        .bind(sealed)
+        .bind(next_attempt_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Takes the deliveries due at `now`, leased until `lease_until`.
+    pub async fn claim_webhook_retries(
+        &self,
+        now: i64,
+        lease_until: i64,
+    ) -> Result<Vec<WebhookRetry>, sqlx::Error> {
+        let columns = "id, url, payload, sealed, attempts";
+        let rows: Vec<(i64, String, Vec<u8>, bool, i64)> =
+            self.claim_queued(TABLE, columns, now, lease_until).await?;
+        let mut claimed = Vec::with_capacity(rows.len());
+        for (id, url, payload, sealed, attempts) in rows {
+            // Stays queued until the recovery key is configured again.
+            let payload = match self.open_queued(TABLE, payload, sealed) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    tracing::warn!("Cannot open webhook payload for {}: {}", url, e);
+                    continue;
+                }
+            };
+            claimed.push(WebhookRetry {
+                id,
+                url,
+                payload,
+                attempts,
+            });
+        }
+        Ok(claimed)
+    }
+
+    pub async fn reschedule_webhook_retry(
+        &self,
+        id: i64,
+        attempts: i64,
+        next_attempt_at: i64,
+    ) -> Result<(), sqlx::Error> {
+        self.reschedule_queued(TABLE, id, attempts, next_attempt_at)
+            .await
+    }
+
+    pub async fn delete_webhook_retry(&self, id: i64) -> Result<(), sqlx::Error> {
+        self.delete_queued(TABLE, id).await
+    }
+}
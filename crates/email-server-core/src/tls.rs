@@ -0,0 +1,32 @@
+//! TLS for the servers that offer STARTTLS.
+
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+
+pub use tokio_rustls::TlsAcceptor;
+
+/// Loads a PEM certificate chain and its PEM private key.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, std::io::Error> {
+    acceptor_from_pem(&std::fs::read(cert)?, &std::fs::read(key)?)
+}
+
+pub fn acceptor_from_pem(cert: &[u8], key: &[u8]) -> Result<TlsAcceptor, std::io::Error> {
+    let certs = CertificateDer::pem_slice_iter(cert)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_slice(key).map_err(invalid)?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn invalid<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,289 @@
+//! Automatic replies that follow RFC 3834, for the Sieve `vacation`
+//! action and the auto-responder.
+
+use crate::mime;
+use crate::smtp::address;
+use base64::Engine;
+
+/// Senders that are never answered: list software and bounces.
+const ROBOTS: &[&str] = &["mailer-daemon", "listserv", "majordomo", "postmaster"];
+
+/// An auto-reply a user wants to send, and how often.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoReply {
+    /// The user replying; replies are rate limited per user.
+    pub owner: String,
+    /// Every address of the user. Mail that names none of them in `To` or
+    /// `Cc` was not sent to them personally and gets no reply.
+    pub addresses: Vec<String>,
+    /// The `From` header of the reply.
+    pub from: String,
+    /// Defaults to the original subject with an `Auto:` prefix.
+    pub subject: Option<String>,
+    pub body: String,
+    /// `body` is a MIME entity with its own headers rather than text.
+    pub mime: bool,
+    /// Replies with the same handle share one rate limit.
+    pub handle: String,
+    /// Minimum time between replies to the same sender, in seconds.
+    pub interval: i64,
+}
+
+impl AutoReply {
+    /// Whether `sender` may be answered automatically for `message`.
+    pub fn may_reply(&self, sender: &str, message: &mime::Part) -> bool {
+        // This also rules out the null sender of bounces.
+        let Some((local, _)) = address::split(sender) else {
+            return false;
+        };
+        let local = local.to_ascii_lowercase();
+        if ROBOTS.contains(&local.as_str())
+            || local.starts_with("owner-")
+            || local.ends_with("-request")
+            || self
+                .addresses
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(sender))
+        {
+            return false;
+        }
+        let auto_submitted = message.header("auto-submitted");
+        if auto_submitted.is_some_and(|v| !v.trim().eq_ignore_ascii_case("no")) {
+            return false;
+        }
+        if ["list-id", "list-post", "list-unsubscribe"]
+            .iter()
+            .any(|name| message.header(name).is_some())
+        {
+            return false;
+        }
+        let precedence = message.header("precedence").unwrap_or_default();
+        if ["bulk", "list", "junk"].contains(&precedence.trim().to_ascii_lowercase().as_str()) {
+            return false;
+        }
+        message
+            .headers()
+            .iter()
+            .filter(|h| {
+                ["to", "cc", "resent-to", "resent-cc"]
+                    .iter()
+                    .any(|name| h.name.eq_ignore_ascii_case(name))
+            })
+            .flat_map(|h| mime::addresses(&h.value()))
+            .any(|a| {
+                self.addresses
+                    .iter()
+                    .any(|own| own.eq_ignore_ascii_case(&a))
+            })
+    }
+
+    /// The reply to `message` from `sender`, to be sent with a null
+    /// envelope sender.
+    pub fn compose(&self, sender: &str, message: &mime::Part, now: i64) -> Vec<u8> {
+        let subject = match &self.subject {
+            Some(subject) => one_line(subject),
+            None => format!("Auto: {}", one_line(&message.subject().unwrap_or_default())),
+        };
+        let domain = address::split(address::mailbox(&self.from))
+            .map_or_else(|| "localhost".to_string(), |(_, domain)| domain);
+        let mut reply = format!(
+            "From: {}\r\nTo: <{}>\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n",
+            one_line(&self.from),
+            sender,
+            encode_word(&subject),
+            date(now),
+            uuid::Uuid::new_v4(),
+            domain
+        );
+        if let Some(id) = message.header("message-id") {
+            let id = id.trim();
+            let references = message.header("references").unwrap_or_default();
+            reply.push_str(&format!("In-Reply-To: {}\r\n", id));
+            reply.push_str(&format!(
+                "References: {}\r\n",
+                format!("{} {}", references.trim(), id).trim()
+            ));
+        }
+        reply.push_str("Auto-Submitted: auto-replied\r\nMIME-Version: 1.0\r\n");
+        if !self.mime {
+            reply.push_str(
+                "Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            );
+        }
+        for line in self.body.lines() {
+            reply.push_str(line);
+            reply.push_str("\r\n");
+        }
+        reply.into_bytes()
+    }
+}
+
+/// `s` with line breaks and other control characters, which a decoded
+/// encoded word may hold, turned into spaces so that it cannot add header
+/// fields or end the header.
+fn one_line(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// An RFC 2047 encoded word for non-ASCII header text.
+fn encode_word(s: &str) -> String {
+    match s.is_ascii() {
+        true => s.to_string(),
+        false => format!(
+            "=?UTF-8?B?{}?=",
+            base64::engine::general_purpose::STANDARD.encode(s)
+        ),
+    }
+}
+
+/// Formats Unix time as an RFC 5322 date in UTC.
+pub(crate) fn date(now: i64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, secs) = (now.div_euclid(86400), now.rem_euclid(86400));
+    // Civil date from days since the epoch, after Howard Hinnant.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auto_reply() -> AutoReply {
+        AutoReply {
+            owner: "bob@example.com".to_string(),
+            addresses: vec!["bob@example.com".to_string(), "bob@example.org".to_string()],
+            from: "bob@example.com".to_string(),
+            subject: None,
+            body: "Away until Monday.\n".to_string(),
+            mime: false,
+            handle: String::new(),
+            interval: 7 * 86400,
+        }
+    }
+
+    #[test]
+    fn test_may_reply() {
+        let reply = auto_reply();
+        let to_bob = "To: Bob <BOB@example.org>\r\n";
+        for (sender, headers, expected) in [
+            ("alice@example.net", "", true),
+            ("", "", false),
+            ("MAILER-DAEMON@example.net", "", false),
+            ("owner-list@example.net", "", false),
+            ("list-request@example.net", "", false),
+            ("bob@example.com", "", false),
+            ("alice@example.net", "Auto-Submitted: no\r\n", true),
+            (
+                "alice@example.net",
+                "Auto-Submitted: auto-replied\r\n",
+                false,
+            ),
+            (
+                "alice@example.net",
+                "List-Id: <list.example.net>\r\n",
+                false,
+            ),
+            ("alice@example.net", "Precedence: Bulk\r\n", false),
+        ] {
+            let data = format!("{}{}Subject: Hi\r\n\r\nHello\r\n", to_bob, headers);
+            let message = mime::Part::parse(data.as_bytes());
+            assert_eq!(
+                reply.may_reply(sender, &message),
+                expected,
+                "{} {}",
+                sender,
+                headers
+            );
+        }
+        // Mail that reached Bob through Bcc or a list he is on.
+        let message = mime::Part::parse(b"To: team@example.com\r\n\r\nHello\r\n");
+        assert!(!reply.may_reply("alice@example.net", &message));
+    }
+
+    #[test]
+    fn test_compose() {
+        let message = mime::Part::parse(
+            b"Subject: Lunch\r\nMessage-ID: <2@example.net>\r\nReferences: <1@example.net>\r\n\r\nHi\r\n",
+        );
+        let data = auto_reply().compose("alice@example.net", &message, 1_700_000_000);
+        let reply = mime::Part::parse(&data);
+        assert_eq!(reply.header("to").unwrap(), "<alice@example.net>");
+        assert_eq!(reply.subject().unwrap(), "Auto: Lunch");
+        assert_eq!(
+            reply.header("date").unwrap(),
+            "Tue, 14 Nov 2023 22:13:20 +0000"
+        );
+        assert_eq!(reply.header("in-reply-to").unwrap(), "<2@example.net>");
+        assert_eq!(
+            reply.header("references").unwrap(),
+            "<1@example.net> <2@example.net>"
+        );
+        assert_eq!(reply.header("auto-submitted").unwrap(), "auto-replied");
+        assert!(reply
+            .header("message-id")
+            .unwrap()
+            .ends_with("@example.com>"));
+        assert_eq!(reply.text().unwrap(), "Away until Monday.\r\n");
+
+        let reply = AutoReply {
+            subject: Some("Abwesenheit f\u{fc}r Bob".to_string()),
+            ..auto_reply()
+        };
+        let data = reply.compose("alice@example.net", &message, 0);
+        let reply = mime::Part::parse(&data);
+        assert_eq!(reply.subject().unwrap(), "Abwesenheit f\u{fc}r Bob");
+        assert_eq!(
+            reply.header("date").unwrap(),
+            "Thu, 1 Jan 1970 00:00:00 +0000"
+        );
+    }
+
+    #[test]
+    fn test_compose_keeps_subject_on_one_line() {
+        let message = mime::Part::parse(
+            b"Subject: =?us-ascii?Q?x=0D=0ABcc:_mallory@example.net=0D=0A=0D=0Abody?=\r\n\r\nHi\r\n",
+        );
+        let reply = AutoReply {
+            from: "bob@example.com\r\nBcc: mallory@example.net".to_string(),
+            ..auto_reply()
+        };
+        let data = reply.compose("alice@example.net", &message, 0);
+        let reply = mime::Part::parse(&data);
+        assert!(reply.header("bcc").is_none());
+        assert_eq!(
+            reply.subject().unwrap(),
+            "Auto: x  Bcc: mallory@example.net    body"
+        );
+        assert_eq!(reply.text().unwrap(), "Away until Monday.\r\n");
+
+        let reply = AutoReply {
+            subject: Some("Away\nBcc: mallory@example.net".to_string()),
+            ..auto_reply()
+        };
+        let data = reply.compose("alice@example.net", &message, 0);
+        assert!(mime::Part::parse(&data).header("bcc").is_none());
+    }
+}
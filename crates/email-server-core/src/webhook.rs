@@ -0,0 +1,377 @@
+use crate::message::{self, HandlerError, Message};
+use crate::mime;
+use crate::retry::{Backoff, LEASE};
+use crate::storage::SqliteStore;
+use crate::time::unix_time;
+use async_trait::async_trait;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Header carrying `sha256=<hex>`, the HMAC-SHA256 of the request body.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Deliveries still failing after this many attempts are dropped.
+pub const MAX_ATTEMPTS: i64 = 12;
+
+const BACKOFF: Backoff = Backoff {
+    initial: 30,
+    max: 6 * 60 * 60,
+};
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum WebhookError {
+    HttpError(reqwest::Error),
+    /// The endpoint answered with something other than 2xx.
+    Status(reqwest::StatusCode),
+}
+
+impl Display for WebhookError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            WebhookError::HttpError(e) => write!(f, "HttpError: {}", e),
+            WebhookError::Status(status) => write!(f, "Status: {}", status),
+        }
+    }
+}
+
+impl Error for WebhookError {}
+
+impl From<reqwest::Error> for WebhookError {
+    fn from(e: reqwest::Error) -> Self {
+        WebhookError::HttpError(e)
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    envelope: Envelope<'a>,
+    parsed: Parsed,
+    /// The message as received, base64 encoded.
+    #[serde(serialize_with = "base64")]
+    raw: &'a [u8],
+}
+
+/// Encodes straight into the output, without a copy of the message.
+fn base64<S: serde::Serializer>(data: &&[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    serializer.collect_str(&base64::display::Base64Display::new(data, &engine))
+}
+
+#[derive(Serialize)]
+struct Parsed {
+    subject: Option<String>,
+    headers: Vec<(String, String)>,
+    text: Option<String>,
+    html: Option<String>,
+    attachments: Vec<mime::Attachment>,
+}
+
+impl Parsed {
+    fn new(mime: &mime::Part) -> Self {
+        Self {
+            subject: mime.subject(),
+            headers: mime
+                .headers()
+                .iter()
+                .map(|h| (h.name.to_string(), h.value()))
+                .collect(),
+            text: mime.text_body(),
+            html: mime.html_body(),
+            attachments: mime.attachments(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    helo: &'a str,
+    from: &'a str,
+    to: &'a [String],
+    recipients: Vec<&'a str>,
+}
+
+/// Posts every message as JSON to an HTTP endpoint. Failed posts are kept
+/// in the store and retried with exponential backoff by [`Self::run_retries`],
+/// so an unavailable endpoint never bounces mail.
+#[derive(Debug, Clone)]
+pub struct WebhookHandler {
+    client: reqwest::Client,
+    url: String,
+    secret: Vec<u8>,
+    store: SqliteStore,
+}
+
+impl WebhookHandler {
+    pub fn new(url: impl Into<String>, secret: impl Into<Vec<u8>>, store: SqliteStore) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("TLS backend should initialize"),
+            url: url.into(),
+            secret: secret.into(),
+            store,
+        }
+    }
+
+    async fn payload(message: &Message) -> std::io::Result<Bytes> {
+        // The payload carries the whole message along with its parse; the
+        // message is dropped once it is encoded.
+        let data = message.data.to_vec().await?;
+        let payload = Payload {
+            envelope: Envelope {
+                helo: &message.sender_domain,
+                from: &message.from,
+                to: &message.to,
+                recipients: message
+                    .recipients
+                    .iter()
+                    .map(|r| r.address.as_str())
+                    .collect(),
+            },
+            parsed: Parsed::new(&mime::Part::parse(&data)),
+            raw: &data,
+        };
+        Ok(serde_json::to_vec(&payload)?.into())
+    }
+
+    async fn post(&self, url: &str, payload: Bytes) -> Result<(), WebhookError> {
+        let response = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature(&self.secret, &payload))
+            .body(payload)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(WebhookError::Status(response.status()));
+        }
+        Ok(())
+    }
+
+    /// Makes one more attempt at every delivery that is due, returning how
+    /// many succeeded.
+    pub async fn retry_due(&self) -> Result<usize, sqlx::Error> {
+        let now = unix_time();
+        let mut delivered = 0;
+        for retry in self.store.claim_webhook_retries(now, now + LEASE).await? {
+            match self.post(&retry.url, retry.payload.into()).await {
+                Ok(()) => {
+                    self.store.delete_webhook_retry(retry.id).await?;
+                    delivered += 1;
+                }
+                Err(e) if retry.attempts + 1 >= MAX_ATTEMPTS => {
+                    tracing::error!(
+                        "Giving up on webhook {} after {} attempts: {}",
+                        retry.url,
+                        retry.attempts + 1,
+                        e
+                    );
+                    self.store.delete_webhook_retry(retry.id).await?;
+                }
+                Err(e) => {
+                    tracing::warn!("Webhook {} failed again: {}", retry.url, e);
+                    let attempts = retry.attempts + 1;
+                    self.store
+                        .reschedule_webhook_retry(retry.id, attempts, now + BACKOFF.after(attempts))
+                        .await?;
+                }
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Retries failed deliveries every `interval`, forever.
+    pub async fn run_retries(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.retry_due().await {
+                tracing::error!("Failed to retry webhooks: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns the signature header value for `payload`.
+pub fn signature(secret: &[u8], payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take key of any size");
+    mac.update(payload);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[async_trait]
+impl message::Handler for WebhookHandler {
+    async fn handle_message(&self, message: &Message) -> Result<(), HandlerError> {
+        let payload = Self::payload(message).await?;
+        // Queued, leased to this attempt, before it is made: an attempt cut
+        // short by the caller's timeout is then retried too.
+        let now = unix_time();
+        let id = self
+            .store
+            .enqueue_webhook_retry(&self.url, &payload, now + LEASE)
+            .await?;
+        match self.post(&self.url, payload).await {
+            Ok(()) => self.store.delete_webhook_retry(id).await?,
+            Err(e) => {
+                tracing::warn!("Webhook {} failed, will retry: {}", self.url, e);
+                self.store
+                    .reschedule_webhook_retry(id, 1, now + BACKOFF.after(1))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Handler, Recipient};
+    use base64::Engine;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[derive(Debug, Clone, Default)]
+    struct Request {
+        signature: String,
+        body: Vec<u8>,
+    }
+
+    /// A tiny HTTP server answering each request with the next status from
+    /// `statuses` and recording what it received.
+    async fn http_sink(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut socket = BufReader::new(socket);
+                let mut request = Request::default();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    socket.read_line(&mut line).await.unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_once(": ").unwrap_or((line, ""));
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.parse().unwrap();
+                    } else if name.eq_ignore_ascii_case(SIGNATURE_HEADER) {
+                        request.signature = value.to_string();
+                    }
+                }
+                request.body = vec![0; length];
+                socket.read_exact(&mut request.body).await.unwrap();
+                received.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                socket
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    async fn store() -> (SqliteStore, tempfile::NamedTempFile) {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        (SqliteStore::new(file.path()).await.unwrap(), file)
+    }
+
+    fn message() -> Message {
+        Message {
+            sender_domain: "example.net".to_string(),
+            from: "<carol@example.net>".to_string(),
+            to: vec!["<alice@example.com>".to_string()],
+            recipients: vec![Recipient::new("alice@example.com")],
+            data: b"Subject: Hi\r\n\r\nHello\r\n"[..].into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_posts_signed_payload() {
+        let (url, requests) = http_sink(vec![200]).await;
+        let (store, _file) = store().await;
+        let handler = WebhookHandler::new(url, "secret", store.clone());
+
+        handler.handle_message(&message()).await.unwrap();
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.signature, signature(b"secret", &request.body));
+        let json: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(json["envelope"]["from"], "<carol@example.net>");
+        assert_eq!(json["envelope"]["recipients"][0], "alice@example.com");
+        assert_eq!(json["parsed"]["subject"], "Hi");
+        assert_eq!(json["parsed"]["text"], "Hello\r\n");
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD
+                .decode(json["raw"].as_str().unwrap())
+                .unwrap(),
+            b"Subject: Hi\r\n\r\nHello\r\n"
+        );
+        assert!(store
+            .claim_webhook_retries(i64::MAX, i64::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failure_is_retried() {
+        let (url, requests) = http_sink(vec![503, 200]).await;
+        let (store, _file) = store().await;
+        let handler = WebhookHandler::new(url, "secret", store.clone());
+
+        // The message is still accepted while the endpoint is down.
+        handler.handle_message(&message()).await.unwrap();
+        let retries = store
+            .claim_webhook_retries(i64::MAX, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].attempts, 1);
+
+        // Leased by the claim above, as by another worker.
+        assert_eq!(handler.retry_due().await.unwrap(), 0);
+        store
+            .reschedule_webhook_retry(retries[0].id, 1, 0)
+            .await
+            .unwrap();
+        assert_eq!(handler.retry_due().await.unwrap(), 1);
+        assert!(store
+            .claim_webhook_retries(i64::MAX, i64::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, requests[1].body);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(BACKOFF.after(1), 30);
+        assert_eq!(BACKOFF.after(2), 60);
+        assert_eq!(BACKOFF.after(5), 480);
+        assert_eq!(BACKOFF.after(MAX_ATTEMPTS), BACKOFF.max);
+    }
+}
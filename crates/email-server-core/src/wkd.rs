@@ -0,0 +1,195 @@
+use crate::pgp::PublicKey;
+use crate::smtp::address;
+use crate::socket::{SocketError, SocketHandler};
+use crate::storage::SqliteStore;
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use sha1::{Digest, Sha1};
+use tokio::net::TcpStream;
+
+const PREFIX: &str = "/.well-known/openpgpkey/";
+const ZBASE32: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+/// The z-base-32 SHA-1 of the lowercased local part, as WKD names keys.
+pub fn hash_local_part(local: &str) -> String {
+    let digest = Sha1::digest(local.to_lowercase().as_bytes());
+    let mut hash = String::with_capacity(32);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in digest {
+        buffer = buffer << 8 | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            hash.push(ZBASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    hash
+}
+
+/// Serves the registered OpenPGP keys of local users over the Web Key
+/// Directory protocol, in both the direct (`/.well-known/openpgpkey/hu/`)
+/// and the advanced (`/.well-known/openpgpkey/<domain>/hu/`) layout. WKD
+/// clients only use HTTPS, so this runs behind a TLS terminating proxy.
+#[derive(Clone)]
+pub struct Server {
+    store: SqliteStore,
+}
+
+impl Server {
+    pub fn new(store: SqliteStore) -> Self {
+        Self { store }
+    }
+
+    /// Returns the body to answer `path` with, or `None` for a 404. The
+    /// direct layout takes the domain from `host`.
+    pub async fn lookup(&self, host: &str, path: &str) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        let Some(rest) = path.strip_prefix(PREFIX) else {
+            return Ok(None);
+        };
+        let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+        let (domain, rest) = match rest.split_once('/') {
+            Some(("hu", _)) | None => (host.to_string(), rest),
+            Some((domain, rest)) => (domain.to_string(), rest),
+        };
+        let domain = domain.to_ascii_lowercase();
+        if !self.store.is_local_domain(&domain).await? {
+            return Ok(None);
+        }
+        match rest.split_once('/') {
+            // Publishing the policy file tells clients the domain supports WKD.
+            None if rest == "policy" => Ok(Some(vec![])),
+            Some(("hu", hash)) => self.key(&domain, hash).await,
+            _ => Ok(None),
+        }
+    }
+
+    async fn key(&self, domain: &str, hash: &str) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        for (address, key) in self.store.list_pgp_keys().await? {
+            let Some((local, key_domain)) = address::split(&address) else {
+                continue;
+            };
+            if key_domain == domain && hash_local_part(local) == hash {
+                // Keys were validated on import, but serve only sound ones.
+                return Ok(PublicKey::parse(&key).ok().map(|_| key));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn respond<B>(&self, request: Request<B>) -> Response<Full<Bytes>> {
+        let host = request
+            .headers()
+            .get(hyper::header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+        let (status, body) = match self.lookup(host, request.uri().path()).await {
+            Ok(Some(body)) => (StatusCode::OK, body),
+            Ok(None) => (StatusCode::NOT_FOUND, vec![]),
+            Err(e) => {
+                tracing::error!("failed to look up WKD key: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, vec![])
+            }
+        };
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/octet-stream"),
+        );
+        response.headers_mut().insert(
+            hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            hyper::header::HeaderValue::from_static("*"),
+        );
+        response
+    }
+}
+
+#[async_trait]
+impl SocketHandler for Server {
+    async fn handle_connection(&mut self, stream: TcpStream) -> Result<(), SocketError> {
+        let server = self.clone();
+        let service = hyper::service::service_fn(move |request| {
+            let server = server.clone();
+            async move { Ok::<_, std::convert::Infallible>(server.respond(request).await) }
+        });
+        hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+            .map_err(SocketError::boxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_hash_local_part() {
+        // The example from draft-koch-openpgp-webkey-service.
+        assert_eq!(
+            hash_local_part("Joe.Doe"),
+            "iy9q119eutrkn8s1mk4r39qejnbu3n5q"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wkd() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let store = SqliteStore::new(temp_file.path()).await.unwrap();
+        store.add_domain("example.com").await.unwrap();
+        let key = PublicKey::parse(include_bytes!("../tests/data/bob.asc")).unwrap();
+        let key = key.to_bytes();
+        store.set_pgp_key("bob@example.com", key).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            if let Err(e) = crate::socket::run(listener, Server::new(store)).await {
+                tracing::error!("WKD Server Error: {}", e);
+            }
+        });
+        let client = reqwest::Client::new();
+        let get = |host: &'static str, path: String| {
+            client
+                .get(format!("http://{}{}", addr, path))
+                .header("Host", host)
+                .send()
+        };
+
+        let hash = hash_local_part("bob");
+        for (host, path) in [
+            (
+                "example.com",
+                format!("/.well-known/openpgpkey/hu/{}", hash),
+            ),
+            (
+                "openpgpkey.example.com",
+                format!("/.well-known/openpgpkey/example.com/hu/{}?l=bob", hash),
+            ),
+        ] {
+            let response = get(host, path).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.bytes().await.unwrap(), key);
+        }
+        let policy = get("example.com", "/.well-known/openpgpkey/policy".to_string());
+        assert_eq!(policy.await.unwrap().status(), 200);
+
+        for (host, path) in [
+            (
+                "example.com",
+                format!("/.well-known/openpgpkey/hu/{}", hash_local_part("eve")),
+            ),
+            (
+                "elsewhere.test",
+                format!("/.well-known/openpgpkey/hu/{}", hash),
+            ),
+            ("example.com", "/index.html".to_string()),
+        ] {
+            assert_eq!(get(host, path).await.unwrap().status(), 404);
+        }
+    }
+}
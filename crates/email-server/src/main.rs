@@ -1,5 +1,10 @@
-use clap::Parser;
-use email_server_core::logging;
+use clap::{Parser, Subcommand};
+use email_server_core::smtp::greylist;
+use email_server_core::storage::{AutoResponder, FsBlobStore, RecoveryKey, SqliteStore};
+use email_server_core::{dnsbl, lmtp, logging, milter, pgp, sieve, smtp, spam, srs, tls};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -7,16 +12,713 @@ struct Args {
     #[arg(env, long, default_value = "0.0.0.0:25")]
     smtp_listen_address: String,
 
+    /// Also accept mail over LMTP on this address
+    #[arg(env, long)]
+    lmtp_listen_address: Option<String>,
+
+    /// Serve users' OpenPGP keys over the Web Key Directory on this address,
+    /// behind a proxy that terminates TLS
+    #[arg(env, long)]
+    wkd_listen_address: Option<String>,
+
+    /// Let users manage their Sieve scripts over ManageSieve on this
+    /// address, usually port 4190
+    #[arg(env, long)]
+    managesieve_listen_address: Option<String>,
+
+    /// PEM certificate chain offered through STARTTLS. Without it,
+    /// ManageSieve takes passwords in the clear
+    #[arg(env, long, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+
+    /// PEM private key of the STARTTLS certificate
+    #[arg(env, long, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
+
+    /// Deliver accepted mail to this LMTP server (`host:port` or
+    /// `unix:/path`) instead of the SQLite store
+    #[arg(env, long)]
+    lmtp_delivery_address: Option<lmtp::LmtpAddress>,
+
+    /// Relay mail sent by Sieve redirects and auto-replies through this
+    /// SMTP smarthost (`host:port`)
+    #[arg(env, long)]
+    relay_host: Option<lmtp::LmtpAddress>,
+
     #[arg(env, long, default_value = "email.db")]
     sqlite_path: String,
+
+    /// Name this server gives itself to relays, LMTP servers and content
+    /// filters, and signs bounces with
+    #[arg(env, long, default_value = "localhost")]
+    hostname: String,
+
+    /// Keep message bodies as files under this directory instead of in the
+    /// database
+    #[arg(env, long)]
+    blob_path: Option<PathBuf>,
+
+    /// File holding the hex recovery key, needed to encrypt mail for users
+    /// who enabled encryption and to reset their passwords
+    #[arg(env, long)]
+    recovery_key_file: Option<PathBuf>,
+
+    /// Secret used to sign SRS rewritten envelope senders
+    #[arg(env, long, requires = "srs_domain")]
+    srs_secret: Option<String>,
+
+    /// Domain that SRS rewritten envelope senders are placed under
+    #[arg(env, long, requires = "srs_secret")]
+    srs_domain: Option<String>,
+
+    /// Largest accepted message in bytes, zero for no limit
+    #[arg(env, long, default_value_t = smtp::config::DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: usize,
+
+    /// Message bodies larger than this many bytes are spooled to disk
+    #[arg(env, long, default_value_t = email_server_core::body::DEFAULT_SPOOL_THRESHOLD)]
+    spool_threshold: usize,
+
+    /// Also post every accepted message as JSON to this URL
+    #[arg(env, long)]
+    webhook_url: Option<String>,
+
+    /// Secret used to sign webhook requests
+    #[arg(env, long, default_value = "")]
+    webhook_secret: String,
+
+    /// Encrypt mail to recipients with a registered OpenPGP key
+    #[arg(env, long)]
+    pgp_encryption: bool,
+
+    /// Collect the keys of peers from the Autocrypt headers of inbound
+    /// mail, and announce users' keys in auto-replies and redirects
+    #[arg(env, long)]
+    autocrypt: bool,
+
+    /// Score incoming mail for spam and keep spam in the Junk folder
+    #[arg(env, long)]
+    spam_filter: bool,
+
+    /// Spam score from which mail goes to the Junk folder
+    #[arg(env, long, default_value_t = 5.0)]
+    spam_junk_score: f64,
+
+    /// Spam score from which mail is rejected, when it is that high for
+    /// all recipients; otherwise it goes to the Junk folder
+    #[arg(env, long)]
+    spam_reject_score: Option<f64>,
+
+    /// Trust SPF, DKIM and DMARC results in Authentication-Results headers
+    /// added by this host, e.g. a filtering MTA in front
+    #[arg(env, long)]
+    spam_authserv_id: Option<String>,
+
+    /// Refuse clients listed on this DNS blocklist, given as
+    /// `ZONE[=CODE,...][*WEIGHT]`; repeat or separate with spaces for more
+    #[arg(env, long, value_delimiter = ' ')]
+    dnsbl: Vec<dnsbl::Blocklist>,
+
+    /// Refuse HELO and sender domains listed on this domain blocklist, in
+    /// the same form as --dnsbl
+    #[arg(env, long, value_delimiter = ' ')]
+    rhsbl: Vec<dnsbl::Blocklist>,
+
+    /// Weight of listings from which a client or domain is refused
+    #[arg(env, long, default_value_t = 1.0)]
+    dnsbl_threshold: f64,
+
+    /// Defer the first delivery attempt from unknown clients with a 451
+    #[arg(env, long)]
+    greylist: bool,
+
+    /// Seconds a greylisted client must wait before retrying
+    #[arg(env, long, default_value_t = 300)]
+    greylist_delay_secs: u64,
+
+    /// Seconds after the first attempt within which a retry is accepted
+    #[arg(env, long, default_value_t = 2 * 24 * 3600)]
+    greylist_retry_window_secs: u64,
+
+    /// Days passed senders and whitelisted clients are remembered
+    #[arg(env, long, default_value_t = 36)]
+    greylist_lifetime_days: u64,
+
+    /// Stop greylisting clients after this many successful retries; 0
+    /// never does
+    #[arg(env, long, default_value_t = 5)]
+    greylist_auto_whitelist: u32,
+
+    /// Never greylist clients in this network, e.g. 192.0.2.0/24; repeat
+    /// or separate with spaces for more
+    #[arg(env, long, value_delimiter = ' ')]
+    greylist_allow: Vec<greylist::IpNet>,
+
+    /// Pass mail through the content filter (milter) at this address:
+    /// host:port, inet:port@host or unix:/path; repeat or separate with
+    /// spaces for more, consulted in order
+    #[arg(env, long, value_delimiter = ' ')]
+    milter: Vec<milter::MilterAddress>,
+
+    /// Seconds a content filter may take over a command or a message
+    #[arg(env, long, default_value_t = 60)]
+    milter_timeout_secs: u64,
+
+    /// Accept mail when a content filter fails instead of deferring it
+    #[arg(env, long)]
+    milter_accept_on_failure: bool,
+
+    /// Temporarily reject all recipients with a 451
+    #[arg(env, long)]
+    maintenance: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the domains this server receives mail for
+    Domain {
+        #[command(subcommand)]
+        action: Action,
+    },
+    /// Manage the mailboxes on local domains
+    User {
+        #[command(subcommand)]
+        action: Action,
+    },
+    /// Manage address aliases and forwarding
+    Alias {
+        #[command(subcommand)]
+        action: AliasAction,
+    },
+    /// Manage the address unknown users of a domain are delivered to
+    CatchAll {
+        #[command(subcommand)]
+        action: PairAction,
+    },
+    /// Manage domains that are aliases of local domains
+    DomainAlias {
+        #[command(subcommand)]
+        action: PairAction,
+    },
+    /// Search stored mail, e.g. `from:alice subject:lunch after:2024-01-31`
+    Search {
+        /// Only search mail delivered to this address
+        #[arg(long)]
+        mailbox: Option<String>,
+        #[arg(required = true)]
+        query: Vec<String>,
+    },
+    /// Delete stored blobs that no message refers to any more
+    Gc {
+        /// Keep blobs unreferenced for less than this many seconds
+        #[arg(long, default_value_t = 3600)]
+        grace_secs: u64,
+    },
+    /// Manage encryption of stored mail
+    Encryption {
+        #[command(subcommand)]
+        action: EncryptionAction,
+    },
+    /// Manage the OpenPGP keys incoming mail is encrypted to
+    Pgp {
+        #[command(subcommand)]
+        action: PgpAction,
+    },
+    /// Manage the passwords users log in with
+    Password {
+        #[command(subcommand)]
+        action: PasswordAction,
+    },
+    /// Manage out-of-office replies
+    Vacation {
+        #[command(subcommand)]
+        action: VacationAction,
+    },
+    /// Move a stored message to another folder; moving mail into or out
+    /// of Junk trains the spam filter
+    Move { id: i64, folder: String },
+    /// Manage the Sieve scripts that filter users' mail
+    Sieve {
+        #[command(subcommand)]
+        action: SieveAction,
+    },
+    /// Bring the database schema up to date
+    Migrate {
+        /// Only list the migrations that would be applied
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    Add { name: String },
+    Remove { name: String },
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum AliasAction {
+    Add {
+        name: String,
+        target: String,
+    },
+    Remove {
+        name: String,
+        target: Option<String>,
+    },
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum EncryptionAction {
+    /// Print a new recovery key
+    GenerateRecoveryKey,
+    /// Encrypt the mail of an address with its login password, read from
+    /// stdin
+    Enable { address: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum PgpAction {
+    /// Register the binary or armored public key in a file
+    Import {
+        address: String,
+        file: PathBuf,
+    },
+    Remove {
+        address: String,
+    },
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum PasswordAction {
+    /// Set the password of an address, read from stdin. Encrypted mail is
+    /// rewrapped for it with the recovery key
+    Set {
+        address: String,
+    },
+    Remove {
+        address: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum VacationAction {
+    /// Reply to mail for an address with the text in a file, or on stdin
+    Set {
+        address: String,
+        file: Option<PathBuf>,
+        /// Defaults to the original subject with an `Auto:` prefix
+        #[arg(long)]
+        subject: Option<String>,
+        /// Days before the same sender is answered again
+        #[arg(long, default_value_t = 7, value_parser = clap::value_parser!(u64).range(1..))]
+        days: u64,
+    },
+    Remove {
+        address: String,
+    },
+    Show {
+        address: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SieveAction {
+    /// Store the script in a file, replacing the one with the same name
+    Put {
+        address: String,
+        name: String,
+        file: PathBuf,
+        /// Also make it the active script
+        #[arg(long)]
+        activate: bool,
+    },
+    /// Check that the script in a file compiles
+    Check {
+        file: PathBuf,
+    },
+    /// Make a script the active one, or turn filtering off without a name
+    Activate {
+        address: String,
+        name: Option<String>,
+    },
+    Get {
+        address: String,
+        name: String,
+    },
+    Delete {
+        address: String,
+        name: String,
+    },
+    List {
+        address: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PairAction {
+    Add { name: String, target: String },
+    Remove { name: String },
+    List,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     logging::setup();
     let args = Args::parse();
+    let recovery_key = args.recovery_key_file.as_ref().map(|path| {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .parse::<RecoveryKey>()
+            .unwrap()
+    });
+
+    if let Some(command) = args.command {
+        let mut store = SqliteStore::open(&args.sqlite_path).await.unwrap();
+        if let Some(path) = &args.blob_path {
+            store = store.with_blob_store(Arc::new(FsBlobStore::new(path)));
+        }
+        if let Some(key) = &recovery_key {
+            store = store.with_recovery_key(key.clone());
+        }
+        if !matches!(command, Command::Migrate { .. }) {
+            store.migrate().await.unwrap();
+        }
+        match command {
+            Command::Search { mailbox, query } => {
+                let query = query.join(" ").parse().unwrap();
+                for message in store.search(mailbox.as_deref(), &query).await.unwrap() {
+                    println!(
+                        "{}\t{}\t{}",
+                        message.id,
+                        message.metadata.from.unwrap_or_default(),
+                        message.metadata.subject.unwrap_or_default()
+                    );
+                }
+            }
+            Command::Gc { grace_secs } => {
+                let deleted = store
+                    .collect_garbage(Duration::from_secs(grace_secs))
+                    .await
+                    .unwrap();
+                println!("deleted {} blobs", deleted);
+            }
+            Command::Encryption { action } => match action {
+                EncryptionAction::GenerateRecoveryKey => {
+                    println!("{}", RecoveryKey::generate().to_hex())
+                }
+                EncryptionAction::Enable { address } => store
+                    .enable_encryption(&address, &read_password())
+                    .await
+                    .unwrap(),
+            },
+            Command::Pgp { action } => match action {
+                PgpAction::Import { address, file } => {
+                    let key = pgp::PublicKey::parse(&std::fs::read(file).unwrap()).unwrap();
+                    store.set_pgp_key(&address, key.to_bytes()).await.unwrap();
+                    println!("{} {}", address, key.fingerprint());
+                }
+                PgpAction::Remove { address } => store.remove_pgp_key(&address).await.unwrap(),
+                PgpAction::List => {
+                    for (address, key) in store.list_pgp_keys().await.unwrap() {
+                        match pgp::PublicKey::parse(&key) {
+                            Ok(key) => println!("{} {}", address, key.fingerprint()),
+                            Err(e) => println!("{} {}", address, e),
+                        }
+                    }
+                }
+            },
+            Command::Password { action } => match action {
+                PasswordAction::Set { address } => store
+                    .set_password(&address, &read_password())
+                    .await
+                    .unwrap(),
+                PasswordAction::Remove { address } => {
+                    store.remove_password(&address).await.unwrap()
+                }
+            },
+            Command::Move { id, folder } => {
+                if !store.move_message(id, &folder).await.unwrap() {
+                    eprintln!("No message {}", id);
+                    std::process::exit(1);
+                }
+            }
+            Command::Vacation { action } => match action {
+                VacationAction::Set {
+                    address,
+                    file,
+                    subject,
+                    days,
+                } => {
+                    let body = match file {
+                        Some(file) => std::fs::read_to_string(file).unwrap(),
+                        None => std::io::read_to_string(std::io::stdin()).unwrap(),
+                    };
+                    let responder = AutoResponder {
+                        subject,
+                        body,
+                        days,
+                    };
+                    store
+                        .set_auto_responder(&address, &responder)
+                        .await
+                        .unwrap();
+                }
+                VacationAction::Remove { address } => {
+                    store.remove_auto_responder(&address).await.unwrap()
+                }
+                VacationAction::Show { address } => {
+                    match store.auto_responder(&address).await.unwrap() {
+                        Some(responder) => {
+                            if let Some(subject) = responder.subject {
+                                println!("Subject: {}", subject);
+                            }
+                            println!("Days: {}\n\n{}", responder.days, responder.body);
+                        }
+                        None => println!("no auto-responder for {}", address),
+                    }
+                }
+            },
+            Command::Sieve { action } => match action {
+                SieveAction::Put {
+                    address,
+                    name,
+                    file,
+                    activate,
+                } => {
+                    let script = std::fs::read_to_string(file).unwrap();
+                    store
+                        .put_sieve_script(&address, &name, &script)
+                        .await
+                        .unwrap();
+                    if activate {
+                        store
+                            .activate_sieve_script(&address, Some(&name))
+                            .await
+                            .unwrap();
+                    }
+                }
+                SieveAction::Check { file } => {
+                    let script = std::fs::read_to_string(file).unwrap();
+                    if let Err(e) = sieve::Script::compile(&script) {
+                        println!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+                SieveAction::Activate { address, name } => store
+                    .activate_sieve_script(&address, name.as_deref())
+                    .await
+                    .unwrap(),
+                SieveAction::Get { address, name } => {
+                    match store.sieve_script(&address, &name).await.unwrap() {
+                        Some(script) => print!("{}", script),
+                        None => {
+                            println!("no script {}", name);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                SieveAction::Delete { address, name } => {
+                    store.delete_sieve_script(&address, &name).await.unwrap()
+                }
+                SieveAction::List { address } => {
+                    for (name, active) in store.list_sieve_scripts(&address).await.unwrap() {
+                        match active {
+                            true => println!("{} (active)", name),
+                            false => println!("{}", name),
+                        }
+                    }
+                }
+            },
+            Command::Migrate { dry_run } => {
+                println!("schema version {}", store.schema_version().await.unwrap());
+                let migrations = if dry_run {
+                    store.pending_migrations().await.unwrap()
+                } else {
+                    store.migrate().await.unwrap()
+                };
+                let verb = if dry_run { "would apply" } else { "applied" };
+                for migration in migrations {
+                    println!("{} {} ({})", verb, migration.version, migration.description);
+                }
+            }
+            Command::Domain { action } => match action {
+                Action::Add { name } => store.add_domain(&name).await.unwrap(),
+                Action::Remove { name } => store.remove_domain(&name).await.unwrap(),
+                Action::List => store
+                    .list_domains()
+                    .await
+                    .unwrap()
+                    .iter()
+                    .for_each(|d| println!("{}", d)),
+            },
+            Command::User { action } => match action {
+                Action::Add { name } => store.add_user(&name).await.unwrap(),
+                Action::Remove { name } => store.remove_user(&name).await.unwrap(),
+                Action::List => store
+                    .list_users()
+                    .await
+                    .unwrap()
+                    .iter()
+                    .for_each(|u| println!("{}", u)),
+            },
+            Command::Alias { action } => match action {
+                AliasAction::Add { name, target } => store.add_alias(&name, &target).await.unwrap(),
+                AliasAction::Remove { name, target } => {
+                    store.remove_alias(&name, target.as_deref()).await.unwrap()
+                }
+                AliasAction::List => store
+                    .list_aliases()
+                    .await
+                    .unwrap()
+                    .iter()
+                    .filter(|(name, _)| !name.starts_with('@'))
+                    .for_each(|(name, target)| println!("{} -> {}", name, target)),
+            },
+            Command::CatchAll { action } => match action {
+                PairAction::Add { name, target } => {
+                    store.set_catch_all(&name, &target).await.unwrap()
+                }
+                PairAction::Remove { name } => store.remove_catch_all(&name).await.unwrap(),
+                PairAction::List => store
+                    .list_aliases()
+                    .await
+                    .unwrap()
+                    .iter()
+                    .filter_map(|(name, target)| Some((name.strip_prefix('@')?, target)))
+                    .for_each(|(name, target)| println!("{} -> {}", name, target)),
+            },
+            Command::DomainAlias { action } => match action {
+                PairAction::Add { name, target } => {
+                    store.add_domain_alias(&name, &target).await.unwrap()
+                }
+                PairAction::Remove { name } => store.remove_domain_alias(&name).await.unwrap(),
+                PairAction::List => store
+                    .list_domain_aliases()
+                    .await
+                    .unwrap()
+                    .iter()
+                    .for_each(|(name, target)| println!("{} -> {}", name, target)),
+            },
+        }
+        return Ok(());
+    }
+
+    // Migrated before any listener opens the database, so that bodies moved
+    // out of it go to the configured blob store.
+    let mut store = SqliteStore::open(&args.sqlite_path).await.unwrap();
+    if let Some(path) = &args.blob_path {
+        store = store.with_blob_store(Arc::new(FsBlobStore::new(path)));
+    }
+    store.migrate().await.unwrap();
+    drop(store);
+
+    let mut config = smtp::ConfigBuilder::default();
+    config.hostname(args.hostname);
+    config.maintenance(args.maintenance);
+    config.pgp_encryption(args.pgp_encryption);
+    config.autocrypt(args.autocrypt);
+    if args.spam_filter {
+        config.spam(spam::SpamConfig {
+            junk_score: args.spam_junk_score,
+            reject_score: args.spam_reject_score,
+            authserv_id: args.spam_authserv_id,
+        });
+    }
+    if !args.dnsbl.is_empty() || !args.rhsbl.is_empty() {
+        let resolver = dnsbl::system_resolver()?;
+        let mut blocklists = dnsbl::Blocklists::new(resolver).with_threshold(args.dnsbl_threshold);
+        for list in args.dnsbl {
+            blocklists = blocklists.with_list(dnsbl::ListKind::Address, list);
+        }
+        for list in args.rhsbl {
+            blocklists = blocklists.with_list(dnsbl::ListKind::Domain, list);
+        }
+        config.blocklists(Arc::new(blocklists));
+    }
+    if args.greylist {
+        config.greylist(greylist::GreylistConfig {
+            delay: Duration::from_secs(args.greylist_delay_secs),
+            retry_window: Duration::from_secs(args.greylist_retry_window_secs),
+            lifetime: Duration::from_secs(args.greylist_lifetime_days * 24 * 3600),
+            auto_whitelist: args.greylist_auto_whitelist,
+            allow: args.greylist_allow,
+        });
+    }
+    if !args.milter.is_empty() {
+        config.milter(milter::MilterConfig {
+            milters: args.milter,
+            timeout: Duration::from_secs(args.milter_timeout_secs),
+            accept_on_failure: args.milter_accept_on_failure,
+        });
+    }
+    config.max_message_size(args.max_message_size);
+    config.spool_threshold(args.spool_threshold);
+    if let Some(address) = args.lmtp_delivery_address {
+        config.lmtp_delivery(address);
+    }
+    if let Some(address) = args.relay_host {
+        config.relay_host(address);
+    }
+    if let Some(url) = args.webhook_url {
+        config.webhook_url(url);
+    }
+    config.webhook_secret(args.webhook_secret);
+    if let Some(path) = args.blob_path {
+        config.blob_path(path);
+    }
+    if let Some(key) = recovery_key {
+        config.recovery_key(key);
+    }
+    if let (Some(secret), Some(domain)) = (args.srs_secret, args.srs_domain) {
+        config.srs(Arc::new(srs::Srs::new(secret, domain)));
+    }
+
+    let config = config.build().unwrap();
+    let smtp = email_server_core::smtp_server(
+        &*args.smtp_listen_address,
+        &*args.sqlite_path,
+        config.clone(),
+    );
+    let lmtp = async {
+        match &args.lmtp_listen_address {
+            Some(address) => {
+                email_server_core::lmtp_server(&**address, &*args.sqlite_path, config).await
+            }
+            None => Ok(()),
+        }
+    };
+    let wkd = async {
+        match &args.wkd_listen_address {
+            Some(address) => email_server_core::wkd_server(&**address, &*args.sqlite_path).await,
+            None => Ok(()),
+        }
+    };
+    let tls = match (&args.tls_cert_file, &args.tls_key_file) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key).unwrap()),
+        _ => None,
+    };
+    let managesieve = async {
+        match &args.managesieve_listen_address {
+            Some(address) => {
+                email_server_core::managesieve_server(&**address, &*args.sqlite_path, tls).await
+            }
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(smtp, lmtp, wkd, managesieve)?;
+    Ok(())
+}
 
-    email_server_core::smtp_server(&*args.smtp_listen_address, &*args.sqlite_path)
-        .await
-        .unwrap();
+/// Reads one line from stdin, so that passwords stay out of shell history.
+fn read_password() -> String {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    line.trim_end_matches(['\r', '\n']).to_string()
 }